@@ -0,0 +1,51 @@
+//! Compares the buffer capacities chosen from the sizes of the files being
+//! patched with a fixed capacity, the 8 KiB every buffer had before, on
+//! patches whose sizes are far from it.
+//!
+//! Run with `cargo bench --bench buffer_sizes`.
+
+mod common;
+
+use romhacks_testkit::{BpsBuilder, IpsBuilder, UpsBuilder, rom};
+
+/// The size of the ROM every patch is for, the most an IPS patch can reach,
+/// so that copying and writing it outweighs starting the process.
+const ROM_LEN: usize = 16 * 1024 * 1024;
+
+/// The fixed capacity the chosen ones are compared with.
+const FIXED: &str = "8192";
+
+fn main() {
+  let workspace = common::Workspace::new("buffer-sizes");
+  let source = rom::random(ROM_LEN, 1);
+  let roms = [workspace.write("rom.bin", &source)];
+
+  // A few bytes changed in a huge ROM, which is written over a copy of it.
+  let ips = (0..16).fold(IpsBuilder::new(), |ips, i| {
+    ips.hunk(i * (ROM_LEN as u32 / 16), b"hack")
+  });
+  // A change every 4 KiB, so that hunks are many and small.
+  let ups = (0..ROM_LEN / 4096).fold(UpsBuilder::new(&source), |ups, i| {
+    ups.hunk(i * 4096, &[0xFF; 16])
+  });
+  // A file rebuilt from start to end, mostly from the source.
+  let bps = BpsBuilder::new(&source)
+    .source_read(ROM_LEN / 2)
+    .target_read(&[0xFF; 4096])
+    .source_read(ROM_LEN / 2 - 4096);
+
+  let patches = [
+    ("sparse IPS", workspace.write("sparse.ips", &ips.build())),
+    ("dense UPS", workspace.write("dense.ups", &ups.build())),
+    ("rebuilding BPS", workspace.write("rebuilding.bps", &bps.build())),
+  ];
+  for (name, patch) in &patches {
+    workspace.bench_apply(&format!("{name}, sized buffers"), &roms, patch, &[]);
+    workspace.bench_apply(
+      &format!("{name}, {FIXED}-byte buffers"),
+      &roms,
+      patch,
+      &["--buffer-size", FIXED],
+    );
+  }
+}
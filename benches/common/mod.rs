@@ -0,0 +1,124 @@
+//! Times runs of the romhacks binary on generated files.
+//!
+//! The crate has no library target for a benchmark to link against, so the
+//! benchmarks run the binary Cargo built for them, like a user would. Each
+//! time includes starting the process, which is the same for every case.
+
+// Each benchmark compiles this module on its own and uses only part of it.
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use std::{env, fs, process};
+
+/// How many times each case runs. The median of the runs is reported.
+const RUNS: usize = 7;
+
+/// A directory for a benchmark's files, deleted when it's dropped.
+pub struct Workspace {
+  dir: PathBuf,
+}
+
+impl Workspace {
+  pub fn new(name: &str) -> Self {
+    let dir = env::temp_dir().join(format!("romhacks-bench-{name}-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    Self { dir }
+  }
+
+  /// Writes a file into the workspace and returns its path.
+  pub fn write(&self, name: &str, contents: &[u8]) -> PathBuf {
+    let path = self.dir.join(name);
+    fs::write(&path, contents).unwrap();
+    path
+  }
+
+  /// Creates a `format` patch named `name` that turns `rom` into `target`.
+  pub fn create(&self, name: &str, rom: &Path, target: &Path, format: &str) -> PathBuf {
+    let patch = self.dir.join(name);
+    let mut command = self.command();
+    command
+      .args(["create", "--format", format])
+      .arg("--rom")
+      .arg(rom)
+      .arg("--target")
+      .arg(target)
+      .arg("--output")
+      .arg(&patch);
+    run(&mut command);
+    patch
+  }
+
+  /// Applies `patch` to each of `roms` in a single run, with `options`
+  /// added to the command line, and prints the median time as `label`.
+  pub fn bench_apply(
+    &self,
+    label: &str,
+    roms: &[PathBuf],
+    patch: &Path,
+    options: &[&str],
+  ) -> Duration {
+    let mut times: Vec<Duration> = (0..RUNS)
+      .map(|index| self.apply(index, roms, patch, options))
+      .collect();
+    times.sort();
+    let median = times[RUNS / 2];
+    println!("{label:<48} {:>9.2} ms", median.as_secs_f64() * 1000.0);
+    median
+  }
+
+  /// Runs `romhacks apply` in an empty directory, which the patched files
+  /// and the manifest are written to, so that no run sees an earlier one's.
+  fn apply(&self, index: usize, roms: &[PathBuf], patch: &Path, options: &[&str]) -> Duration {
+    let out = self.dir.join(format!("run-{index}"));
+    fs::create_dir(&out).unwrap();
+    let mut command = self.command();
+    command
+      .current_dir(&out)
+      .args(["apply", "--no-cache", "--no-hooks"])
+      .args(["--hack-url", "https://example.org/", "--hack-version", "1.0"])
+      .arg("--patch")
+      .arg(patch)
+      .args(options);
+    for rom in roms {
+      command.arg("--rom").arg(rom);
+    }
+    let start = Instant::now();
+    run(&mut command);
+    let elapsed = start.elapsed();
+    fs::remove_dir_all(&out).unwrap();
+    elapsed
+  }
+
+  /// A command that runs romhacks with its cache, configuration and state
+  /// in the workspace.
+  fn command(&self) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_romhacks"));
+    command
+      .env("XDG_CACHE_HOME", self.dir.join("cache"))
+      .env("XDG_CONFIG_HOME", self.dir.join("config"))
+      .env("XDG_STATE_HOME", self.dir.join("state"));
+    command
+  }
+}
+
+/// Runs `command`, panicking with its error output if it fails.
+fn run(command: &mut Command) {
+  let output = command
+    .stdout(Stdio::null())
+    .stderr(Stdio::piped())
+    .output()
+    .unwrap();
+  assert!(
+    output.status.success(),
+    "romhacks failed: {}",
+    String::from_utf8_lossy(&output.stderr)
+  );
+}
+
+impl Drop for Workspace {
+  fn drop(&mut self) {
+    let _ = fs::remove_dir_all(&self.dir);
+  }
+}
@@ -0,0 +1,35 @@
+//! Applies a small Vcdiff patch to many ROMs in one run, where allocating
+//! the decoding buffers again for each ROM would outweigh the patching.
+//!
+//! Run with `cargo bench --bench many_small_patches`.
+
+mod common;
+
+use romhacks_testkit::rom;
+
+/// The size of each ROM, small enough that the buffers matter.
+const ROM_LEN: usize = 256 * 1024;
+
+/// How many ROMs the patch is applied to in the larger run.
+const ROMS: usize = 64;
+
+fn main() {
+  let workspace = common::Workspace::new("many-small-patches");
+  let source = rom::random(ROM_LEN, 1);
+  let target = rom::with_bytes(&source, ROM_LEN / 2, &[0xFF; 256]);
+  let roms: Vec<_> = (0..ROMS)
+    .map(|i| workspace.write(&format!("rom-{i}.bin"), &source))
+    .collect();
+  let target = workspace.write("target.bin", &target);
+  let patch = workspace.create("small.vcdiff", &roms[0], &target, "vcd");
+
+  let one = workspace.bench_apply("1 ROM", &roms[..1], &patch, &[]);
+  let all = workspace.bench_apply(&format!("{ROMS} ROMs"), &roms, &patch, &[]);
+  // Starting the process and reading the patch are shared by every ROM.
+  let each = all.saturating_sub(one) / (ROMS as u32 - 1);
+  println!(
+    "{:<48} {:>9.2} ms",
+    "each ROM after the first",
+    each.as_secs_f64() * 1000.0
+  );
+}
@@ -0,0 +1,107 @@
+/*
+ * The interface of romhacks plugins, which add patch formats.
+ *
+ * A plugin is a shared library in the "plugins" directory of romhacks's
+ * configuration directory, loaded by builds with the `plugins` feature. It
+ * exports romhacks_plugin(), which returns a descriptor of its format that
+ * lives as long as the library.
+ *
+ * ROMHACKS_PLUGIN_ABI_VERSION is raised whenever the structures below change,
+ * and plugins built against another version aren't loaded.
+ */
+
+#ifndef ROMHACKS_PLUGIN_H
+#define ROMHACKS_PLUGIN_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#if defined(_WIN32)
+#define ROMHACKS_PLUGIN_EXPORT __declspec(dllexport)
+#else
+#define ROMHACKS_PLUGIN_EXPORT __attribute__((visibility("default")))
+#endif
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define ROMHACKS_PLUGIN_ABI_VERSION 1
+
+/* The longest magic string a format can have. */
+#define ROMHACKS_PLUGIN_MAX_MAGIC_LEN 16
+
+/* Capability flags. */
+#define ROMHACKS_SOURCE_CHECKSUM (1u << 0) /* The patch records the ROM's checksum. */
+#define ROMHACKS_TARGET_CHECKSUM (1u << 1) /* The patch records the patched file's checksum. */
+#define ROMHACKS_PATCH_CHECKSUM (1u << 2)  /* The patch ends with a CRC32 of everything before it. */
+#define ROMHACKS_IN_PLACE (1u << 3)        /* The output starts as a copy of the ROM. */
+#define ROMHACKS_SEEKS_SOURCE (1u << 4)
+#define ROMHACKS_SEEKS_PATCH (1u << 5)
+#define ROMHACKS_SEEKS_OUTPUT (1u << 6)
+
+/* The statuses apply() returns. */
+#define ROMHACKS_OK 0
+#define ROMHACKS_STREAM_ERROR 1 /* A stream function failed; its error is reported. */
+#define ROMHACKS_BAD_PATCH 2
+#define ROMHACKS_WRONG_INPUT_FILE 3
+#define ROMHACKS_ALREADY_PATCHED 4
+#define ROMHACKS_UNSUPPORTED_FEATURE 5
+#define ROMHACKS_FILE_TOO_LARGE 6
+
+/*
+ * A stream passed to a plugin. Each function returns -1 if it fails, in which
+ * case apply() should return ROMHACKS_STREAM_ERROR. Only the output can be
+ * written to and resized.
+ */
+typedef struct RomhacksStream {
+  void *context;
+  /* Returns how many bytes were read, or 0 at the end of the stream. buf may
+     only be NULL if len is 0. */
+  ptrdiff_t (*read)(void *context, uint8_t *buf, size_t len);
+  /* Returns how many bytes were written. buf may only be NULL if len is 0. */
+  ptrdiff_t (*write)(void *context, const uint8_t *buf, size_t len);
+  /* Seeks like fseek with SEEK_SET (0), SEEK_CUR (1) or SEEK_END (2), and
+     returns the new position. */
+  int64_t (*seek)(void *context, int64_t offset, int32_t whence);
+  /* Truncates or extends the stream, returning 0. */
+  int32_t (*set_len)(void *context, uint64_t len);
+} RomhacksStream;
+
+typedef struct RomhacksStreams {
+  RomhacksStream rom;
+  RomhacksStream patch;
+  RomhacksStream output;
+  uint32_t rom_crc32;
+  uint32_t patch_crc32;
+  uint64_t patch_len;
+} RomhacksStreams;
+
+typedef struct RomhacksPlugin {
+  /* Must be ROMHACKS_PLUGIN_ABI_VERSION. */
+  uint32_t abi_version;
+  /* The name of the format on the command line, such as "aps": lowercase
+     ASCII letters and digits. */
+  const char *name;
+  /* The name of the format shown to users, in UTF-8. */
+  const char *display_name;
+  /* The magic string at the start of the format's patches. Formats whose
+     patches start with a built-in format's magic string are never used. */
+  const uint8_t *magic;
+  size_t magic_len;
+  /* A combination of the capability flags. */
+  uint32_t flags;
+  /* The largest file the format can address, in bytes, or 0 for no limit. */
+  uint64_t max_file_size;
+  /* Applies a patch, returning one of the statuses. May be called from any
+     thread. Plugins that leave it NULL aren't loaded. */
+  int32_t (*apply)(const RomhacksStreams *streams);
+} RomhacksPlugin;
+
+ROMHACKS_PLUGIN_EXPORT const RomhacksPlugin *romhacks_plugin(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -1,110 +1,1093 @@
+use crate::cache::DigestCache;
 use crate::crc::Crc32;
 use crate::error::prelude::*;
 use crate::io::prelude::*;
-use crate::patch::{bps, ips, ppf, ups, vcd};
-use crate::{filename, hack, manifest, patch};
-use fs_err as fs;
-use std::{ffi, io, path};
-use ulid::Ulid;
+use crate::render::{Stream, Style};
+use crate::{
+  blockmap, cache, chd, console, cue, dat, dirs, disc, filename, fs, hack, hooks, i18n, io, lookup,
+  manifest, mem, metadata, parts, patch, profile, report, sandbox, signature, temp, trim,
+};
+use std::borrow::Cow;
+use std::{ffi, fmt, path, time};
 
 #[derive(Clone, Debug, clap::Args)]
 pub struct Args {
-  #[arg(short, long)]
-  pub rom: path::PathBuf,
-  #[arg(short, long)]
-  pub patch: path::PathBuf,
+  /// The ROM to patch. Can be given more than once to apply the same patch
+  /// to several ROMs. For a disc image, give its CUE sheet to patch the BIN
+  /// file of the first track; a CUE sheet for the patched disc is written
+  /// next to the patched file. CHD files can only be patched when built with
+  /// the `chdman` feature and MAME's chdman is on the PATH. For a ROM split
+  /// into numbered parts, such as "game.1", "game.2" and so on, give the
+  /// directory they're in.
+  #[arg(short, long, required = true, num_args = 1..)]
+  pub rom: Vec<path::PathBuf>,
+  #[arg(short, long, required_unless_present = "patch_dir")]
+  pub patch: Option<path::PathBuf>,
+  /// Apply the patch in this directory whose expected source checksum
+  /// matches the ROM, such as one of a set of patches for each region of a
+  /// game. Only one ROM can be given, and exactly one patch must match it.
+  #[arg(long, value_name = "DIR", conflicts_with = "patch")]
+  pub patch_dir: Option<path::PathBuf>,
   #[command(flatten)]
   pub hack: hack::RomHack,
   #[arg(short, long)]
   pub no_backup: bool,
+  /// Clone the ROM to create the patched file instead of copying it, for
+  /// formats that patch in place. On filesystems with copy-on-write support,
+  /// such as Btrfs, XFS and APFS, only the modified parts of the file are
+  /// written.
+  #[arg(long)]
+  pub reflink: bool,
+  /// Also write a block map of the patched file, so that mirrors can verify
+  /// it chunk by chunk.
+  #[arg(long)]
+  pub blockmap: bool,
+  /// Also write a report of the formats, sizes, checksums and warnings of the
+  /// patching run next to the patched file, which `report show` can print.
+  #[arg(long, value_enum, value_name = "FORMAT", num_args = 0..=1, default_missing_value = "kdl")]
+  pub report: Option<report::Format>,
+  /// Also copy extended attributes (alternate data streams on Windows) from
+  /// the ROM to the patched file. Timestamps are always copied.
+  #[arg(long)]
+  pub preserve_xattrs: bool,
+  /// Hash the ROM again after patching to verify that it wasn't modified.
+  #[arg(long)]
+  pub paranoid: bool,
+  /// Hash the patched file again once it's been moved into place, to verify
+  /// that it was written intact. Its size is always checked.
+  #[arg(long)]
+  pub verify_output: bool,
+  /// With --verify-output, hash only the chunks of the patched file that the
+  /// patch changed and this percentage of the others, picked at random,
+  /// rather than the whole file. This is much faster for large files, but
+  /// only makes it likely that the file was written intact. Reports list it
+  /// as a sampled verification.
+  #[arg(
+    long,
+    value_name = "PERCENT",
+    requires = "verify_output",
+    value_parser = clap::value_parser!(u8).range(0..=100)
+  )]
+  pub verify_sample: Option<u8>,
+  /// Also write the patched file to this directory, under the same name,
+  /// such as to put it on an SD card while keeping it on disk. Can be given
+  /// more than once; the copies are written in a single pass over the
+  /// patched file. A copy that can't be written is reported with a warning
+  /// and doesn't stop the others.
+  #[arg(long, value_name = "DIR")]
+  pub also_write: Vec<path::PathBuf>,
+  /// Patch an IPS or IPS32 file even if it appears to have been patched
+  /// already, and a GBA APS file even if the checksums of its blocks don't
+  /// match, with a warning.
+  #[arg(long)]
+  pub force: bool,
+  /// Undo a PPF3 patch rather than apply it: restore the original ROM from
+  /// the undo data the patch holds, given the patched file as the ROM. The
+  /// restored ROM is named after the file the patch was applied to, if a
+  /// manifest next to the patched file records it, and isn't recorded in a
+  /// manifest itself.
+  #[arg(long, conflicts_with_all = ["trace_ops", "report", "name_template"])]
+  pub reverse: bool,
+  /// Before applying a PPF patch to a disc image, check that its hunks fall
+  /// inside the image and warn if it appears to be for an image with a
+  /// different sector size (2048-byte ISO or 2352-byte BIN).
+  #[arg(long)]
+  pub check_sectors: bool,
+  /// The sector size of the disc image the patch was made for. If the ROM
+  /// has the other sector size, the patch is applied to a converted copy of
+  /// it, which is converted back with regenerated error correction codes.
+  #[arg(long, value_name = "SIZE")]
+  pub patch_sectors: Option<disc::SectorSize>,
+  /// Pad a trimmed Nintendo DS, GameCube or Wii dump to its full size before
+  /// patching it. DS dumps are padded with 0xFF bytes and disc images with
+  /// zeroes.
+  #[arg(long)]
+  pub auto_pad: bool,
+  /// Trim the patched file again after padding it with --auto-pad, keeping
+  /// anything the patch wrote past the end of the ROM.
+  #[arg(long, requires = "auto_pad")]
+  pub restore_trim: bool,
+  /// Split the patched file of a ROM given as a directory of parts into
+  /// parts of the same sizes, rather than write it as one file.
+  #[arg(long)]
+  pub split_output: bool,
+  /// If patching fails, keep what was patched so far next to where the
+  /// patched file would have been, with ".partial" appended to its name.
+  #[arg(long)]
+  pub keep_temp: bool,
+  /// Write a line for each operation of the patch to this file as it's
+  /// applied: its command, offset and length, and the position in the output
+  /// after it. Only IPS, IPS32, UPS and PPF patches can be traced.
+  #[arg(long, value_name = "FILE")]
+  pub trace_ops: Option<path::PathBuf>,
+  /// Don't read or update the cache of previously computed checksums.
+  #[arg(long)]
+  pub no_cache: bool,
+  /// If the manifest records a different file with the ROM's name, keep the
+  /// patches it records under a "superseded" node and record patches from
+  /// the ROM as it is now, rather than refuse to patch it.
+  #[arg(long)]
+  pub reset_manifest_entry: bool,
+  /// A minisign public key to verify the patch with. Can be given more than
+  /// once. The patch must have a signature made with one of the keys, named
+  /// like the patch with ".sig" appended, or it won't be applied.
+  #[arg(long)]
+  pub trusted_key: Vec<path::PathBuf>,
+  /// Don't run the pre-apply and post-apply hooks set up in "hooks.kdl" in
+  /// the configuration directory.
+  #[arg(long)]
+  pub no_hooks: bool,
+  /// Before reading the patch, restrict romhacks to reading the ROMs, the
+  /// patch and its signature, and to writing files in the current directory
+  /// and the temporary and cache directories. Only supported on Linux, where
+  /// Landlock and seccomp are used. Programs can't be run in the sandbox, so
+  /// hooks aren't run and CHD files can't be patched.
+  #[arg(long)]
+  pub sandbox: bool,
+  /// The name of the patched file. The placeholders {name}, {hack},
+  /// {version} and {ext} are replaced with the game's name, the hack's name,
+  /// the hack's version and the original file's extension.
+  #[arg(long, default_value = filename::DEFAULT_NAME_TEMPLATE)]
+  pub name_template: String,
+  /// Set by `upgrade` to replace an older version of the hack that was
+  /// applied to the same ROM, rather than refuse to patch the ROM again.
+  #[arg(skip)]
+  pub upgrade: bool,
 }
 
 impl Args {
   pub fn call(self) -> Result<(), Error> {
-    let mut rom = fs::File::open(&self.rom)?;
-    let mut patch = fs::File::open(&self.patch)?;
+    let hooks = match self.no_hooks {
+      true => hooks::Hooks::default(),
+      false => hooks::Hooks::load()?,
+    };
+    let hooks = match self.sandbox && !hooks.is_empty() {
+      true => {
+        log::warn!("{}", i18n::text("romhacks::sandbox::no_hooks"));
+        hooks::Hooks::default()
+      }
+      false => hooks,
+    };
+    if self.sandbox {
+      self.enter_sandbox()?;
+    }
 
-    let patch_eof: u64 = patch.seek(io::SeekFrom::End(0))?;
+    let mut digest_cache = (!self.no_cache)
+      .then(|| DigestCache::load(cache::path()))
+      .transpose()?;
+    let selected = match &self.patch_dir {
+      Some(patch_dir) => Some(self.select_patch(patch_dir, digest_cache.as_mut())?),
+      None => None,
+    };
+    // Picking the patch hashes the ROM, so it isn't hashed again.
+    let (patch_path, known_digest) = match (&self.patch, &selected) {
+      (Some(patch), _) => (patch.as_path(), None),
+      (None, Some((patch, rom_digest))) => {
+        (patch.as_path(), Some((self.rom[0].as_path(), *rom_digest)))
+      }
+      (None, None) => unreachable!("clap requires --patch or --patch-dir"),
+    };
+
+    let parse_start = time::Instant::now();
+    let mut patch = patch::compression::open(patch_path)?;
+
+    let patch_eof: u64 = patch.known_len()?;
     assert!(patch_eof <= i64::MAX as u64);
-    patch.seek(io::SeekFrom::Start(0))?;
-    let (patch_kind, checksum_limit, patch_in_place) = match &patch.read_array::<3>()?[..] {
-      ips::MAGIC => (patch::Kind::IPS, patch_eof, true),
-      ups::MAGIC => (patch::Kind::UPS, patch_eof - 4, true),
-      bps::MAGIC => (patch::Kind::BPS, patch_eof - 4, false),
-      ppf::MAGIC => (patch::Kind::PPF, patch_eof, true),
-      vcd::MAGIC => (patch::Kind::VCD, patch_eof, false),
-      _ => {
-        return Err(Error::IO(io::Error::new(
-          io::ErrorKind::InvalidData,
-          "Unknown patch format",
-        )));
+    let Some(patch_kind) = patch::Kind::detect(&mut patch)? else {
+      if patch::xdelta1::detect(&mut patch)? {
+        return Err(patch::Error::Xdelta1.into());
       }
+      return Err(Error::IO(io::Error::new(
+        io::ErrorKind::InvalidData,
+        i18n::text("romhacks::apply::unknown_format"),
+      )));
+    };
+    // Fail on anything the patch says about itself before any ROM is hashed.
+    let prechecked = patch::precheck::patch(patch_kind, &mut patch)?;
+    let patch_digest = prechecked.digest;
+    if self.reverse && patch_kind != patch::Kind::PPF {
+      return Err(Error::CantReverse { format: patch_kind });
+    }
+
+    let signature = match self.trusted_key.is_empty() {
+      true => None,
+      false => {
+        let trusted_keys = (self.trusted_key.iter())
+          .map(|path| signature::PublicKey::read(path))
+          .collect::<Result<Vec<_>, _>>()?;
+        // Signatures cover the patch as it was published, compressed or not.
+        let verified =
+          signature::verify(&trusted_keys, patch_path, &mut fs::File::open(patch_path)?)?;
+        log::info!(
+          "{}",
+          i18n::format(
+            "romhacks::signature::verified",
+            &[
+              ("key_id", &verified.key_id),
+              ("comment", &verified.trusted_comment)
+            ]
+          )
+        );
+        Some(verified)
+      }
+    };
+
+    // When patching several ROMs, decode the patch once instead of parsing
+    // and validating it again for each ROM.
+    let decoded = match self.rom.len() > 1 && !self.reverse {
+      true => match patch::ops::DecodedPatch::decode(patch_kind, &mut patch) {
+        Ok(decoded) => Some(decoded),
+        Err(patch::Error::UnsupportedPatchFeature) => None,
+        Err(err) => return Err(err.into()),
+      },
+      false => None,
+    };
+    let patch_parse = report::Timing::since(report::Phase::PatchParse, parse_start, patch_eof);
+
+    let mut trace = self.trace_ops.as_ref().map(fs::File::create).transpose()?;
+    for rom_path in &self.rom {
+      let (parts, joined) = match rom_path.is_dir() {
+        true => {
+          let parts = parts::Parts::find(rom_path)?;
+          let joined = parts.join(rom_path)?;
+          (Some(parts), Some(joined))
+        }
+        false => (None, None),
+      };
+      let rom_path = joined
+        .as_ref()
+        .map_or(rom_path.as_path(), parts::Joined::path);
+      let chd = match chd::is_chd(rom_path)? {
+        true => Some(chd::extract(rom_path)?),
+        false => None,
+      };
+      let rom_path = chd.as_ref().map_or(rom_path, chd::Extracted::cue_path);
+      let cue =
+        match (rom_path.extension()).is_some_and(|ext| ext.eq_ignore_ascii_case(cue::EXTENSION)) {
+          true => Some(mem::try_init(cue::CueSheet::read(rom_path)?, |cue| {
+            cue.validate()
+          })?),
+          false => None,
+        };
+      let bin_path = cue.as_ref().map(|cue| cue.path_of(cue.first_track_file()));
+      let rom_path = bin_path.as_deref().unwrap_or(rom_path);
+      let job = Job {
+        args: &self,
+        rom_path,
+        rom_digest: (known_digest.filter(|&(path, _)| path == rom_path)).map(|(_, digest)| digest),
+        cue: cue.as_ref(),
+        patch_path,
+        patch_kind,
+        patch_digest,
+        prechecked: &prechecked,
+        patch_eof,
+        decoded: decoded.as_ref(),
+        signature: signature.as_ref(),
+        patch_parse,
+        hooks: &hooks,
+      };
+      let patched_path = job.run(&mut patch, digest_cache.as_mut(), trace.as_mut())?;
+      if let Some(chd) = &chd {
+        chd.compress(&patched_path)?;
+      }
+      if let Some(parts) = parts.as_ref().filter(|_| self.split_output) {
+        parts.split(&patched_path)?;
+      }
+    }
+    if let Some(digest_cache) = &digest_cache {
+      digest_cache.save()?;
+    }
+
+    Ok(())
+  }
+
+  /// The name of the file that patching the ROM at `rom_path` with the patch
+  /// at `patch_path` writes, rendered from --name-template.
+  pub fn output_name(
+    &self,
+    rom_path: &path::Path,
+    patch_path: &path::Path,
+  ) -> Result<String, filename::TemplateError> {
+    filename::render_template(
+      &self.name_template,
+      &filename::TemplateVars {
+        name: &filename::infer_game_name(rom_path).to_string_lossy(),
+        hack: &(self.hack.name.as_deref())
+          .map(Cow::Borrowed)
+          .unwrap_or_else(|| patch_path.file_stem().unwrap_or_default().to_string_lossy()),
+        version: self.hack.version.as_str(),
+        ext: &rom_path.extension().unwrap_or_default().to_string_lossy(),
+      },
+    )
+  }
+
+  /// Picks the patch in `patch_dir` whose expected source checksum matches
+  /// the ROM, and returns it with the ROM's checksum.
+  fn select_patch(
+    &self,
+    patch_dir: &path::Path,
+    digest_cache: Option<&mut DigestCache>,
+  ) -> Result<(path::PathBuf, Crc32), Error> {
+    let [rom_path] = self.rom.as_slice() else {
+      return Err(Error::PatchDirRoms);
     };
+    let mut rom = fs::File::open(rom_path)?;
+    let rom_len = rom.known_len()?;
+    let rom_digest = cache::read_and_hash(digest_cache, &mut rom)?;
+    let rom_name = rom_path.file_name().unwrap_or_default().to_string_lossy();
+    let mut matches: Vec<path::PathBuf> = lookup::scan(patch_dir)?
+      .into_iter()
+      .filter(|found| {
+        lookup::Verdict::new(&found.header, &rom_name, rom_len, rom_digest)
+          == lookup::Verdict::Applies
+      })
+      .map(|found| found.path)
+      .collect();
+    match matches.len() {
+      0 => Err(Error::NoMatchingPatch { dir: patch_dir.to_path_buf() }),
+      1 => {
+        let selected = matches.remove(0);
+        log::info!(
+          "{}",
+          i18n::format(
+            "romhacks::apply::selected_patch",
+            &[("path", &selected.display())]
+          )
+        );
+        Ok((selected, rom_digest))
+      }
+      _ => Err(Error::AmbiguousPatchDir { dir: patch_dir.to_path_buf(), matches }),
+    }
+  }
+
+  /// Restricts the process to the files patching the ROMs involves.
+  fn enter_sandbox(&self) -> Result<(), Error> {
+    let mut policy = sandbox::Policy::default();
+    for rom_path in &self.rom {
+      if rom_path.is_file() && chd::is_chd(rom_path)? {
+        return Err(Error::SandboxedChd { path: rom_path.clone() });
+      }
+      policy.read.push(rom_path.clone());
+      // The BIN files of a disc image are next to its CUE sheet.
+      if (rom_path.extension()).is_some_and(|ext| ext.eq_ignore_ascii_case(cue::EXTENSION)) {
+        policy.read.push(dirs::temp_dir_for(rom_path));
+      }
+    }
+    policy.read.extend(self.patch_dir.iter().cloned());
+    if let Some(patch) = &self.patch {
+      policy.read.push(patch.clone());
+      policy.read.push(signature::sidecar_path(patch));
+    }
+    policy.read.extend(self.trusted_key.iter().cloned());
+    // DAT files are read to name the dump a patch is for.
+    policy.read.push(dirs::config_dir());
+    // The patched file and its manifest are written to the current directory.
+    policy.write.push(path::PathBuf::from("."));
+    policy.write.push(dirs::temp_dir());
+    if !self.no_cache {
+      let cache_dir = dirs::cache_dir();
+      fs::create_dir_all(&cache_dir)?;
+      policy.write.push(cache_dir);
+    }
+    if let Some(trace_ops) = &self.trace_ops {
+      policy.write.push(dirs::temp_dir_for(trace_ops));
+    }
+    policy.write.extend(self.also_write.iter().cloned());
+    sandbox::enter(&policy)?;
+    Ok(())
+  }
+}
 
-    let rom_digest = Crc32::read_and_hash(&mut rom)?;
-    let patch_digest = Crc32::read_and_hash(&mut (&mut patch).take(checksum_limit))?;
+/// Applying the patch to one of the ROMs given on the command line.
+struct Job<'a> {
+  args: &'a Args,
+  rom_path: &'a path::Path,
+  /// The ROM's checksum, if it was already computed to pick the patch.
+  rom_digest: Option<Crc32>,
+  /// The CUE sheet that `rom_path` was found in, if one was given.
+  cue: Option<&'a cue::CueSheet>,
+  patch_path: &'a path::Path,
+  patch_kind: patch::Kind,
+  patch_digest: Crc32,
+  prechecked: &'a patch::precheck::Prechecked,
+  patch_eof: u64,
+  decoded: Option<&'a patch::ops::DecodedPatch>,
+  signature: Option<&'a signature::Verified>,
+  /// How long reading the patch took, which is shared by every job.
+  patch_parse: report::Timing,
+  hooks: &'a hooks::Hooks,
+}
+
+impl Job<'_> {
+  fn run(
+    &self,
+    patch: &mut fs::File,
+    mut digest_cache: Option<&mut DigestCache>,
+    trace: Option<&mut fs::File>,
+  ) -> Result<path::PathBuf, Error> {
+    let args = self.args;
+    let capabilities = self.patch_kind.capabilities();
+    // The ROM is only ever opened for reading.
+    let mut rom = io::retry(|| fs::OpenOptions::new().read(true).open(self.rom_path))?;
+    let rom_len = rom.known_len()?;
+    let rom_console = console::Rom::detect(&mut rom, self.rom_path)?;
+    // Whether the ROM is patched as it is, so that its size alone can rule it
+    // out. A ROM with a copier header is left to fail once it's been hashed,
+    // where the header can be pointed out.
+    let as_is = !args.auto_pad
+      && args.patch_sectors.is_none()
+      && rom_console.as_ref().is_none_or(|rom| rom.header.is_none());
+    self.prechecked.rom(rom_len, as_is)?;
+    let mut timings = vec![self.patch_parse];
+    let start = time::Instant::now();
+    let rom_digest = match self.rom_digest {
+      Some(rom_digest) => {
+        rom.seek(io::SeekFrom::Start(0))?;
+        rom_digest
+      }
+      None => cache::read_and_hash(digest_cache.as_deref_mut(), &mut rom)?,
+    };
+    timings.push(report::Timing::since(
+      report::Phase::SourceHash,
+      start,
+      rom_len,
+    ));
+    let mut warnings: Vec<String> = Vec::new();
+    if args.check_sectors && self.patch_kind == patch::Kind::PPF {
+      self.check_sectors(&mut rom, patch, &mut warnings)?;
+    }
+    let conversion = match args.patch_sectors {
+      Some(patch_sectors) => self.sector_conversion(&mut rom, patch_sectors)?,
+      None => None,
+    };
+    let padding = match rom_console.as_ref().and_then(trim::Trimmed::detect) {
+      Some(trimmed) if args.auto_pad => {
+        log::info!(
+          "{}",
+          i18n::format("romhacks::trim::padding", &[("trimmed", &trimmed)])
+        );
+        Some(trimmed)
+      }
+      Some(trimmed) => {
+        warn(
+          &mut warnings,
+          i18n::format("romhacks::trim::use_auto_pad", &[("trimmed", &trimmed)]),
+        );
+        None
+      }
+      None => None,
+    };
 
     let game_name: ffi::OsString = ffi::OsString::from(filename::infer_game_name(&rom.path()));
-    let manifest_path: ffi::OsString = {
-      let mut buf = ffi::OsString::from(&game_name);
-      buf.push(" (patched).romhacks.kdl");
-      buf
-    };
-    let mut doc = manifest::get_or_create(&manifest_path, &self.rom, rom_digest, patch_digest)?;
-
-    let mut temp_file: fs::File = {
-      let mut file_name = Ulid::new().to_string();
-      file_name.push_str(".tmp");
-      fs::OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(file_name)?
-    };
-    if patch_in_place {
-      // Some formats modify the file to be patched in place,
-      // rather than build up the result from scratch.
-      io::copy(&mut rom, &mut temp_file)?;
-    };
-
-    let patcher = patch::Patcher::from_patch_kind(patch_kind);
-    patcher.patch(
-      &mut rom,
-      &mut patch,
-      &mut temp_file,
-      rom_digest,
-      patch_digest,
-      patch_eof,
-    )?;
+    let patched_file_name: String = match args.reverse {
+      true => self.original_name(&game_name.to_string_lossy(), rom_digest),
+      false => args.output_name(rom.path(), self.patch_path)?,
+    };
+    // The manifest is kept next to the patched file.
+    let manifest_path = manifest::path_for(
+      self.rom_path,
+      &dirs::temp_dir_for(path::Path::new(&patched_file_name)),
+    );
+    if is_same_file(self.rom_path, path::Path::new(&patched_file_name)) {
+      return Err(Error::WouldOverwriteSource);
+    }
+    let hook_context = |report| hooks::Context {
+      rom: self.rom_path,
+      patch: self.patch_path,
+      output: path::Path::new(&patched_file_name),
+      manifest: &manifest_path,
+      hack: &args.hack,
+      report,
+    };
+    self
+      .hooks
+      .run(hooks::Event::PreApply, &hook_context(None))?;
+    // The ROM that --reverse restores is the file the manifest already
+    // records, so it isn't recorded again.
+    let doc = match args.reverse {
+      true => None,
+      false => Some(manifest::get_or_create(
+        &manifest_path,
+        &self.rom_path,
+        rom_digest,
+        self.patch_digest,
+        &(path::Path::new(&patched_file_name).file_name())
+          .unwrap_or_default()
+          .to_string_lossy(),
+        args.upgrade.then(|| args.hack.url.as_str()),
+        args.reset_manifest_entry,
+      )?),
+    };
+
+    // Some formats modify the file to be patched in place,
+    // rather than build up the result from scratch.
+    let start = time::Instant::now();
+    rom.seek(io::SeekFrom::Start(0))?;
+    let padded = match &padding {
+      Some(trimmed) => trim::Padded::new(io::Retrying(&mut rom), trimmed)?,
+      None => trim::Padded::unchanged(io::Retrying(&mut rom)),
+    };
+    let mut source = match conversion {
+      Some((rom_sectors, patch_sectors)) => {
+        disc::Converter::new(padded, rom_sectors, patch_sectors, None::<fs::File>)?
+      }
+      None => disc::Converter::unchanged(padded),
+    };
+    // Checksums in the patch are for the image it was made for.
+    let source_digest = match conversion.is_some() || padding.is_some() {
+      true => {
+        let digest = Crc32::read_and_hash(&mut source)?;
+        source.seek(io::SeekFrom::Start(0))?;
+        digest
+      }
+      false => rom_digest,
+    };
+    // The patched file is renamed into place, which only works within a
+    // filesystem.
+    let patched_path = path::Path::new(&patched_file_name);
+    let temp_dir = dirs::temp_dir_for(patched_path);
+    // Finding them is only a courtesy, so it isn't worth failing over.
+    let leftovers = temp::find_leftovers(&temp_dir, temp::STALE_AGE).unwrap_or_default();
+    if !leftovers.is_empty() {
+      warn(
+        &mut warnings,
+        i18n::format(
+          "romhacks::apply::leftover_temp_files",
+          &[("count", &leftovers.len()), ("dir", &temp_dir.display())],
+        ),
+      );
+    }
+    let reflink = args.reflink && conversion.is_none() && padding.is_none();
+    let mut temp_file = match (capabilities.in_place, reflink) {
+      (true, true) => io::SpooledTempBuffer::clone_of(self.rom_path, patched_path)?,
+      (true, false) => mem::try_init(
+        io::SpooledTempBuffer::for_output(profile::get().spool_threshold, patched_path),
+        |buf| io::copy(&mut source, buf),
+      )?,
+      (false, _) => io::SpooledTempBuffer::for_output(profile::get().spool_threshold, patched_path),
+    };
+
+    // Start from the beginning, so that where patching stops shows how far it got.
+    temp_file.seek(io::SeekFrom::Start(0))?;
+    let applied = match trace {
+      Some(trace) if patch::ops::decodes(self.patch_kind) => {
+        self.trace_patch(patch, &mut temp_file, source_digest, trace)
+      }
+      Some(_) => {
+        warn(
+          &mut warnings,
+          i18n::format(
+            "romhacks::apply::cant_trace",
+            &[("format", &self.patch_kind)],
+          ),
+        );
+        self.apply_patch(&mut source, patch, &mut temp_file, source_digest)
+      }
+      None => self.apply_patch(&mut source, patch, &mut temp_file, source_digest),
+    };
+    let applied = match applied {
+      Err(Error::Patching(patch::Error::WrongInputFile)) => {
+        Err(self.wrong_input_file(patch, &mut source, source_digest, rom_console.as_ref())?)
+      }
+      applied => applied,
+    };
+    let overlap = match applied {
+      Ok(overlap) => overlap,
+      Err(err) if args.keep_temp => {
+        keep_partial(temp_file, path::Path::new(&patched_file_name))?;
+        return Err(err);
+      }
+      Err(err) => return Err(err),
+    };
+    if let Some(overlap) = overlap.filter(patch::ops::Overlap::looks_patched) {
+      if !args.force {
+        return Err(Error::AppearsPatched { percent: overlap.percent() });
+      }
+      warn(
+        &mut warnings,
+        i18n::format(
+          "romhacks::apply::appears_patched",
+          &[("percent", &overlap.percent())],
+        ),
+      );
+    }
+    if let Some((rom_sectors, patch_sectors)) = conversion {
+      // Copy the headers of the ROM's sectors, since a cooked image doesn't
+      // have them.
+      let template = fs::File::open(self.rom_path)?;
+      temp_file.seek(io::SeekFrom::Start(0))?;
+      let mut converted =
+        io::SpooledTempBuffer::for_output(profile::get().spool_threshold, patched_path);
+      io::copy(
+        &mut disc::Converter::new(&mut temp_file, patch_sectors, rom_sectors, Some(template))?,
+        &mut converted,
+      )?;
+      temp_file = converted;
+    }
+    if let Some(trimmed) = padding.filter(|_| args.restore_trim) {
+      let len = trimmed.trimmed_len(&mut temp_file)?;
+      temp_file.set_len(len)?;
+      log::info!(
+        "{}",
+        i18n::format("romhacks::trim::restored", &[("len", &len)])
+      );
+    }
+    let patched_len = temp_file.seek(io::SeekFrom::End(0))?;
+    timings.push(report::Timing::since(
+      report::Phase::Apply,
+      start,
+      patched_len,
+    ));
 
-    log::info!("ROM patched successfully.");
+    log::info!(
+      "{}",
+      Stream::Stderr.paint(
+        Style::Ok,
+        i18n::text(match args.reverse {
+          true => "romhacks::apply::reversed",
+          false => "romhacks::apply::success",
+        })
+      )
+    );
 
+    let start = time::Instant::now();
     temp_file.seek(io::SeekFrom::Start(0))?;
     let patched_digest = Crc32::read_and_hash(&mut temp_file)?;
-    let patched_file_name: ffi::OsString = {
-      let mut buf = ffi::OsString::from(&game_name);
-      buf.push(" (patched)");
-      if let Some(ext) = rom.path().extension() {
-        buf.push(ext);
-      }
-      buf
-    };
-    manifest::update(
-      &mut doc,
-      &self.rom,
-      &self.patch,
-      self.hack,
-      rom_digest,
-      patch_digest,
-      patched_digest,
+    timings.push(report::Timing::since(
+      report::Phase::OutputHash,
+      start,
+      patched_len,
+    ));
+    // Sampled verification checks the patched file against its block map.
+    let block_map = match args.blockmap || args.verify_sample.is_some() {
+      true => {
+        temp_file.seek(io::SeekFrom::Start(0))?;
+        let mut block_map =
+          blockmap::BlockMap::compute(&mut temp_file, blockmap::DEFAULT_CHUNK_SIZE)?;
+        let source_len = source.seek(io::SeekFrom::End(0))?;
+        self.mark_changes(patch, source_len, conversion.is_some(), &mut block_map)?;
+        Some(block_map)
+      }
+      false => None,
+    };
+
+    let copies = match args.also_write.is_empty() {
+      true => Vec::new(),
+      false => write_copies(&mut temp_file, patched_path, &args.also_write)?,
+    };
+
+    let start = time::Instant::now();
+    temp_file.persist(&patched_file_name)?;
+    metadata::copy(
+      self.rom_path,
+      path::Path::new(&patched_file_name),
+      metadata::Options { extended_attributes: args.preserve_xattrs },
+    )?;
+    self.write_cue(patched_path)?;
+    timings.push(report::Timing::since(
+      report::Phase::Rename,
+      start,
+      patched_len,
+    ));
+
+    // Some filesystems, such as network shares and folders synced to the
+    // cloud, can lose data when a file is renamed, so the patched file is
+    // checked before the manifest records it.
+    let recheck = match (&block_map, args.verify_sample) {
+      (Some(block_map), Some(percent)) => Recheck::Sampled(block_map, percent),
+      _ if args.verify_output => Recheck::Full,
+      _ => Recheck::None,
+    };
+    let start = time::Instant::now();
+    let hashed = check_output(patched_path, patched_len, patched_digest, recheck)?;
+    match recheck {
+      Recheck::None => {}
+      Recheck::Full => timings.push(report::Timing::since(
+        report::Phase::OutputVerify,
+        start,
+        hashed,
+      )),
+      Recheck::Sampled(..) => timings.push(report::Timing::since(
+        report::Phase::OutputVerifySampled,
+        start,
+        hashed,
+      )),
+    }
+    for (dest, copy) in copies {
+      let persisted = copy.map_err(Error::from).and_then(|copy| {
+        copy.persist(&dest)?;
+        metadata::copy(
+          self.rom_path,
+          &dest,
+          metadata::Options { extended_attributes: args.preserve_xattrs },
+        )?;
+        self.write_cue(&dest)?;
+        check_output(&dest, patched_len, patched_digest, recheck).map(|_| ())
+      });
+      match persisted {
+        Ok(()) => log::info!(
+          "{}",
+          i18n::format(
+            "romhacks::apply::copy_written",
+            &[("path", &dest.display())]
+          )
+        ),
+        Err(err) => warn(
+          &mut warnings,
+          i18n::format(
+            "romhacks::apply::copy_failed",
+            &[("path", &dest.display()), ("error", &err)],
+          ),
+        ),
+      }
+    }
+    let rom_after = match args.paranoid {
+      true => {
+        // Hash the ROM again, bypassing the cache, to prove it wasn't modified.
+        // This is done before the manifest records the patched file, so that
+        // it never records a run whose ROM changed under it.
+        rom.seek(io::SeekFrom::Start(0))?;
+        let after = Crc32::read_and_hash(&mut rom)?;
+        if after != rom_digest {
+          return Err(Error::SourceModified { before: rom_digest, after });
+        }
+        log::info!(
+          "{}",
+          Stream::Stderr.paint(
+            Style::Ok,
+            i18n::format(
+              "romhacks::apply::source_unchanged",
+              &[
+                ("before", &format!("{:08X}", rom_digest.value())),
+                ("after", &format!("{:08X}", after.value())),
+              ]
+            )
+          )
+        );
+        Some(after)
+      }
+      false => None,
+    };
+
+    if let Some(mut doc) = doc {
+      manifest::update(
+        &mut doc,
+        self.rom_path,
+        self.patch_path,
+        path::Path::new(&patched_file_name),
+        args.hack.clone(),
+        rom_digest,
+        self.patch_digest,
+        patched_digest,
+        self.signature,
+      );
+      let manifest_string: String = doc.to_string();
+      fs::write(&manifest_path, &manifest_string)?;
+      println!("{manifest_string}");
+    }
+    if let Some(block_map) = block_map.filter(|_| args.blockmap) {
+      let map_path = blockmap::sidecar_path(path::Path::new(&patched_file_name));
+      fs::write(map_path, block_map.to_string())?;
+    }
+    let file = |path: &path::Path, size: u64, crc32: Crc32| report::File {
+      path: path.display().to_string(),
+      size,
+      crc32,
+    };
+    // Restoring a ROM with --reverse isn't a patching run to report on.
+    let report = (!args.reverse).then(|| report::Report {
+      format: self.patch_kind.to_string(),
+      rom: file(self.rom_path, rom_len, rom_digest),
+      rom_after,
+      patch: file(self.patch_path, self.patch_eof, self.patch_digest),
+      output: file(
+        path::Path::new(&patched_file_name),
+        patched_len,
+        patched_digest,
+      ),
+      hack_url: args.hack.url.to_string(),
+      hack_version: args.hack.version.clone(),
+      warnings,
+      timings,
+    });
+    if let (Some(report), Some(format)) = (&report, args.report) {
+      report.write(path::Path::new(&patched_file_name), format)?;
+    }
+
+    if let Some(digest_cache) = digest_cache {
+      digest_cache.insert(path::Path::new(&patched_file_name), patched_digest)?;
+    }
+    self
+      .hooks
+      .run(hooks::Event::PostApply, &hook_context(report.as_ref()))?;
+
+    Ok(patched_file_name.into())
+  }
+
+  /// Names the ROM that --reverse restores after the file the patch was
+  /// applied to, if a manifest next to the patched file records it, or else
+  /// after the game, so that it isn't named like a patched file.
+  fn original_name(&self, game_name: &str, rom_digest: Crc32) -> String {
+    let dir = dirs::temp_dir_for(self.rom_path);
+    // Finding it is only a courtesy, so it isn't worth failing over.
+    let name = match manifest::find_source(&dir, rom_digest, self.patch_digest) {
+      Ok(Some(name)) => name,
+      _ => {
+        let ext = self.rom_path.extension().unwrap_or_default();
+        format!("{game_name} (original).{}", ext.to_string_lossy())
+      }
+    };
+    filename::safe_file_name(&name).into_owned()
+  }
+
+  /// Writes a CUE sheet next to the patched file at `patched_path`, if the
+  /// ROM is a disc image.
+  fn write_cue(&self, patched_path: &path::Path) -> io::Result<()> {
+    let Some(cue) = self.cue else {
+      return Ok(());
+    };
+    let output_dir = (patched_path.parent())
+      .filter(|dir| !dir.as_os_str().is_empty())
+      .unwrap_or(path::Path::new("."));
+    let patched_cue = cue.rewrite(
+      cue.first_track_file(),
+      &patched_path.file_name().unwrap().to_string_lossy(),
+      output_dir,
     );
-    let manifest_string: String = doc.to_string();
-    fs::write(&manifest_path, &manifest_string)?;
-    println!("{manifest_string}");
+    fs::write(patched_path.with_extension(cue::EXTENSION), patched_cue)
+  }
+
+  /// Marks the chunks of `block_map` that the patch changed, or all of them
+  /// if that can't be known without applying it again, such as when the
+  /// patched file was converted to another sector size.
+  fn mark_changes(
+    &self,
+    patch: &mut fs::File,
+    source_len: u64,
+    converted: bool,
+    block_map: &mut blockmap::BlockMap,
+  ) -> Result<(), Error> {
+    if converted || !patch::ops::decodes(self.patch_kind) {
+      block_map.mark_changed(0..block_map.size());
+      return Ok(());
+    }
+    let mut stats = patch::stats::Statistics::default();
+    match self.decoded {
+      Some(decoded) => decoded.replay(&mut stats)?,
+      None => patch::ops::decode(self.patch_kind, patch, &mut stats)?,
+    }
+    for range in stats.ranges() {
+      block_map.mark_changed(range.clone());
+    }
+    if let Some(len) = stats.resize {
+      block_map.mark_changed(source_len.min(len)..source_len.max(len));
+    }
+    Ok(())
+  }
+
+  /// Patches `temp_file`, which holds a copy of `source` for formats that
+  /// patch in place. For IPS and IPS32 patches, returns how many of the bytes
+  /// they write already had their new values. With --reverse, the PPF patch's
+  /// undo data is written instead.
+  fn apply_patch(
+    &self,
+    source: &mut (impl Read + Seek),
+    patch: &mut fs::File,
+    temp_file: &mut io::SpooledTempBuffer,
+    source_digest: Crc32,
+  ) -> Result<Option<patch::ops::Overlap>, Error> {
+    if self.args.reverse {
+      patch::ppf::unpatch(temp_file, patch)?;
+      return Ok(None);
+    }
+    // IPS and IPS32 patches have no checksums, so the bytes each record
+    // overwrites are compared against its replacement bytes instead.
+    match (self.patch_kind, self.decoded) {
+      (patch::Kind::IPS | patch::Kind::IPS32, decoded) => {
+        let mut applier = patch::ops::Applier::verifying(&mut *temp_file);
+        match decoded {
+          Some(decoded) => decoded.replay(&mut applier)?,
+          None => patch::ops::decode(self.patch_kind, patch, &mut applier)?,
+        }
+        let overlap = applier.overlap();
+        temp_file.flush()?;
+        Ok(overlap)
+      }
+      (_, Some(decoded)) => {
+        decoded.apply(temp_file, source_digest)?;
+        Ok(None)
+      }
+      (_, None) => {
+        (patch::Patcher::from_patch_kind(self.patch_kind).strict(!self.args.force)).patch(
+          source,
+          patch,
+          temp_file,
+          source_digest,
+          self.patch_digest,
+          self.patch_eof,
+        )?;
+        Ok(None)
+      }
+    }
+  }
+
+  /// Describes how the ROM differs from the file the patch was made for,
+  /// naming that file if a DAT file lists it.
+  fn wrong_input_file(
+    &self,
+    patch: &mut fs::File,
+    source: &mut (impl Read + Seek),
+    source_digest: Crc32,
+    rom_console: Option<&console::Rom>,
+  ) -> Result<Error, Error> {
+    let header = patch::header::read(self.patch_kind, patch)?;
+    let database = dat::Database::load().unwrap_or_else(|err| {
+      log::warn!(
+        "{}",
+        i18n::format(
+          "romhacks::dat::unreadable",
+          &[("dir", &dat::dir().display()), ("error", &err)]
+        )
+      );
+      dat::Database::default()
+    });
+    let dump = match (header.source_size, header.source_crc32) {
+      (Some(size), Some(crc32)) => database.find(size, crc32).map(str::to_owned),
+      _ => None,
+    };
+    let size = source.seek(io::SeekFrom::End(0))?;
+    // Patches are sometimes made for the dump that DAT files list, which
+    // doesn't have the header that the ROM does.
+    let without_header = match (rom_console, header.source_size) {
+      (Some(rom), Some(expected_size))
+        if rom.header.is_some()
+          && size.checked_sub(rom.hashed_range().start) == Some(expected_size) =>
+      {
+        source.seek(io::SeekFrom::Start(rom.hashed_range().start))?;
+        let crc32 = Crc32::read_and_hash(source)?;
+        rom.header.filter(|_| header.source_crc32 == Some(crc32))
+      }
+      _ => None,
+    };
+    Ok(Error::WrongInputFile {
+      mismatch: Mismatch {
+        expected_size: header.source_size,
+        expected_crc32: header.source_crc32,
+        dump,
+        has_dats: !database.is_empty(),
+        size,
+        crc32: source_digest,
+        header: without_header,
+        byte_order: (rom_console.and_then(|rom| rom.byte_order))
+          .filter(|byte_order| *byte_order != console::ByteOrder::BigEndian),
+      },
+    })
+  }
 
-    let (temp_file, temp_file_name) = temp_file.into_parts();
-    drop(temp_file); // close the file prior to renaming
-    fs::rename(&temp_file_name, &patched_file_name)?;
+  /// Patches `temp_file` like [`Job::apply_patch`], writing each operation
+  /// to `trace` as it's applied.
+  fn trace_patch(
+    &self,
+    patch: &mut fs::File,
+    temp_file: &mut io::SpooledTempBuffer,
+    source_digest: Crc32,
+    trace: &mut fs::File,
+  ) -> Result<Option<patch::ops::Overlap>, Error> {
+    let mut trace = io::BufWriter::new(trace);
+    writeln!(trace, "# {}", self.rom_path.display())?;
+    let overlap = match (self.patch_kind, self.decoded) {
+      (patch::Kind::IPS | patch::Kind::IPS32, decoded) => {
+        let mut applier = patch::ops::Applier::verifying(&mut *temp_file);
+        let mut tracer = patch::trace::Tracer::new(&mut applier, &mut trace);
+        match decoded {
+          Some(decoded) => decoded.replay(&mut tracer)?,
+          None => patch::ops::decode(self.patch_kind, patch, &mut tracer)?,
+        }
+        applier.overlap()
+      }
+      (_, decoded) => {
+        let owned;
+        let decoded = match decoded {
+          Some(decoded) => decoded,
+          None => {
+            owned = patch::ops::DecodedPatch::decode(self.patch_kind, patch)?;
+            &owned
+          }
+        };
+        decoded.check_source(source_digest)?;
+        let mut applier = patch::ops::Applier::new(&mut *temp_file);
+        decoded.replay(&mut patch::trace::Tracer::new(&mut applier, &mut trace))?;
+        None
+      }
+    };
+    temp_file.flush()?;
+    trace.flush()?;
+    Ok(overlap)
+  }
+
+  /// Detects the sector size of the ROM. Returns the ROM's sector size and
+  /// `patch_sectors` if they differ and the ROM has to be converted.
+  fn sector_conversion(
+    &self,
+    rom: &mut fs::File,
+    patch_sectors: disc::SectorSize,
+  ) -> Result<Option<(disc::SectorSize, disc::SectorSize)>, Error> {
+    let image = disc::Image::detect(rom)?;
+    rom.seek(io::SeekFrom::Start(0))?;
+    let Some(image) = image else {
+      return Err(Error::NotADisc);
+    };
+    if image.sector_size == patch_sectors {
+      return Ok(None);
+    }
+    log::info!(
+      "{}",
+      i18n::format(
+        "romhacks::disc::converting",
+        &[("from", &image.sector_size), ("to", &patch_sectors)]
+      )
+    );
+    Ok(Some((image.sector_size, patch_sectors)))
+  }
 
+  /// Logs a warning for each sign that the PPF patch doesn't fit the ROM's
+  /// sector layout.
+  fn check_sectors(
+    &self,
+    rom: &mut fs::File,
+    patch: &mut fs::File,
+    warnings: &mut Vec<String>,
+  ) -> Result<(), Error> {
+    let image = disc::Image::detect(rom)?;
+    rom.seek(io::SeekFrom::Start(0))?;
+    let Some(image) = image else {
+      warn(
+        warnings,
+        i18n::text("romhacks::disc::not_a_disc").to_owned(),
+      );
+      return Ok(());
+    };
+    let ops: Cow<[patch::ops::Op<'static>]> = match self.decoded {
+      Some(decoded) => Cow::Borrowed(decoded.ops()),
+      None => {
+        let mut ops = Vec::new();
+        patch::ops::decode(self.patch_kind, patch, &mut ops)?;
+        Cow::Owned(ops)
+      }
+    };
+    let sector_warnings = disc::check_ppf(&image, &ops);
+    for warning in &sector_warnings {
+      warn(warnings, warning.to_string());
+    }
+    if sector_warnings.is_empty() {
+      log::info!(
+        "{}",
+        i18n::format(
+          "romhacks::disc::sectors_ok",
+          &[("sector_size", &image.sector_size)]
+        )
+      );
+    }
     Ok(())
   }
 }
@@ -118,7 +1101,205 @@ pub enum Error {
   #[error(transparent)]
   IO(#[from] io::Error),
   #[error(transparent)]
+  #[diagnostic(transparent)]
   Patching(#[from] patch::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  NameTemplate(#[from] filename::TemplateError),
+  #[error("{}", i18n::text("romhacks::apply::would_overwrite_source"))]
+  #[diagnostic(code(romhacks::apply::would_overwrite_source))]
+  WouldOverwriteSource,
+  #[error("{}", i18n::format(
+    "romhacks::apply::source_modified",
+    &[
+      ("before", &format!("{:08X}", before.value())),
+      ("after", &format!("{:08X}", after.value())),
+    ]
+  ))]
+  #[diagnostic(code(romhacks::apply::source_modified))]
+  SourceModified { before: Crc32, after: Crc32 },
+  #[error("{}", i18n::format(
+    "romhacks::apply::output_size_mismatch",
+    &[("path", &path.display()), ("expected", expected), ("found", found)]
+  ))]
+  #[diagnostic(
+    code(romhacks::apply::output_size_mismatch),
+    help("{}", i18n::text("romhacks::apply::output_corrupted::help"))
+  )]
+  OutputSizeMismatch {
+    path: path::PathBuf,
+    expected: u64,
+    found: u64,
+  },
+  #[error("{}", i18n::format(
+    "romhacks::apply::output_modified",
+    &[
+      ("path", &path.display()),
+      ("expected", &format!("{:08X}", expected.value())),
+      ("found", &format!("{:08X}", found.value())),
+    ]
+  ))]
+  #[diagnostic(
+    code(romhacks::apply::output_modified),
+    help("{}", i18n::text("romhacks::apply::output_corrupted::help"))
+  )]
+  OutputModified {
+    path: path::PathBuf,
+    expected: Crc32,
+    found: Crc32,
+  },
+  #[error("{}", i18n::format(
+    "romhacks::apply::output_chunks_modified",
+    &[("path", &path.display()), ("count", count), ("chunks", chunks)]
+  ))]
+  #[diagnostic(
+    code(romhacks::apply::output_chunks_modified),
+    help("{}", i18n::text("romhacks::apply::output_corrupted::help"))
+  )]
+  OutputChunksModified {
+    path: path::PathBuf,
+    count: usize,
+    chunks: usize,
+  },
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Signature(#[from] signature::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Cue(#[from] cue::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Chd(#[from] chd::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Parts(#[from] parts::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Hook(#[from] hooks::Error),
+  #[error("{}", i18n::text("romhacks::apply::not_a_disc"))]
+  #[diagnostic(code(romhacks::apply::not_a_disc))]
+  NotADisc,
+  #[error("{}", i18n::format("romhacks::apply::sandboxed_chd", &[("path", &path.display())]))]
+  #[diagnostic(code(romhacks::apply::sandboxed_chd))]
+  SandboxedChd { path: path::PathBuf },
+  #[error("{}", i18n::format("romhacks::apply::appears_patched_force", &[("percent", percent)]))]
+  #[diagnostic(code(romhacks::apply::appears_patched))]
+  AppearsPatched { percent: u64 },
+  #[error("{}", i18n::format("romhacks::apply::cant_reverse", &[("format", format)]))]
+  #[diagnostic(code(romhacks::apply::cant_reverse))]
+  CantReverse { format: patch::Kind },
+  #[error("{}", i18n::text("romhacks::apply::patch_dir_roms"))]
+  #[diagnostic(code(romhacks::apply::patch_dir_roms))]
+  PatchDirRoms,
+  #[error("{}", i18n::format("romhacks::apply::no_matching_patch", &[("dir", &dir.display())]))]
+  #[diagnostic(
+    code(romhacks::apply::no_matching_patch),
+    help("{}", i18n::text("romhacks::apply::no_matching_patch::help"))
+  )]
+  NoMatchingPatch { dir: path::PathBuf },
+  #[error("{}", i18n::format(
+    "romhacks::apply::ambiguous_patch_dir",
+    &[
+      ("dir", &dir.display()),
+      ("matches", &matches.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", ")),
+    ]
+  ))]
+  #[diagnostic(code(romhacks::apply::ambiguous_patch_dir))]
+  AmbiguousPatchDir {
+    dir: path::PathBuf,
+    matches: Vec<path::PathBuf>,
+  },
+  #[error("{}", i18n::text("romhacks::patch::wrong_input_file"))]
+  #[diagnostic(code(romhacks::patch::wrong_input_file), help("{mismatch}"))]
+  WrongInputFile { mismatch: Mismatch },
+}
+
+/// The file a patch was made for, as far as its header tells, and the ROM it
+/// was applied to instead.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mismatch {
+  pub expected_size: Option<u64>,
+  pub expected_crc32: Option<Crc32>,
+  /// The name of the expected file, if a DAT file lists it.
+  pub dump: Option<String>,
+  /// Whether any DAT files were read, to suggest adding some if not.
+  pub has_dats: bool,
+  pub size: u64,
+  pub crc32: Crc32,
+  /// The ROM's header, if the ROM is the file the patch was made for once
+  /// it's removed.
+  pub header: Option<console::Header>,
+  /// The order of the ROM's bytes, if it's a Nintendo 64 ROM that isn't
+  /// big-endian.
+  pub byte_order: Option<console::ByteOrder>,
+}
+
+impl fmt::Display for Mismatch {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let hex = |crc32: Crc32| format!("{:08X}", crc32.value());
+    if let Some(expected_crc32) = self.expected_crc32 {
+      let expected = match self.expected_size {
+        Some(size) => i18n::format(
+          "romhacks::apply::wrong_input::expected",
+          &[("crc32", &hex(expected_crc32)), ("size", &size)],
+        ),
+        None => i18n::format(
+          "romhacks::apply::wrong_input::expected_crc32",
+          &[("crc32", &hex(expected_crc32))],
+        ),
+      };
+      writeln!(f, "{expected}")?;
+      match (&self.dump, self.has_dats) {
+        (Some(dump), _) => writeln!(
+          f,
+          "{}",
+          i18n::format("romhacks::apply::wrong_input::dump", &[("dump", dump)])
+        )?,
+        (None, true) => writeln!(
+          f,
+          "{}",
+          i18n::text("romhacks::apply::wrong_input::unknown_dump")
+        )?,
+        (None, false) => writeln!(
+          f,
+          "{}",
+          i18n::format(
+            "romhacks::apply::wrong_input::no_dats",
+            &[("dir", &dat::dir().display())]
+          )
+        )?,
+      }
+    }
+    write!(
+      f,
+      "{}",
+      i18n::format(
+        "romhacks::apply::wrong_input::actual",
+        &[("crc32", &hex(self.crc32)), ("size", &self.size)]
+      )
+    )?;
+    if let Some(header) = self.header {
+      write!(
+        f,
+        "\n{}",
+        i18n::format(
+          "romhacks::apply::wrong_input::header",
+          &[("header", &i18n::text(header.name)), ("len", &header.len)]
+        )
+      )?;
+    }
+    if let Some(byte_order) = self.byte_order {
+      write!(
+        f,
+        "\n{}",
+        i18n::format(
+          "romhacks::apply::wrong_input::byte_order",
+          &[("byte_order", &byte_order)]
+        )
+      )?;
+    }
+    Ok(())
+  }
 }
 
 impl Error {
@@ -132,7 +1313,35 @@ impl Error {
         manifest::GetOrCreateError::ManifestOutdated => K::ManifestOutdated,
       },
       Error::IO(_) => K::IOError,
+      Error::Patching(patch::Error::Compression(patch::compression::Error::IO(_))) => K::IOError,
+      Error::Patching(patch::Error::Compression(_)) => K::BadArgument,
       Error::Patching(_) => K::Patching,
+      Error::NameTemplate(_) => K::BadArgument,
+      Error::WouldOverwriteSource => K::BadArgument,
+      Error::SourceModified { .. } => K::SourceModified,
+      Error::OutputSizeMismatch { .. }
+      | Error::OutputModified { .. }
+      | Error::OutputChunksModified { .. } => K::OutputCorrupted,
+      Error::AppearsPatched { .. } => K::AlreadyPatched,
+      Error::CantReverse { .. } => K::BadArgument,
+      Error::PatchDirRoms | Error::NoMatchingPatch { .. } | Error::AmbiguousPatchDir { .. } => {
+        K::BadArgument
+      }
+      Error::WrongInputFile { .. } => K::Patching,
+      Error::NotADisc => K::BadArgument,
+      Error::SandboxedChd { .. } => K::BadArgument,
+      Error::Signature(signature::Error::IO(_)) => K::IOError,
+      Error::Signature(_) => K::BadSignature,
+      Error::Cue(cue::Error::IO(_)) => K::IOError,
+      Error::Cue(_) => K::BadArgument,
+      Error::Chd(chd::Error::IO(_) | chd::Error::Chdman { .. }) => K::IOError,
+      Error::Chd(chd::Error::Mismatch { .. }) => K::Patching,
+      Error::Chd(_) => K::BadArgument,
+      Error::Parts(parts::Error::IO(_)) => K::IOError,
+      Error::Parts(_) => K::BadArgument,
+      Error::Hook(hooks::Error::IO(_) | hooks::Error::Spawn { .. }) => K::IOError,
+      Error::Hook(hooks::Error::Failed { .. } | hooks::Error::TimedOut { .. }) => K::HookFailed,
+      Error::Hook(_) => K::BadArgument,
     }
   }
 }
@@ -145,4 +1354,139 @@ pub enum ErrorKind {
   AlreadyPatched,
   ManifestOutdated,
   Patching,
+  BadArgument,
+  SourceModified,
+  BadSignature,
+  HookFailed,
+  OutputCorrupted,
+}
+
+/// How a patched file is checked once it's been moved into place, besides
+/// its size.
+#[derive(Clone, Copy, Debug)]
+enum Recheck<'a> {
+  None,
+  /// Hash the whole file.
+  Full,
+  /// Hash the chunks of the block map that the patch changed and this
+  /// percentage of the others.
+  Sampled(&'a blockmap::BlockMap, u8),
+}
+
+/// Checks the patched file at `path` and returns how many bytes of it were
+/// hashed.
+fn check_output(
+  path: &path::Path,
+  patched_len: u64,
+  patched_digest: Crc32,
+  recheck: Recheck<'_>,
+) -> Result<u64, Error> {
+  let len = fs::metadata(path)?.len();
+  if len != patched_len {
+    return Err(Error::OutputSizeMismatch {
+      path: path.to_owned(),
+      expected: patched_len,
+      found: len,
+    });
+  }
+  match recheck {
+    Recheck::None => Ok(0),
+    Recheck::Full => {
+      let found = Crc32::read_and_hash(&mut io::BufReader::new(fs::File::open(path)?))?;
+      if found != patched_digest {
+        return Err(Error::OutputModified {
+          path: path.to_owned(),
+          expected: patched_digest,
+          found,
+        });
+      }
+      Ok(len)
+    }
+    Recheck::Sampled(block_map, percent) => {
+      let sampled = block_map.verify_sampled(&mut fs::File::open(path)?, percent)?;
+      for mismatch in &sampled.mismatches {
+        log::error!("{mismatch}");
+      }
+      if !sampled.mismatches.is_empty() {
+        return Err(Error::OutputChunksModified {
+          path: path.to_owned(),
+          count: sampled.mismatches.len(),
+          chunks: sampled.chunks,
+        });
+      }
+      log::info!(
+        "{}",
+        i18n::format(
+          "romhacks::apply::output_sampled",
+          &[
+            ("path", &path.display()),
+            ("chunks", &sampled.chunks),
+            ("total", &block_map.chunks().len())
+          ]
+        )
+      );
+      Ok(sampled.bytes)
+    }
+  }
+}
+
+/// Writes the patched file to a temporary file in each of `dirs`, named
+/// after `patched`, in a single pass over it. Each copy is returned with
+/// where it should be moved to, or with the error that kept it from being
+/// written.
+fn write_copies(
+  temp_file: &mut io::SpooledTempBuffer,
+  patched: &path::Path,
+  dirs: &[path::PathBuf],
+) -> io::Result<Vec<(path::PathBuf, io::Result<io::SpooledTempBuffer>)>> {
+  let name = patched.file_name().unwrap_or_default();
+  let dests: Vec<path::PathBuf> = dirs.iter().map(|dir| dir.join(name)).collect();
+  // Each copy spills into its temporary file straight away.
+  let mut copies =
+    io::FanOut::new((dests.iter()).map(|dest| io::SpooledTempBuffer::for_output(0, dest)));
+  temp_file.seek(io::SeekFrom::Start(0))?;
+  let copied = io::copy(temp_file, &mut copies);
+  let copies = copies.into_results();
+  // If every copy failed, each has its own error. Otherwise, reading failed.
+  match copied {
+    Err(err) if copies.iter().any(Result::is_ok) => Err(err),
+    _ => Ok(dests.into_iter().zip(copies).collect()),
+  }
+}
+
+/// Keeps a partially patched file at the patched file's path with ".partial"
+/// appended, and logs where patching stopped.
+fn keep_partial(mut temp_file: io::SpooledTempBuffer, patched: &path::Path) -> io::Result<()> {
+  let offset = temp_file.stream_position()?;
+  let len = temp_file.known_len()?;
+  let mut partial_path = ffi::OsString::from(patched);
+  partial_path.push(".partial");
+  let partial_path = path::PathBuf::from(partial_path);
+  temp_file.persist(&partial_path)?;
+  log::error!(
+    "{}",
+    i18n::format(
+      "romhacks::apply::kept_partial",
+      &[
+        ("path", &partial_path.display()),
+        ("offset", &format!("{offset:#X}")),
+        ("len", &len),
+      ]
+    )
+  );
+  Ok(())
+}
+
+/// Logs a warning and keeps it for the report.
+fn warn(warnings: &mut Vec<String>, message: String) {
+  log::warn!("{message}");
+  warnings.push(message);
+}
+
+/// Returns `true` if both paths exist and refer to the same file.
+fn is_same_file(a: &path::Path, b: &path::Path) -> bool {
+  match (fs::canonicalize(a), fs::canonicalize(b)) {
+    (Ok(a), Ok(b)) => a == b,
+    _ => false,
+  }
 }
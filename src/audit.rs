@@ -0,0 +1,45 @@
+//! A log of what the program did with the user's files, kept in the state
+//! directory so that it outlives any one command.
+//!
+//! Each entry is a KDL node on its own line, named after what happened and
+//! with the time it happened, in seconds since the Unix epoch:
+//!
+//! ```kdl
+//! launch time=1760000000 game="Game" file="Game (Hack v1.0).sfc" emulator="snes9x"
+//! ```
+
+use crate::{dirs, fs, io, kdl};
+use std::io::Write;
+use std::{path, time};
+
+/// The name of the file entries are appended to.
+const AUDIT_FILE_NAME: &str = "audit.kdl";
+
+// props
+const TIME: &str = "time";
+
+/// The path entries are appended to.
+pub fn path() -> path::PathBuf {
+  dirs::state_dir().join(AUDIT_FILE_NAME)
+}
+
+/// Appends an entry named `event` with the properties `props`.
+pub fn record(event: &str, props: &[(&str, &str)]) -> io::Result<()> {
+  let now = (time::SystemTime::now())
+    .duration_since(time::UNIX_EPOCH)
+    .unwrap_or_default();
+  let mut node = kdl::KdlNode::new(event);
+  node.insert(TIME, i128::from(now.as_secs()));
+  for (key, value) in props {
+    node.insert(*key, *value);
+  }
+  let path = path();
+  if let Some(dir) = path.parent() {
+    fs::create_dir_all(dir)?;
+  }
+  let mut file = fs::OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(&path)?;
+  writeln!(file, "{}", node.to_string().trim())
+}
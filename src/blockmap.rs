@@ -0,0 +1,336 @@
+use crate::error::prelude::*;
+use crate::io::prelude::*;
+use crate::render::{Stream, Style};
+use crate::{fs, i18n, io, kdl, mem};
+use sha2::{Digest, Sha256};
+use std::hash::BuildHasher;
+use std::ops::Range;
+use std::{collections, ffi, fmt, path};
+
+/// The extension appended to a file's name to get the name of its block map.
+pub const EXTENSION: &str = "blockmap";
+
+/// The number of bytes covered by each checksum in a block map.
+pub const DEFAULT_CHUNK_SIZE: u64 = 1024 * 1024;
+
+// nodes
+const BLOCKMAP: &str = "blockmap";
+const CHUNK: &str = "chunk";
+
+// props
+const CHUNK_SIZE: &str = "chunk-size";
+const SIZE: &str = "size";
+const CHANGED: &str = "changed";
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct Args {
+  #[command(subcommand)]
+  pub command: Command,
+}
+
+#[derive(Clone, Debug, clap::Subcommand)]
+pub enum Command {
+  /// Write a block map for a file.
+  Create {
+    file: path::PathBuf,
+    /// Where to write the block map. Defaults to the file's path with
+    /// ".blockmap" appended.
+    #[arg(short, long)]
+    map: Option<path::PathBuf>,
+  },
+  /// Check a file against its block map.
+  Verify {
+    file: path::PathBuf,
+    /// The block map to check against. Defaults to the file's path with
+    /// ".blockmap" appended.
+    #[arg(short, long)]
+    map: Option<path::PathBuf>,
+  },
+}
+
+impl Args {
+  pub fn call(self) -> Result<(), Error> {
+    match self.command {
+      Command::Create { file, map } => {
+        let map_path = map.unwrap_or_else(|| sidecar_path(&file));
+        let block_map = BlockMap::compute(&mut fs::File::open(&file)?, DEFAULT_CHUNK_SIZE)?;
+        fs::write(&map_path, block_map.to_string())?;
+        Ok(())
+      }
+      Command::Verify { file, map } => {
+        let map_path = map.unwrap_or_else(|| sidecar_path(&file));
+        let block_map: BlockMap = fs::read_to_string(&map_path)?.parse()?;
+        let mismatches = block_map.verify(&mut fs::File::open(&file)?)?;
+        for mismatch in &mismatches {
+          log::error!("{mismatch}");
+        }
+        if !mismatches.is_empty() {
+          return Err(Error::Mismatch(mismatches.len()));
+        }
+        log::info!(
+          "{}",
+          Stream::Stderr.paint(Style::Ok, i18n::text("romhacks::blockmap::valid"))
+        );
+        Ok(())
+      }
+    }
+  }
+}
+
+/// Returns the default path of the block map for the file at `path`.
+pub fn sidecar_path(path: &path::Path) -> path::PathBuf {
+  let mut buf = ffi::OsString::from(path);
+  buf.push(".");
+  buf.push(EXTENSION);
+  buf.into()
+}
+
+/// The SHA-256 digests of each fixed-size chunk of a file.
+///
+/// Block maps let mirroring and distribution tools verify a file, or find the
+/// parts of it that changed, without hashing or transferring the whole file.
+/// The block map of a patched file also marks the chunks the patch changed,
+/// which [`BlockMap::verify_sampled`] always hashes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockMap {
+  chunk_size: u64,
+  size: u64,
+  chunks: Vec<[u8; 32]>,
+  /// Whether each chunk was changed by a patch.
+  changed: Vec<bool>,
+}
+
+/// What [`BlockMap::verify_sampled`] found.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Sampled {
+  pub mismatches: Vec<Mismatch>,
+  /// How many chunks were hashed, and how many bytes they hold.
+  pub chunks: usize,
+  pub bytes: u64,
+}
+
+impl BlockMap {
+  /// Hashes `reader` from its current position to the end, in chunks of `chunk_size` bytes.
+  pub fn compute(reader: &mut impl Read, chunk_size: u64) -> io::Result<Self> {
+    assert!(chunk_size > 0);
+    let mut block_map = Self {
+      chunk_size,
+      size: 0,
+      chunks: Vec::new(),
+      changed: Vec::new(),
+    };
+    loop {
+      let mut hasher = Sha256::new();
+      let len = io::copy(&mut reader.by_ref().take(chunk_size), &mut hasher)?;
+      if len == 0 {
+        break;
+      }
+      block_map.size += len;
+      block_map.chunks.push(hasher.finalize().into());
+      block_map.changed.push(false);
+      if len < chunk_size {
+        break;
+      }
+    }
+    Ok(block_map)
+  }
+
+  /// Hashes `reader` and returns every chunk that doesn't match this block map.
+  pub fn verify(&self, reader: &mut impl Read) -> io::Result<Vec<Mismatch>> {
+    let actual = Self::compute(reader, self.chunk_size)?;
+    let mut mismatches: Vec<Mismatch> = (self.chunks.iter().zip(&actual.chunks))
+      .enumerate()
+      .filter(|(_, (expected, actual))| expected != actual)
+      .map(|(index, _)| Mismatch::Chunk { index, offset: index as u64 * self.chunk_size })
+      .collect();
+    if actual.size != self.size {
+      mismatches.push(Mismatch::Size { expected: self.size, actual: actual.size });
+    }
+    Ok(mismatches)
+  }
+
+  /// Hashes only the chunks of `reader` that are marked as changed and about
+  /// `percent` percent of the others, picked at random on each call, and
+  /// returns those that don't match. A file that matches has probably not
+  /// been modified, but unlike with [`BlockMap::verify`], that isn't certain.
+  pub fn verify_sampled(
+    &self,
+    reader: &mut (impl Read + Seek),
+    percent: u8,
+  ) -> io::Result<Sampled> {
+    let mut sampled = Sampled::default();
+    let size = reader.seek(io::SeekFrom::End(0))?;
+    if size != self.size {
+      sampled
+        .mismatches
+        .push(Mismatch::Size { expected: self.size, actual: size });
+      return Ok(sampled);
+    }
+    let random = collections::hash_map::RandomState::new();
+    for (index, expected) in self.chunks.iter().enumerate() {
+      if !self.changed[index] && random.hash_one(index) % 100 >= u64::from(percent) {
+        continue;
+      }
+      let offset = index as u64 * self.chunk_size;
+      reader.seek(io::SeekFrom::Start(offset))?;
+      let mut hasher = Sha256::new();
+      sampled.bytes += io::copy(&mut reader.by_ref().take(self.chunk_size), &mut hasher)?;
+      sampled.chunks += 1;
+      if hasher.finalize().as_slice() != expected {
+        sampled.mismatches.push(Mismatch::Chunk { index, offset });
+      }
+    }
+    Ok(sampled)
+  }
+
+  /// Marks the chunks that overlap `range` as changed. Bytes past the end of
+  /// the file are ignored.
+  pub fn mark_changed(&mut self, range: Range<u64>) {
+    let end = range.end.min(self.size);
+    if range.start >= end {
+      return;
+    }
+    let first = (range.start / self.chunk_size) as usize;
+    let last = ((end - 1) / self.chunk_size) as usize;
+    self.changed[first..=last].fill(true);
+  }
+
+  pub fn chunk_size(&self) -> u64 {
+    self.chunk_size
+  }
+
+  /// The size of the file the block map was computed from.
+  pub fn size(&self) -> u64 {
+    self.size
+  }
+
+  pub fn chunks(&self) -> &[[u8; 32]] {
+    &self.chunks
+  }
+}
+
+impl fmt::Display for BlockMap {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let mut doc = kdl::KdlDocument::new();
+    doc
+      .nodes_mut()
+      .push(mem::init(kdl::KdlNode::new(BLOCKMAP), |node| {
+        node.insert(CHUNK_SIZE, self.chunk_size as i128);
+        node.insert(SIZE, self.size as i128);
+        let children = node.ensure_children().nodes_mut();
+        for (chunk, changed) in self.chunks.iter().zip(&self.changed) {
+          children.push(mem::init(kdl::KdlNode::new(CHUNK), |node| {
+            node.insert(0, to_hex(chunk));
+            if *changed {
+              node.insert(CHANGED, true);
+            }
+          }));
+        }
+      }));
+    doc.autoformat();
+    write!(f, "{doc}")
+  }
+}
+
+impl std::str::FromStr for BlockMap {
+  type Err = ParseError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let doc: kdl::KdlDocument = s.parse()?;
+    let node = doc.get(BLOCKMAP).ok_or(ParseError::Malformed)?;
+    let get_u64 = |key: &str| -> Result<u64, ParseError> {
+      (node.get(key).and_then(|value| value.as_integer()))
+        .and_then(|value| u64::try_from(value).ok())
+        .ok_or(ParseError::Malformed)
+    };
+    let chunk_size = get_u64(CHUNK_SIZE)?;
+    let size = get_u64(SIZE)?;
+    if chunk_size == 0 {
+      return Err(ParseError::Malformed);
+    }
+    let children: &[kdl::KdlNode] = node.children().map_or(&[], |children| children.nodes());
+    let (chunks, changed) = (children.iter())
+      .filter(|child| child.name().value() == CHUNK)
+      .map(|child| {
+        let chunk = (child.get(0).and_then(|value| value.as_string()))
+          .and_then(from_hex)
+          .ok_or(ParseError::Malformed)?;
+        // Block maps that weren't written for a patched file don't mark
+        // any chunks.
+        let changed = match child.get(CHANGED) {
+          Some(value) => value.as_bool().ok_or(ParseError::Malformed)?,
+          None => false,
+        };
+        Ok((chunk, changed))
+      })
+      .collect::<Result<(Vec<[u8; 32]>, Vec<bool>), ParseError>>()?;
+    if chunks.len() as u64 != size.div_ceil(chunk_size) {
+      return Err(ParseError::Malformed);
+    }
+    Ok(Self { chunk_size, size, chunks, changed })
+  }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(str: &str) -> Option<[u8; 32]> {
+  if str.len() != 64 || !str.is_ascii() {
+    return None;
+  }
+  mem::try_init([0u8; 32], |bytes| {
+    for (i, byte) in bytes.iter_mut().enumerate() {
+      *byte = u8::from_str_radix(&str[i * 2..i * 2 + 2], 16).map_err(|_| ())?;
+    }
+    Ok::<(), ()>(())
+  })
+  .ok()
+}
+
+/// A difference between a file and its block map.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Mismatch {
+  /// The chunk at `index`, starting at byte `offset`, has a different digest.
+  Chunk { index: usize, offset: u64 },
+  /// The file's size is different.
+  Size { expected: u64, actual: u64 },
+}
+
+impl fmt::Display for Mismatch {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Mismatch::Chunk { index, offset } => f.write_str(&i18n::format(
+        "romhacks::blockmap::chunk_mismatch",
+        &[("index", index), ("offset", &format!("{offset:#X}"))],
+      )),
+      Mismatch::Size { expected, actual } => f.write_str(&i18n::format(
+        "romhacks::blockmap::size_mismatch",
+        &[("expected", expected), ("actual", actual)],
+      )),
+    }
+  }
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum ParseError {
+  #[error(transparent)]
+  Kdl(#[from] kdl::KdlError),
+  #[error("{}", i18n::text("romhacks::blockmap::malformed"))]
+  #[diagnostic(code(romhacks::blockmap::malformed))]
+  Malformed,
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Parse(#[from] ParseError),
+  #[error("{}", i18n::format("romhacks::blockmap::mismatch", &[("count", .0)]))]
+  #[diagnostic(code(romhacks::blockmap::mismatch))]
+  Mismatch(usize),
+}
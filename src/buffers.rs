@@ -0,0 +1,54 @@
+//! Choosing the capacities of buffered readers and writers from the sizes of
+//! the files being patched.
+//!
+//! A single capacity is too large for a patch of a few hundred bytes and too
+//! small for a patch of hundreds of megabytes. Unless `--buffer-size` is
+//! given, each buffer is sized for what it holds, between [`MIN_CAPACITY`]
+//! and the profile's maximum.
+
+use crate::profile;
+
+/// The smallest capacity of any buffer, which is also the smallest
+/// `--buffer-size`.
+pub const MIN_CAPACITY: usize = 512;
+
+/// Outputs are written in this many chunks, so that a large file isn't
+/// written a few kilobytes at a time.
+const OUTPUT_CHUNKS: u64 = 64;
+
+/// The capacity of a reader that reads a patch of `patch_len` bytes from
+/// start to end. Any capacity beyond the size of the patch would never be
+/// filled.
+pub fn patch_reader(patch_len: u64) -> usize {
+  choose(patch_len)
+}
+
+/// The capacity of a writer that writes the hunks of a patch of `patch_len`
+/// bytes over a copy of the ROM. Seeking flushes the buffer, so it only
+/// helps as far as hunks are contiguous, and contiguous hunks can't add up to
+/// more bytes than the patch has.
+pub fn hunk_writer(patch_len: u64) -> usize {
+  choose(patch_len)
+}
+
+/// The capacity of a writer that writes a file of about `output_len` bytes
+/// from start to end, as formats that rebuild the file do.
+pub fn output_writer(output_len: u64) -> usize {
+  choose(
+    output_len
+      .div_ceil(OUTPUT_CHUNKS)
+      .max(profile::get().buf_size as u64),
+  )
+}
+
+/// Clamps `ideal` to the profile's bounds, or returns the fixed capacity set
+/// with `--buffer-size`.
+fn choose(ideal: u64) -> usize {
+  let profile = profile::get();
+  match profile.adaptive_buffers {
+    true => usize::try_from(ideal)
+      .unwrap_or(usize::MAX)
+      .clamp(MIN_CAPACITY, profile.max_buf_size),
+    false => profile.buf_size,
+  }
+}
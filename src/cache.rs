@@ -0,0 +1,157 @@
+use crate::crc::Crc32;
+use crate::io::prelude::*;
+use crate::{dirs, fs, i18n, io, kdl, mem};
+use std::path;
+use std::time::UNIX_EPOCH;
+
+pub const FILE_NAME: &str = "romhacks.cache.kdl";
+
+/// Where the cache is kept: in the platform's cache directory, or in the
+/// current directory with `--portable`.
+pub fn path() -> path::PathBuf {
+  dirs::cache_dir().join(FILE_NAME)
+}
+
+// nodes
+const DIGEST: &str = "digest";
+
+// props
+const SIZE: &str = "size";
+const MTIME: &str = "mtime";
+const CRC_32: &str = "crc32";
+
+/// A record of previously computed file digests, keyed by each file's
+/// canonical path, size and modification time.
+///
+/// An entry is only used while the file's size and modification time are the
+/// same as when it was recorded, so modifying a file invalidates its entry.
+#[derive(Debug)]
+pub struct DigestCache {
+  path: path::PathBuf,
+  doc: kdl::KdlDocument,
+  dirty: bool,
+}
+
+impl DigestCache {
+  /// Loads the cache stored at `path`. If the file doesn't exist or can't be
+  /// parsed, the cache starts out empty.
+  pub fn load(path: impl Into<path::PathBuf>) -> io::Result<Self> {
+    let path = path.into();
+    let doc = match fs::read_to_string(&path) {
+      Ok(str) => str.parse::<kdl::KdlDocument>().unwrap_or_else(|err| {
+        log::warn!(
+          "{}",
+          i18n::format(
+            "romhacks::cache::malformed",
+            &[("path", &path.display()), ("error", &err)]
+          )
+        );
+        kdl::KdlDocument::new()
+      }),
+      Err(err) if err.kind() == io::ErrorKind::NotFound => kdl::KdlDocument::new(),
+      Err(err) => return Err(err),
+    };
+    Ok(Self { path, doc, dirty: false })
+  }
+
+  /// Returns the cached digest of `file` if it hasn't changed since it was hashed.
+  pub fn get(&self, file: &path::Path) -> io::Result<Option<Crc32>> {
+    let key = Key::new(file)?;
+    Ok(self.find(&key.path).and_then(|node| {
+      let matches = node.get(SIZE).and_then(|v| v.as_integer()) == Some(key.size)
+        && node.get(MTIME).and_then(|v| v.as_integer()) == Some(key.mtime);
+      matches
+        .then(|| node.get(CRC_32).and_then(|v| v.as_integer()))
+        .flatten()
+        .map(|crc32| Crc32::new(crc32 as u32))
+    }))
+  }
+
+  /// Records the digest of `file`, replacing any previous entry.
+  pub fn insert(&mut self, file: &path::Path, digest: Crc32) -> io::Result<()> {
+    let key = Key::new(file)?;
+    let nodes = self.doc.nodes_mut();
+    nodes.retain(|node| !Self::is_entry_for(node, &key.path));
+    nodes.push(mem::init(kdl::KdlNode::new(DIGEST), |node| {
+      node.insert(0, key.path);
+      node.insert(SIZE, key.size);
+      node.insert(MTIME, key.mtime);
+      node.insert(CRC_32, digest);
+    }));
+    self.dirty = true;
+    Ok(())
+  }
+
+  /// Writes the cache back to disk if any entries were added.
+  pub fn save(&self) -> io::Result<()> {
+    if self.dirty {
+      if let Some(dir) = self.path.parent() {
+        fs::create_dir_all(dir)?;
+      }
+      fs::write(&self.path, self.doc.to_string())?;
+    }
+    Ok(())
+  }
+
+  fn find(&self, path: &str) -> Option<&kdl::KdlNode> {
+    self
+      .doc
+      .nodes()
+      .iter()
+      .find(|node| Self::is_entry_for(node, path))
+  }
+
+  fn is_entry_for(node: &kdl::KdlNode, path: &str) -> bool {
+    node.name().value() == DIGEST && node.get(0).and_then(|v| v.as_string()) == Some(path)
+  }
+}
+
+/// The properties of a file that identify a cache entry.
+struct Key {
+  path: String,
+  size: i128,
+  mtime: i128,
+}
+
+impl Key {
+  fn new(file: &path::Path) -> io::Result<Self> {
+    let metadata = fs::metadata(file)?;
+    let mtime = metadata
+      .modified()?
+      .duration_since(UNIX_EPOCH)
+      .map_or(0, |duration| duration.as_nanos() as i128);
+    Ok(Self {
+      path: fs::canonicalize(file)?.to_string_lossy().into_owned(),
+      size: metadata.len().into(),
+      mtime,
+    })
+  }
+}
+
+/// Hashes `file`, or returns its cached digest if `cache` has an up-to-date
+/// entry for it. Newly computed digests are added to the cache.
+///
+/// The file's cursor is left at the start of the file.
+pub fn read_and_hash(cache: Option<&mut DigestCache>, file: &mut fs::File) -> io::Result<Crc32> {
+  let path = file.path().to_path_buf();
+  if let Some(digest) = cache
+    .as_ref()
+    .map(|cache| cache.get(&path))
+    .transpose()?
+    .flatten()
+  {
+    log::debug!(
+      "{}",
+      i18n::format("romhacks::cache::hit", &[("path", &path.display())])
+    );
+    file.seek(io::SeekFrom::Start(0))?;
+    return Ok(digest);
+  }
+  file.seek(io::SeekFrom::Start(0))?;
+  let digest = Crc32::read_and_hash(file)?;
+  file.seek(io::SeekFrom::Start(0))?;
+  if let Some(cache) = cache {
+    cache.insert(&path, digest)?;
+  }
+  Ok(digest)
+}
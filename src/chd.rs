@@ -0,0 +1,222 @@
+//! Recognizing disc images compressed as MAME CHD files, which can't be
+//! patched directly.
+//!
+//! With the `chdman` feature, and if MAME's `chdman` is on the `PATH`, a CD
+//! image is extracted to a BIN file and CUE sheet, patched, and compressed
+//! again. Otherwise, patching a CHD file fails with instructions for doing
+//! this by hand.
+
+use crate::crc::Crc32;
+use crate::error::prelude::*;
+use crate::io::prelude::*;
+use crate::{fs, i18n, io};
+use std::{env, ffi, path, process};
+
+/// The first bytes of every CHD file.
+pub const MAGIC: &[u8; 8] = b"MComprHD";
+
+/// The extension of CHD files.
+pub const EXTENSION: &str = "chd";
+
+/// The metadata tags that `chdman info` lists for CD images.
+const CD_METADATA_TAGS: [&str; 3] = ["'CHT2'", "'CHTR'", "'CHCD'"];
+
+/// Returns `true` if the file at `path` starts with the CHD magic number.
+pub fn is_chd(path: &path::Path) -> io::Result<bool> {
+  let mut file = fs::File::open(path)?;
+  let mut magic = [0u8; MAGIC.len()];
+  match file.read_exact(&mut magic) {
+    Ok(()) => Ok(&magic == MAGIC),
+    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+    Err(err) => Err(err),
+  }
+}
+
+/// A CD image extracted from a CHD file into a temporary directory, which is
+/// deleted when this is dropped.
+#[derive(Debug)]
+pub struct Extracted {
+  chdman: path::PathBuf,
+  dir: path::PathBuf,
+  cue: path::PathBuf,
+}
+
+impl Extracted {
+  /// The CUE sheet of the extracted image, which lists a single BIN file.
+  pub fn cue_path(&self) -> &path::Path {
+    &self.cue
+  }
+
+  /// Compresses the patched BIN file and the CUE sheet written next to it
+  /// into a CHD file with the same name, then deletes them. The new CHD file
+  /// is extracted again to verify that it holds the patched BIN file.
+  pub fn compress(&self, patched_bin: &path::Path) -> Result<path::PathBuf, Error> {
+    let patched_cue = patched_bin.with_extension(crate::cue::EXTENSION);
+    let patched_chd = patched_bin.with_extension(EXTENSION);
+    let expected = Crc32::read_and_hash(&mut fs::File::open(patched_bin)?)?;
+
+    run(
+      &self.chdman,
+      "createcd",
+      [
+        "-i".as_ref(),
+        patched_cue.as_os_str(),
+        "-o".as_ref(),
+        patched_chd.as_os_str(),
+      ],
+    )?;
+    run(
+      &self.chdman,
+      "verify",
+      ["-i".as_ref(), patched_chd.as_os_str()],
+    )?;
+    let round_trip = extract_cd(&self.chdman, &patched_chd)?;
+    let actual = Crc32::read_and_hash(&mut fs::File::open(round_trip.cue.with_extension("bin"))?)?;
+    if actual != expected {
+      fs::remove_file(&patched_chd)?;
+      return Err(Error::Mismatch { path: patched_chd, expected, actual });
+    }
+
+    fs::remove_file(patched_bin)?;
+    fs::remove_file(&patched_cue)?;
+    log::info!(
+      "{}",
+      i18n::format(
+        "romhacks::chd::compressed",
+        &[("path", &patched_chd.display())]
+      )
+    );
+    Ok(patched_chd)
+  }
+}
+
+impl Drop for Extracted {
+  fn drop(&mut self) {
+    if let Err(err) = fs::remove_dir_all(&self.dir) {
+      log::warn!("{err}");
+    }
+  }
+}
+
+/// Extracts the CD image in the CHD file at `path` so that it can be patched,
+/// after checking the checksums that the CHD file records.
+pub fn extract(path: &path::Path) -> Result<Extracted, Error> {
+  if !cfg!(feature = "chdman") {
+    return Err(Error::Unsupported { path: path.to_owned() });
+  }
+  let chdman = find_chdman().ok_or_else(|| Error::ChdmanMissing { path: path.to_owned() })?;
+  let info = run(&chdman, "info", ["-i".as_ref(), path.as_os_str()])?;
+  if !CD_METADATA_TAGS.iter().any(|tag| info.contains(tag)) {
+    return Err(Error::NotCd { path: path.to_owned() });
+  }
+  run(&chdman, "verify", ["-i".as_ref(), path.as_os_str()])?;
+  extract_cd(&chdman, path)
+}
+
+/// The commands that extract, patch and compress the image by hand.
+fn instructions(path: &path::Path) -> String {
+  let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+  i18n::format(
+    "romhacks::chd::instructions",
+    &[("path", &path.display()), ("stem", &stem)],
+  )
+}
+
+fn extract_cd(chdman: &path::Path, path: &path::Path) -> Result<Extracted, Error> {
+  let dir = env::temp_dir().join(format!("romhacks-{}", ulid::Ulid::new()));
+  fs::create_dir(&dir)?;
+  // Name the files after the CHD file, since the game's name is inferred
+  // from the name of the file being patched.
+  let stem = path.file_stem().unwrap_or_default();
+  let extracted = Extracted {
+    chdman: chdman.to_owned(),
+    cue: dir.join(stem).with_extension(crate::cue::EXTENSION),
+    dir,
+  };
+  let bin = extracted.cue.with_extension("bin");
+  // A single BIN file holds every track, so patches for the first track
+  // still line up with the start of the file.
+  run(
+    chdman,
+    "extractcd",
+    [
+      "-i".as_ref(),
+      path.as_os_str(),
+      "-o".as_ref(),
+      extracted.cue.as_os_str(),
+      "-ob".as_ref(),
+      bin.as_os_str(),
+    ],
+  )?;
+  Ok(extracted)
+}
+
+/// Searches the `PATH` for `chdman`.
+pub fn find_chdman() -> Option<path::PathBuf> {
+  let file_name = format!("chdman{}", env::consts::EXE_SUFFIX);
+  env::split_paths(&env::var_os("PATH")?)
+    .map(|dir| dir.join(&file_name))
+    .find(|path| path.is_file())
+}
+
+/// Runs a `chdman` command and returns what it printed.
+fn run<'a>(
+  chdman: &path::Path,
+  command: &str,
+  args: impl IntoIterator<Item = &'a ffi::OsStr>,
+) -> Result<String, Error> {
+  log::debug!("{} {command}", chdman.display());
+  let output = process::Command::new(chdman)
+    .arg(command)
+    .args(args)
+    .stdin(process::Stdio::null())
+    .output()?;
+  if !output.status.success() {
+    return Err(Error::Chdman {
+      command: command.to_owned(),
+      status: output.status,
+      stderr: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+    });
+  }
+  Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error("{}", i18n::format("romhacks::chd::unsupported", &[("path", &path.display())]))]
+  #[diagnostic(code(romhacks::chd::unsupported), help("{}", instructions(path)))]
+  Unsupported { path: path::PathBuf },
+  #[error("{}", i18n::format("romhacks::chd::chdman_missing", &[("path", &path.display())]))]
+  #[diagnostic(code(romhacks::chd::chdman_missing), help("{}", instructions(path)))]
+  ChdmanMissing { path: path::PathBuf },
+  #[error("{}", i18n::format("romhacks::chd::not_cd", &[("path", &path.display())]))]
+  #[diagnostic(code(romhacks::chd::not_cd))]
+  NotCd { path: path::PathBuf },
+  #[error("{}", i18n::format(
+    "romhacks::chd::chdman_failed",
+    &[("command", command), ("status", status), ("stderr", stderr)]
+  ))]
+  #[diagnostic(code(romhacks::chd::chdman_failed))]
+  Chdman {
+    command: String,
+    status: process::ExitStatus,
+    stderr: String,
+  },
+  #[error("{}", i18n::format(
+    "romhacks::chd::mismatch",
+    &[
+      ("path", &path.display()),
+      ("expected", &format!("{:08X}", expected.value())),
+      ("actual", &format!("{:08X}", actual.value())),
+    ]
+  ))]
+  #[diagnostic(code(romhacks::chd::mismatch))]
+  Mismatch {
+    path: path::PathBuf,
+    expected: Crc32,
+    actual: Crc32,
+  },
+}
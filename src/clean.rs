@@ -0,0 +1,93 @@
+//! Deleting the temporary files left behind by runs that crashed or were
+//! killed before they could delete them.
+
+use crate::error::prelude::*;
+use crate::{dirs, fs, i18n, info, io, temp};
+use std::{path, time};
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct Args {
+  /// The directories to clean. By default, the current directory and the
+  /// directory for temporary files are cleaned.
+  pub dirs: Vec<path::PathBuf>,
+  /// Only delete temporary files last written to at least this many minutes
+  /// ago. Newer ones may belong to a run that's still going.
+  #[arg(long, value_name = "MINUTES", default_value_t = temp::STALE_AGE.as_secs() / 60)]
+  pub older_than: u64,
+  /// List the temporary files that would be deleted without deleting them.
+  #[arg(short = 'n', long)]
+  pub dry_run: bool,
+}
+
+impl Args {
+  pub fn call(self) -> Result<(), Error> {
+    let min_age = time::Duration::from_secs(self.older_than.saturating_mul(60));
+    let dirs = match self.dirs.is_empty() {
+      true => [path::PathBuf::from("."), dirs::temp_dir()]
+        .into_iter()
+        .filter(|dir| dir.is_dir())
+        .collect(),
+      false => self.dirs,
+    };
+    let mut leftovers = Vec::new();
+    for dir in &dirs {
+      leftovers.extend(temp::find_leftovers(dir, min_age)?);
+    }
+    if leftovers.is_empty() {
+      log::info!("{}", i18n::text("romhacks::clean::none"));
+      return Ok(());
+    }
+
+    let mut rows = vec![vec![
+      i18n::text("romhacks::clean::file").to_owned(),
+      i18n::text("romhacks::clean::size").to_owned(),
+      i18n::text("romhacks::clean::age").to_owned(),
+    ]];
+    let mut freed = 0;
+    for leftover in &leftovers {
+      if !self.dry_run {
+        fs::remove_file(&leftover.path)?;
+      }
+      freed += leftover.len;
+      rows.push(vec![
+        leftover.path.display().to_string(),
+        info::format_size(leftover.len),
+        format_age(leftover.age),
+      ]);
+    }
+    info::print_table(&rows);
+    let key = match self.dry_run {
+      true => "romhacks::clean::would_delete",
+      false => "romhacks::clean::deleted",
+    };
+    log::info!(
+      "{}",
+      i18n::format(
+        key,
+        &[
+          ("count", &leftovers.len()),
+          ("size", &info::format_size(freed))
+        ]
+      )
+    );
+    Ok(())
+  }
+}
+
+/// Formats an age in the largest whole unit it has at least one of, from
+/// minutes to days.
+fn format_age(age: time::Duration) -> String {
+  let minutes = age.as_secs() / 60;
+  match minutes {
+    0..60 => format!("{minutes} min"),
+    60..1440 => format!("{} h", minutes / 60),
+    _ => format!("{} d", minutes / 1440),
+  }
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+}
@@ -1,15 +1,114 @@
-use crate::{apply, validate};
+use crate::{
+  apply, blockmap, clean, compare, create, dirs, doctor, explain, genpatch, identify, info, io,
+  launch, lookup, manifest, patch, precheck, preview, profile, rebase, render, report, split,
+  unpack, upgrade, validate,
+};
 
 #[derive(Clone, Debug, clap::Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
   #[command(subcommand)]
   pub command: CommandKind,
+  /// When to color the output.
+  #[arg(long, value_enum, default_value_t, global = true)]
+  pub color: render::ColorChoice,
+  #[command(flatten)]
+  pub profile: profile::Options,
+  #[command(flatten)]
+  pub dirs: dirs::Options,
+  #[command(flatten)]
+  pub format_options: patch::options::Args,
+  #[command(flatten)]
+  pub retry: io::RetryOptions,
 }
 
 #[derive(Clone, Debug, clap::Subcommand)]
 #[command(about)]
 pub enum CommandKind {
   Apply(apply::Args),
+  Blockmap(blockmap::Args),
+  /// Delete the temporary files left behind by runs that crashed or were
+  /// killed.
+  ///
+  /// Temporary files are named after the file they would have become, like
+  /// ".game.sfc.1234.romhacks.tmp". Only those last written to an hour or
+  /// more ago are deleted, unless --older-than is given.
+  Clean(clean::Args),
+  /// Compare a file with a known-good copy and list where they differ.
+  ///
+  /// Also suggests why they differ: a copier header, truncation, changed
+  /// headers or corruption.
+  Compare(compare::Args),
+  Create(create::Args),
+  /// Check the environment and the files to be patched for common problems.
+  ///
+  /// Looks for paths that aren't valid UTF-8, directories that can't be
+  /// written to, too little disk space for the patched files, missing
+  /// external tools and manifests that conflict with the ROMs. The exit
+  /// status is 9 if any problems are found.
+  Doctor(doctor::Args),
+  /// Describe the layout of a patch format.
+  ///
+  /// Lists the fields found at fixed offsets in every patch of the format,
+  /// such as its magic bytes, version and checksums, and what the program
+  /// can do with it. The description comes from the tables used to read
+  /// patches.
+  Explain(explain::Args),
+  /// Create a patch that replaces a byte pattern in a ROM.
+  ///
+  /// Every place the pattern is found is listed, and it must be found only
+  /// once unless --all is given. Matches that overlap an earlier one are
+  /// skipped with a warning.
+  Genpatch(genpatch::Args),
+  /// Print the format of a patch, for scripts.
+  ///
+  /// Prints one of ips, ups, bps, ppf1, ppf2, ppf3, vcd, rup, aps, aps-gba,
+  /// bsdiff, gdiff, ips32 or unknown, or the name of a format added by a
+  /// plugin. The exit status is 64 for unknown files, 65 for IPS, 66 for
+  /// UPS, 67 for BPS, 68 for PPF, 69 for VCDIFF, 70 for NINJA 2.0, 71 for
+  /// N64 APS, 72 for GBA APS, 73 for bsdiff, 74 for GDIFF, 75 for IPS32 and
+  /// 127 for plugin formats.
+  Identify(identify::Args),
+  Info(info::Args),
+  /// Open the newest patched file of a game in an emulator.
+  ///
+  /// The file is found in the game's manifest, and the emulator is the one
+  /// set up in "emulators.kdl" in the configuration directory for the file's
+  /// extension. Each launch is recorded in "audit.kdl" in the state
+  /// directory.
+  Launch(launch::Args),
+  Manifest(manifest::Args),
+  Match(lookup::Args),
+  /// Check a patch without applying it.
+  ///
+  /// Checks everything that can be known without writing anything or
+  /// hashing the ROMs: the patch's format and version, its own checksum, the
+  /// size limits of its format and the size of the ROMs it's for. `apply`
+  /// runs the same checks before it starts. The exit status is 6 if a check
+  /// fails.
+  Precheck(precheck::Args),
+  /// Show what a patch would change in a region of a ROM, as a hexdump.
+  ///
+  /// Only the parts of the patch that touch the region are applied, and
+  /// nothing is written. Rows the patch changes are shown before ("-") and
+  /// after ("+") patching.
+  Preview(preview::Args),
+  Rebase(rebase::Args),
+  Report(report::Args),
+  Split(split::Args),
+  /// Extract the patches and readmes from a hack's ZIP archive.
+  ///
+  /// Other files are skipped, and screenshots are only kept with
+  /// --keep-images. Files are extracted without their directories, with
+  /// names that are valid on this platform.
+  Unpack(unpack::Args),
+  /// Apply a newer version of a hack, checking the versions recorded in the
+  /// manifest.
+  ///
+  /// Refuses to apply the patch if the manifest already records the same or
+  /// a newer version of the hack with the same URL. If that version was the
+  /// last patch applied to the ROM, the new version replaces it in the
+  /// manifest.
+  Upgrade(upgrade::Args),
   Validate(validate::Args),
 }
@@ -0,0 +1,272 @@
+//! Comparing a patched file with a known-good copy, to tell whether they
+//! differ by a copier header, by truncation or by corrupted contents.
+
+use crate::console::COPIER_HEADER_LEN;
+use crate::error::prelude::*;
+use crate::fingerprint::Fingerprint;
+use crate::io::prelude::*;
+use crate::{fs, i18n, info, io};
+use std::{fmt, path};
+
+/// The number of bytes compared at a time.
+const CHUNK_LEN: u64 = 64 * 1024;
+
+/// Files that are at least this similar are likely dumps of the same game,
+/// even if one was overdumped or padded to twice the size of the other.
+const SAME_GAME_SIMILARITY: f64 = 0.5;
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct Args {
+  /// The file to check, such as a freshly patched ROM.
+  pub actual: path::PathBuf,
+  /// A known-good copy of the file.
+  pub expected: path::PathBuf,
+  /// Stop after finding this many ranges of differing bytes.
+  #[arg(short = 'n', long, default_value_t = 10)]
+  pub limit: usize,
+  /// The number of bytes of each range to print in hexadecimal.
+  #[arg(long, default_value_t = 16)]
+  pub preview: usize,
+  /// Also score how similar the files' contents are, even where bytes were
+  /// inserted or removed, to tell a different dump of the same game from a
+  /// different game.
+  #[arg(long)]
+  pub fuzzy: bool,
+}
+
+impl Args {
+  pub fn call(self) -> Result<(), Error> {
+    let mut actual = fs::File::open(&self.actual)?;
+    let mut expected = fs::File::open(&self.expected)?;
+    let (actual_len, expected_len) = (actual.known_len()?, expected.known_len()?);
+    let (differences, more) = differences(
+      &mut io::BufReader::new(&mut actual),
+      &mut io::BufReader::new(&mut expected),
+      self.limit,
+      self.preview,
+    )?;
+    if differences.is_empty() && actual_len == expected_len {
+      log::info!("{}", i18n::text("romhacks::compare::identical"));
+      return Ok(());
+    }
+    if !differences.is_empty() {
+      info::print_table(&table(&differences, more));
+    }
+    let diagnosis = match (differences.is_empty(), actual_len.abs_diff(expected_len)) {
+      (true, _) if !more => Diagnosis::Truncated { actual_len, expected_len },
+      (true, _) => Diagnosis::Corrupt,
+      (false, COPIER_HEADER_LEN) if copier_header(&mut actual, &mut expected)? => {
+        Diagnosis::CopierHeader { in_actual: actual_len > expected_len }
+      }
+      (false, 0)
+        if !more
+          && differences
+            .iter()
+            .all(|d| d.offset + d.len <= COPIER_HEADER_LEN) =>
+      {
+        Diagnosis::Header
+      }
+      (false, _) => Diagnosis::Corrupt,
+    };
+    log::warn!("{diagnosis}");
+    if self.fuzzy {
+      log::info!("{}", similarity(&mut actual, &mut expected)?);
+    }
+    Err(Error::Different { actual_len, expected_len })
+  }
+}
+
+/// A range of bytes that differ, and the first few bytes of each file there.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Difference {
+  pub offset: u64,
+  pub len: u64,
+  actual: Vec<u8>,
+  expected: Vec<u8>,
+}
+
+/// Finds up to `limit` ranges of differing bytes where both files have data,
+/// and whether there are more.
+fn differences(
+  actual: &mut impl Read,
+  expected: &mut impl Read,
+  limit: usize,
+  preview: usize,
+) -> io::Result<(Vec<Difference>, bool)> {
+  let mut found: Vec<Difference> = Vec::new();
+  let mut open: Option<Difference> = None;
+  let (mut actual_chunk, mut expected_chunk) = (Vec::new(), Vec::new());
+  let mut offset = 0u64;
+  loop {
+    actual_chunk.clear();
+    expected_chunk.clear();
+    actual
+      .by_ref()
+      .take(CHUNK_LEN)
+      .read_to_end(&mut actual_chunk)?;
+    expected
+      .by_ref()
+      .take(CHUNK_LEN)
+      .read_to_end(&mut expected_chunk)?;
+    let len = actual_chunk.len().min(expected_chunk.len());
+    let (a, e) = (&actual_chunk[..len], &expected_chunk[..len]);
+    // Most chunks are identical, and comparing slices is much faster than
+    // comparing byte by byte.
+    if a == e {
+      found.extend(open.take());
+    } else {
+      for (i, (&a, &e)) in a.iter().zip(e).enumerate() {
+        match (a != e, &mut open) {
+          (true, Some(difference)) => {
+            difference.len += 1;
+            if difference.actual.len() < preview {
+              difference.actual.push(a);
+              difference.expected.push(e);
+            }
+          }
+          (true, None) => {
+            if found.len() == limit {
+              return Ok((found, true));
+            }
+            open = Some(Difference {
+              offset: offset + i as u64,
+              len: 1,
+              actual: Vec::from_iter((preview > 0).then_some(a)),
+              expected: Vec::from_iter((preview > 0).then_some(e)),
+            });
+          }
+          (false, _) => found.extend(open.take()),
+        }
+      }
+    }
+    offset += len as u64;
+    if (len as u64) < CHUNK_LEN {
+      found.extend(open.take());
+      return Ok((found, false));
+    }
+  }
+}
+
+/// Whether the longer file is the shorter one with a copier header before it.
+fn copier_header(actual: &mut fs::File, expected: &mut fs::File) -> io::Result<bool> {
+  let (mut longer, mut shorter) = match actual.known_len()? > expected.known_len()? {
+    true => (actual, expected),
+    false => (expected, actual),
+  };
+  longer.seek(io::SeekFrom::Start(COPIER_HEADER_LEN))?;
+  shorter.seek(io::SeekFrom::Start(0))?;
+  // With a limit of 0, any difference is reported as there being more.
+  let (_, more) = differences(
+    &mut io::BufReader::new(&mut longer),
+    &mut io::BufReader::new(&mut shorter),
+    0,
+    0,
+  )?;
+  Ok(!more)
+}
+
+/// Fingerprints both files and describes how similar they are.
+fn similarity(actual: &mut fs::File, expected: &mut fs::File) -> io::Result<String> {
+  let fingerprint = |file: &mut fs::File| {
+    file.seek(io::SeekFrom::Start(0))?;
+    Fingerprint::read(&mut io::BufReader::new(file))
+  };
+  let (actual, expected) = (fingerprint(actual)?, fingerprint(expected)?);
+  let similarity = actual.similarity(&expected);
+  let key = match similarity >= SAME_GAME_SIMILARITY {
+    true => "romhacks::compare::similar",
+    false => "romhacks::compare::dissimilar",
+  };
+  Ok(i18n::format(
+    key,
+    &[
+      ("percent", &format!("{:.1}", similarity * 100.0)),
+      ("shared", &actual.shared_bytes(&expected)),
+    ],
+  ))
+}
+
+fn table(differences: &[Difference], more: bool) -> Vec<Vec<String>> {
+  let hex = |bytes: &[u8]| {
+    (bytes.iter())
+      .map(|byte| format!("{byte:02X}"))
+      .collect::<Vec<_>>()
+      .join(" ")
+  };
+  let header = [
+    "romhacks::compare::offset",
+    "romhacks::compare::length",
+    "romhacks::compare::actual",
+    "romhacks::compare::expected",
+  ];
+  let rows = differences.iter().map(|difference| {
+    vec![
+      format!("{:#X}", difference.offset),
+      difference.len.to_string(),
+      hex(&difference.actual),
+      hex(&difference.expected),
+    ]
+  });
+  let more = more.then(|| vec![i18n::text("romhacks::compare::more").to_owned()]);
+  std::iter::once(header.map(|key| i18n::text(key).to_owned()).to_vec())
+    .chain(rows)
+    .chain(more)
+    .collect()
+}
+
+/// The likely reason that two files differ.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Diagnosis {
+  /// One file is the start of the other.
+  Truncated {
+    actual_len: u64,
+    expected_len: u64,
+  },
+  /// One file is the other with a copier header before it.
+  CopierHeader {
+    in_actual: bool,
+  },
+  /// The files only differ in their first bytes, where headers are.
+  Header,
+  Corrupt,
+}
+
+impl fmt::Display for Diagnosis {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&match self {
+      Diagnosis::Truncated { actual_len, expected_len } if actual_len < expected_len => {
+        i18n::format(
+          "romhacks::compare::truncated",
+          &[("missing", &(expected_len - actual_len))],
+        )
+      }
+      Diagnosis::Truncated { actual_len, expected_len } => i18n::format(
+        "romhacks::compare::extended",
+        &[("extra", &(actual_len - expected_len))],
+      ),
+      Diagnosis::CopierHeader { in_actual: true } => {
+        i18n::text("romhacks::compare::copier_header_actual").to_owned()
+      }
+      Diagnosis::CopierHeader { in_actual: false } => {
+        i18n::text("romhacks::compare::copier_header_expected").to_owned()
+      }
+      Diagnosis::Header => {
+        i18n::format("romhacks::compare::header", &[("len", &COPIER_HEADER_LEN)])
+      }
+      Diagnosis::Corrupt => i18n::text("romhacks::compare::corrupt").to_owned(),
+    })
+  }
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error("{}", i18n::format(
+    "romhacks::compare::different",
+    &[("actual_len", actual_len), ("expected_len", expected_len)]
+  ))]
+  #[diagnostic(code(romhacks::compare::different))]
+  Different { actual_len: u64, expected_len: u64 },
+}
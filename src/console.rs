@@ -0,0 +1,396 @@
+//! Recognizing which console a ROM is for, and the rules that differ between
+//! consoles' dumps: the headers some dumps have and others don't, the order
+//! of their bytes, the checksums in their internal headers, how trimmed
+//! dumps are padded and which bytes DAT files hash.
+//!
+//! A ROM is recognized by its internal header where it has one, and failing
+//! that by its extension. Other modules ask for a [`Rom`] instead of
+//! checking for each console themselves.
+
+use crate::io;
+use crate::io::prelude::*;
+use std::ops::Range;
+use std::{fmt, path};
+
+/// The size of the header that copiers added to SNES and other dumps.
+pub const COPIER_HEADER_LEN: u64 = 512;
+
+/// The size of an iNES header.
+const INES_HEADER_LEN: u64 = 16;
+
+/// How much of the start of a ROM is read to recognize it: enough for a SNES
+/// HiROM header after a copier header.
+const PROBE_LEN: u64 = 0x1_0000 + COPIER_HEADER_LEN;
+
+/// The size of a GameCube disc image.
+const GAMECUBE_LEN: u64 = 1_459_978_240;
+/// The size of a single-layer Wii disc image.
+const WII_SINGLE_LAYER_LEN: u64 = 4_699_979_776;
+/// The size of a dual-layer Wii disc image.
+const WII_DUAL_LAYER_LEN: u64 = 8_511_160_320;
+
+const GAMECUBE_MAGIC_OFFSET: usize = 0x1C;
+const GAMECUBE_MAGIC: [u8; 4] = [0xC2, 0x33, 0x9F, 0x3D];
+const WII_MAGIC_OFFSET: usize = 0x18;
+const WII_MAGIC: [u8; 4] = [0x5D, 0x1C, 0x9E, 0xA3];
+
+/// The offset of the cartridge's capacity in a DS header, as a power of two
+/// times 128 KiB.
+const NDS_CAPACITY_OFFSET: usize = 0x14;
+/// The offset of the CRC-16 of the Nintendo logo in a DS header, which is the
+/// same for every game.
+const NDS_LOGO_CRC_OFFSET: usize = 0x15C;
+const NDS_LOGO_CRC: [u8; 2] = [0x56, 0xCF];
+/// Capacities above this are too large for any DS cartridge.
+const NDS_MAX_CAPACITY_SHIFT: u8 = 15;
+
+/// The first word of a Nintendo 64 ROM in big-endian order.
+const N64_MAGIC: [u8; 4] = [0x80, 0x37, 0x12, 0x40];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Console {
+  Nes,
+  Snes,
+  GameBoy,
+  GameBoyAdvance,
+  MegaDrive,
+  Nintendo64,
+  NintendoDs,
+  GameCube,
+  Wii,
+}
+
+impl Console {
+  pub const ALL: [Console; 9] = [
+    Console::Nes,
+    Console::Snes,
+    Console::GameBoy,
+    Console::GameBoyAdvance,
+    Console::MegaDrive,
+    Console::Nintendo64,
+    Console::NintendoDs,
+    Console::GameCube,
+    Console::Wii,
+  ];
+
+  /// The rules for this console's dumps.
+  pub fn policy(self) -> Policy {
+    const NONE: Policy = Policy {
+      extensions: &[],
+      checksums: &[],
+      trim_filler: None,
+      hash: HashRule::Whole,
+    };
+    match self {
+      Console::Nes => Policy {
+        extensions: &["nes"],
+        // No-Intro lists NES games without their iNES headers.
+        hash: HashRule::WithoutHeader,
+        ..NONE
+      },
+      Console::Snes => Policy {
+        extensions: &["sfc", "smc", "swc", "fig"],
+        checksums: &[Region {
+          name: "romhacks::console::snes_checksum",
+          range: 0x1C..0x20,
+        }],
+        hash: HashRule::WithoutHeader,
+        ..NONE
+      },
+      Console::GameBoy => Policy {
+        extensions: &["gb", "gbc"],
+        checksums: &[
+          Region {
+            name: "romhacks::console::header_checksum",
+            range: 0x14D..0x14E,
+          },
+          Region {
+            name: "romhacks::console::global_checksum",
+            range: 0x14E..0x150,
+          },
+        ],
+        ..NONE
+      },
+      Console::GameBoyAdvance => Policy {
+        extensions: &["gba"],
+        checksums: &[Region {
+          name: "romhacks::console::header_checksum",
+          range: 0xBD..0xBE,
+        }],
+        ..NONE
+      },
+      Console::MegaDrive => Policy {
+        extensions: &["md", "gen", "smd"],
+        checksums: &[Region {
+          name: "romhacks::console::global_checksum",
+          range: 0x18E..0x190,
+        }],
+        ..NONE
+      },
+      // The checksums are 8 bytes aligned to 8 bytes, so they're in the same
+      // place whatever the byte order.
+      Console::Nintendo64 => Policy {
+        extensions: &["z64", "n64", "v64"],
+        checksums: &[Region {
+          name: "romhacks::console::global_checksum",
+          range: 0x10..0x18,
+        }],
+        ..NONE
+      },
+      Console::NintendoDs => Policy {
+        extensions: &["nds"],
+        trim_filler: Some(0xFF),
+        ..NONE
+      },
+      // Real discs fill unused space with pseudorandom junk instead of
+      // zeroes, which can't be restored.
+      Console::GameCube => Policy { extensions: &["gcm"], trim_filler: Some(0), ..NONE },
+      Console::Wii => Policy { trim_filler: Some(0), ..NONE },
+    }
+  }
+}
+
+impl fmt::Display for Console {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      Console::Nes => "NES",
+      Console::Snes => "SNES",
+      Console::GameBoy => "Game Boy",
+      Console::GameBoyAdvance => "Game Boy Advance",
+      Console::MegaDrive => "Mega Drive",
+      Console::Nintendo64 => "Nintendo 64",
+      Console::NintendoDs => "Nintendo DS",
+      Console::GameCube => "GameCube",
+      Console::Wii => "Wii",
+    })
+  }
+}
+
+/// The rules for a console's dumps, which the commands that patch, hash and
+/// check ROMs follow.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Policy {
+  /// The extensions its dumps are given, in lowercase, for recognizing ROMs
+  /// without an internal header.
+  pub extensions: &'static [&'static str],
+  /// The checksums in its internal header, relative to the start of it,
+  /// which differ between hacks and the games they're made from.
+  pub checksums: &'static [Region],
+  /// The byte its trimmed dumps are padded with, for consoles whose dumps
+  /// are commonly trimmed.
+  pub trim_filler: Option<u8>,
+  pub hash: HashRule,
+}
+
+/// Which bytes of a dump DAT files list the checksum of.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HashRule {
+  Whole,
+  /// Everything after the dump's [`Header`], if it has one.
+  WithoutHeader,
+}
+
+/// A part of a ROM that differs between dumps of the same game.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Region {
+  /// The key of the region's name in the message catalog.
+  pub name: &'static str,
+  pub range: Range<usize>,
+}
+
+/// A header before the game's data, which some dumps of a game have and
+/// others don't.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Header {
+  /// The key of the header's name in the message catalog.
+  pub name: &'static str,
+  pub len: u64,
+}
+
+/// The order of the bytes in a Nintendo 64 ROM. Patches are usually made for
+/// big-endian ROMs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ByteOrder {
+  /// As the cartridge stores them, in ".z64" files.
+  BigEndian,
+  /// Each pair of bytes swapped, in ".v64" files.
+  ByteSwapped,
+  /// Each group of four bytes reversed, in some ".n64" files.
+  LittleEndian,
+}
+
+impl fmt::Display for ByteOrder {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      ByteOrder::BigEndian => "big-endian (.z64)",
+      ByteOrder::ByteSwapped => "byte-swapped (.v64)",
+      ByteOrder::LittleEndian => "little-endian (.n64)",
+    })
+  }
+}
+
+/// A ROM whose console was recognized.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Rom {
+  pub console: Console,
+  pub len: u64,
+  pub header: Option<Header>,
+  /// Where the internal header starts, if the ROM was recognized by it.
+  internal_header: Option<usize>,
+  /// The order of the ROM's bytes, for Nintendo 64 ROMs.
+  pub byte_order: Option<ByteOrder>,
+  /// The size of the untrimmed dump, for consoles whose dumps are trimmed.
+  pub full_len: Option<u64>,
+}
+
+impl Rom {
+  /// Recognizes the ROM in `file`, which is named `path`.
+  pub fn detect(file: &mut (impl Read + Seek), path: &path::Path) -> io::Result<Option<Self>> {
+    let len = file.seek(io::SeekFrom::End(0))?;
+    file.seek(io::SeekFrom::Start(0))?;
+    let mut start = Vec::new();
+    (&mut *file).take(PROBE_LEN).read_to_end(&mut start)?;
+    let extension = path
+      .extension()
+      .map(|extension| extension.to_string_lossy());
+    Ok(Self::recognize(&start, len, extension.as_deref()))
+  }
+
+  /// Recognizes a ROM of `len` bytes from its first bytes, `start`, or
+  /// failing that from its extension.
+  pub fn recognize(start: &[u8], len: u64, extension: Option<&str>) -> Option<Self> {
+    let rom = |console| Rom {
+      console,
+      len,
+      header: None,
+      internal_header: Some(0),
+      byte_order: None,
+      full_len: None,
+    };
+    let bytes = |offset: usize, n: usize| start.get(offset..offset + n);
+    if bytes(GAMECUBE_MAGIC_OFFSET, 4) == Some(&GAMECUBE_MAGIC) {
+      return Some(Rom {
+        full_len: Some(GAMECUBE_LEN),
+        ..rom(Console::GameCube)
+      });
+    }
+    if bytes(WII_MAGIC_OFFSET, 4) == Some(&WII_MAGIC) {
+      let full_len = match len <= WII_SINGLE_LAYER_LEN {
+        true => WII_SINGLE_LAYER_LEN,
+        false => WII_DUAL_LAYER_LEN,
+      };
+      return Some(Rom { full_len: Some(full_len), ..rom(Console::Wii) });
+    }
+    if bytes(NDS_LOGO_CRC_OFFSET, 2) == Some(&NDS_LOGO_CRC) {
+      let shift = start[NDS_CAPACITY_OFFSET];
+      let full_len = (shift <= NDS_MAX_CAPACITY_SHIFT).then(|| 0x20000 << shift);
+      return Some(Rom { full_len, ..rom(Console::NintendoDs) });
+    }
+    if start.starts_with(b"NES\x1A") {
+      return Some(Rom { header: Some(INES_HEADER), ..rom(Console::Nes) });
+    }
+    if let Some(byte_order) = n64_byte_order(start) {
+      return Some(Rom {
+        byte_order: Some(byte_order),
+        ..rom(Console::Nintendo64)
+      });
+    }
+    if bytes(0x104, 4) == Some(&[0xCE, 0xED, 0x66, 0x66]) {
+      return Some(rom(Console::GameBoy));
+    }
+    if bytes(0x100, 4) == Some(b"SEGA") {
+      return Some(rom(Console::MegaDrive));
+    }
+    if let Some(internal_header) = snes_internal_header(start, len) {
+      return Some(Rom {
+        header: copier_header(len),
+        internal_header: Some(internal_header),
+        ..rom(Console::Snes)
+      });
+    }
+    if start.get(0xB2) == Some(&0x96) {
+      return Some(rom(Console::GameBoyAdvance));
+    }
+
+    // Without an internal header, there's no telling where the checksums are.
+    let extension = extension?.to_ascii_lowercase();
+    let console = (Console::ALL.into_iter())
+      .find(|console| console.policy().extensions.contains(&extension.as_str()))?;
+    Some(Rom {
+      header: match console {
+        Console::Snes => copier_header(len),
+        _ => None,
+      },
+      internal_header: None,
+      ..rom(console)
+    })
+  }
+
+  pub fn policy(&self) -> Policy {
+    self.console.policy()
+  }
+
+  /// The parts of the ROM that differ between dumps of the same game, from
+  /// its start: its header, if it has one, and the checksums in its internal
+  /// header.
+  pub fn regions(&self) -> Vec<Region> {
+    let header =
+      (self.header).map(|header| Region { name: header.name, range: 0..header.len as usize });
+    let checksums = (self.internal_header.into_iter()).flat_map(|base| {
+      (self.policy().checksums.iter()).map(move |region| Region {
+        name: region.name,
+        range: base + region.range.start..base + region.range.end,
+      })
+    });
+    header.into_iter().chain(checksums).collect()
+  }
+
+  /// The bytes of the ROM that DAT files list the checksum of.
+  pub fn hashed_range(&self) -> Range<u64> {
+    match (self.policy().hash, self.header) {
+      (HashRule::WithoutHeader, Some(header)) => header.len.min(self.len)..self.len,
+      _ => 0..self.len,
+    }
+  }
+}
+
+const INES_HEADER: Header = Header {
+  name: "romhacks::console::ines_header",
+  len: INES_HEADER_LEN,
+};
+
+/// The copier header of a SNES dump, which it's assumed to have if its size
+/// is 512 bytes past a multiple of 1024.
+fn copier_header(len: u64) -> Option<Header> {
+  (len % 1024 == COPIER_HEADER_LEN).then_some(Header {
+    name: "romhacks::console::copier_header",
+    len: COPIER_HEADER_LEN,
+  })
+}
+
+/// Finds the internal header of a LoROM or HiROM SNES game, after a copier
+/// header if there is one, by checking that its checksum and complement add
+/// up.
+fn snes_internal_header(start: &[u8], len: u64) -> Option<usize> {
+  let copier_header = (len % 1024) as usize;
+  [0x7FC0, 0xFFC0]
+    .into_iter()
+    .map(|offset| copier_header + offset)
+    .find(|&header| {
+      let word = |offset: usize| {
+        (start.get(header + offset..header + offset + 2)).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+      };
+      matches!((word(0x1C), word(0x1E)), (Some(complement), Some(checksum)) if complement ^ checksum == 0xFFFF)
+    })
+}
+
+/// Recognizes the first word of a Nintendo 64 ROM in any byte order.
+fn n64_byte_order(start: &[u8]) -> Option<ByteOrder> {
+  let [a, b, c, d] = N64_MAGIC;
+  match start.get(..4)? {
+    word if word == N64_MAGIC => Some(ByteOrder::BigEndian),
+    word if word == [b, a, d, c] => Some(ByteOrder::ByteSwapped),
+    word if word == [d, c, b, a] => Some(ByteOrder::LittleEndian),
+    _ => None,
+  }
+}
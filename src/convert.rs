@@ -1,4 +1,5 @@
 use crate::error::prelude::*;
+use crate::i18n;
 
 pub mod prelude {
   pub use super::TryIntoBool;
@@ -19,5 +20,5 @@ impl TryIntoBool for u8 {
 }
 
 #[derive(Clone, Debug, Error)]
-#[error("Value couldn't be converted into a bool.")]
+#[error("{}", i18n::text("romhacks::convert::not_a_bool"))]
 pub struct TryIntoBoolError(pub(crate) ());
@@ -5,6 +5,23 @@ use std::sync;
 
 const BUF_SIZE: usize = 8 * 1024;
 
+/// The CRC-32 polynomial, with its bits reversed as the checksum is computed.
+const POLYNOMIAL: u32 = 0xEDB88320;
+
+/// x^(2^k) modulo the polynomial, for each k, which the checksums of
+/// concatenated data are computed with.
+const X_POW_2K: [u32; 32] = {
+  let mut table = [0u32; 32];
+  // x^1, with the bits reversed like the polynomial.
+  table[0] = 1 << 30;
+  let mut k = 1;
+  while k < 32 {
+    table[k] = multiply(table[k - 1], table[k - 1]);
+    k += 1;
+  }
+  table
+};
+
 #[repr(transparent)]
 #[derive(Clone, Copy, Debug, Ord, PartialOrd, Eq, PartialEq, Hash)]
 pub struct Crc32(u32);
@@ -18,6 +35,30 @@ impl Crc32 {
     self.0
   }
 
+  /// Hashes bytes that are already in memory, on the current thread.
+  #[cfg(test)]
+  pub fn of(bytes: &[u8]) -> Self {
+    Self(crc32fast::hash(bytes))
+  }
+
+  /// Returns the checksum of the bytes this is the checksum of, followed by
+  /// `len` bytes whose checksum is `next`, without reading either again. The
+  /// checksum of a file split into parts, or of the tracks of a disc image
+  /// joined into one, can be computed from the checksums of its parts this
+  /// way.
+  pub fn combine(self, next: Crc32, len: u64) -> Self {
+    Self(multiply(x_pow_8n(len), self.0) ^ next.0)
+  }
+
+  /// Returns the checksum of the bytes this is the checksum of, followed by
+  /// `len` zeroes, as when a trimmed dump is padded, without hashing the
+  /// zeroes.
+  pub fn extend_with_zeros(self, len: u64) -> Self {
+    // Unlike combining, this works on the checksum as it is before its
+    // final inversion.
+    Self(!multiply(x_pow_8n(len), !self.0))
+  }
+
   pub fn read_and_hash<R: Read>(reader: &mut R) -> io::Result<Self> {
     // The crc32 is computed in parallel.
     // The current thread updates a shared buffer which the crc32 thread reads.
@@ -51,6 +92,41 @@ impl Crc32 {
   }
 }
 
+/// Multiplies `a` and `b` modulo the polynomial, with their bits reversed.
+const fn multiply(a: u32, mut b: u32) -> u32 {
+  let mut product = 0;
+  let mut bit = 1 << 31;
+  while bit != 0 {
+    if a & bit != 0 {
+      product ^= b;
+    }
+    bit >>= 1;
+    b = match b & 1 {
+      0 => b >> 1,
+      _ => (b >> 1) ^ POLYNOMIAL,
+    };
+  }
+  product
+}
+
+/// Returns x^(8 * len) modulo the polynomial, which appending `len` bytes
+/// multiplies a checksum by.
+fn x_pow_8n(len: u64) -> u32 {
+  // x^(8 * len) is the product of x^(2^k) for each bit k of 8 * len, which
+  // is `len` shifted by 3.
+  let mut power = 1 << 31;
+  let mut len = len;
+  let mut k = 3;
+  while len != 0 {
+    if len & 1 != 0 {
+      power = multiply(X_POW_2K[k % 32], power);
+    }
+    len >>= 1;
+    k += 1;
+  }
+  power
+}
+
 fn spawn_crc32_thread(
   lock: &sync::Arc<sync::RwLock<io::Cursor<[u8; BUF_SIZE]>>>,
   barrier: &sync::Arc<sync::Barrier>,
@@ -81,3 +157,113 @@ fn spawn_crc32_thread(
     }
   })
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use romhacks_testkit::rom;
+
+  /// How many random cases each property is checked with.
+  const CASES: u64 = 256;
+
+  /// A random number below `bound`, drawn from `seed`.
+  fn below(seed: u64, bound: usize) -> usize {
+    let bytes: [u8; 8] = rom::random(8, seed).try_into().unwrap();
+    (u64::from_le_bytes(bytes) % bound as u64) as usize
+  }
+
+  /// Random data of up to 4 KiB and a point to split it at, drawn from `seed`.
+  fn case(seed: u64) -> (Vec<u8>, usize) {
+    let data = rom::random(below(seed, 4097), seed);
+    let split = below(!seed, data.len() + 1);
+    (data, split)
+  }
+
+  #[test]
+  fn combine_matches_checksum_of_concatenation() {
+    for seed in 0..CASES {
+      let (data, split) = case(seed);
+      let (a, b) = data.split_at(split);
+      assert_eq!(
+        Crc32::of(a).combine(Crc32::of(b), b.len() as u64),
+        Crc32::of(&data),
+        "seed {seed}"
+      );
+    }
+  }
+
+  #[test]
+  fn combine_is_associative() {
+    for seed in 0..CASES {
+      let (data, split) = case(seed);
+      let (a, rest) = data.split_at(split);
+      let (b, c) = rest.split_at(below(seed ^ 0x5555, rest.len() + 1));
+      let (a, b, c, b_len, c_len) = (
+        Crc32::of(a),
+        Crc32::of(b),
+        Crc32::of(c),
+        b.len() as u64,
+        c.len() as u64,
+      );
+      assert_eq!(
+        a.combine(b, b_len).combine(c, c_len),
+        a.combine(b.combine(c, c_len), b_len + c_len),
+        "seed {seed}"
+      );
+    }
+  }
+
+  #[test]
+  fn combine_with_nothing_is_identity() {
+    for seed in 0..CASES {
+      let (data, _) = case(seed);
+      let crc32 = Crc32::of(&data);
+      assert_eq!(crc32.combine(Crc32::of(&[]), 0), crc32, "seed {seed}");
+      assert_eq!(
+        Crc32::of(&[]).combine(crc32, data.len() as u64),
+        crc32,
+        "seed {seed}"
+      );
+    }
+  }
+
+  #[test]
+  fn extend_with_zeros_matches_padded_checksum() {
+    for seed in 0..CASES {
+      let (data, zeros) = case(seed);
+      let padded = [&data[..], &rom::filled(zeros, 0)].concat();
+      assert_eq!(
+        Crc32::of(&data).extend_with_zeros(zeros as u64),
+        Crc32::of(&padded),
+        "seed {seed}"
+      );
+    }
+  }
+
+  #[test]
+  fn extend_with_zeros_agrees_with_combine() {
+    // Lengths too large to hash, like padding a disc image.
+    for len in [1 << 20, (1 << 32) + 7, u64::MAX / 8] {
+      for seed in 0..16 {
+        let (data, _) = case(seed);
+        let crc32 = Crc32::of(&data);
+        let zeros = Crc32::of(&[]).extend_with_zeros(len);
+        assert_eq!(
+          crc32.extend_with_zeros(len),
+          crc32.combine(zeros, len),
+          "seed {seed}"
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn read_and_hash_matches_of() {
+    for seed in 0..16 {
+      // Spans several of the buffers the data is hashed in.
+      let data = rom::random(below(seed, 4 * BUF_SIZE), seed);
+      let crc32 = Crc32::read_and_hash(&mut io::Cursor::new(&data)).unwrap();
+      assert_eq!(crc32, Crc32::of(&data), "seed {seed}");
+    }
+  }
+}
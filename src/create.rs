@@ -0,0 +1,364 @@
+use crate::error::prelude::*;
+use crate::io::prelude::*;
+use crate::patch::vcd;
+use crate::{dirs, fs, i18n, identify, io, kdl, lint, mem, patch, profile};
+use std::cell::OnceCell;
+use std::ops::Range;
+use std::path;
+
+// nodes
+const MASK: &str = "mask";
+
+// props
+const START: &str = "start";
+const END: &str = "end";
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct Args {
+  /// The unmodified ROM.
+  #[arg(short, long)]
+  pub rom: path::PathBuf,
+  /// The modified ROM, or "-" to read it from standard input. Can be given
+  /// more than once to create a patch for each target, such as each language
+  /// of a translation, from the same ROM.
+  #[arg(short, long, required = true)]
+  pub target: Vec<path::PathBuf>,
+  #[arg(short, long, value_enum)]
+  pub format: Format,
+  /// Where to write the patch, or "-" for standard output. With more than one
+  /// target, the directory to write the patches to, each named after its
+  /// target.
+  #[arg(short, long, default_value = io::STDIO)]
+  pub output: path::PathBuf,
+  /// Don't record the names of the files and the version of this program in
+  /// Vcdiff patches.
+  #[arg(long)]
+  pub no_app_header: bool,
+  /// Create the same patch from the same files with any version of this
+  /// program, so that the patch's checksum can be published. Leaves the
+  /// program's version out of Vcdiff patches; nothing else in the output
+  /// depends on when or where it was created.
+  #[arg(long)]
+  pub reproducible: bool,
+  /// Compress the patch, e.g. with "zstd" or "zstd:19" for a level from 1 to
+  /// 22. Needs a build with the zstd feature.
+  #[arg(long, value_name = "FORMAT[:LEVEL]", value_parser = patch::compression::parse_setting)]
+  pub compress: Option<patch::compression::Setting>,
+  /// A region to leave out of the patch, such as "0x7FDC..0x7FE0" for the
+  /// checksum in a SNES header. The target is treated as having the ROM's
+  /// bytes there, so the patch doesn't change them. Can be given more than
+  /// once.
+  #[arg(long, value_name = "START..END", value_parser = parse_mask)]
+  pub mask: Vec<Range<u64>>,
+  /// A KDL file of regions to leave out of the patch, with a node like
+  /// `mask start=0x7FDC end=0x7FE0` for each.
+  #[arg(long, value_name = "FILE")]
+  pub mask_file: Option<path::PathBuf>,
+}
+
+/// The patch formats that can be created.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, clap::ValueEnum)]
+pub enum Format {
+  Ips,
+  Bps,
+  Vcd,
+}
+
+impl From<Format> for patch::Kind {
+  fn from(format: Format) -> Self {
+    match format {
+      Format::Ips => patch::Kind::IPS,
+      Format::Bps => patch::Kind::BPS,
+      Format::Vcd => patch::Kind::VCD,
+    }
+  }
+}
+
+impl Args {
+  pub fn call(self) -> Result<(), Error> {
+    let several = self.target.len() > 1;
+    if several
+      && self
+        .target
+        .iter()
+        .any(|target| target.as_os_str() == io::STDIO)
+    {
+      return Err(Error::SeveralTargetsFromStdin);
+    }
+    if several && self.output.as_os_str() == io::STDIO {
+      return Err(Error::SeveralTargetsToStdout);
+    }
+    let mut masks = self.mask.clone();
+    if let Some(mask_file) = &self.mask_file {
+      let text = fs::read_to_string(mask_file)?;
+      masks.extend(
+        parse_mask_file(&text).ok_or_else(|| Error::MalformedMask { path: mask_file.clone() })?,
+      );
+    }
+    let source = fs::read(&self.rom)?;
+    let source = Source::new(&source);
+    if several {
+      fs::create_dir_all(&self.output)?;
+    }
+    for target_path in &self.target {
+      let output = match several {
+        true => self.output.join(patch_name(target_path, self.format)),
+        false => self.output.clone(),
+      };
+      self.create(&source, target_path, &masks, &output)?;
+      if several {
+        log::info!(
+          "{}",
+          i18n::format("romhacks::create::created", &[("path", &output.display())])
+        );
+      }
+    }
+    Ok(())
+  }
+
+  /// Creates the patch from `source` to the target at `target_path` and
+  /// writes it to `output`.
+  fn create(
+    &self,
+    source: &Source<'_>,
+    target_path: &path::Path,
+    masks: &[Range<u64>],
+    output: &path::Path,
+  ) -> Result<(), Error> {
+    let mut target = match target_path.as_os_str() == io::STDIO {
+      true => read_stdin()?,
+      false => fs::read(target_path)?,
+    };
+    let masked = mask(source.bytes, &mut target, masks);
+    if masked > 0 {
+      log::info!(
+        "{}",
+        i18n::format("romhacks::create::masked", &[("count", &masked)])
+      );
+    }
+    let app_header = (!self.no_app_header).then(|| {
+      vcd::app_header(
+        &file_name(&self.rom),
+        &file_name(target_path),
+        !self.reproducible,
+      )
+    });
+    let patch = build(self.format, source, &target, app_header.as_deref())?;
+    let patch = match self.compress {
+      Some(setting) => setting.compress(&patch)?,
+      None => patch,
+    };
+    io::write_file_or_stdout(output, &patch)?;
+    Ok(())
+  }
+}
+
+/// A ROM that patches are created from.
+///
+/// The index of its bytes that Vcdiff patches are encoded with is built the
+/// first time it's needed, and shared by every patch created from the ROM.
+pub struct Source<'a> {
+  bytes: &'a [u8],
+  vcd_index: OnceCell<vcd::SourceIndex<'a>>,
+}
+
+impl<'a> Source<'a> {
+  pub fn new(bytes: &'a [u8]) -> Self {
+    Self { bytes, vcd_index: OnceCell::new() }
+  }
+}
+
+/// Creates a patch in the given format that turns `source` into `target`.
+/// Logs a warning for each header or checksum of `source` that the patch
+/// changes, since they differ between dumps. Only Vcdiff patches have room
+/// for an application header; it's ignored for the other formats.
+pub fn build(
+  format: Format,
+  source: &Source<'_>,
+  target: &[u8],
+  app_header: Option<&[u8]>,
+) -> Result<Vec<u8>, Error> {
+  let index = &source.vcd_index;
+  let source = source.bytes;
+  if target.len() as u64 > patch::Kind::from(format).capabilities().max_file_size {
+    return Err(Error::TooLarge);
+  }
+  for change in lint::header_changes(source, target) {
+    log::warn!("{change}");
+  }
+  Ok(match format {
+    Format::Ips => flips::IpsBuilder::new()
+      .source(source)
+      .target(target)
+      .build()?
+      .as_ref()
+      .to_vec(),
+    Format::Bps => flips::BpsDeltaBuilder::new()
+      .source(source)
+      .target(target)
+      .build()?
+      .as_ref()
+      .to_vec(),
+    Format::Vcd if source == target => return Err(Error::Identical),
+    Format::Vcd => mem::try_init(Vec::new(), |patch| {
+      vcd::encode(
+        index.get_or_init(|| vcd::SourceIndex::new(source)),
+        target,
+        app_header,
+        patch,
+      )
+    })?,
+  })
+}
+
+/// Copies the bytes of `source` over those of `target` in each of `masks`,
+/// so that a patch doesn't change them. The parts of a mask past the end of
+/// either file are left alone. Returns how many bytes were changed.
+pub fn mask(source: &[u8], target: &mut [u8], masks: &[Range<u64>]) -> usize {
+  let mut masked = 0;
+  for range in masks {
+    let end = range.end.min(source.len() as u64).min(target.len() as u64);
+    if range.start >= end {
+      continue;
+    }
+    let range = range.start as usize..end as usize;
+    masked += (source[range.clone()].iter())
+      .zip(&target[range.clone()])
+      .filter(|(a, b)| a != b)
+      .count();
+    target[range.clone()].copy_from_slice(&source[range]);
+  }
+  masked
+}
+
+/// Parses a range like "0x7FDC..0x7FE0", whose end is exclusive. Offsets
+/// are decimal, or hexadecimal with a "0x" prefix.
+fn parse_mask(arg: &str) -> Result<Range<u64>, String> {
+  let parse_offset = |offset: &str| match offset
+    .strip_prefix("0x")
+    .or_else(|| offset.strip_prefix("0X"))
+  {
+    Some(hex) => u64::from_str_radix(hex, 16).ok(),
+    None => offset.parse().ok(),
+  };
+  (arg.split_once(".."))
+    .and_then(|(start, end)| Some(parse_offset(start.trim())?..parse_offset(end.trim())?))
+    .filter(|range| !range.is_empty())
+    .ok_or_else(|| i18n::text("romhacks::create::bad_mask").to_owned())
+}
+
+/// Reads the `mask` nodes of a mask file, or returns `None` if any is
+/// malformed.
+fn parse_mask_file(text: &str) -> Option<Vec<Range<u64>>> {
+  let doc: kdl::KdlDocument = text.parse().ok()?;
+  (doc.nodes().iter())
+    .filter(|node| node.name().value() == MASK)
+    .map(|node| {
+      let get_u64 = |key: &str| {
+        (node.get(key).and_then(|value| value.as_integer()))
+          .and_then(|value| u64::try_from(value).ok())
+      };
+      Some(get_u64(START)?..get_u64(END)?).filter(|range| !range.is_empty())
+    })
+    .collect()
+}
+
+/// The name of the patch for the target at `path` when several are created:
+/// the target's name with the format's extension.
+fn patch_name(path: &path::Path, format: Format) -> path::PathBuf {
+  path::Path::new(path.file_name().unwrap_or_default())
+    .with_extension(identify::name(format.into()))
+}
+
+fn file_name(path: &path::Path) -> std::borrow::Cow<'_, str> {
+  path.file_name().unwrap_or_default().to_string_lossy()
+}
+
+/// Reads all of standard input.
+///
+/// Toolchains may pipe in images larger than the profile allows keeping in
+/// memory, so the input is spooled to a temporary file until it's complete.
+fn read_stdin() -> io::Result<Vec<u8>> {
+  let mut buffer = io::SpooledTempBuffer::new(profile::get().spool_threshold, dirs::temp_dir());
+  io::copy(&mut io::stdin().lock(), &mut buffer)?;
+  let len = buffer.stream_position()?;
+  buffer.seek(io::SeekFrom::Start(0))?;
+  let mut target = Vec::with_capacity(len as usize);
+  buffer.read_to_end(&mut target)?;
+  Ok(target)
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error("{}", i18n::text("romhacks::create::identical"))]
+  #[diagnostic(code(romhacks::create::identical))]
+  Identical,
+  #[error("{}", i18n::text("romhacks::create::too_large"))]
+  #[diagnostic(code(romhacks::create::too_large))]
+  TooLarge,
+  #[error("{}", i18n::text("romhacks::create::failed"))]
+  #[diagnostic(code(romhacks::create::failed))]
+  Failed,
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Compression(#[from] patch::compression::Error),
+  #[error("{}", i18n::format("romhacks::create::malformed_mask", &[("path", &path.display())]))]
+  #[diagnostic(
+    code(romhacks::create::malformed_mask),
+    help("{}", i18n::text("romhacks::create::malformed_mask::help"))
+  )]
+  MalformedMask { path: path::PathBuf },
+  #[error("{}", i18n::text("romhacks::create::several_targets_from_stdin"))]
+  #[diagnostic(code(romhacks::create::several_targets_from_stdin))]
+  SeveralTargetsFromStdin,
+  #[error("{}", i18n::text("romhacks::create::several_targets_to_stdout"))]
+  #[diagnostic(
+    code(romhacks::create::several_targets_to_stdout),
+    help("{}", i18n::text("romhacks::create::several_targets_to_stdout::help"))
+  )]
+  SeveralTargetsToStdout,
+}
+
+impl From<flips::Error> for Error {
+  fn from(err: flips::Error) -> Self {
+    match err {
+      flips::Error::Identical => Error::Identical,
+      flips::Error::TooBig | flips::Error::OutOfMem => Error::TooLarge,
+      _ => Error::Failed,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::crc::Crc32;
+  use romhacks_testkit::rom;
+
+  /// Encodes the same files twice, as two runs of `create --reproducible`
+  /// would, and compares the patches' digests.
+  #[test]
+  fn reproducible_patches_are_identical() {
+    let source = rom::random(0x2_0000, 1);
+    // A hack that changes some code and extends the ROM.
+    let target = rom::with_bytes(&source, 0x1_F000, &rom::random(0x2000, 2));
+    for format in [Format::Ips, Format::Bps, Format::Vcd] {
+      let digest = || {
+        let app_header = vcd::app_header("Game.sfc", "Game (Hack).sfc", false);
+        Crc32::of(&build(format, &Source::new(&source), &target, Some(&app_header)).unwrap())
+      };
+      assert_eq!(digest(), digest(), "{format:?}");
+    }
+  }
+
+  #[test]
+  fn reproducible_app_header_leaves_out_version() {
+    assert_eq!(
+      vcd::app_header("Game.sfc", "Game (Hack).sfc", false),
+      b"Game (Hack).sfc//Game.sfc/"
+    );
+  }
+}
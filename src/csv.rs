@@ -0,0 +1,84 @@
+//! Reading and writing comma-separated values as described in RFC 4180.
+
+use crate::error::prelude::*;
+use crate::i18n;
+
+/// Appends a record to `out`, quoting the fields that need it.
+pub fn write_record<S: AsRef<str>>(out: &mut String, fields: &[S]) {
+  for (i, field) in fields.iter().enumerate() {
+    if i > 0 {
+      out.push(',');
+    }
+    let field = field.as_ref();
+    match field.contains([',', '"', '\n', '\r']) {
+      true => {
+        out.push('"');
+        out.push_str(&field.replace('"', "\"\""));
+        out.push('"');
+      }
+      false => out.push_str(field),
+    }
+  }
+  out.push_str("\r\n");
+}
+
+/// Splits `text` into records of fields. Blank lines are skipped.
+pub fn parse(text: &str) -> Result<Vec<Vec<String>>, ParseError> {
+  let mut records = Vec::new();
+  let mut record = Vec::new();
+  let mut field = String::new();
+  let mut chars = text.chars().peekable();
+  let mut line = 1;
+  // Whether the current field was quoted, so that `""` isn't mistaken for a blank line.
+  let mut quoted = false;
+  while let Some(c) = chars.next() {
+    match c {
+      '"' if field.is_empty() && !quoted => {
+        quoted = true;
+        loop {
+          match chars.next() {
+            Some('"') if chars.peek() == Some(&'"') => {
+              chars.next();
+              field.push('"');
+            }
+            Some('"') => break,
+            Some(c) => {
+              line += (c == '\n') as usize;
+              field.push(c);
+            }
+            None => return Err(ParseError { line }),
+          }
+        }
+        if !matches!(chars.peek(), None | Some(',' | '\r' | '\n')) {
+          return Err(ParseError { line });
+        }
+      }
+      ',' => {
+        record.push(std::mem::take(&mut field));
+        quoted = false;
+      }
+      '\r' if chars.peek() == Some(&'\n') => {}
+      '\n' => {
+        if !record.is_empty() || !field.is_empty() || quoted {
+          record.push(std::mem::take(&mut field));
+          records.push(std::mem::take(&mut record));
+        }
+        quoted = false;
+        line += 1;
+      }
+      c => field.push(c),
+    }
+  }
+  if !record.is_empty() || !field.is_empty() || quoted {
+    record.push(field);
+    records.push(record);
+  }
+  Ok(records)
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("{}", i18n::format("romhacks::csv::malformed", &[("line", line)]))]
+#[diagnostic(code(romhacks::csv::malformed))]
+pub struct ParseError {
+  line: usize,
+}
@@ -0,0 +1,268 @@
+//! Reading and rewriting CUE sheets, which describe the tracks of a disc
+//! image stored in one or more BIN files.
+
+use crate::error::prelude::*;
+use crate::{fs, i18n, io};
+use std::path;
+
+/// The extension of CUE sheets.
+pub const EXTENSION: &str = "cue";
+
+/// The number of sectors per second of audio, which INDEX times count in.
+const FRAMES_PER_SECOND: u64 = 75;
+
+#[derive(Clone, Debug)]
+pub struct CueSheet {
+  /// The directory that the file names are relative to.
+  dir: path::PathBuf,
+  /// The sheet's lines, so that it can be rewritten without losing comments
+  /// and commands that aren't needed for patching.
+  lines: Vec<String>,
+  pub files: Vec<File>,
+}
+
+/// A FILE command and the tracks stored in it.
+#[derive(Clone, Debug)]
+pub struct File {
+  pub name: String,
+  file_type: String,
+  /// The line of the FILE command in the sheet.
+  line: usize,
+  pub tracks: Vec<Track>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Track {
+  pub number: u8,
+  pub mode: String,
+  /// The start of each of the track's indexes, in sectors from the start of
+  /// the file.
+  pub indexes: Vec<u64>,
+}
+
+impl Track {
+  /// The number of bytes in each of the track's sectors, if the mode is known.
+  pub fn sector_size(&self) -> Option<u64> {
+    match self.mode.to_ascii_uppercase().as_str() {
+      "AUDIO" | "MODE1/2352" | "MODE2/2352" | "CDI/2352" => Some(2352),
+      "MODE1/2048" => Some(2048),
+      "MODE2/2336" | "CDI/2336" => Some(2336),
+      "CDG" => Some(2448),
+      _ => None,
+    }
+  }
+
+  pub fn is_data(&self) -> bool {
+    !self.mode.eq_ignore_ascii_case("AUDIO")
+  }
+}
+
+impl CueSheet {
+  /// Reads and parses the CUE sheet at `path`.
+  pub fn read(path: &path::Path) -> Result<Self, Error> {
+    let text = fs::read_to_string(path)?;
+    let dir = (path.parent())
+      .filter(|dir| !dir.as_os_str().is_empty())
+      .unwrap_or(path::Path::new("."))
+      .to_owned();
+    parse(dir, &text)
+  }
+
+  /// The file that holds the first track. Patches for a disc, such as PPF
+  /// patches for PlayStation games, are made for this file.
+  pub fn first_track_file(&self) -> &File {
+    // Parsing ensures there's at least one file and that tracks are in order.
+    &self.files[0]
+  }
+
+  pub fn path_of(&self, file: &File) -> path::PathBuf {
+    self.dir.join(&file.name)
+  }
+
+  /// Checks that the files exist and that the tracks and indexes fit in them.
+  pub fn validate(&self) -> Result<(), Error> {
+    if !self.first_track_file().tracks[0].is_data() {
+      return Err(Error::NoDataTrack);
+    }
+    for file in &self.files {
+      let path = self.path_of(file);
+      let len = match fs::metadata(&path) {
+        Ok(metadata) => metadata.len(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+          return Err(Error::MissingFile { path });
+        }
+        Err(err) => return Err(err.into()),
+      };
+      let mut previous_index = 0;
+      for track in &file.tracks {
+        let sector_size = track
+          .sector_size()
+          .ok_or_else(|| Error::UnknownMode { track: track.number, mode: track.mode.clone() })?;
+        if len % sector_size != 0 {
+          return Err(Error::Misaligned { path, sector_size });
+        }
+        for &index in &track.indexes {
+          if index < previous_index || index * sector_size > len {
+            return Err(Error::IndexOutOfBounds { track: track.number });
+          }
+          previous_index = index;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Writes the sheet for a copy of the disc in `output_dir` whose file
+  /// `patched` was replaced by `patched_name`. The other files are referred to
+  /// by their absolute paths unless they're also in `output_dir`.
+  pub fn rewrite(&self, patched: &File, patched_name: &str, output_dir: &path::Path) -> String {
+    let same_dir = match (fs::canonicalize(&self.dir), fs::canonicalize(output_dir)) {
+      (Ok(dir), Ok(output_dir)) => dir == output_dir,
+      _ => false,
+    };
+    let mut lines = self.lines.clone();
+    for file in &self.files {
+      let name = match (file.line == patched.line, same_dir) {
+        (true, _) => patched_name.to_owned(),
+        (false, true) => file.name.clone(),
+        (false, false) => (fs::canonicalize(self.path_of(file)))
+          .unwrap_or_else(|_| self.path_of(file))
+          .to_string_lossy()
+          .into_owned(),
+      };
+      let indent: String = lines[file.line]
+        .chars()
+        .take_while(|c| c.is_whitespace())
+        .collect();
+      lines[file.line] = format!("{indent}FILE \"{name}\" {}", file.file_type);
+    }
+    lines.iter().map(|line| format!("{line}\r\n")).collect()
+  }
+}
+
+fn parse(dir: path::PathBuf, text: &str) -> Result<CueSheet, Error> {
+  let lines: Vec<String> = text.lines().map(str::to_owned).collect();
+  let mut files: Vec<File> = Vec::new();
+  for (i, line) in lines.iter().enumerate() {
+    let malformed = || Error::Malformed { line: i + 1 };
+    // Comments may contain unbalanced quotes.
+    if (line.split_whitespace().next()).is_some_and(|word| word.eq_ignore_ascii_case("REM")) {
+      continue;
+    }
+    let tokens = tokenize(line).ok_or_else(malformed)?;
+    let Some((command, args)) = tokens.split_first() else {
+      continue;
+    };
+    match command.to_ascii_uppercase().as_str() {
+      "FILE" => {
+        let [name, file_type] = args else {
+          return Err(malformed());
+        };
+        files.push(File {
+          name: name.clone(),
+          file_type: file_type.clone(),
+          line: i,
+          tracks: Vec::new(),
+        });
+      }
+      "TRACK" => {
+        let [number, mode] = args else {
+          return Err(malformed());
+        };
+        let number: u8 = number.parse().map_err(|_| malformed())?;
+        let expected = (files.iter()).map(|file| file.tracks.len()).sum::<usize>() + 1;
+        if usize::from(number) != expected {
+          return Err(Error::TrackOrder { track: number });
+        }
+        let file = files.last_mut().ok_or_else(malformed)?;
+        file
+          .tracks
+          .push(Track { number, mode: mode.clone(), indexes: Vec::new() });
+      }
+      "INDEX" => {
+        let [_, time] = args else {
+          return Err(malformed());
+        };
+        let track = (files.last_mut())
+          .and_then(|file| file.tracks.last_mut())
+          .ok_or_else(malformed)?;
+        track.indexes.push(parse_time(time).ok_or_else(malformed)?);
+      }
+      _ => {}
+    }
+  }
+  if files.is_empty() || files[0].tracks.is_empty() {
+    return Err(Error::NoTracks);
+  }
+  Ok(CueSheet { dir, lines, files })
+}
+
+/// Splits a line into words, keeping quoted strings together.
+fn tokenize(line: &str) -> Option<Vec<String>> {
+  let mut tokens = Vec::new();
+  let mut rest = line.trim_start();
+  while !rest.is_empty() {
+    let (token, tail) = match rest.strip_prefix('"') {
+      Some(quoted) => {
+        let end = quoted.find('"')?;
+        (&quoted[..end], &quoted[end + 1..])
+      }
+      None => rest.split_at(rest.find(char::is_whitespace).unwrap_or(rest.len())),
+    };
+    tokens.push(token.to_owned());
+    rest = tail.trim_start();
+  }
+  Some(tokens)
+}
+
+/// Parses an "mm:ss:ff" time into a number of sectors.
+fn parse_time(time: &str) -> Option<u64> {
+  let mut parts = time.split(':').map(|part| part.parse::<u64>().ok());
+  let (Some(Some(minutes)), Some(Some(seconds)), Some(Some(frames)), None) =
+    (parts.next(), parts.next(), parts.next(), parts.next())
+  else {
+    return None;
+  };
+  (seconds < 60 && frames < FRAMES_PER_SECOND)
+    .then(|| (minutes * 60 + seconds) * FRAMES_PER_SECOND + frames)
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error("{}", i18n::format("romhacks::cue::malformed", &[("line", line)]))]
+  #[diagnostic(code(romhacks::cue::malformed))]
+  Malformed { line: usize },
+  #[error("{}", i18n::text("romhacks::cue::no_tracks"))]
+  #[diagnostic(code(romhacks::cue::no_tracks))]
+  NoTracks,
+  #[error("{}", i18n::format("romhacks::cue::track_order", &[("track", track)]))]
+  #[diagnostic(code(romhacks::cue::track_order))]
+  TrackOrder { track: u8 },
+  #[error("{}", i18n::text("romhacks::cue::no_data_track"))]
+  #[diagnostic(code(romhacks::cue::no_data_track))]
+  NoDataTrack,
+  #[error("{}", i18n::format("romhacks::cue::missing_file", &[("path", &path.display())]))]
+  #[diagnostic(code(romhacks::cue::missing_file))]
+  MissingFile { path: path::PathBuf },
+  #[error("{}", i18n::format(
+    "romhacks::cue::unknown_mode",
+    &[("track", track), ("mode", mode)]
+  ))]
+  #[diagnostic(code(romhacks::cue::unknown_mode))]
+  UnknownMode { track: u8, mode: String },
+  #[error("{}", i18n::format(
+    "romhacks::cue::misaligned",
+    &[("path", &path.display()), ("sector_size", sector_size)]
+  ))]
+  #[diagnostic(code(romhacks::cue::misaligned))]
+  Misaligned {
+    path: path::PathBuf,
+    sector_size: u64,
+  },
+  #[error("{}", i18n::format("romhacks::cue::index_out_of_bounds", &[("track", track)]))]
+  #[diagnostic(code(romhacks::cue::index_out_of_bounds))]
+  IndexOutOfBounds { track: u8 },
+}
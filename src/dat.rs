@@ -0,0 +1,114 @@
+//! Checksums of known good dumps, read from the DAT files that No-Intro and
+//! Redump publish, for telling the user which dump a patch was made for.
+//!
+//! DAT files are kept in the "dats" directory within the configuration
+//! directory. Only the Logiqx XML format is read, which both groups use; a
+//! ROM is recognized by its size and CRC32.
+
+use crate::crc::Crc32;
+use crate::{dirs, fs, io};
+use regex_lite::Regex;
+use std::collections::HashMap;
+use std::path;
+
+/// The name of the directory DAT files are read from.
+const DATS_DIR: &str = "dats";
+
+/// The extensions of files in the DAT directory that are read.
+const EXTENSIONS: &[&str] = &["dat", "xml"];
+
+/// The directory DAT files are read from.
+pub fn dir() -> path::PathBuf {
+  dirs::config_dir().join(DATS_DIR)
+}
+
+/// The names of dumps, by their size and checksum.
+#[derive(Clone, Debug, Default)]
+pub struct Database {
+  dumps: HashMap<(u64, Crc32), String>,
+}
+
+impl Database {
+  /// Reads every DAT file in [`dir`]. The database is empty if the
+  /// directory doesn't exist.
+  pub fn load() -> io::Result<Self> {
+    let mut database = Self::default();
+    let entries = match fs::read_dir(dir()) {
+      Ok(entries) => entries,
+      Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(database),
+      Err(err) => return Err(err),
+    };
+    let parser = Parser::new();
+    for entry in entries {
+      let path = entry?.path();
+      let extension = path.extension().unwrap_or_default().to_string_lossy();
+      if !EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()) {
+        continue;
+      }
+      let xml = fs::read(&path)?;
+      database
+        .dumps
+        .extend(parser.roms(&String::from_utf8_lossy(&xml)));
+    }
+    Ok(database)
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.dumps.is_empty()
+  }
+
+  /// The name of the dump with this size and checksum, if a DAT file lists
+  /// it.
+  pub fn find(&self, size: u64, crc32: Crc32) -> Option<&str> {
+    self.dumps.get(&(size, crc32)).map(String::as_str)
+  }
+}
+
+/// Finds `rom` elements in a DAT file, without parsing the rest of the XML.
+struct Parser {
+  rom: Regex,
+  attribute: Regex,
+}
+
+impl Parser {
+  fn new() -> Self {
+    Self {
+      rom: Regex::new(r#"<rom\s[^>]*>"#).unwrap(),
+      attribute: Regex::new(r#"([\w-]+)\s*=\s*"([^"]*)""#).unwrap(),
+    }
+  }
+
+  /// Returns the size, checksum and name of each ROM in `xml` that has all
+  /// three.
+  fn roms<'a>(&'a self, xml: &'a str) -> impl Iterator<Item = ((u64, Crc32), String)> + 'a {
+    self.rom.find_iter(xml).filter_map(|element| {
+      let (mut name, mut size, mut crc32) = (None, None, None);
+      for captures in self.attribute.captures_iter(element.as_str()) {
+        let value = &captures[2];
+        match &captures[1] {
+          "name" => name = Some(unescape(value)),
+          "size" => size = value.parse::<u64>().ok(),
+          "crc" => crc32 = u32::from_str_radix(value, 16).ok().map(Crc32::new),
+          _ => {}
+        }
+      }
+      Some(((size?, crc32?), name?))
+    })
+  }
+}
+
+/// Replaces the entities XML predefines, which are the only ones DAT files
+/// use.
+fn unescape(value: &str) -> String {
+  [
+    ("&lt;", "<"),
+    ("&gt;", ">"),
+    ("&quot;", "\""),
+    ("&apos;", "'"),
+    ("&amp;", "&"),
+  ]
+  .iter()
+  .fold(value.to_owned(), |value, (entity, char)| {
+    value.replace(entity, char)
+  })
+}
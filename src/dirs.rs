@@ -0,0 +1,129 @@
+//! Where the files that the program writes for itself are kept.
+//!
+//! Configuration, caches and state go in the directories the platform sets
+//! aside for them: the XDG base directories on Linux and other Unix systems,
+//! `~/Library` on macOS and the application data folders on Windows. With
+//! `--portable`, they're kept in the current directory instead.
+
+use std::sync::OnceLock;
+use std::{env, path};
+
+/// The name of the directory the program's files are kept in, within each of
+/// the platform's directories.
+const APP_DIR: &str = "romhacks";
+
+static OPTIONS: OnceLock<Options> = OnceLock::new();
+
+/// Command line options that override where files are kept.
+#[derive(Clone, Debug, Default, clap::Args)]
+#[group(id = "dirs")]
+pub struct Options {
+  /// Keep the checksum cache and any other files the program writes for
+  /// itself in the current directory, rather than in the platform's
+  /// configuration, cache and state directories.
+  #[arg(long, global = true)]
+  pub portable: bool,
+  /// The directory for temporary files that are deleted once a command
+  /// finishes. By default, the platform's temporary directory is used.
+  /// Patched files are always written next to where they're moved to.
+  #[arg(long, global = true, value_name = "DIR")]
+  pub temp_dir: Option<path::PathBuf>,
+}
+
+/// Sets the options used by the functions of this module. Until this is
+/// called, the platform's directories are used.
+pub fn init(options: Options) {
+  let _ = OPTIONS.set(options);
+}
+
+fn options() -> &'static Options {
+  OPTIONS.get_or_init(Options::default)
+}
+
+/// The directory for configuration files.
+pub fn config_dir() -> path::PathBuf {
+  platform_dir(Kind::Config)
+}
+
+/// The directory for caches, which can be deleted at any time.
+pub fn cache_dir() -> path::PathBuf {
+  platform_dir(Kind::Cache)
+}
+
+/// The directory for state that should outlive a command but isn't worth
+/// backing up, such as logs.
+pub fn state_dir() -> path::PathBuf {
+  platform_dir(Kind::State)
+}
+
+/// The directory for temporary files that are never moved elsewhere.
+pub fn temp_dir() -> path::PathBuf {
+  options().temp_dir.clone().unwrap_or_else(env::temp_dir)
+}
+
+/// The directory for a temporary file that will be renamed to `dest` once
+/// it's complete. Renaming only works within a filesystem, so it's the
+/// directory `dest` is in.
+pub fn temp_dir_for(dest: &path::Path) -> path::PathBuf {
+  match dest.parent() {
+    Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+    _ => path::PathBuf::from("."),
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+  Config,
+  Cache,
+  State,
+}
+
+/// Returns the directory of `kind` for this program, or the current
+/// directory if `--portable` was given or the user's home can't be found.
+fn platform_dir(kind: Kind) -> path::PathBuf {
+  if options().portable {
+    return path::PathBuf::from(".");
+  }
+  base_dir(kind).map_or_else(|| path::PathBuf::from("."), |base| base.join(APP_DIR))
+}
+
+/// Reads an environment variable that holds an absolute path. The XDG
+/// specification says that relative paths must be ignored.
+fn absolute_var(name: &str) -> Option<path::PathBuf> {
+  env::var_os(name)
+    .map(path::PathBuf::from)
+    .filter(|path| path.is_absolute())
+}
+
+#[cfg(windows)]
+fn base_dir(kind: Kind) -> Option<path::PathBuf> {
+  match kind {
+    Kind::Config => absolute_var("APPDATA"),
+    Kind::Cache | Kind::State => absolute_var("LOCALAPPDATA"),
+  }
+}
+
+#[cfg(target_os = "macos")]
+fn base_dir(kind: Kind) -> Option<path::PathBuf> {
+  // XDG variables are only set on macOS by users who want them used.
+  let library = || absolute_var("HOME").map(|home| home.join("Library"));
+  match kind {
+    Kind::Config => {
+      absolute_var("XDG_CONFIG_HOME").or_else(|| Some(library()?.join("Application Support")))
+    }
+    Kind::Cache => absolute_var("XDG_CACHE_HOME").or_else(|| Some(library()?.join("Caches"))),
+    Kind::State => {
+      absolute_var("XDG_STATE_HOME").or_else(|| Some(library()?.join("Application Support")))
+    }
+  }
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+fn base_dir(kind: Kind) -> Option<path::PathBuf> {
+  let (var, default) = match kind {
+    Kind::Config => ("XDG_CONFIG_HOME", ".config"),
+    Kind::Cache => ("XDG_CACHE_HOME", ".cache"),
+    Kind::State => ("XDG_STATE_HOME", ".local/state"),
+  };
+  absolute_var(var).or_else(|| Some(absolute_var("HOME")?.join(default)))
+}
@@ -0,0 +1,627 @@
+//! The sector layouts of CD images, and checks that a patch was made for an
+//! image with the same layout as the one it's applied to.
+//!
+//! Cooked images, usually named .iso, store only the 2048 bytes of user data
+//! of each sector. Raw images, usually named .bin, store whole 2352-byte
+//! sectors, including the sync pattern, header, and error detection and
+//! correction codes. Offsets in a patch made for one don't line up with the
+//! other, but an image can be read as if it had the other sector size with a
+//! [`Converter`].
+
+use crate::io::prelude::*;
+use crate::patch::ops::Op;
+use crate::patch::ppf;
+use crate::{i18n, io};
+use std::fmt;
+
+/// The first 12 bytes of every raw sector.
+pub const SYNC: [u8; 12] = [
+  0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0,
+];
+
+/// The sector that holds the ISO 9660 primary volume descriptor.
+const VOLUME_DESCRIPTOR_SECTOR: u64 = 16;
+/// The type and identifier that start a primary volume descriptor.
+const PRIMARY_VOLUME_DESCRIPTOR: &[u8; 6] = b"\x01CD001";
+/// The offset of the volume's size in sectors in a primary volume descriptor.
+const VOLUME_SPACE_SIZE_OFFSET: u64 = 80;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, clap::ValueEnum)]
+pub enum SectorSize {
+  /// 2048-byte sectors holding only user data.
+  #[value(name = "2048")]
+  Cooked,
+  /// 2352-byte sectors as they're stored on the disc.
+  #[value(name = "2352")]
+  Raw,
+}
+
+impl SectorSize {
+  pub const fn bytes(self) -> u64 {
+    match self {
+      SectorSize::Cooked => 2048,
+      SectorSize::Raw => 2352,
+    }
+  }
+}
+
+impl fmt::Display for SectorSize {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.bytes())
+  }
+}
+
+/// The layout of a CD image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Image {
+  pub sector_size: SectorSize,
+  pub len: u64,
+  /// The number of bytes taken up by the ISO 9660 volume, if the image has
+  /// one. Tracks after the data track come after the volume.
+  pub volume_len: Option<u64>,
+}
+
+impl Image {
+  /// Detects the sector size of the image from the sync pattern of its first
+  /// sector or the position of its volume descriptor, falling back to its
+  /// size. Returns `None` if it doesn't look like a CD image.
+  pub fn detect(file: &mut (impl Read + Seek)) -> io::Result<Option<Self>> {
+    let len = file.seek(io::SeekFrom::End(0))?;
+    let mut sync = [0u8; SYNC.len()];
+    let sector_size = if read_at(file, 0, &mut sync)? && sync == SYNC {
+      Some(SectorSize::Raw)
+    } else if volume_descriptor(file, SectorSize::Cooked)?.is_some() {
+      Some(SectorSize::Cooked)
+    } else {
+      match (
+        len % SectorSize::Cooked.bytes(),
+        len % SectorSize::Raw.bytes(),
+      ) {
+        (0, 0) => None,
+        (0, _) => Some(SectorSize::Cooked),
+        (_, 0) => Some(SectorSize::Raw),
+        _ => None,
+      }
+    };
+    let Some(sector_size) = sector_size else {
+      return Ok(None);
+    };
+    let volume_len = (volume_descriptor(file, sector_size)?)
+      .map(|sectors| u64::from(sectors) * sector_size.bytes());
+    Ok(Some(Self { sector_size, len, volume_len }))
+  }
+}
+
+/// Reads the volume's size in sectors from its primary volume descriptor, if
+/// the image has one where it would be with the given sector size.
+fn volume_descriptor(
+  file: &mut (impl Read + Seek),
+  sector_size: SectorSize,
+) -> io::Result<Option<u32>> {
+  let sector = VOLUME_DESCRIPTOR_SECTOR * sector_size.bytes();
+  let offset = match sector_size {
+    SectorSize::Cooked => sector,
+    SectorSize::Raw => {
+      // Mode 2 sectors have an 8-byte subheader before their user data.
+      let mut mode = [0u8];
+      if !read_at(file, sector + 15, &mut mode)? {
+        return Ok(None);
+      }
+      sector + if mode[0] == 2 { 24 } else { 16 }
+    }
+  };
+  let mut descriptor = [0u8; PRIMARY_VOLUME_DESCRIPTOR.len()];
+  let mut size = [0u8; 4];
+  Ok(
+    (read_at(file, offset, &mut descriptor)?
+      && descriptor == *PRIMARY_VOLUME_DESCRIPTOR
+      && read_at(file, offset + VOLUME_SPACE_SIZE_OFFSET, &mut size)?)
+    .then(|| u32::from_le_bytes(size)),
+  )
+}
+
+/// Fills `buf` with the bytes at `offset`. Returns `false` if the file ends
+/// first.
+fn read_at(file: &mut (impl Read + Seek), offset: u64, buf: &mut [u8]) -> io::Result<bool> {
+  file.seek(io::SeekFrom::Start(offset))?;
+  match file.read_exact(buf) {
+    Ok(()) => Ok(true),
+    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+    Err(err) => Err(err),
+  }
+}
+
+/// A reason to suspect that a PPF patch won't work on an image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Warning {
+  /// Hunks that end past the end of the image.
+  PastEnd { hunks: u64, offset: u64 },
+  /// Hunks that start after the end of the ISO 9660 volume.
+  OutsideVolume { hunks: u64, offset: u64 },
+  /// The patch appears to be for images with a different sector size.
+  SectorSizeMismatch {
+    patch: SectorSize,
+    image: SectorSize,
+  },
+}
+
+impl fmt::Display for Warning {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let text = match self {
+      Warning::PastEnd { hunks, offset } => i18n::format(
+        "romhacks::disc::past_end",
+        &[("hunks", hunks), ("offset", &format!("{offset:#X}"))],
+      ),
+      Warning::OutsideVolume { hunks, offset } => i18n::format(
+        "romhacks::disc::outside_volume",
+        &[("hunks", hunks), ("offset", &format!("{offset:#X}"))],
+      ),
+      Warning::SectorSizeMismatch { patch, image } => i18n::format(
+        "romhacks::disc::sector_size_mismatch",
+        &[("patch", patch), ("image", image)],
+      ),
+    };
+    f.write_str(&text)
+  }
+}
+
+/// Checks that the hunks of a decoded PPF patch fall inside `image`, and
+/// looks for signs that the patch was made for a different sector size:
+///
+/// - The image type of the patch's block check.
+/// - Hunks that overwrite the sync pattern of a raw sector, which patches
+///   for raw images never need to do.
+/// - Hunks that write a sync pattern at the start of a raw sector of a
+///   cooked image, or that only fit in the image if it were raw.
+pub fn check_ppf(image: &Image, ops: &[Op<'_>]) -> Vec<Warning> {
+  let raw = SectorSize::Raw.bytes();
+  let mut past_end: Option<(u64, u64)> = None;
+  let mut outside_volume: Option<(u64, u64)> = None;
+  let mut patch_sector_size = None;
+
+  for op in ops {
+    let (offset, end, data) = match op {
+      Op::Expect { offset, .. } => {
+        if *offset == u64::from(ppf::ImageType::BIN.block_check_offset().get()) {
+          patch_sector_size.get_or_insert(SectorSize::Raw);
+        } else if *offset == u64::from(ppf::ImageType::GI.block_check_offset().get()) {
+          patch_sector_size.get_or_insert(SectorSize::Cooked);
+        }
+        continue;
+      }
+      Op::Write { offset, data } => (*offset, offset + data.len() as u64, Some(data)),
+      Op::Fill { offset, len, .. } => (*offset, offset + len, None),
+      Op::Xor { .. } | Op::Resize { .. } => continue,
+    };
+
+    if end > image.len {
+      count(&mut past_end, offset);
+      let raw_len = image.len / SectorSize::Cooked.bytes() * raw;
+      if image.sector_size == SectorSize::Cooked && end <= raw_len {
+        patch_sector_size.get_or_insert(SectorSize::Raw);
+      }
+    } else if image
+      .volume_len
+      .is_some_and(|volume_len| offset >= volume_len)
+    {
+      count(&mut outside_volume, offset);
+    }
+
+    // Compare the hunk with the sync patterns of the raw sectors it overlaps.
+    let Some(data) = data else {
+      continue;
+    };
+    let mut sector_start = offset / raw * raw;
+    while sector_start < end {
+      let sync = sector_start..sector_start + SYNC.len() as u64;
+      let overlap = sync.start.max(offset)..sync.end.min(end);
+      if !overlap.is_empty() {
+        let written = &data[(overlap.start - offset) as usize..(overlap.end - offset) as usize];
+        let expected =
+          &SYNC[(overlap.start - sync.start) as usize..(overlap.end - sync.start) as usize];
+        match image.sector_size {
+          SectorSize::Raw if written != expected => {
+            patch_sector_size.get_or_insert(SectorSize::Cooked);
+          }
+          SectorSize::Cooked if overlap == sync && written == expected => {
+            patch_sector_size.get_or_insert(SectorSize::Raw);
+          }
+          _ => {}
+        }
+      }
+      sector_start += raw;
+    }
+  }
+
+  let mut warnings = Vec::new();
+  if let Some(patch) = patch_sector_size.filter(|patch| *patch != image.sector_size) {
+    warnings.push(Warning::SectorSizeMismatch { patch, image: image.sector_size });
+  }
+  if let Some((hunks, offset)) = past_end {
+    warnings.push(Warning::PastEnd { hunks, offset });
+  }
+  if let Some((hunks, offset)) = outside_volume {
+    warnings.push(Warning::OutsideVolume { hunks, offset });
+  }
+  warnings
+}
+
+/// Counts a hunk at `offset`, keeping the lowest offset.
+fn count(found: &mut Option<(u64, u64)>, offset: u64) {
+  let (hunks, first) = found.get_or_insert((0, offset));
+  *hunks += 1;
+  *first = (*first).min(offset);
+}
+
+/// The size of a raw sector, for buffers that hold one.
+const RAW_SECTOR_LEN: usize = SectorSize::Raw.bytes() as usize;
+/// The number of sectors before the first sector of a disc's data track,
+/// which addresses in sector headers count from.
+const LEAD_IN_SECTORS: u64 = 150;
+/// The subheader of a mode 2 form 1 data sector, repeated twice.
+const FORM_1_SUBHEADER: [u8; 8] = [0, 0, 0x08, 0, 0, 0, 0x08, 0];
+/// The flag in a mode 2 subheader's submode byte that marks form 2 sectors.
+const FORM_2_FLAG: u8 = 0x20;
+/// Marks a disc whose data track uses mode 2 sectors, such as a PlayStation
+/// disc, in its primary volume descriptor.
+const XA_SIGNATURE: &[u8; 8] = b"CD-XA001";
+const XA_SIGNATURE_OFFSET: u64 = 1024;
+
+/// Reads an image with one sector size as if it had the other, one sector at
+/// a time.
+///
+/// Cooked images are read from raw ones by skipping everything but the user
+/// data of each sector. Raw images are read from cooked ones by adding the
+/// sync pattern and headers, either copied from a template image or made up,
+/// and regenerating the error detection and correction codes. Mode 2 form 2
+/// sectors, such as XA audio and video, have more user data than a cooked
+/// sector can hold, so they're copied from the template unchanged.
+#[derive(Debug)]
+pub struct Converter<R, T> {
+  inner: R,
+  /// The raw image to copy sector headers from when reading a raw image.
+  template: Option<T>,
+  conversion: Option<(SectorSize, SectorSize)>,
+  /// Whether made-up sector headers are for mode 2 sectors.
+  xa: bool,
+  len: u64,
+  pos: u64,
+  /// The index of the sector of the converted image held in `buf`.
+  loaded: Option<u64>,
+  buf: Box<[u8; RAW_SECTOR_LEN]>,
+}
+
+impl<R: Read + Seek, T: Read + Seek> Converter<R, T> {
+  /// Reads `inner`, which has sectors of size `from`, as an image with
+  /// sectors of size `to`. `template` is only used when reading a raw image.
+  pub fn new(
+    mut inner: R,
+    from: SectorSize,
+    to: SectorSize,
+    template: Option<T>,
+  ) -> io::Result<Self> {
+    if from == to {
+      return Ok(Self::unchanged(inner));
+    }
+    let inner_len = inner.seek(io::SeekFrom::End(0))?;
+    let mut signature = [0u8; XA_SIGNATURE.len()];
+    let xa = from == SectorSize::Cooked
+      && read_at(
+        &mut inner,
+        VOLUME_DESCRIPTOR_SECTOR * from.bytes() + XA_SIGNATURE_OFFSET,
+        &mut signature,
+      )?
+      && signature == *XA_SIGNATURE;
+    Ok(Self {
+      inner,
+      template,
+      conversion: Some((from, to)),
+      xa,
+      len: inner_len.div_ceil(from.bytes()) * to.bytes(),
+      pos: 0,
+      loaded: None,
+      buf: Box::new([0; RAW_SECTOR_LEN]),
+    })
+  }
+
+  /// Reads `inner` as it is.
+  pub fn unchanged(inner: R) -> Self {
+    Self {
+      inner,
+      template: None,
+      conversion: None,
+      xa: false,
+      len: 0,
+      pos: 0,
+      loaded: None,
+      buf: Box::new([0; RAW_SECTOR_LEN]),
+    }
+  }
+
+  /// Fills `buf` with sector `index` of the converted image.
+  fn load(&mut self, index: u64, from: SectorSize, to: SectorSize) -> io::Result<()> {
+    let buf = &mut self.buf[..];
+    buf.fill(0);
+    match to {
+      SectorSize::Cooked => {
+        let mut sector = [0u8; RAW_SECTOR_LEN];
+        read_at(&mut self.inner, index * from.bytes(), &mut sector)?;
+        let start = user_data_offset(&sector);
+        buf[..to.bytes() as usize].copy_from_slice(&sector[start..start + to.bytes() as usize]);
+      }
+      SectorSize::Raw => {
+        let template_read = match &mut self.template {
+          Some(template) => read_at(template, index * to.bytes(), buf)?,
+          None => false,
+        };
+        if template_read && buf[15] == 2 && buf[18] & FORM_2_FLAG != 0 {
+          return Ok(());
+        }
+        if !template_read {
+          buf.fill(0);
+          buf[..SYNC.len()].copy_from_slice(&SYNC);
+          buf[12..15].copy_from_slice(&address(index));
+          buf[15] = if self.xa { 2 } else { 1 };
+          if self.xa {
+            buf[16..24].copy_from_slice(&FORM_1_SUBHEADER);
+          }
+        }
+        let start = user_data_offset(buf);
+        let user_data = &mut buf[start..start + from.bytes() as usize];
+        user_data.fill(0);
+        read_at(&mut self.inner, index * from.bytes(), user_data)?;
+        regenerate_edc_ecc(&mut self.buf);
+      }
+    }
+    Ok(())
+  }
+}
+
+impl<R: Read + Seek, T: Read + Seek> Read for Converter<R, T> {
+  fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+    let Some((from, to)) = self.conversion else {
+      return self.inner.read(out);
+    };
+    if self.pos >= self.len || out.is_empty() {
+      return Ok(0);
+    }
+    let index = self.pos / to.bytes();
+    if self.loaded != Some(index) {
+      self.loaded = None;
+      self.load(index, from, to)?;
+      self.loaded = Some(index);
+    }
+    let start = (self.pos % to.bytes()) as usize;
+    let n = out.len().min(to.bytes() as usize - start);
+    out[..n].copy_from_slice(&self.buf[start..start + n]);
+    self.pos += n as u64;
+    Ok(n)
+  }
+}
+
+impl<R: Read + Seek, T: Read + Seek> Seek for Converter<R, T> {
+  fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+    if self.conversion.is_none() {
+      return self.inner.seek(pos);
+    }
+    let new_pos = match pos {
+      io::SeekFrom::Start(offset) => Some(offset),
+      io::SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+      io::SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+    };
+    self.pos = new_pos.ok_or_else(|| {
+      io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "invalid seek to a negative position",
+      )
+    })?;
+    Ok(self.pos)
+  }
+}
+
+/// The offset of the user data in a raw sector, which depends on its mode.
+fn user_data_offset(sector: &[u8]) -> usize {
+  match sector[15] {
+    2 => 24,
+    _ => 16,
+  }
+}
+
+/// The address of the sector at `index` in the data track, in the
+/// binary-coded minutes, seconds and frames that sector headers use.
+fn address(index: u64) -> [u8; 3] {
+  let frames = index + LEAD_IN_SECTORS;
+  let bcd = |n: u64| ((n / 10) << 4 | n % 10) as u8;
+  [
+    bcd(frames / (60 * 75)),
+    bcd(frames / 75 % 60),
+    bcd(frames % 75),
+  ]
+}
+
+/// Recomputes the error detection code (EDC) and, for sectors that have
+/// them, the error correction codes (ECC) of a raw data sector from its
+/// header and user data. Audio and other sectors of unknown modes are left
+/// alone.
+pub fn regenerate_edc_ecc(sector: &mut [u8; RAW_SECTOR_LEN]) {
+  match sector[15] {
+    1 => {
+      let edc = edc(&sector[..0x810]);
+      sector[0x810..0x814].copy_from_slice(&edc.to_le_bytes());
+      sector[0x814..0x81C].fill(0);
+      generate_ecc(sector);
+    }
+    2 if sector[18] & FORM_2_FLAG == 0 => {
+      let edc = edc(&sector[0x10..0x818]);
+      sector[0x818..0x81C].copy_from_slice(&edc.to_le_bytes());
+      // The ECC of mode 2 sectors is computed as if the address were zero.
+      let address: [u8; 4] = sector[12..16].try_into().unwrap();
+      sector[12..16].fill(0);
+      generate_ecc(sector);
+      sector[12..16].copy_from_slice(&address);
+    }
+    2 => {
+      let edc = edc(&sector[0x10..0x92C]);
+      sector[0x92C..0x930].copy_from_slice(&edc.to_le_bytes());
+    }
+    _ => {}
+  }
+}
+
+/// Lookup tables for the EDC and ECC, as used by ECM and other CD tools.
+struct Tables {
+  ecc_f: [u8; 256],
+  ecc_b: [u8; 256],
+  edc: [u32; 256],
+}
+
+const TABLES: Tables = {
+  let mut tables = Tables { ecc_f: [0; 256], ecc_b: [0; 256], edc: [0; 256] };
+  let mut i = 0;
+  while i < 256 {
+    let j = ((i << 1) ^ if i & 0x80 != 0 { 0x11D } else { 0 }) as u8;
+    tables.ecc_f[i] = j;
+    tables.ecc_b[i ^ j as usize] = i as u8;
+    let mut edc = i as u32;
+    let mut bit = 0;
+    while bit < 8 {
+      edc = (edc >> 1) ^ if edc & 1 != 0 { 0xD801_8001 } else { 0 };
+      bit += 1;
+    }
+    tables.edc[i] = edc;
+    i += 1;
+  }
+  tables
+};
+
+fn edc(data: &[u8]) -> u32 {
+  (data.iter()).fold(0, |edc, &byte| {
+    (edc >> 8) ^ TABLES.edc[((edc ^ u32::from(byte)) & 0xFF) as usize]
+  })
+}
+
+/// Computes the P and Q parity of the sector, which cover everything from
+/// its header up to the parity itself.
+fn generate_ecc(sector: &mut [u8; RAW_SECTOR_LEN]) {
+  compute_ecc_block(sector, 86, 24, 2, 86, 0x81C);
+  compute_ecc_block(sector, 52, 43, 86, 88, 0x8C8);
+}
+
+fn compute_ecc_block(
+  sector: &mut [u8; RAW_SECTOR_LEN],
+  major_count: usize,
+  minor_count: usize,
+  major_mult: usize,
+  minor_inc: usize,
+  dest: usize,
+) {
+  const START: usize = 0xC;
+  let size = major_count * minor_count;
+  for major in 0..major_count {
+    let mut index = (major >> 1) * major_mult + (major & 1);
+    let mut ecc_a = 0u8;
+    let mut ecc_b = 0u8;
+    for _ in 0..minor_count {
+      let byte = sector[START + index];
+      index += minor_inc;
+      if index >= size {
+        index -= size;
+      }
+      ecc_a ^= byte;
+      ecc_b ^= byte;
+      ecc_a = TABLES.ecc_f[ecc_a as usize];
+    }
+    ecc_a = TABLES.ecc_b[(TABLES.ecc_f[ecc_a as usize] ^ ecc_b) as usize];
+    sector[dest + major] = ecc_a;
+    sector[dest + major + major_count] = ecc_a ^ ecc_b;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::crc::Crc32;
+  use romhacks_testkit::rom;
+
+  /// The user data of the known-good sectors below.
+  fn user_data() -> Vec<u8> {
+    (0..2048).map(|i| (i * 7 + 3) as u8).collect()
+  }
+
+  /// Sector 16 of a data track in `mode`, holding [`user_data`], without
+  /// its EDC or ECC.
+  fn sector(mode: u8) -> [u8; RAW_SECTOR_LEN] {
+    let mut sector = [0u8; RAW_SECTOR_LEN];
+    sector[..SYNC.len()].copy_from_slice(&SYNC);
+    sector[12..15].copy_from_slice(&address(16));
+    sector[15] = mode;
+    if mode == 2 {
+      sector[16..24].copy_from_slice(&FORM_1_SUBHEADER);
+    }
+    let start = user_data_offset(&sector);
+    sector[start..start + 2048].copy_from_slice(&user_data());
+    sector
+  }
+
+  // The EDCs and the checksums of the whole sectors come from a separate
+  // implementation of ECMA-130, which computes the EDC bit by bit from its
+  // polynomial and solves the parity check equations for the P and Q bytes.
+
+  #[test]
+  fn mode_1_edc_ecc() {
+    let mut sector = sector(1);
+    regenerate_edc_ecc(&mut sector);
+    assert_eq!(sector[12..16], [0x00, 0x02, 0x16, 0x01]);
+    assert_eq!(sector[0x810..0x814], [0x4C, 0xF0, 0x86, 0x1C]);
+    assert_eq!(Crc32::of(&sector).value(), 0x5F0351B9);
+  }
+
+  #[test]
+  fn mode_2_form_1_edc_ecc() {
+    let mut sector = sector(2);
+    regenerate_edc_ecc(&mut sector);
+    assert_eq!(sector[0x818..0x81C], [0xFB, 0x6F, 0x07, 0xD7]);
+    assert_eq!(Crc32::of(&sector).value(), 0x4C59836A);
+  }
+
+  /// Reads all of `image`, which has sectors of size `from`, as an image
+  /// with sectors of size `to`.
+  fn convert(image: &[u8], from: SectorSize, to: SectorSize) -> Vec<u8> {
+    let mut converted = Vec::new();
+    Converter::new(
+      io::Cursor::new(image),
+      from,
+      to,
+      None::<io::Cursor<Vec<u8>>>,
+    )
+    .unwrap()
+    .read_to_end(&mut converted)
+    .unwrap();
+    converted
+  }
+
+  #[test]
+  fn made_up_headers_match_known_good_sector() {
+    let image = user_data().repeat(17);
+    let raw = convert(&image, SectorSize::Cooked, SectorSize::Raw);
+    assert_eq!(raw.len(), 17 * RAW_SECTOR_LEN);
+    let mut expected = sector(1);
+    regenerate_edc_ecc(&mut expected);
+    assert!(raw[16 * RAW_SECTOR_LEN..] == expected);
+  }
+
+  #[test]
+  fn cooked_raw_cooked_round_trip() {
+    let image = rom::random(20 * 2048, 1);
+    let xa = rom::with_bytes(
+      &image,
+      16 * 2048 + XA_SIGNATURE_OFFSET as usize,
+      XA_SIGNATURE,
+    );
+    for (image, mode) in [(image, 1), (xa, 2)] {
+      let raw = convert(&image, SectorSize::Cooked, SectorSize::Raw);
+      assert!(raw.chunks(RAW_SECTOR_LEN).all(|sector| sector[15] == mode));
+      assert!(convert(&raw, SectorSize::Raw, SectorSize::Cooked) == image);
+    }
+  }
+}
@@ -0,0 +1,243 @@
+//! Checking the environment and the files to be patched for problems that
+//! would make patching fail partway, and saying how to fix them.
+
+use crate::crc::Crc32;
+use crate::error::prelude::*;
+use crate::io::prelude::*;
+use crate::{cache, chd, dirs, fs, i18n, io, manifest, patch, temp};
+use std::path;
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct Args {
+  /// A ROM that will be patched. Can be given more than once.
+  #[arg(short, long)]
+  pub rom: Vec<path::PathBuf>,
+  /// The patch that will be applied. Its header may give the size of the
+  /// patched files.
+  #[arg(short, long)]
+  pub patch: Option<path::PathBuf>,
+  /// The directory patched files will be written to.
+  #[arg(long, default_value = ".")]
+  pub dest: path::PathBuf,
+}
+
+impl Args {
+  pub fn call(self) -> Result<(), Error> {
+    let mut problems: Vec<Problem> = Vec::new();
+
+    let paths = self.rom.iter().chain(&self.patch).chain([&self.dest]);
+    problems.extend(
+      paths
+        .filter(|path| path.to_str().is_none())
+        .map(|path| Problem::NonUtf8Path { path: path.clone() }),
+    );
+
+    if let Err(source) = probe_writable(&self.dest) {
+      problems.push(Problem::Unwritable { dir: self.dest.clone(), source });
+    }
+    let cache_dir = dirs::cache_dir();
+    if cache_dir.is_dir() {
+      if let Err(source) = probe_writable(&cache_dir) {
+        problems.push(Problem::Unwritable { dir: cache_dir, source });
+      }
+    }
+    if cfg!(feature = "chdman") && chd::find_chdman().is_none() {
+      problems.push(Problem::ChdmanMissing);
+    }
+
+    let target_size = match &self.patch {
+      Some(patch_path) => self.target_size(patch_path)?,
+      None => None,
+    };
+    let mut needed = 0u64;
+    for rom_path in &self.rom {
+      let mut rom = fs::File::open(rom_path)?;
+      needed += target_size.unwrap_or(rom.known_len()?);
+      if !cfg!(feature = "chdman") && chd::is_chd(rom_path)? {
+        problems.push(chd::Error::Unsupported { path: rom_path.clone() }.into());
+      }
+      problems.extend(check_manifest(rom_path, &self.dest, &mut rom)?);
+    }
+    if let Some(available) = io::available_space(&self.dest)? {
+      if available < needed {
+        problems.push(Problem::NoSpace { dir: self.dest.clone(), needed, available });
+      }
+    }
+
+    if problems.is_empty() {
+      log::info!("{}", i18n::text("romhacks::doctor::ok"));
+      return Ok(());
+    }
+    let count = problems.len();
+    for problem in problems {
+      eprintln!("{:?}", miette::Report::new(problem));
+    }
+    Err(Error::ProblemsFound { count })
+  }
+
+  /// The size of the patched file, if the patch records it.
+  fn target_size(&self, patch_path: &path::Path) -> Result<Option<u64>, Error> {
+    let mut patch = patch::compression::open(patch_path)?;
+    let Some(kind) = patch::Kind::detect(&mut patch)? else {
+      return Ok(None);
+    };
+    Ok(patch::header::read(kind, &mut patch)?.target_size)
+  }
+}
+
+/// Creates and deletes a file in `dir` to find out whether files can be
+/// written there, which permissions alone don't tell on every platform.
+fn probe_writable(dir: &path::Path) -> io::Result<()> {
+  let probe = temp::create(dir, "romhacks-doctor")?.path().to_path_buf();
+  fs::remove_file(&probe)
+}
+
+/// Checks that the manifest of the ROM's game in `dest`, if there is one, can
+/// be read and doesn't record a different file with the ROM's name.
+fn check_manifest(
+  rom_path: &path::Path,
+  dest: &path::Path,
+  rom: &mut fs::File,
+) -> io::Result<Option<Problem>> {
+  let manifest_path = manifest::path_for(rom_path, dest);
+  if !fs::try_exists(&manifest_path)? {
+    return Ok(None);
+  }
+  let manifest = match manifest::read(&manifest_path) {
+    Ok(manifest) => manifest,
+    Err(manifest::Error::IO(err)) => return Err(err),
+    Err(source) => {
+      return Ok(Some(Problem::BadManifest {
+        manifest: manifest_path,
+        source,
+      }));
+    }
+  };
+  let file_name = rom_path.file_name().unwrap_or_default().to_string_lossy();
+  let Some(file) = manifest.files.iter().find(|file| file.name == file_name) else {
+    return Ok(None);
+  };
+  let digest = cache::read_and_hash(None, rom)?;
+  if file.crc32 == digest {
+    return Ok(None);
+  }
+  let rom = rom_path.to_owned();
+  Ok(Some(
+    match file.patches.iter().find(|patch| patch.result == digest) {
+      Some(patch) => Problem::AlreadyPatched {
+        manifest: manifest_path,
+        rom,
+        patch: patch.name.clone(),
+      },
+      None => Problem::ManifestConflict {
+        manifest: manifest_path,
+        rom,
+        recorded: file.crc32,
+        actual: digest,
+      },
+    },
+  ))
+}
+
+/// A problem found by `doctor`, with help on how to fix it.
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Problem {
+  #[error("{}", i18n::format("romhacks::doctor::non_utf8", &[("path", &path.display())]))]
+  #[diagnostic(
+    code(romhacks::doctor::non_utf8),
+    severity(Warning),
+    help("{}", i18n::text("romhacks::doctor::non_utf8::help"))
+  )]
+  NonUtf8Path { path: path::PathBuf },
+  #[error("{}", i18n::format(
+    "romhacks::doctor::unwritable",
+    &[("dir", &dir.display()), ("error", source)]
+  ))]
+  #[diagnostic(
+    code(romhacks::doctor::unwritable),
+    help("{}", i18n::text("romhacks::doctor::unwritable::help"))
+  )]
+  Unwritable {
+    dir: path::PathBuf,
+    source: io::Error,
+  },
+  #[error("{}", i18n::format(
+    "romhacks::doctor::no_space",
+    &[("dir", &dir.display()), ("needed", needed), ("available", available)]
+  ))]
+  #[diagnostic(
+    code(romhacks::doctor::no_space),
+    help("{}", i18n::format("romhacks::doctor::no_space::help", &[("missing", &(needed - available))]))
+  )]
+  NoSpace {
+    dir: path::PathBuf,
+    needed: u64,
+    available: u64,
+  },
+  #[error("{}", i18n::text("romhacks::doctor::chdman_missing"))]
+  #[diagnostic(
+    code(romhacks::doctor::chdman_missing),
+    severity(Warning),
+    help("{}", i18n::text("romhacks::doctor::chdman_missing::help"))
+  )]
+  ChdmanMissing,
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Chd(#[from] chd::Error),
+  #[error("{}", i18n::format("romhacks::doctor::bad_manifest", &[("manifest", &manifest.display())]))]
+  #[diagnostic(
+    code(romhacks::doctor::bad_manifest),
+    help("{}", i18n::text("romhacks::doctor::bad_manifest::help"))
+  )]
+  BadManifest {
+    manifest: path::PathBuf,
+    #[source]
+    source: manifest::Error,
+  },
+  #[error("{}", i18n::format(
+    "romhacks::doctor::already_patched",
+    &[("rom", &rom.display()), ("manifest", &manifest.display()), ("patch", patch)]
+  ))]
+  #[diagnostic(
+    code(romhacks::doctor::already_patched),
+    help("{}", i18n::text("romhacks::doctor::already_patched::help"))
+  )]
+  AlreadyPatched {
+    manifest: path::PathBuf,
+    rom: path::PathBuf,
+    patch: String,
+  },
+  #[error("{}", i18n::format(
+    "romhacks::doctor::manifest_conflict",
+    &[
+      ("rom", &rom.display()),
+      ("manifest", &manifest.display()),
+      ("recorded", &format!("{:08X}", recorded.value())),
+      ("actual", &format!("{:08X}", actual.value()))
+    ]
+  ))]
+  #[diagnostic(
+    code(romhacks::doctor::manifest_conflict),
+    help("{}", i18n::text("romhacks::doctor::manifest_conflict::help"))
+  )]
+  ManifestConflict {
+    manifest: path::PathBuf,
+    rom: path::PathBuf,
+    recorded: Crc32,
+    actual: Crc32,
+  },
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Patch(#[from] patch::Error),
+  #[error("{}", i18n::format("romhacks::doctor::problems_found", &[("count", count)]))]
+  #[diagnostic(code(romhacks::doctor::problems_found))]
+  ProblemsFound { count: usize },
+}
@@ -0,0 +1,277 @@
+//! Describing the layout of each patch format from the same tables the
+//! parsers use, so that what the program says about a format can't drift
+//! from what it reads.
+
+use crate::error::prelude::*;
+use crate::io::prelude::*;
+use crate::patch::header::{Footer, VersionField};
+use crate::{i18n, identify, info, io, json, patch};
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct Args {
+  /// The format to describe: ips, ups, bps, ppf, vcd, rup, aps, aps-gba,
+  /// bsdiff, gdiff, ips32 or the name of a format added by a plugin.
+  pub format: String,
+  /// Print the description as JSON instead of tables. Negative offsets count
+  /// back from the end of the patch.
+  #[arg(long)]
+  pub json: bool,
+}
+
+impl Args {
+  pub fn call(self) -> Result<(), Error> {
+    // Plugins are only loaded once the command line has been parsed, so the
+    // format can't be parsed along with it.
+    let format = parse_format(&self.format)?;
+    let fields = fields(format);
+    match self.json {
+      true => {
+        let mut stdout = io::stdout().lock();
+        writeln!(stdout, "{}", to_json(format, &fields))?;
+        stdout.flush()?;
+      }
+      false => {
+        info::print_table(&field_table(&fields));
+        println!();
+        info::print_table(&capability_table(format.capabilities()));
+      }
+    }
+    Ok(())
+  }
+}
+
+/// A field at the same place in every patch of a format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Field {
+  /// The offset of the field from the start of the patch or, if it's
+  /// negative, back from its end.
+  pub offset: i64,
+  pub len: usize,
+  pub content: Content,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Content {
+  /// The bytes that identify the format.
+  Magic(&'static [u8]),
+  /// The format's version, and the versions that can be applied.
+  Version(&'static [&'static str]),
+  /// A little-endian CRC32.
+  Crc32(Checksummed),
+}
+
+/// What a checksum in a patch is computed over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Checksummed {
+  Source,
+  Target,
+  /// Everything in the patch before the checksum.
+  Patch,
+}
+
+impl Checksummed {
+  fn description_key(self) -> &'static str {
+    match self {
+      Checksummed::Source => "romhacks::explain::source_crc32",
+      Checksummed::Target => "romhacks::explain::target_crc32",
+      Checksummed::Patch => "romhacks::explain::patch_crc32",
+    }
+  }
+}
+
+impl Content {
+  fn name(self) -> &'static str {
+    match self {
+      Content::Magic(_) => "magic",
+      Content::Version(_) => "version",
+      Content::Crc32(Checksummed::Source) => "source_crc32",
+      Content::Crc32(Checksummed::Target) => "target_crc32",
+      Content::Crc32(Checksummed::Patch) => "patch_crc32",
+    }
+  }
+}
+
+/// Returns the fields at fixed places in patches of `kind`, in the order
+/// they're found.
+pub fn fields(kind: patch::Kind) -> Vec<Field> {
+  let mut fields = Vec::new();
+  let magic = kind.magic();
+  fields.push(Field {
+    offset: 0,
+    len: magic.len(),
+    content: Content::Magic(magic),
+  });
+  if let Some(version) = VersionField::of(kind) {
+    fields.push(Field {
+      offset: version.offset as i64,
+      len: version.len,
+      content: Content::Version(version.supported),
+    });
+  }
+  // Formats that store checksums store them in a footer, in this order.
+  let capabilities = kind.capabilities();
+  let checksums = [
+    (capabilities.source_checksum, Checksummed::Source),
+    (capabilities.target_checksum, Checksummed::Target),
+    (capabilities.patch_checksum, Checksummed::Patch),
+  ];
+  let crc32_len = size_of::<u32>();
+  for (index, (stored, checksummed)) in checksums.into_iter().enumerate() {
+    if stored {
+      fields.push(Field {
+        offset: (index * crc32_len) as i64 - Footer::SIZE as i64,
+        len: crc32_len,
+        content: Content::Crc32(checksummed),
+      });
+    }
+  }
+  fields
+}
+
+fn hex(bytes: &[u8]) -> String {
+  let bytes: Vec<String> = bytes.iter().map(|byte| format!("{byte:02X}")).collect();
+  bytes.join(" ")
+}
+
+fn to_json(kind: patch::Kind, fields: &[Field]) -> json::Value {
+  use json::Value as V;
+  let string = |str: &str| V::String(str.to_owned());
+  let fields = fields.iter().map(|field| {
+    let mut entries = vec![
+      ("name".to_owned(), string(field.content.name())),
+      ("offset".to_owned(), V::Integer(field.offset.into())),
+      ("length".to_owned(), V::Integer(field.len as i128)),
+    ];
+    entries.push(match field.content {
+      Content::Magic(magic) => ("bytes".to_owned(), string(&hex(magic))),
+      Content::Version(supported) => (
+        "supported".to_owned(),
+        V::Array(supported.iter().map(|version| string(version)).collect()),
+      ),
+      Content::Crc32(_) => ("byte_order".to_owned(), string("little")),
+    });
+    V::Object(entries)
+  });
+  let capabilities = kind.capabilities();
+  let flag = |name: &str, value: bool| (name.to_owned(), V::Bool(value));
+  V::Object(vec![
+    ("format".to_owned(), string(identify::name(kind))),
+    ("name".to_owned(), string(&kind.to_string())),
+    ("fields".to_owned(), V::Array(fields.collect())),
+    (
+      "capabilities".to_owned(),
+      V::Object(vec![
+        flag("source_checksum", capabilities.source_checksum),
+        flag("target_checksum", capabilities.target_checksum),
+        flag("patch_checksum", capabilities.patch_checksum),
+        flag("creation", capabilities.creation),
+        flag("in_place", capabilities.in_place),
+        (
+          "max_file_size".to_owned(),
+          match capabilities.max_file_size {
+            u64::MAX => V::Null,
+            size => V::Integer(size.into()),
+          },
+        ),
+        flag("seeks_source", capabilities.seeks_source),
+        flag("seeks_patch", capabilities.seeks_patch),
+        flag("seeks_output", capabilities.seeks_output),
+      ]),
+    ),
+  ])
+}
+
+fn field_table(fields: &[Field]) -> Vec<Vec<String>> {
+  let header = [
+    "romhacks::explain::field",
+    "romhacks::explain::offset",
+    "romhacks::explain::length",
+    "romhacks::explain::description",
+  ];
+  let rows = fields.iter().map(|field| {
+    let offset = match field.offset {
+      offset if offset < 0 => i18n::format(
+        "romhacks::explain::from_end",
+        &[("offset", &format!("{:#X}", offset.unsigned_abs()))],
+      ),
+      offset => format!("{offset:#X}"),
+    };
+    let description = match field.content {
+      Content::Magic(magic) => i18n::format(
+        "romhacks::explain::magic",
+        &[
+          ("bytes", &hex(magic)),
+          ("text", &String::from_utf8_lossy(magic)),
+        ],
+      ),
+      Content::Version(supported) => i18n::format(
+        "romhacks::explain::version",
+        &[("supported", &supported.join(", "))],
+      ),
+      Content::Crc32(checksummed) => i18n::text(checksummed.description_key()).to_owned(),
+    };
+    vec![
+      field.content.name().to_owned(),
+      offset,
+      field.len.to_string(),
+      description,
+    ]
+  });
+  std::iter::once(header.map(|key| i18n::text(key).to_owned()).to_vec())
+    .chain(rows)
+    .collect()
+}
+
+fn capability_table(capabilities: patch::Capabilities) -> Vec<Vec<String>> {
+  let yes_no =
+    |value: bool| i18n::text(if value { "romhacks::info::yes" } else { "romhacks::info::no" });
+  let row = |key: &'static str, value: &str| vec![i18n::text(key).to_owned(), value.to_owned()];
+  vec![
+    row("romhacks::info::creation", yes_no(capabilities.creation)),
+    row("romhacks::info::in_place", yes_no(capabilities.in_place)),
+    row(
+      "romhacks::info::max_file_size",
+      &match capabilities.max_file_size {
+        u64::MAX => i18n::text("romhacks::info::unlimited").to_owned(),
+        size => info::format_size(size),
+      },
+    ),
+    row(
+      "romhacks::explain::seeks_source",
+      yes_no(capabilities.seeks_source),
+    ),
+    row(
+      "romhacks::explain::seeks_patch",
+      yes_no(capabilities.seeks_patch),
+    ),
+    row(
+      "romhacks::explain::seeks_output",
+      yes_no(capabilities.seeks_output),
+    ),
+  ]
+}
+
+fn parse_format(arg: &str) -> Result<patch::Kind, Error> {
+  let kinds = patch::Kind::available();
+  (kinds.iter().copied())
+    .find(|&kind| identify::name(kind) == arg.to_ascii_lowercase())
+    .ok_or_else(|| Error::UnknownFormat {
+      format: arg.to_owned(),
+      formats: (kinds.into_iter().map(identify::name))
+        .collect::<Vec<_>>()
+        .join(", "),
+    })
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error("{}", i18n::format(
+    "romhacks::explain::unknown_format",
+    &[("format", format), ("formats", formats)]
+  ))]
+  #[diagnostic(code(romhacks::explain::unknown_format))]
+  UnknownFormat { format: String, formats: String },
+}
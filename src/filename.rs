@@ -1,11 +1,15 @@
+use crate::error::prelude::*;
+use crate::i18n;
 use regex_lite::Regex;
+use std::borrow::Cow;
 use std::cell::LazyCell;
 use std::ffi::OsStr;
 use std::path::Path;
 
 /// The regex state required to match game names in game file names.
 pub struct GameNameMatcher {
-  regex: LazyCell<Regex>,
+  track: LazyCell<Regex>,
+  dump_tags: LazyCell<Regex>,
 }
 
 /// Equivalent to [`GameNameMatcher::new().infer_game_name(file_name)`][infer_game_name].
@@ -19,40 +23,374 @@ pub fn infer_game_name(file_name: &Path) -> &OsStr {
 impl GameNameMatcher {
   pub fn new() -> Self {
     Self {
-      // Use LazyCell so the regex is only compiled the first time it's used.
-      regex: LazyCell::new(|| Regex::new(r#" \(Track \d\d?\)$"#).unwrap()),
+      // Use LazyCell so the regexes are only compiled the first time they're used.
+      track: LazyCell::new(|| Regex::new(r#" \(Track \d\d?\)$"#).unwrap()),
+      // GoodTools-style dump quality tags, e.g. [!], [b1], [a2], [o1].
+      dump_tags: LazyCell::new(|| Regex::new(r#"(?: ?\[(?:!|[abfhopt]\d*)\])+$"#).unwrap()),
     }
   }
 
   /// Returns a substring of [`file_path.file_stem()`][file_stem] that's representative
   /// of the game's name.
   ///
+  /// No-Intro and GoodTools tags in parentheses, such as the region and
+  /// revision in "Game (USA) (Rev 1)", are kept since they distinguish
+  /// different releases of a game. Trailing dump quality tags in square
+  /// brackets, such as "[!]" or "[b1]", are removed, as is the track number of
+  /// a BIN file from a multi-track disc image.
+  ///
   /// [file_stem]: Path::file_stem
   ///
   /// # Panics
   /// This method panics if `file_path.file_stem() == None`.
   pub fn infer_game_name<'a>(&self, file_path: &'a Path) -> &'a OsStr {
     let file_stem = file_path.file_stem().unwrap();
-    if (file_path.extension()).is_some_and(|ext| ext.eq_ignore_ascii_case(".bin")) {
-      let game_name_lossy = file_path.file_stem().unwrap().to_string_lossy();
-      if let Some(m) = self.regex.find(game_name_lossy.as_ref()) {
-        let bytes = file_stem.as_encoded_bytes();
-        let match_len = m.range().len();
-        // Per the function's documentation, it's safe to split an OsStr's bytes immediately before
-        // a non-empty UTF-8 substring.
-        return unsafe { OsStr::from_encoded_bytes_unchecked(&bytes[..bytes.len() - match_len]) };
+    // Tags are only recognized in file names that are valid UTF-8.
+    let Some(mut game_name) = file_stem.to_str() else {
+      return file_stem;
+    };
+    if let Some(m) = self.dump_tags.find(game_name) {
+      game_name = &game_name[..m.start()];
+    }
+    if (file_path.extension()).is_some_and(|ext| ext.eq_ignore_ascii_case("bin")) {
+      if let Some(m) = self.track.find(game_name) {
+        game_name = &game_name[..m.start()];
       }
     }
-    file_stem
+    OsStr::new(game_name)
+  }
+}
+
+/// The template used to name patched files if the user doesn't provide one.
+pub const DEFAULT_NAME_TEMPLATE: &str = "{name} ({hack} v{version}).{ext}";
+
+/// The values that can be substituted into a file name template.
+#[derive(Clone, Debug, Default)]
+pub struct TemplateVars<'a> {
+  /// The inferred game name.
+  pub name: &'a str,
+  /// The name of the ROM hack.
+  pub hack: &'a str,
+  /// The version of the ROM hack.
+  pub version: &'a str,
+  /// The extension of the original file, without the leading period.
+  pub ext: &'a str,
+}
+
+/// Renders a file name template such as `"{name} [{hack} v{version}].{ext}"`.
+///
+/// Literal braces can be written as `{{` and `}}`. If `vars.ext` is empty,
+/// a trailing period is removed from the result. Substituted values are
+/// [sanitized](sanitize) so they can't introduce illegal characters or
+/// directories, and the file name they end up in is made
+/// [safe](safe_file_name), since they come from patches and their metadata.
+pub fn render_template(template: &str, vars: &TemplateVars) -> Result<String, TemplateError> {
+  let mut result = String::with_capacity(template.len() + vars.name.len());
+  let mut rest = template;
+  while let Some(i) = rest.find(['{', '}']) {
+    result.push_str(&rest[..i]);
+    rest = &rest[i..];
+    if let Some(tail) = rest.strip_prefix("{{") {
+      result.push('{');
+      rest = tail;
+    } else if let Some(tail) = rest.strip_prefix("}}") {
+      result.push('}');
+      rest = tail;
+    } else if let Some(tail) = rest.strip_prefix('{') {
+      let end = tail.find('}').ok_or(TemplateError::Unclosed)?;
+      result.push_str(&sanitize(match &tail[..end] {
+        "name" => vars.name,
+        "hack" => vars.hack,
+        "version" => vars.version,
+        "ext" => vars.ext,
+        key => return Err(TemplateError::UnknownKey(key.to_owned())),
+      }));
+      rest = &tail[end + 1..];
+    } else {
+      return Err(TemplateError::Unopened);
+    }
+  }
+  result.push_str(rest);
+  if vars.ext.is_empty() && result.ends_with('.') {
+    result.pop();
+  }
+  // Directories in the template itself are kept.
+  Ok(match result.rsplit_once(std::path::is_separator) {
+    Some((dir, name)) => {
+      let separator = &result[dir.len()..result.len() - name.len()];
+      format!("{dir}{separator}{}", safe_file_name(name))
+    }
+    None => safe_file_name(&result).into_owned(),
+  })
+}
+
+/// Replaces characters that can't appear in a file name on the current
+/// platform with underscores, as well as control characters and both path
+/// separators, so that a name made for one platform can't name a directory
+/// on another.
+pub fn sanitize(name: &str) -> Cow<'_, str> {
+  #[cfg(windows)]
+  const ILLEGAL: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+  #[cfg(not(windows))]
+  const ILLEGAL: &[char] = &['/', '\\'];
+
+  let is_illegal = |c: char| c.is_control() || ILLEGAL.contains(&c);
+  if name.contains(is_illegal) {
+    Cow::Owned(name.replace(is_illegal, "_"))
+  } else {
+    Cow::Borrowed(name)
   }
 }
 
+/// The longest file name, in bytes, that common filesystems allow.
+const MAX_FILE_NAME_LEN: usize = 255;
+
+/// The longest extension that's kept when a file name is shortened.
+const MAX_KEPT_EXTENSION_LEN: usize = 16;
+
+/// Names of devices that Windows opens instead of files, whatever their
+/// extension, when they're compared without ASCII case.
+const RESERVED_NAMES: &[&str] = &["CON", "PRN", "AUX", "NUL", "CONIN$", "CONOUT$"];
+
+/// Devices that Windows reserves with a digit after their name.
+const RESERVED_NUMBERED_NAMES: &[&str] = &["COM", "LPT"];
+
+/// Makes `name` a file name that can only name a file in the directory it's
+/// written to, on any platform: it's
+/// [sanitized](sanitize), trailing periods and spaces are removed since
+/// Windows drops them, a name that's empty or only periods becomes an
+/// underscore, a name Windows reserves for a device gets an underscore in
+/// front, and a name longer than 255 bytes is shortened before its
+/// extension.
+///
+/// Names are only compared as ASCII, so the result doesn't depend on the
+/// locale.
+pub fn safe_file_name(name: &str) -> Cow<'_, str> {
+  let sanitized = sanitize(name);
+  let trimmed = sanitized.trim_end_matches(['.', ' ']);
+  let mut safe = match trimmed {
+    "" => Cow::Borrowed("_"),
+    name if is_reserved(name) => Cow::Owned(format!("_{name}")),
+    name if name.len() == sanitized.len() => sanitized,
+    name => Cow::Owned(name.to_owned()),
+  };
+  if safe.len() > MAX_FILE_NAME_LEN {
+    safe = Cow::Owned(shorten(&safe));
+  }
+  safe
+}
+
+/// Whether Windows opens a device for `name`, which it does if the part
+/// before the first period, without trailing spaces, is a device's name.
+fn is_reserved(name: &str) -> bool {
+  let stem = name
+    .split('.')
+    .next()
+    .unwrap_or_default()
+    .trim_end_matches(' ');
+  RESERVED_NAMES
+    .iter()
+    .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    || RESERVED_NUMBERED_NAMES.iter().any(|reserved| {
+      stem
+        .get(..reserved.len())
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case(reserved))
+        && matches!(
+          &stem[reserved.len()..],
+          "1" | "2" | "3" | "4" | "5" | "6" | "7" | "8" | "9" | "¹" | "²" | "³"
+        )
+    })
+}
+
+/// Cuts `name` down to [`MAX_FILE_NAME_LEN`] bytes, from before its
+/// extension if it has a short one, without splitting a character.
+fn shorten(name: &str) -> String {
+  let extension = match name.rfind('.') {
+    Some(i) if i > 0 && name.len() - i <= MAX_KEPT_EXTENSION_LEN + 1 => &name[i..],
+    _ => "",
+  };
+  let mut end = MAX_FILE_NAME_LEN - extension.len();
+  while !name.is_char_boundary(end) {
+    end -= 1;
+  }
+  format!("{}{extension}", name[..end].trim_end_matches(['.', ' ']))
+}
+
+#[derive(Clone, Debug, Error, Diagnostic)]
+pub enum TemplateError {
+  #[error("{}", i18n::format("romhacks::template::unknown_key", &[("key", .0)]))]
+  #[diagnostic(
+    code(romhacks::template::unknown_key),
+    help("{}", i18n::format("romhacks::template::unknown_key::help", &[]))
+  )]
+  UnknownKey(String),
+  #[error("{}", i18n::format("romhacks::template::unclosed", &[]))]
+  #[diagnostic(code(romhacks::template::unclosed))]
+  Unclosed,
+  #[error("{}", i18n::format("romhacks::template::unopened", &[]))]
+  #[diagnostic(code(romhacks::template::unopened))]
+  Unopened,
+}
+
 pub trait FileName {
   fn file_name(&self) -> &OsStr;
 }
 
-impl FileName for fs_err::File {
+impl FileName for crate::fs::File {
   fn file_name(&self) -> &OsStr {
     self.path().file_name().unwrap()
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::path::Component;
+
+  /// Checks that `name` can only name a file in the directory it's written
+  /// to.
+  fn assert_safe(name: &str) {
+    let components: Vec<_> = Path::new(name).components().collect();
+    assert_eq!(
+      components,
+      [Component::Normal(OsStr::new(name))],
+      "{name:?}"
+    );
+    assert!(name.len() <= MAX_FILE_NAME_LEN, "{name:?}");
+    assert!(!name.contains(['/', '\\', '\0']), "{name:?}");
+    assert!(!is_reserved(name), "{name:?}");
+  }
+
+  #[test]
+  fn parent_and_current_directories() {
+    for name in ["..", ".", "...", ". .", ""] {
+      assert_eq!(safe_file_name(name), "_");
+    }
+    assert_safe(&safe_file_name("../../.bashrc"));
+    assert_safe(&safe_file_name("..\\..\\autoexec.bat"));
+  }
+
+  #[test]
+  fn absolute_paths() {
+    assert_eq!(safe_file_name("/etc/passwd"), "_etc_passwd");
+    for name in [
+      "/",
+      "C:\\Windows\\win.ini",
+      "\\\\server\\share\\rom.sfc",
+      "//server/share",
+    ] {
+      assert_safe(&safe_file_name(name));
+    }
+  }
+
+  #[test]
+  fn nul_and_control_characters() {
+    assert_eq!(safe_file_name("Game\0.sfc"), "Game_.sfc");
+    assert_eq!(safe_file_name("Game\n(Hack)\t.sfc"), "Game_(Hack)_.sfc");
+  }
+
+  #[test]
+  fn path_separators() {
+    assert_eq!(safe_file_name("Hack/v1.2"), "Hack_v1.2");
+    assert_eq!(safe_file_name("Hack\\v1.2"), "Hack_v1.2");
+  }
+
+  #[test]
+  fn windows_reserved_names() {
+    for (name, safe) in [
+      ("CON", "_CON"),
+      ("con.sfc", "_con.sfc"),
+      ("Aux.tar.gz", "_Aux.tar.gz"),
+      ("NUL ", "_NUL"),
+      ("nul .txt", "_nul .txt"),
+      ("COM1", "_COM1"),
+      ("lpt9.smc", "_lpt9.smc"),
+      ("COM²", "_COM²"),
+      ("CONIN$", "_CONIN$"),
+    ] {
+      assert_eq!(safe_file_name(name), safe, "{name:?}");
+    }
+    for name in ["CONSOLE", "COM0", "COM10", "LPT", "Contra.nes", "_CON"] {
+      assert_eq!(safe_file_name(name), name, "{name:?}");
+    }
+  }
+
+  #[test]
+  fn long_names_keep_their_extension() {
+    let safe = safe_file_name(&format!("{}.sfc", "a".repeat(300))).into_owned();
+    assert_eq!(safe.len(), MAX_FILE_NAME_LEN);
+    assert!(safe.ends_with("a.sfc"));
+
+    // Characters aren't split when the name is cut.
+    let safe = safe_file_name(&format!("{}.sfc", "é".repeat(200))).into_owned();
+    assert!(safe.len() <= MAX_FILE_NAME_LEN);
+    assert!(safe.ends_with("é.sfc"));
+
+    // An extension too long to be one isn't kept.
+    let safe = safe_file_name(&format!("Game.{}", "b".repeat(300))).into_owned();
+    assert_eq!(safe.len(), MAX_FILE_NAME_LEN);
+    assert!(safe.starts_with("Game.bbb"));
+
+    // Periods and spaces at the cut are trimmed, as Windows would.
+    let safe = safe_file_name(&format!("{}{}.sfc", "a".repeat(250), " .".repeat(20))).into_owned();
+    assert_eq!(safe, format!("{}.sfc", "a".repeat(250)));
+  }
+
+  #[test]
+  fn names_that_are_already_safe() {
+    for name in ["Game (USA) (Hack v1.0).sfc", ".hidden", "v1.2.3"] {
+      assert!(matches!(safe_file_name(name), Cow::Borrowed(_)), "{name:?}");
+    }
+  }
+
+  #[test]
+  fn hostile_template_values() {
+    let hostile = [
+      "..",
+      "../..",
+      "/etc/passwd",
+      "C:\\Windows",
+      "a\0b",
+      "CON",
+      "lpt1",
+      " . ",
+    ];
+    for value in hostile {
+      let name = render_template(
+        DEFAULT_NAME_TEMPLATE,
+        &TemplateVars {
+          name: value,
+          hack: value,
+          version: value,
+          ext: value,
+        },
+      )
+      .unwrap();
+      assert_safe(&name);
+      let name = render_template(
+        "{hack}",
+        &TemplateVars { hack: value, ..TemplateVars::default() },
+      )
+      .unwrap();
+      assert_safe(&name);
+    }
+    let name = render_template(
+      "{hack}",
+      &TemplateVars { hack: &"x".repeat(1000), ..TemplateVars::default() },
+    );
+    assert_safe(&name.unwrap());
+  }
+
+  #[test]
+  fn directories_in_template_are_kept() {
+    let vars = TemplateVars { name: "..", hack: "CON", version: "1", ext: "sfc" };
+    assert_eq!(
+      render_template("patched/{hack}.{ext}", &vars).unwrap(),
+      "patched/_CON.sfc"
+    );
+    assert_eq!(
+      render_template("patched/{name}", &vars).unwrap(),
+      "patched/_"
+    );
+  }
+}
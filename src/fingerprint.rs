@@ -0,0 +1,106 @@
+//! Fingerprints of files made of content-defined chunks, for recognizing
+//! different dumps of the same game.
+//!
+//! A file is split wherever a rolling hash of the last few bytes matches a
+//! pattern, so chunk boundaries depend only on the bytes around them. Bytes
+//! inserted, removed or appended in one place, as in an overdump or a dump
+//! with different padding, only change the chunks around them, and the rest
+//! of the chunks are the same in both files.
+
+use crate::io;
+use crate::io::prelude::*;
+use std::collections::HashMap;
+
+/// Chunks are never shorter than this, except at the end of the file.
+const MIN_CHUNK_LEN: usize = 2 * 1024;
+
+/// Chunks are never longer than this, so that a run of identical bytes is
+/// still split up.
+const MAX_CHUNK_LEN: usize = 64 * 1024;
+
+/// A chunk ends where these bits of the rolling hash are zero, which makes
+/// chunks 8 KiB long on average.
+const BOUNDARY_MASK: u64 = (1 << 13) - 1;
+
+/// A random value for each byte, which the rolling hash adds up.
+const GEAR: [u64; 256] = {
+  // SplitMix64, seeded with a fixed value so that fingerprints are the same
+  // on every run.
+  let mut table = [0u64; 256];
+  let mut state: u64 = 0x726F_6D68_6163_6B73;
+  let mut i = 0;
+  while i < table.len() {
+    state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    table[i] = z ^ (z >> 31);
+    i += 1;
+  }
+  table
+};
+
+/// The checksums and lengths of a file's chunks.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Fingerprint {
+  /// The number of chunks with each CRC-32 and length.
+  chunks: HashMap<(u32, u32), u32>,
+  len: u64,
+}
+
+impl Fingerprint {
+  /// Splits everything `reader` reads into chunks and hashes them.
+  pub fn read(reader: &mut impl Read) -> io::Result<Self> {
+    let mut fingerprint = Self::default();
+    let mut chunk: Vec<u8> = Vec::with_capacity(MAX_CHUNK_LEN);
+    let mut hash = 0u64;
+    let mut buf = vec![0u8; MAX_CHUNK_LEN];
+    loop {
+      let read = match reader.read(&mut buf) {
+        Ok(0) => break,
+        Ok(read) => read,
+        Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+        Err(err) => return Err(err),
+      };
+      for &byte in &buf[..read] {
+        chunk.push(byte);
+        hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+        let boundary = chunk.len() >= MIN_CHUNK_LEN && hash & BOUNDARY_MASK == 0;
+        if boundary || chunk.len() == MAX_CHUNK_LEN {
+          fingerprint.add(&chunk);
+          chunk.clear();
+          hash = 0;
+        }
+      }
+    }
+    if !chunk.is_empty() {
+      fingerprint.add(&chunk);
+    }
+    Ok(fingerprint)
+  }
+
+  /// The number of bytes in chunks that both fingerprints have.
+  pub fn shared_bytes(&self, other: &Fingerprint) -> u64 {
+    (self.chunks.iter())
+      .filter_map(|(key @ &(_, len), &count)| {
+        let shared = count.min(*other.chunks.get(key)?);
+        Some(u64::from(shared) * u64::from(len))
+      })
+      .sum()
+  }
+
+  /// How alike the files are, from 0 for files with no chunks in common to 1
+  /// for files with the same chunks.
+  pub fn similarity(&self, other: &Fingerprint) -> f64 {
+    match self.len + other.len {
+      0 => 1.0,
+      total => 2.0 * self.shared_bytes(other) as f64 / total as f64,
+    }
+  }
+
+  fn add(&mut self, chunk: &[u8]) {
+    let key = (crc32fast::hash(chunk), chunk.len() as u32);
+    *self.chunks.entry(key).or_default() += 1;
+    self.len += chunk.len() as u64;
+  }
+}
@@ -0,0 +1,76 @@
+//! File operations whose errors say which file they were for and what was
+//! being done to it, so that "Permission denied" comes with a path.
+//!
+//! Everything in [`fs_err`] is re-exported, so this module is used in place
+//! of `std::fs`. [`with_path`] adds the same context to errors from
+//! operations `fs_err` doesn't wrap, such as system calls made directly.
+
+use crate::{i18n, io};
+pub use fs_err::*;
+use std::{fmt, path};
+
+/// What was being done to a file when an error happened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Operation {
+  /// Checking whether the file exists.
+  CheckExists,
+  /// Reading the file's metadata, or that of the filesystem it's on.
+  Stat,
+  ReadAttributes,
+  WriteAttributes,
+  SetTimes,
+  /// Copying another file's contents into the file.
+  Copy,
+}
+
+impl Operation {
+  fn key(self) -> &'static str {
+    match self {
+      Operation::CheckExists => "romhacks::fs::check_exists",
+      Operation::Stat => "romhacks::fs::stat",
+      Operation::ReadAttributes => "romhacks::fs::read_attributes",
+      Operation::WriteAttributes => "romhacks::fs::write_attributes",
+      Operation::SetTimes => "romhacks::fs::set_times",
+      Operation::Copy => "romhacks::fs::copy",
+    }
+  }
+}
+
+/// An error from an operation on a file, with the file's path. Like the
+/// errors of [`fs_err`], it's wrapped in an [`io::Error`] of the same kind,
+/// and the original error is its source.
+#[derive(Debug)]
+struct PathError {
+  operation: Operation,
+  path: path::PathBuf,
+  source: io::Error,
+}
+
+impl fmt::Display for PathError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&i18n::format(
+      self.operation.key(),
+      &[("path", &self.path.display())],
+    ))
+  }
+}
+
+impl std::error::Error for PathError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    Some(&self.source)
+  }
+}
+
+/// Returns a function that adds `path` and `operation` to an error, for use
+/// with [`Result::map_err`].
+pub fn with_path(operation: Operation, path: &path::Path) -> impl FnOnce(io::Error) -> io::Error {
+  let path = path.to_owned();
+  move |source| io::Error::new(source.kind(), PathError { operation, path, source })
+}
+
+/// Returns whether a file exists at `path`, like [`path::Path::try_exists`].
+pub fn try_exists(path: &path::Path) -> io::Result<bool> {
+  path
+    .try_exists()
+    .map_err(with_path(Operation::CheckExists, path))
+}
@@ -0,0 +1,173 @@
+//! Creating a patch that replaces a byte pattern wherever it's found in a
+//! ROM, for quick fixes that don't need a hex editor.
+
+use crate::create::{self, Format};
+use crate::error::prelude::*;
+use crate::{fs, i18n, io};
+use std::path;
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct Args {
+  /// The ROM to search.
+  pub rom: path::PathBuf,
+  // The full path to `Vec` keeps clap from taking each byte as a separate
+  // value.
+  /// The bytes to look for, in hexadecimal, such as "DEADBEEF".
+  #[arg(long, value_name = "HEX", value_parser = parse_hex)]
+  pub find: ::std::vec::Vec<u8>,
+  /// The bytes to put in their place, which must be as many as are found.
+  #[arg(long, value_name = "HEX", value_parser = parse_hex)]
+  pub replace: ::std::vec::Vec<u8>,
+  /// The size in bytes of the values in --find and --replace. Each value is
+  /// written in the ROM's byte order, so with "--width 2 --endian little",
+  /// "1234" is looked for as the bytes 34 12.
+  #[arg(long, default_value_t = 1, value_parser = parse_width)]
+  pub width: usize,
+  /// The byte order of values wider than a byte.
+  #[arg(long, value_enum, default_value_t)]
+  pub endian: Endian,
+  /// Replace every match. Without this, the pattern must be found only once.
+  #[arg(long)]
+  pub all: bool,
+  #[arg(short, long, value_enum, default_value = "ips")]
+  pub format: Format,
+  /// Where to write the patch, or "-" for standard output.
+  #[arg(short, long, default_value = io::STDIO)]
+  pub output: path::PathBuf,
+}
+
+/// The order of the bytes of a value in the ROM.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, clap::ValueEnum)]
+pub enum Endian {
+  /// The most significant byte first, as the value is written.
+  #[default]
+  Big,
+  /// The least significant byte first.
+  Little,
+}
+
+impl Args {
+  pub fn call(self) -> Result<(), Error> {
+    let find = self.to_rom_order(&self.find)?;
+    let replace = self.to_rom_order(&self.replace)?;
+    if find.len() != replace.len() {
+      return Err(Error::LengthMismatch);
+    }
+    let source = fs::read(&self.rom)?;
+    let matches = find_matches(&source, &find);
+    match matches.len() {
+      0 => return Err(Error::NotFound),
+      1 => {}
+      count if !self.all => {
+        let offsets: Vec<String> = matches.iter().map(|&offset| hex(offset)).collect();
+        return Err(Error::Ambiguous { count, offsets: offsets.join(", ") });
+      }
+      _ => {}
+    }
+    let mut target = source.clone();
+    for &offset in &matches {
+      log::info!(
+        "{}",
+        i18n::format("romhacks::genpatch::replacing", &[("offset", &hex(offset))])
+      );
+      target[offset..][..replace.len()].copy_from_slice(&replace);
+    }
+    let patch = create::build(self.format, &create::Source::new(&source), &target, None)?;
+    io::write_file_or_stdout(&self.output, &patch)?;
+    Ok(())
+  }
+
+  /// Reverses the bytes of each value in `bytes` if the ROM is little-endian.
+  fn to_rom_order(&self, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    if bytes.len() % self.width != 0 {
+      return Err(Error::Misaligned { width: self.width });
+    }
+    let mut bytes = bytes.to_vec();
+    if self.endian == Endian::Little {
+      bytes.chunks_mut(self.width).for_each(<[u8]>::reverse);
+    }
+    Ok(bytes)
+  }
+}
+
+/// Returns the offsets where `pattern` is found in `rom`. A match that
+/// overlaps an earlier one can't be replaced too, so it's left out with a
+/// warning.
+fn find_matches(rom: &[u8], pattern: &[u8]) -> Vec<usize> {
+  let mut matches: Vec<usize> = Vec::new();
+  for (offset, window) in rom.windows(pattern.len()).enumerate() {
+    if window != pattern {
+      continue;
+    }
+    match matches.last() {
+      Some(&previous) if offset < previous + pattern.len() => log::warn!(
+        "{}",
+        i18n::format(
+          "romhacks::genpatch::overlap",
+          &[("offset", &hex(offset)), ("previous", &hex(previous))]
+        )
+      ),
+      _ => matches.push(offset),
+    }
+  }
+  matches
+}
+
+fn hex(offset: usize) -> String {
+  format!("0x{offset:08X}")
+}
+
+/// Parses bytes written in hexadecimal, optionally with a "0x" prefix.
+fn parse_hex(arg: &str) -> Result<Vec<u8>, String> {
+  let digits = arg
+    .strip_prefix("0x")
+    .or_else(|| arg.strip_prefix("0X"))
+    .unwrap_or(arg);
+  let bytes = match digits.len() % 2 == 0 && !digits.is_empty() {
+    true => (0..digits.len())
+      .step_by(2)
+      .map(|i| {
+        digits
+          .get(i..i + 2)
+          .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+      })
+      .collect(),
+    false => None,
+  };
+  bytes.ok_or_else(|| i18n::text("romhacks::genpatch::bad_hex").to_owned())
+}
+
+fn parse_width(arg: &str) -> Result<usize, String> {
+  match arg {
+    "1" | "2" | "4" | "8" => Ok(arg.parse().unwrap()),
+    _ => Err(i18n::text("romhacks::genpatch::bad_width").to_owned()),
+  }
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Create(#[from] create::Error),
+  #[error("{}", i18n::text("romhacks::genpatch::length_mismatch"))]
+  #[diagnostic(code(romhacks::genpatch::length_mismatch))]
+  LengthMismatch,
+  #[error("{}", i18n::format("romhacks::genpatch::misaligned", &[("width", width)]))]
+  #[diagnostic(code(romhacks::genpatch::misaligned))]
+  Misaligned { width: usize },
+  #[error("{}", i18n::text("romhacks::genpatch::not_found"))]
+  #[diagnostic(code(romhacks::genpatch::not_found))]
+  NotFound,
+  #[error("{}", i18n::format(
+    "romhacks::genpatch::ambiguous",
+    &[("count", count), ("offsets", offsets)]
+  ))]
+  #[diagnostic(
+    code(romhacks::genpatch::ambiguous),
+    help("{}", i18n::text("romhacks::genpatch::ambiguous::help"))
+  )]
+  Ambiguous { count: usize, offsets: String },
+}
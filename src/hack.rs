@@ -1,7 +1,178 @@
+use crate::error::prelude::*;
+use crate::i18n;
+use std::{cmp, fmt};
+
 #[derive(Clone, Debug, clap::Args)]
 pub struct RomHack {
+  /// The name of the ROM hack. Defaults to the name of the patch file.
+  #[arg(long = "hack-name")]
+  pub name: Option<String>,
   #[arg(short, long = "hack-url")]
   pub url: url::Url,
   #[arg(short, long = "hack-version")]
-  pub version: String,
+  pub version: Version,
+}
+
+/// A version number like "1.2", "v2.0.1" or "1.0-beta.3".
+///
+/// Versions are compared like semantic versions, except that any number of
+/// release components is allowed and missing components count as zeros, so
+/// "1.2" and "1.2.0" are equal. A pre-release is older than its release.
+/// Build metadata after a "+" is ignored. The original text is kept so it can
+/// be written back unchanged.
+#[derive(Clone, Debug)]
+pub struct Version {
+  text: String,
+  release: Vec<u64>,
+  pre_release: Option<String>,
+}
+
+impl Version {
+  pub fn as_str(&self) -> &str {
+    &self.text
+  }
+}
+
+impl std::str::FromStr for Version {
+  type Err = VersionError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let error = || VersionError { version: s.to_owned() };
+    let version = s.strip_prefix(['v', 'V']).unwrap_or(s);
+    let version = version
+      .split_once('+')
+      .map_or(version, |(version, _)| version);
+    let (release, pre_release) = match version.split_once('-') {
+      Some((release, pre_release)) if !pre_release.is_empty() => {
+        (release, Some(pre_release.to_owned()))
+      }
+      Some(_) => return Err(error()),
+      None => (version, None),
+    };
+    let release = (release.split('.'))
+      .map(
+        |component| match component.bytes().all(|b| b.is_ascii_digit()) {
+          true => component.parse().ok(),
+          false => None,
+        },
+      )
+      .collect::<Option<Vec<u64>>>()
+      .ok_or_else(error)?;
+    Ok(Self { text: s.to_owned(), release, pre_release })
+  }
+}
+
+impl fmt::Display for Version {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.text)
+  }
+}
+
+impl Ord for Version {
+  fn cmp(&self, other: &Self) -> cmp::Ordering {
+    let len = self.release.len().max(other.release.len());
+    let component = |version: &Self, i: usize| version.release.get(i).copied().unwrap_or(0);
+    (0..len)
+      .map(|i| component(self, i).cmp(&component(other, i)))
+      .find(|ordering| ordering.is_ne())
+      .unwrap_or(cmp::Ordering::Equal)
+      .then_with(|| match (&self.pre_release, &other.pre_release) {
+        (None, None) => cmp::Ordering::Equal,
+        (None, Some(_)) => cmp::Ordering::Greater,
+        (Some(_), None) => cmp::Ordering::Less,
+        (Some(a), Some(b)) => compare_pre_releases(a, b),
+      })
+  }
+}
+
+impl PartialOrd for Version {
+  fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl PartialEq for Version {
+  fn eq(&self, other: &Self) -> bool {
+    self.cmp(other).is_eq()
+  }
+}
+
+impl Eq for Version {}
+
+/// Compares dot-separated identifiers in order. Numeric identifiers are
+/// compared as numbers and are older than alphanumeric ones, and a shorter
+/// list of otherwise equal identifiers is older.
+fn compare_pre_releases(a: &str, b: &str) -> cmp::Ordering {
+  let mut a = a.split('.');
+  let mut b = b.split('.');
+  loop {
+    let ordering = match (a.next(), b.next()) {
+      (None, None) => return cmp::Ordering::Equal,
+      (None, Some(_)) => return cmp::Ordering::Less,
+      (Some(_), None) => return cmp::Ordering::Greater,
+      (Some(a), Some(b)) => match (a.parse::<u64>(), b.parse::<u64>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        (Ok(_), Err(_)) => cmp::Ordering::Less,
+        (Err(_), Ok(_)) => cmp::Ordering::Greater,
+        (Err(_), Err(_)) => a.cmp(b),
+      },
+    };
+    if ordering.is_ne() {
+      return ordering;
+    }
+  }
+}
+
+/// A hack's version as a manifest records it.
+///
+/// Manifests written before versions were checked can hold any text, such as
+/// "1.0a" or "Beta 2". Such versions are kept as they are, so that the
+/// manifest can be written back unchanged, but aren't compared with others.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RecordedVersion {
+  Parsed(Version),
+  Opaque(String),
+}
+
+impl RecordedVersion {
+  pub fn new(text: &str) -> Self {
+    match text.parse() {
+      Ok(version) => RecordedVersion::Parsed(version),
+      Err(_) => RecordedVersion::Opaque(text.to_owned()),
+    }
+  }
+
+  pub fn as_str(&self) -> &str {
+    match self {
+      RecordedVersion::Parsed(version) => version.as_str(),
+      RecordedVersion::Opaque(text) => text,
+    }
+  }
+
+  /// The version, if it can be compared with others.
+  pub fn parsed(&self) -> Option<&Version> {
+    match self {
+      RecordedVersion::Parsed(version) => Some(version),
+      RecordedVersion::Opaque(_) => None,
+    }
+  }
+}
+
+impl From<Version> for RecordedVersion {
+  fn from(version: Version) -> Self {
+    RecordedVersion::Parsed(version)
+  }
+}
+
+impl fmt::Display for RecordedVersion {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.as_str())
+  }
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("{}", i18n::format("romhacks::hack::bad_version", &[("version", version)]))]
+#[diagnostic(code(romhacks::hack::bad_version))]
+pub struct VersionError {
+  version: String,
 }
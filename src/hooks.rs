@@ -0,0 +1,279 @@
+//! Commands run before and after a ROM is patched, such as launching an
+//! emulator, checksumming or backing up the patched file.
+//!
+//! Hooks are set up in "hooks.kdl" in the configuration directory, with one
+//! node per command, named after the event it runs on:
+//!
+//! ```kdl
+//! pre-apply "sh" "-c" "test -w \"$ROMHACKS_OUTPUT_DIR\"" timeout=10
+//! post-apply "sh" "-c" "cp \"$ROMHACKS_OUTPUT\" ~/backups/" on-failure="warn"
+//! ```
+//!
+//! Each command gets the paths involved in environment variables and a JSON
+//! object on stdin, which after patching includes the same facts as a
+//! report. A command that fails or runs longer than its timeout, in seconds,
+//! stops `apply` unless its `on-failure` is "warn".
+
+use crate::error::prelude::*;
+use crate::io::prelude::*;
+use crate::{dirs, fs, hack, i18n, io, json, kdl, report};
+use std::{ffi, fmt, path, process, thread, time};
+
+/// The name of the file hooks are read from.
+const HOOKS_FILE_NAME: &str = "hooks.kdl";
+
+/// How long a hook can run if it doesn't set a timeout.
+const DEFAULT_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+
+/// How often a running hook is checked on.
+const POLL_INTERVAL: time::Duration = time::Duration::from_millis(20);
+
+// props
+const TIMEOUT: &str = "timeout";
+const ON_FAILURE: &str = "on-failure";
+
+/// The path hooks are read from.
+pub fn path() -> path::PathBuf {
+  dirs::config_dir().join(HOOKS_FILE_NAME)
+}
+
+/// When a hook runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Event {
+  /// Once the patched file's path is known, before the ROM is patched.
+  PreApply,
+  /// Once the patched file, its manifest and any report have been written.
+  PostApply,
+}
+
+impl Event {
+  pub fn name(self) -> &'static str {
+    match self {
+      Event::PreApply => "pre-apply",
+      Event::PostApply => "post-apply",
+    }
+  }
+
+  fn from_name(name: &str) -> Option<Self> {
+    [Event::PreApply, Event::PostApply]
+      .into_iter()
+      .find(|event| event.name() == name)
+  }
+}
+
+impl fmt::Display for Event {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.name())
+  }
+}
+
+/// What happens when a hook fails or times out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum OnFailure {
+  /// Stop and fail with an error.
+  #[default]
+  Abort,
+  /// Log a warning and carry on.
+  Warn,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hook {
+  pub event: Event,
+  /// The program to run, followed by its arguments.
+  pub command: Vec<String>,
+  pub timeout: time::Duration,
+  pub on_failure: OnFailure,
+}
+
+/// The paths involved in patching a ROM, which are passed to hooks.
+#[derive(Clone, Copy, Debug)]
+pub struct Context<'a> {
+  pub rom: &'a path::Path,
+  pub patch: &'a path::Path,
+  pub output: &'a path::Path,
+  pub manifest: &'a path::Path,
+  pub hack: &'a hack::RomHack,
+  /// What happened, once the ROM has been patched.
+  pub report: Option<&'a report::Report>,
+}
+
+impl Context<'_> {
+  /// The environment variables hooks are run with.
+  fn env(&self, event: Event) -> Vec<(&'static str, ffi::OsString)> {
+    let output_dir = (self.output.parent())
+      .filter(|dir| !dir.as_os_str().is_empty())
+      .unwrap_or(path::Path::new("."));
+    vec![
+      ("ROMHACKS_HOOK", event.name().into()),
+      ("ROMHACKS_ROM", self.rom.into()),
+      ("ROMHACKS_PATCH", self.patch.into()),
+      ("ROMHACKS_OUTPUT", self.output.into()),
+      ("ROMHACKS_OUTPUT_DIR", output_dir.into()),
+      ("ROMHACKS_MANIFEST", self.manifest.into()),
+      ("ROMHACKS_HACK_URL", self.hack.url.as_str().into()),
+      ("ROMHACKS_HACK_VERSION", self.hack.version.as_str().into()),
+    ]
+  }
+
+  /// The JSON object written to hooks' stdin.
+  fn to_json(&self, event: Event) -> json::Value {
+    use json::Value as V;
+    let path = |path: &path::Path| V::String(path.display().to_string());
+    V::Object(vec![
+      ("hook".to_owned(), V::String(event.name().to_owned())),
+      ("rom".to_owned(), path(self.rom)),
+      ("patch".to_owned(), path(self.patch)),
+      ("output".to_owned(), path(self.output)),
+      ("manifest".to_owned(), path(self.manifest)),
+      ("hack_url".to_owned(), V::String(self.hack.url.to_string())),
+      (
+        "hack_version".to_owned(),
+        V::String(self.hack.version.as_str().to_owned()),
+      ),
+      (
+        "report".to_owned(),
+        self.report.map_or(V::Null, report::Report::to_json),
+      ),
+    ])
+  }
+}
+
+/// The hooks set up in the configuration directory.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Hooks {
+  hooks: Vec<Hook>,
+}
+
+impl Hooks {
+  /// Reads the hooks from [`path`]. There are none if it doesn't exist.
+  pub fn load() -> Result<Self, Error> {
+    let path = path();
+    let text = match fs::read_to_string(&path) {
+      Ok(text) => text,
+      Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+      Err(err) => return Err(err.into()),
+    };
+    let doc: kdl::KdlDocument = text.parse()?;
+    let hooks = (doc.nodes().iter())
+      .map(|node| parse_hook(node).ok_or_else(|| Error::Malformed { path: path.clone() }))
+      .collect::<Result<_, _>>()?;
+    Ok(Self { hooks })
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.hooks.is_empty()
+  }
+
+  /// Runs the hooks for `event` in the order they're listed, each with
+  /// `context`.
+  pub fn run(&self, event: Event, context: &Context<'_>) -> Result<(), Error> {
+    let hooks = self.hooks.iter().filter(|hook| hook.event == event);
+    for hook in hooks {
+      match run(hook, context) {
+        Ok(()) => {}
+        Err(err) if hook.on_failure == OnFailure::Warn => log::warn!("{err}"),
+        Err(err) => return Err(err),
+      }
+    }
+    Ok(())
+  }
+}
+
+fn parse_hook(node: &kdl::KdlNode) -> Option<Hook> {
+  let event = Event::from_name(node.name().value())?;
+  let command = (node.entries().iter())
+    .filter(|entry| entry.name().is_none())
+    .map(|entry| entry.value().as_string().map(str::to_owned))
+    .collect::<Option<Vec<String>>>()
+    .filter(|command| !command.is_empty())?;
+  let timeout = match node.get(TIMEOUT) {
+    Some(value) => time::Duration::from_secs(u64::try_from(value.as_integer()?).ok()?),
+    None => DEFAULT_TIMEOUT,
+  };
+  let on_failure = match node.get(ON_FAILURE).map(|value| value.as_string()) {
+    None => OnFailure::default(),
+    Some(Some("abort")) => OnFailure::Abort,
+    Some(Some("warn")) => OnFailure::Warn,
+    Some(_) => return None,
+  };
+  Some(Hook { event, command, timeout, on_failure })
+}
+
+/// Runs `hook` and waits for it to exit, killing it if it runs longer than
+/// its timeout.
+fn run(hook: &Hook, context: &Context<'_>) -> Result<(), Error> {
+  let command = hook.command.join(" ");
+  log::debug!(
+    "{}",
+    i18n::format(
+      "romhacks::hooks::running",
+      &[("event", &hook.event), ("command", &command)]
+    )
+  );
+  let mut child = process::Command::new(&hook.command[0])
+    .args(&hook.command[1..])
+    .envs(context.env(hook.event))
+    .stdin(process::Stdio::piped())
+    .spawn()
+    .map_err(|source| Error::Spawn { command: command.clone(), source })?;
+  // Hooks don't have to read their input, so it's written from another
+  // thread that can't block the timeout, and failing to write it is ignored.
+  let mut stdin = child.stdin.take().unwrap();
+  let input = context.to_json(hook.event).to_string();
+  thread::spawn(move || {
+    let _ = writeln!(stdin, "{input}");
+  });
+  let start = time::Instant::now();
+  let status = loop {
+    if let Some(status) = child.try_wait()? {
+      break status;
+    }
+    if start.elapsed() >= hook.timeout {
+      child.kill()?;
+      child.wait()?;
+      return Err(Error::TimedOut { command, seconds: hook.timeout.as_secs() });
+    }
+    thread::sleep(POLL_INTERVAL);
+  };
+  if !status.success() {
+    return Err(Error::Failed { command, status });
+  }
+  Ok(())
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error(transparent)]
+  Kdl(#[from] kdl::KdlError),
+  #[error("{}", i18n::format("romhacks::hooks::malformed", &[("path", &path.display())]))]
+  #[diagnostic(
+    code(romhacks::hooks::malformed),
+    help("{}", i18n::text("romhacks::hooks::malformed::help"))
+  )]
+  Malformed { path: path::PathBuf },
+  #[error("{}", i18n::format(
+    "romhacks::hooks::spawn",
+    &[("command", command), ("error", source)]
+  ))]
+  #[diagnostic(code(romhacks::hooks::spawn))]
+  Spawn { command: String, source: io::Error },
+  #[error("{}", i18n::format(
+    "romhacks::hooks::failed",
+    &[("command", command), ("status", status)]
+  ))]
+  #[diagnostic(code(romhacks::hooks::failed))]
+  Failed {
+    command: String,
+    status: process::ExitStatus,
+  },
+  #[error("{}", i18n::format(
+    "romhacks::hooks::timed_out",
+    &[("command", command), ("seconds", seconds)]
+  ))]
+  #[diagnostic(code(romhacks::hooks::timed_out))]
+  TimedOut { command: String, seconds: u64 },
+}
@@ -0,0 +1,99 @@
+use crate::kdl;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::{env, fmt};
+
+/// The message catalogs bundled with the program, keyed by language code.
+///
+/// To add a translation, copy `locales/en.kdl`, translate the messages and add
+/// an entry here.
+const CATALOGS: &[(&str, &str)] = &[("en", include_str!("locales/en.kdl"))];
+
+/// The language whose catalog is used for messages missing from a translation.
+const FALLBACK_LANGUAGE: &str = "en";
+
+struct Catalog(HashMap<String, String>);
+
+impl Catalog {
+  fn parse(source: &str) -> Self {
+    let doc: kdl::KdlDocument = source
+      .parse()
+      .expect("bundled catalogs should be valid KDL");
+    Self(
+      (doc.nodes().iter())
+        .filter_map(|node| {
+          let text = node.get(0)?.as_string()?;
+          Some((node.name().value().to_owned(), text.to_owned()))
+        })
+        .collect(),
+    )
+  }
+}
+
+/// The catalogs to search for messages, in order of preference.
+fn catalogs() -> &'static [Catalog] {
+  static CATALOGS_IN_USE: OnceLock<Vec<Catalog>> = OnceLock::new();
+  CATALOGS_IN_USE.get_or_init(|| {
+    let mut languages: Vec<String> = preferred_language().into_iter().collect();
+    if languages
+      .first()
+      .is_none_or(|lang| lang != FALLBACK_LANGUAGE)
+    {
+      languages.push(FALLBACK_LANGUAGE.to_owned());
+    }
+    (languages.iter())
+      .filter_map(|lang| CATALOGS.iter().find(|(code, _)| code == lang))
+      .map(|(_, source)| Catalog::parse(source))
+      .collect()
+  })
+}
+
+/// Returns the user's preferred language according to the `LC_ALL`,
+/// `LC_MESSAGES` and `LANG` environment variables, e.g. "pt" for "pt_BR.UTF-8".
+pub fn preferred_language() -> Option<String> {
+  ["LC_ALL", "LC_MESSAGES", "LANG"]
+    .iter()
+    .filter_map(|var| env::var(var).ok())
+    .find(|value| !value.is_empty())
+    .and_then(|value| {
+      value
+        .split(['_', '.', '@'])
+        .next()
+        .map(str::to_ascii_lowercase)
+    })
+    .filter(|lang| !lang.is_empty() && lang != "c" && lang != "posix")
+}
+
+/// Returns the message with the given key in the user's language.
+/// If no catalog contains the key, the key itself is returned.
+pub fn text(key: &'static str) -> &'static str {
+  (catalogs().iter())
+    .find_map(|catalog| catalog.0.get(key))
+    .map_or(key, String::as_str)
+}
+
+/// Returns the message with the given key, with each `{name}` placeholder
+/// replaced by the matching argument. `{{` and `}}` produce literal braces.
+pub fn format(key: &'static str, args: &[(&str, &dyn fmt::Display)]) -> String {
+  let template = text(key);
+  let mut result = String::with_capacity(template.len());
+  let mut rest = template;
+  while let Some(i) = rest.find(['{', '}']) {
+    result.push_str(&rest[..i]);
+    rest = &rest[i..];
+    if rest.starts_with("{{") || rest.starts_with("}}") {
+      result.push_str(&rest[..1]);
+      rest = &rest[2..];
+    } else if let Some((name, tail)) = rest[1..].split_once('}') {
+      match args.iter().find(|(arg, _)| *arg == name) {
+        Some((_, value)) => result.push_str(&value.to_string()),
+        None => result.push_str(&rest[..name.len() + 2]),
+      }
+      rest = tail;
+    } else {
+      break;
+    }
+  }
+  result.push_str(rest);
+  result
+}
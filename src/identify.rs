@@ -0,0 +1,133 @@
+use crate::error::prelude::*;
+use crate::io;
+use crate::io::prelude::*;
+use crate::patch::{self, ppf};
+use std::{path, process};
+
+/// The exit status when the file isn't a patch in any supported format.
+/// Identifying a file exits with statuses from 64 to 127, which are never
+/// used for errors.
+const UNKNOWN_STATUS: i32 = 64;
+
+/// The exit status for formats added by plugins, which is the same for all
+/// of them.
+#[cfg(feature = "plugins")]
+const PLUGIN_STATUS: i32 = 127;
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct Args {
+  /// The file to identify, or "-" to read it from standard input. Unless it's
+  /// compressed, only as much of it is read as it takes to tell its format.
+  pub file: path::PathBuf,
+}
+
+impl Args {
+  /// Prints the format of the file and exits with a status specific to it.
+  pub fn call(self) -> Result<(), Error> {
+    let (name, status) = match self.file.as_os_str() == io::STDIO {
+      true => {
+        let mut stdin = io::PeekReader::new(io::stdin().lock());
+        match patch::compression::Compression::sniff(&mut stdin)? {
+          Some(compression) => {
+            let file = patch::compression::spool(compression, &mut stdin)?;
+            identify(&mut io::PeekReader::new(file))?
+          }
+          None => identify(&mut stdin)?,
+        }
+      }
+      false => {
+        let file = patch::compression::open(&self.file)?;
+        identify(&mut io::PeekReader::new(file))?
+      }
+    };
+    let mut stdout = io::stdout().lock();
+    writeln!(stdout, "{name}")?;
+    stdout.flush()?;
+    process::exit(status)
+  }
+}
+
+/// Returns the name and exit status of the format of `patch`, from its first
+/// bytes.
+fn identify(patch: &mut impl BufReadExt) -> Result<(&'static str, i32), Error> {
+  Ok(match patch::Kind::sniff(patch)? {
+    Some(kind @ patch::Kind::PPF) => {
+      let name = match ppf::sniff_version(patch) {
+        Ok(ppf::Version::V1) => "ppf1",
+        Ok(ppf::Version::V2) => "ppf2",
+        Ok(ppf::Version::V3) => "ppf3",
+        Err(patch::Error::IO(err)) => return Err(err.into()),
+        Err(_) => "ppf",
+      };
+      (name, status(kind))
+    }
+    Some(kind) => (name(kind), status(kind)),
+    None => ("unknown", UNKNOWN_STATUS),
+  })
+}
+
+pub fn name(kind: patch::Kind) -> &'static str {
+  match kind {
+    patch::Kind::IPS => "ips",
+    patch::Kind::UPS => "ups",
+    patch::Kind::BPS => "bps",
+    patch::Kind::PPF => "ppf",
+    patch::Kind::VCD => "vcd",
+    patch::Kind::RUP => "rup",
+    patch::Kind::APS => "aps",
+    patch::Kind::APSGBA => "aps-gba",
+    patch::Kind::BSDIFF => "bsdiff",
+    patch::Kind::GDIFF => "gdiff",
+    patch::Kind::IPS32 => "ips32",
+    #[cfg(feature = "plugins")]
+    patch::Kind::Plugin(id) => id.plugin().name(),
+  }
+}
+
+/// The exit status for each format. Scripts depend on these, so they never
+/// change: a new format takes the next unused status.
+fn status(kind: patch::Kind) -> i32 {
+  match kind {
+    patch::Kind::IPS => 65,
+    patch::Kind::UPS => 66,
+    patch::Kind::BPS => 67,
+    patch::Kind::PPF => 68,
+    patch::Kind::VCD => 69,
+    patch::Kind::RUP => 70,
+    patch::Kind::APS => 71,
+    patch::Kind::APSGBA => 72,
+    patch::Kind::BSDIFF => 73,
+    patch::Kind::GDIFF => 74,
+    patch::Kind::IPS32 => 75,
+    #[cfg(feature = "plugins")]
+    patch::Kind::Plugin(_) => PLUGIN_STATUS,
+  }
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Patch(#[from] patch::Error),
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+
+  fn identify_bytes(bytes: &[u8]) -> (&'static str, i32) {
+    identify(&mut io::PeekReader::new(Cursor::new(bytes.to_vec()))).unwrap()
+  }
+
+  #[test]
+  fn statuses_are_pinned() {
+    assert_eq!(identify_bytes(b"PATCH"), ("ips", 65));
+    assert_eq!(identify_bytes(b"UPS1"), ("ups", 66));
+    assert_eq!(identify_bytes(b"BPS1"), ("bps", 67));
+    assert_eq!(identify_bytes(b"not a patch"), ("unknown", 64));
+  }
+}
@@ -0,0 +1,246 @@
+use crate::error::prelude::*;
+use crate::patch::header::Header;
+use crate::{i18n, patch};
+use std::{io, path};
+
+#[derive(Clone, Debug, clap::Args)]
+#[group(required = true, multiple = true)]
+pub struct Args {
+  /// A patch whose header should be printed.
+  pub patch: Option<path::PathBuf>,
+  /// Print the properties of every supported patch format.
+  #[arg(long)]
+  pub formats: bool,
+  /// Also read every hunk of the patch and print how many there are, how
+  /// many bytes they change and where. Only IPS, UPS and PPF patches can be
+  /// read hunk by hunk.
+  #[arg(long, requires = "patch")]
+  pub stats: bool,
+}
+
+impl Args {
+  pub fn call(self) -> Result<(), Error> {
+    if self.formats {
+      print_table(&format_table());
+    }
+    if let Some(patch_path) = &self.patch {
+      let mut patch = patch::compression::open(patch_path)?;
+      let kind = patch::Kind::detect(&mut patch)?.ok_or(patch::UnknownPatchKindError(()))?;
+      let header = match patch::header::read(kind, &mut patch) {
+        Ok(header) => header,
+        // Show the version that was found, even though nothing else can be read.
+        Err(patch::Error::UnsupportedVersion { found, supported }) => {
+          log::warn!(
+            "{}",
+            i18n::format(
+              "romhacks::patch::unsupported_version",
+              &[("found", &found), ("supported", &supported)]
+            )
+          );
+          let header = Header { version: Some(found), ..Header::default() };
+          print_table(&header_table(kind, &header));
+          return Ok(());
+        }
+        Err(err) => return Err(err.into()),
+      };
+      print_table(&header_table(kind, &header));
+      if self.stats {
+        let mut stats = patch::stats::Statistics::default();
+        match patch::ops::decode(kind, &mut patch, &mut stats) {
+          Ok(()) => {
+            println!();
+            print_table(&stats_table(&stats));
+            println!();
+            print_table(&coverage_table(&stats));
+          }
+          Err(patch::Error::UnsupportedPatchFeature) => log::warn!(
+            "{}",
+            i18n::format("romhacks::info::no_stats", &[("format", &kind)])
+          ),
+          Err(err) => return Err(err.into()),
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+fn format_table() -> Vec<Vec<String>> {
+  let yes_no =
+    |value: bool| i18n::text(if value { "romhacks::info::yes" } else { "romhacks::info::no" });
+  let header = [
+    "romhacks::info::format",
+    "romhacks::info::source_checksum",
+    "romhacks::info::target_checksum",
+    "romhacks::info::patch_checksum",
+    "romhacks::info::creation",
+    "romhacks::info::in_place",
+    "romhacks::info::max_file_size",
+    "romhacks::info::seeks",
+  ];
+  let rows = patch::Kind::available().into_iter().map(|kind| {
+    let capabilities = kind.capabilities();
+    let seeks: Vec<&str> = [
+      (capabilities.seeks_source, "romhacks::info::source"),
+      (capabilities.seeks_patch, "romhacks::info::patch"),
+      (capabilities.seeks_output, "romhacks::info::output"),
+    ]
+    .into_iter()
+    .filter(|(seeks, _)| *seeks)
+    .map(|(_, key)| i18n::text(key))
+    .collect();
+    vec![
+      kind.to_string(),
+      yes_no(capabilities.source_checksum).to_owned(),
+      yes_no(capabilities.target_checksum).to_owned(),
+      yes_no(capabilities.patch_checksum).to_owned(),
+      yes_no(capabilities.creation).to_owned(),
+      yes_no(capabilities.in_place).to_owned(),
+      match capabilities.max_file_size {
+        u64::MAX => i18n::text("romhacks::info::unlimited").to_owned(),
+        size => format_size(size),
+      },
+      seeks.join(", "),
+    ]
+  });
+  std::iter::once(header.map(|key| i18n::text(key).to_owned()).to_vec())
+    .chain(rows)
+    .collect()
+}
+
+fn header_table(kind: patch::Kind, header: &Header) -> Vec<Vec<String>> {
+  let unknown = || i18n::text("romhacks::info::unknown").to_owned();
+  let row = |key: &'static str, value: Option<String>| {
+    vec![i18n::text(key).to_owned(), value.unwrap_or_else(unknown)]
+  };
+  vec![
+    row("romhacks::info::format", Some(kind.to_string())),
+    row("romhacks::info::version", header.version.clone()),
+    row(
+      "romhacks::info::source_size",
+      header.source_size.map(format_size),
+    ),
+    row(
+      "romhacks::info::target_size",
+      header.target_size.map(format_size),
+    ),
+    row(
+      "romhacks::info::source_checksum",
+      (header.source_crc32).map(|crc32| format!("{:08X}", crc32.value())),
+    ),
+    row(
+      "romhacks::info::target_checksum",
+      (header.target_crc32).map(|crc32| format!("{:08X}", crc32.value())),
+    ),
+    row("romhacks::info::app_header", header.app_header.clone()),
+  ]
+}
+
+fn stats_table(stats: &patch::stats::Statistics) -> Vec<Vec<String>> {
+  let row = |key: &'static str, value: String| vec![i18n::text(key).to_owned(), value];
+  vec![
+    row("romhacks::info::hunks", stats.hunks.to_string()),
+    row(
+      "romhacks::info::bytes_changed",
+      stats.bytes_changed.to_string(),
+    ),
+    row(
+      "romhacks::info::largest_hunk",
+      (stats.largest_hunk.as_ref()).map_or_else(
+        || i18n::text("romhacks::info::none").to_owned(),
+        |range| {
+          i18n::format(
+            "romhacks::info::hunk",
+            &[
+              ("len", &(range.end - range.start)),
+              ("offset", &format!("{:#X}", range.start)),
+            ],
+          )
+        },
+      ),
+    ),
+    row(
+      "romhacks::info::rle_hunks",
+      i18n::format(
+        "romhacks::info::rle",
+        &[("hunks", &stats.rle_hunks), ("bytes", &stats.rle_bytes)],
+      ),
+    ),
+  ]
+}
+
+/// Shows how the changed bytes are spread across the file, as a bar and a
+/// percentage of each range's bytes.
+fn coverage_table(stats: &patch::stats::Statistics) -> Vec<Vec<String>> {
+  const BUCKETS: u64 = 16;
+  const BAR_WIDTH: u64 = 32;
+  let header = vec![
+    i18n::text("romhacks::info::offsets").to_owned(),
+    i18n::text("romhacks::info::coverage").to_owned(),
+  ];
+  let rows = stats.coverage(BUCKETS).into_iter().map(|bucket| {
+    let len = bucket.range.end - bucket.range.start;
+    // Overlapping hunks can cover a byte more than once.
+    let bytes = bucket.bytes.min(len);
+    let bar = "#".repeat((bytes * BAR_WIDTH).div_ceil(len) as usize);
+    vec![
+      format!(
+        "{:#010X}-{:#010X}",
+        bucket.range.start,
+        bucket.range.end - 1
+      ),
+      format!(
+        "{bar:width$} {}%",
+        bytes * 100 / len,
+        width = BAR_WIDTH as usize
+      ),
+    ]
+  });
+  std::iter::once(header).chain(rows).collect()
+}
+
+/// Formats a size in bytes with the largest binary unit it's a whole multiple of.
+pub fn format_size(size: u64) -> String {
+  const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+  let (mut value, mut unit) = (size, "B");
+  for next_unit in UNITS {
+    if value == 0 || value % 1024 != 0 {
+      break;
+    }
+    value /= 1024;
+    unit = next_unit;
+  }
+  format!("{value} {unit}")
+}
+
+/// Prints rows of cells with each column padded to the width of its widest cell.
+pub fn print_table(rows: &[Vec<String>]) {
+  let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+  let widths: Vec<usize> = (0..columns)
+    .map(|column| {
+      (rows.iter())
+        .filter_map(|row| row.get(column))
+        .map(|cell| cell.chars().count())
+        .max()
+        .unwrap_or(0)
+    })
+    .collect();
+  for row in rows {
+    let line: Vec<String> = (row.iter().zip(&widths))
+      .map(|(cell, &width)| format!("{cell:width$}"))
+      .collect();
+    println!("{}", line.join("  ").trim_end());
+  }
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error(transparent)]
+  UnknownPatchKind(#[from] patch::UnknownPatchKindError),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Patch(#[from] patch::Error),
+}
@@ -1,10 +1,11 @@
-use crate::mem;
-use fs_err as fs;
+use crate::{dirs, fs, i18n, mem, temp};
 pub use std::io::*;
+use std::sync::OnceLock;
+use std::{fmt, ops, path, thread, time};
 
 /// Exports all traits and marker types used by this crate.
 pub mod prelude {
-  pub use super::{ReadArray, Resize};
+  pub use super::{BufReadExt, BufWrite, KnownLen, ReadArray, Remaining, Resize};
   pub use byteorder::{ReadBytesExt, BE, LE};
   pub use std::io::prelude::*;
 }
@@ -17,6 +18,238 @@ pub trait ReadArray: Read {
 
 impl<T: Read> ReadArray for T {}
 
+/// Types whose total length can be determined without seeking.
+pub trait KnownLen {
+  /// Returns the length of the stream in bytes.
+  fn known_len(&self) -> Result<u64>;
+}
+
+impl KnownLen for fs::File {
+  fn known_len(&self) -> Result<u64> {
+    Ok(self.metadata()?.len())
+  }
+}
+
+impl KnownLen for std::fs::File {
+  fn known_len(&self) -> Result<u64> {
+    Ok(self.metadata()?.len())
+  }
+}
+
+impl KnownLen for [u8] {
+  fn known_len(&self) -> Result<u64> {
+    Ok(self.len() as u64)
+  }
+}
+
+impl KnownLen for Vec<u8> {
+  fn known_len(&self) -> Result<u64> {
+    Ok(self.len() as u64)
+  }
+}
+
+impl<T: AsRef<[u8]>> KnownLen for Cursor<T> {
+  fn known_len(&self) -> Result<u64> {
+    Ok(self.get_ref().as_ref().len() as u64)
+  }
+}
+
+impl<R: KnownLen> KnownLen for BufReader<R> {
+  fn known_len(&self) -> Result<u64> {
+    self.get_ref().known_len()
+  }
+}
+
+impl<T: KnownLen + ?Sized> KnownLen for &T {
+  fn known_len(&self) -> Result<u64> {
+    (**self).known_len()
+  }
+}
+
+impl<T: KnownLen + ?Sized> KnownLen for &mut T {
+  fn known_len(&self) -> Result<u64> {
+    (**self).known_len()
+  }
+}
+
+/// Seekable streams that know how many bytes are left to read.
+pub trait Remaining: Seek + KnownLen {
+  /// Returns the number of bytes between the current position and the end of
+  /// the stream, or 0 if the position is past the end.
+  fn remaining(&mut self) -> Result<u64> {
+    Ok(self.known_len()?.saturating_sub(self.stream_position()?))
+  }
+}
+
+impl<T: Seek + KnownLen + ?Sized> Remaining for T {}
+
+/// Buffered readers that can look at the bytes ahead without consuming them,
+/// so that streams that can't seek back, such as standard input, can be
+/// identified by their first bytes.
+pub trait BufReadExt: BufRead {
+  /// Fills the buffer with at least `amount` bytes, or as many as it can hold,
+  /// and returns them without consuming them. Fewer bytes are returned only
+  /// if the stream ends first.
+  fn peek(&mut self, amount: usize) -> Result<&[u8]>;
+}
+
+impl<T: BufReadExt + ?Sized> BufReadExt for &mut T {
+  fn peek(&mut self, amount: usize) -> Result<&[u8]> {
+    (**self).peek(amount)
+  }
+}
+
+/// A buffered reader that can top up its buffer without consuming it, which
+/// [`BufReader`] can't.
+#[derive(Debug)]
+pub struct PeekReader<R> {
+  inner: R,
+  buf: Box<[u8]>,
+  /// The range of `buf` that's been read from `inner` but not consumed.
+  pos: usize,
+  filled: usize,
+}
+
+impl<R: Read> PeekReader<R> {
+  /// The capacity of a reader created with [`new`](PeekReader::new), which
+  /// is the same as a [`BufReader`]'s.
+  const DEFAULT_CAPACITY: usize = 8 * 1024;
+
+  pub fn new(inner: R) -> Self {
+    Self::with_capacity(Self::DEFAULT_CAPACITY, inner)
+  }
+
+  pub fn with_capacity(capacity: usize, inner: R) -> Self {
+    Self { inner, buf: vec![0; capacity].into_boxed_slice(), pos: 0, filled: 0 }
+  }
+}
+
+impl<R: Read> Read for PeekReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+    // Large reads bypass the buffer once it's empty.
+    if self.pos == self.filled && buf.len() >= self.buf.len() {
+      return self.inner.read(buf);
+    }
+    let len = self.fill_buf()?.read(buf)?;
+    self.consume(len);
+    Ok(len)
+  }
+}
+
+impl<R: Read> BufRead for PeekReader<R> {
+  fn fill_buf(&mut self) -> Result<&[u8]> {
+    if self.pos == self.filled {
+      self.filled = self.inner.read(&mut self.buf)?;
+      self.pos = 0;
+    }
+    Ok(&self.buf[self.pos..self.filled])
+  }
+
+  fn consume(&mut self, amount: usize) {
+    self.pos = (self.pos + amount).min(self.filled);
+  }
+}
+
+impl<R: Read> BufReadExt for PeekReader<R> {
+  fn peek(&mut self, amount: usize) -> Result<&[u8]> {
+    let amount = amount.min(self.buf.len());
+    if self.filled - self.pos < amount {
+      // Move the unconsumed bytes to the front to make room after them.
+      self.buf.copy_within(self.pos..self.filled, 0);
+      self.filled -= self.pos;
+      self.pos = 0;
+      while self.filled < amount {
+        match self.inner.read(&mut self.buf[self.filled..]) {
+          Ok(0) => break,
+          Ok(len) => self.filled += len,
+          Err(err) if err.kind() == ErrorKind::Interrupted => {}
+          Err(err) => return Err(err),
+        }
+      }
+    }
+    let end = self.filled.min(self.pos + amount);
+    Ok(&self.buf[self.pos..end])
+  }
+}
+
+/// Writers that may hold back written bytes before passing them on to an
+/// inner stream.
+///
+/// Once [flush_and_get_inner](BufWrite::flush_and_get_inner) returns `Ok`,
+/// every byte written so far has reached the inner stream, and the inner
+/// stream's position is the position the next write would go to. Unbuffered
+/// writers implement this trait with `Inner = Self`, so code that needs direct
+/// access to the underlying stream can accept either kind of writer.
+pub trait BufWrite: Write {
+  type Inner: ?Sized;
+
+  /// Writes out any buffered bytes and returns the inner stream.
+  fn flush_and_get_inner(&mut self) -> Result<&mut Self::Inner>;
+
+  /// Flushes the writer and applies `f` to the inner stream.
+  fn with_inner<T>(&mut self, f: impl FnOnce(&mut Self::Inner) -> Result<T>) -> Result<T> {
+    f(self.flush_and_get_inner()?)
+  }
+
+  /// Copies previously written bytes in `range` into `dest`, then restores
+  /// the stream's position. Returns the number of bytes copied, which is less
+  /// than the length of `range` if it extends beyond the end of the stream.
+  fn read_back(&mut self, range: ops::Range<u64>, dest: &mut impl Write) -> Result<u64>
+  where
+    Self::Inner: Read + Seek,
+  {
+    self.with_inner(|inner| {
+      let position = inner.stream_position()?;
+      inner.seek(SeekFrom::Start(range.start))?;
+      let len = range.end.saturating_sub(range.start);
+      let copied = copy(&mut (&mut *inner).take(len), dest)?;
+      inner.seek(SeekFrom::Start(position))?;
+      Ok(copied)
+    })
+  }
+}
+
+impl<W: Write> BufWrite for BufWriter<W> {
+  type Inner = W;
+
+  fn flush_and_get_inner(&mut self) -> Result<&mut W> {
+    self.flush()?;
+    Ok(self.get_mut())
+  }
+}
+
+impl BufWrite for fs::File {
+  type Inner = Self;
+
+  fn flush_and_get_inner(&mut self) -> Result<&mut Self> {
+    Ok(self)
+  }
+}
+
+impl BufWrite for std::fs::File {
+  type Inner = Self;
+
+  fn flush_and_get_inner(&mut self) -> Result<&mut Self> {
+    Ok(self)
+  }
+}
+
+impl BufWrite for Cursor<Vec<u8>> {
+  type Inner = Self;
+
+  fn flush_and_get_inner(&mut self) -> Result<&mut Self> {
+    Ok(self)
+  }
+}
+
+impl<T: BufWrite + ?Sized> BufWrite for &mut T {
+  type Inner = T::Inner;
+
+  fn flush_and_get_inner(&mut self) -> Result<&mut T::Inner> {
+    (**self).flush_and_get_inner()
+  }
+}
+
 /// File-like types that support resizing.
 pub trait Resize {
   /// See [File::set_len](fs::File::set_len).
@@ -38,9 +271,1016 @@ impl Resize for Vec<u8> {
   }
 }
 
+impl Resize for Cursor<Vec<u8>> {
+  /// See [Vec::resize](Vec::<u8>::resize). The cursor's position is unchanged.
+  fn set_len(&mut self, new_size: u64) -> Result<()> {
+    Resize::set_len(self.get_mut(), new_size)
+  }
+}
+
+impl<T: Resize + ?Sized> Resize for &mut T {
+  fn set_len(&mut self, new_size: u64) -> Result<()> {
+    (**self).set_len(new_size)
+  }
+}
+
+impl<T: Resize + ?Sized> Resize for Box<T> {
+  fn set_len(&mut self, new_size: u64) -> Result<()> {
+    (**self).set_len(new_size)
+  }
+}
+
 impl Resize for fs::File {
   /// See [File::set_len](fs::File::set_len).
   fn set_len(&mut self, new_size: u64) -> Result<()> {
     fs::File::set_len(self, new_size)
   }
 }
+
+/// The path that stands for standard input or output on the command line.
+pub const STDIO: &str = "-";
+
+static RETRY_POLICY: OnceLock<RetryPolicy> = OnceLock::new();
+
+/// Command line options for retrying file operations that fail.
+#[derive(Clone, Debug, Default, clap::Args)]
+#[group(id = "retry")]
+pub struct RetryOptions {
+  /// Retry reading and writing files up to this many times when it fails
+  /// with an error that's often temporary on network shares, such as a
+  /// stale file handle or a timeout, waiting twice as long before each retry.
+  #[arg(long, global = true, value_name = "COUNT", default_value_t = 0)]
+  pub io_retries: u32,
+}
+
+/// How many times, and how patiently, [`retry`] retries an operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RetryPolicy {
+  /// How many times an operation is retried after it first fails.
+  pub retries: u32,
+  /// How long to wait before the first retry.
+  pub initial_delay: time::Duration,
+  /// The longest to wait before a retry, however many came before it.
+  pub max_delay: time::Duration,
+}
+
+impl RetryPolicy {
+  /// Operations are tried once.
+  pub const NEVER: RetryPolicy = RetryPolicy {
+    retries: 0,
+    initial_delay: time::Duration::from_millis(100),
+    max_delay: time::Duration::from_secs(5),
+  };
+}
+
+impl Default for RetryPolicy {
+  fn default() -> Self {
+    Self::NEVER
+  }
+}
+
+impl From<&RetryOptions> for RetryPolicy {
+  fn from(args: &RetryOptions) -> Self {
+    RetryPolicy { retries: args.io_retries, ..RetryPolicy::NEVER }
+  }
+}
+
+/// Sets the policy [`retry`] follows. Until this is called, operations
+/// aren't retried.
+pub fn init_retry_policy(policy: RetryPolicy) {
+  let _ = RETRY_POLICY.set(policy);
+}
+
+/// Whether an operation that failed with `err` may succeed if it's tried
+/// again. Network filesystems report these for connections that drop, and
+/// some report a full disk while a server is busy.
+fn is_transient(err: &Error) -> bool {
+  use ErrorKind as K;
+  matches!(
+    err.kind(),
+    K::Interrupted
+      | K::WouldBlock
+      | K::TimedOut
+      | K::ResourceBusy
+      | K::StaleNetworkFileHandle
+      | K::StorageFull
+      | K::NetworkDown
+      | K::NetworkUnreachable
+      | K::HostUnreachable
+      | K::ConnectionReset
+      | K::ConnectionAborted
+  )
+}
+
+/// Runs `op`, and runs it again while it fails with a transient error, as
+/// long as the [policy](init_retry_policy) allows, waiting longer before
+/// each retry. If it still fails, the last error is returned with how many
+/// times it was tried.
+pub fn retry<T>(mut op: impl FnMut() -> Result<T>) -> Result<T> {
+  let policy = RETRY_POLICY.get_or_init(RetryPolicy::default);
+  let mut delay = policy.initial_delay;
+  for attempt in 1..=policy.retries {
+    match op() {
+      Err(err) if is_transient(&err) => {
+        log::warn!(
+          "{}",
+          i18n::format(
+            "romhacks::io::retrying",
+            &[
+              ("error", &err),
+              ("milliseconds", &delay.as_millis()),
+              ("attempt", &attempt),
+              ("retries", &policy.retries),
+            ]
+          )
+        );
+        thread::sleep(delay);
+        delay = (delay * 2).min(policy.max_delay);
+      }
+      result => return result,
+    }
+  }
+  op().map_err(|err| match policy.retries > 0 && is_transient(&err) {
+    true => Error::new(
+      err.kind(),
+      RetriesExhausted { attempts: policy.retries + 1, source: err },
+    ),
+    false => err,
+  })
+}
+
+/// An error that was still returned after an operation was retried.
+#[derive(Debug)]
+struct RetriesExhausted {
+  attempts: u32,
+  source: Error,
+}
+
+impl fmt::Display for RetriesExhausted {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&i18n::format(
+      "romhacks::io::retries_exhausted",
+      &[("attempts", &self.attempts), ("error", &self.source)],
+    ))
+  }
+}
+
+impl std::error::Error for RetriesExhausted {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    Some(&self.source)
+  }
+}
+
+/// A reader or writer whose operations are [retried](retry) when they fail
+/// with a transient error.
+#[derive(Debug)]
+pub struct Retrying<T>(pub T);
+
+impl<T: Read> Read for Retrying<T> {
+  fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+    retry(|| self.0.read(buf))
+  }
+}
+
+impl<T: Write> Write for Retrying<T> {
+  fn write(&mut self, buf: &[u8]) -> Result<usize> {
+    retry(|| self.0.write(buf))
+  }
+
+  fn flush(&mut self) -> Result<()> {
+    retry(|| self.0.flush())
+  }
+}
+
+impl<T: Seek> Seek for Retrying<T> {
+  fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+    retry(|| self.0.seek(pos))
+  }
+}
+
+/// Writes `contents` to the file at `path`, or to standard output if `path`
+/// is [`STDIO`].
+pub fn write_file_or_stdout(path: &path::Path, contents: &[u8]) -> Result<()> {
+  match path.as_os_str() == STDIO {
+    true => {
+      let mut stdout = stdout().lock();
+      stdout.write_all(contents)?;
+      stdout.flush()
+    }
+    false => retry(|| fs::write(path, contents)),
+  }
+}
+
+/// Returns how many bytes can be written to the filesystem that `dir` is on,
+/// or `None` if the platform can't tell.
+pub fn available_space(dir: &path::Path) -> Result<Option<u64>> {
+  #[cfg(target_os = "linux")]
+  {
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = std::ffi::CString::new(dir.as_os_str().as_bytes())
+      .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    // SAFETY: `c_path` is NUL-terminated and `stat` is the struct that
+    // statvfs fills in.
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+      return Err(fs::with_path(fs::Operation::Stat, dir)(Error::last_os_error()));
+    }
+    Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+  }
+  #[cfg(windows)]
+  {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+    let wide_dir: Vec<u16> = dir.as_os_str().encode_wide().chain([0]).collect();
+    let mut available = 0u64;
+    // SAFETY: `wide_dir` is NUL-terminated, and the totals that aren't
+    // needed may be null.
+    let succeeded = unsafe {
+      GetDiskFreeSpaceExW(
+        wide_dir.as_ptr(),
+        &mut available,
+        std::ptr::null_mut(),
+        std::ptr::null_mut(),
+      )
+    };
+    if succeeded == 0 {
+      return Err(fs::with_path(fs::Operation::Stat, dir)(Error::last_os_error()));
+    }
+    Ok(Some(available))
+  }
+  #[cfg(not(any(target_os = "linux", windows)))]
+  {
+    let _ = dir;
+    Ok(None)
+  }
+}
+
+/// Copies the file at `source` to a new file at `dest`, sharing the
+/// underlying storage on filesystems that support copy-on-write clones, such
+/// as Btrfs, XFS and APFS. Falls back to a regular copy if cloning fails,
+/// e.g. because the files are on different filesystems.
+///
+/// Fails if `dest` already exists.
+pub fn reflink_or_copy(source: &path::Path, dest: &path::Path) -> Result<()> {
+  #[cfg(target_os = "macos")]
+  {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = |path: &path::Path| {
+      CString::new(path.as_os_str().as_bytes()).map_err(|_| Error::from(ErrorKind::InvalidInput))
+    };
+    let (source_c, dest_c) = (c_path(source)?, c_path(dest)?);
+    // clonefile creates `dest` itself, and fails if it already exists.
+    // SAFETY: both pointers are to NUL-terminated strings that outlive the call.
+    if unsafe { libc::clonefile(source_c.as_ptr(), dest_c.as_ptr(), 0) } == 0 {
+      return Ok(());
+    }
+    let err = Error::last_os_error();
+    if err.kind() == ErrorKind::AlreadyExists {
+      return Err(fs::with_path(fs::Operation::Copy, dest)(err));
+    }
+  }
+  let mut source_file = fs::File::open(source)?;
+  let mut dest_file = fs::OpenOptions::new()
+    .write(true)
+    .create_new(true)
+    .open(dest)?;
+  #[cfg(target_os = "linux")]
+  {
+    use std::os::fd::AsRawFd;
+    // SAFETY: both file descriptors are open for the duration of the call.
+    let cloned =
+      unsafe { libc::ioctl(dest_file.as_raw_fd(), libc::FICLONE, source_file.as_raw_fd()) };
+    if cloned == 0 {
+      return Ok(());
+    }
+  }
+  // Copying between the std files rather than their fs_err wrappers lets std
+  // use copy_file_range on Linux, which still shares storage on some
+  // filesystems, such as NFS.
+  copy(source_file.file_mut(), dest_file.file_mut())
+    .map(|_| ())
+    .map_err(fs::with_path(fs::Operation::Copy, dest))
+    .inspect_err(|_| {
+      let _ = fs::remove_file(dest);
+    })
+}
+
+/// A read/write buffer that's kept in memory until it grows larger than a
+/// threshold, at which point its contents are moved into a temporary file.
+///
+/// The temporary file is created in a caller-provided directory so that
+/// [persist](SpooledTempBuffer::persist) can rename it into place, and it's
+/// deleted if the buffer is dropped without being persisted. It's named as
+/// described in [temp](crate::temp).
+#[derive(Debug)]
+pub struct SpooledTempBuffer {
+  threshold: usize,
+  dir: path::PathBuf,
+  /// The name of the file the buffer will be persisted as.
+  name: String,
+  storage: Storage,
+}
+
+#[derive(Debug)]
+enum Storage {
+  Memory(Cursor<Vec<u8>>),
+  Disk(fs::File),
+}
+
+impl SpooledTempBuffer {
+  /// Creates an empty buffer that will spill into a file in `dir` once it
+  /// exceeds `threshold` bytes.
+  pub fn new(threshold: usize, dir: impl Into<path::PathBuf>) -> Self {
+    Self {
+      threshold,
+      dir: dir.into(),
+      name: temp::DEFAULT_NAME.to_owned(),
+      storage: Storage::Memory(Cursor::new(Vec::new())),
+    }
+  }
+
+  /// Creates an empty buffer that will be persisted as `dest`, and spill into
+  /// a file next to it once it exceeds `threshold` bytes.
+  pub fn for_output(threshold: usize, dest: &path::Path) -> Self {
+    Self {
+      threshold,
+      dir: dirs::temp_dir_for(dest),
+      name: output_name(dest),
+      storage: Storage::Memory(Cursor::new(Vec::new())),
+    }
+  }
+
+  /// Creates a buffer that will be persisted as `dest` and is already
+  /// spilled into a copy of the file at `source`, made with
+  /// [`reflink_or_copy`]. On filesystems that support copy-on-write clones,
+  /// only the ranges that are later modified take up additional space.
+  pub fn clone_of(source: &path::Path, dest: &path::Path) -> Result<Self> {
+    let dir = dirs::temp_dir_for(dest);
+    let name = output_name(dest);
+    let temp_path = temp::create_with(&dir, &name, |temp_path| {
+      retry(|| reflink_or_copy(source, temp_path)).map(|()| temp_path.to_path_buf())
+    })?;
+    let file = retry(|| fs::OpenOptions::new().read(true).write(true).open(&temp_path))
+      .inspect_err(|_| {
+        let _ = fs::remove_file(&temp_path);
+      })?;
+    Ok(Self {
+      threshold: 0,
+      dir,
+      name,
+      storage: Storage::Disk(file),
+    })
+  }
+
+  /// Returns `true` if the buffer's contents have been moved to disk.
+  pub fn is_spilled(&self) -> bool {
+    matches!(self.storage, Storage::Disk(_))
+  }
+
+  /// Moves the contents of the buffer into a temporary file.
+  /// Does nothing if the buffer has already been spilled.
+  pub fn spill(&mut self) -> Result<()> {
+    if let Storage::Memory(cursor) = &self.storage {
+      let mut file = temp::create(&self.dir, &self.name)?;
+      Retrying(&mut file).write_all(cursor.get_ref())?;
+      Retrying(&mut file).seek(SeekFrom::Start(cursor.position()))?;
+      self.storage = Storage::Disk(file);
+    }
+    Ok(())
+  }
+
+  /// Writes the buffer's contents to `path`, renaming the temporary file if
+  /// the buffer was spilled.
+  pub fn persist(mut self, path: impl AsRef<path::Path>) -> Result<()> {
+    let empty = Storage::Memory(Cursor::new(Vec::new()));
+    match mem::replace(&mut self.storage, empty) {
+      Storage::Memory(cursor) => retry(|| fs::write(&path, cursor.get_ref())),
+      Storage::Disk(file) => {
+        let (file, temp_path) = file.into_parts();
+        drop(file); // close the file prior to renaming
+        retry(|| fs::rename(&temp_path, &path))
+      }
+    }
+  }
+
+  /// Spills the buffer if it would grow beyond the threshold.
+  fn reserve(&mut self, new_len: u64) -> Result<()> {
+    if !self.is_spilled() && new_len > self.threshold as u64 {
+      self.spill()?;
+    }
+    Ok(())
+  }
+}
+
+impl Read for SpooledTempBuffer {
+  fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+    match &mut self.storage {
+      Storage::Memory(cursor) => cursor.read(buf),
+      Storage::Disk(file) => retry(|| file.read(buf)),
+    }
+  }
+}
+
+impl Write for SpooledTempBuffer {
+  fn write(&mut self, buf: &[u8]) -> Result<usize> {
+    if let Storage::Memory(cursor) = &self.storage {
+      self.reserve(cursor.position() + buf.len() as u64)?;
+    }
+    match &mut self.storage {
+      Storage::Memory(cursor) => cursor.write(buf),
+      Storage::Disk(file) => retry(|| file.write(buf)),
+    }
+  }
+
+  fn flush(&mut self) -> Result<()> {
+    match &mut self.storage {
+      Storage::Memory(_) => Ok(()),
+      Storage::Disk(file) => retry(|| file.flush()),
+    }
+  }
+}
+
+impl Seek for SpooledTempBuffer {
+  fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+    match &mut self.storage {
+      Storage::Memory(cursor) => cursor.seek(pos),
+      Storage::Disk(file) => retry(|| file.seek(pos)),
+    }
+  }
+}
+
+impl Resize for SpooledTempBuffer {
+  fn set_len(&mut self, new_size: u64) -> Result<()> {
+    self.reserve(new_size)?;
+    match &mut self.storage {
+      Storage::Memory(cursor) => Resize::set_len(cursor, new_size),
+      Storage::Disk(file) => retry(|| Resize::set_len(file, new_size)),
+    }
+  }
+}
+
+impl BufWrite for SpooledTempBuffer {
+  type Inner = Self;
+
+  fn flush_and_get_inner(&mut self) -> Result<&mut Self> {
+    Ok(self)
+  }
+}
+
+impl KnownLen for SpooledTempBuffer {
+  fn known_len(&self) -> Result<u64> {
+    match &self.storage {
+      Storage::Memory(cursor) => cursor.known_len(),
+      Storage::Disk(file) => file.known_len(),
+    }
+  }
+}
+
+impl Drop for SpooledTempBuffer {
+  fn drop(&mut self) {
+    if let Storage::Disk(file) = &self.storage {
+      // Failing to delete a temporary file isn't worth panicking over.
+      let _ = std::fs::remove_file(file.path());
+    }
+  }
+}
+
+/// The name of the file at `dest`, which temporary files for it are named
+/// after.
+fn output_name(dest: &path::Path) -> String {
+  match dest.file_name() {
+    Some(name) => name.to_string_lossy().into_owned(),
+    None => temp::DEFAULT_NAME.to_owned(),
+  }
+}
+
+/// A writer that writes everything to several sinks.
+///
+/// A sink that fails is set aside along with its error, so that the others
+/// are still written to; writing only fails once every sink has failed.
+/// [`into_results`](FanOut::into_results) tells which sinks were written to
+/// in full.
+#[derive(Debug)]
+pub struct FanOut<W> {
+  sinks: Vec<Result<W>>,
+}
+
+impl<W: Write> FanOut<W> {
+  pub fn new(sinks: impl IntoIterator<Item = W>) -> Self {
+    Self { sinks: sinks.into_iter().map(Ok).collect() }
+  }
+
+  /// Flushes the sinks that haven't failed, and returns each sink or the
+  /// error it failed with, in the order they were given.
+  pub fn into_results(self) -> Vec<Result<W>> {
+    (self.sinks.into_iter())
+      .map(|sink| sink.and_then(|mut sink| sink.flush().map(|()| sink)))
+      .collect()
+  }
+
+  /// Calls `op` on each sink that hasn't failed, setting aside those it
+  /// fails for.
+  fn for_each(&mut self, mut op: impl FnMut(&mut W) -> Result<()>) -> Result<()> {
+    for sink in &mut self.sinks {
+      let written = match sink {
+        Ok(writer) => op(writer),
+        Err(_) => continue,
+      };
+      if let Err(err) = written {
+        *sink = Err(err);
+      }
+    }
+    match self.sinks.iter().find_map(|sink| sink.as_ref().err()) {
+      Some(err) if self.sinks.iter().all(Result::is_err) => {
+        Err(Error::new(err.kind(), err.to_string()))
+      }
+      _ => Ok(()),
+    }
+  }
+}
+
+impl<W: Write> Write for FanOut<W> {
+  /// Writes all of `buf` to each sink, so that they stay in step.
+  fn write(&mut self, buf: &[u8]) -> Result<usize> {
+    self.for_each(|sink| sink.write_all(buf))?;
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> Result<()> {
+    self.for_each(|sink| sink.flush())
+  }
+}
+
+/// A buffered writer that keeps track of its position in the underlying
+/// stream, so that previously written bytes can be
+/// [read back](BufWrite::read_back) and the inner writer can always be
+/// recovered, even if the final flush fails.
+#[derive(Debug)]
+pub struct TrackedBufWriter<W: Write> {
+  inner: BufWriter<W>,
+  position: u64,
+}
+
+impl<W: Write + Seek> TrackedBufWriter<W> {
+  /// Wraps `inner`, starting from its current position.
+  pub fn new(mut inner: W) -> Result<Self> {
+    let position = inner.stream_position()?;
+    Ok(Self { inner: BufWriter::new(inner), position })
+  }
+
+  /// Wraps `inner` with a buffer of at least `capacity` bytes, starting from
+  /// its current position.
+  pub fn with_capacity(capacity: usize, mut inner: W) -> Result<Self> {
+    let position = inner.stream_position()?;
+    Ok(Self { inner: BufWriter::with_capacity(capacity, inner), position })
+  }
+
+  /// The position of the end of the buffered data within the underlying stream.
+  pub fn position(&self) -> u64 {
+    self.position
+  }
+
+  pub fn get_ref(&self) -> &W {
+    self.inner.get_ref()
+  }
+
+  /// Flushes the buffer and returns the inner writer along with the result of
+  /// the flush. Unlike [BufWriter::into_inner], the writer is returned even if
+  /// flushing fails; any unwritten bytes are discarded.
+  pub fn into_inner(self) -> (W, Result<()>) {
+    match self.inner.into_inner() {
+      Ok(inner) => (inner, Ok(())),
+      Err(err) => {
+        let (err, writer) = err.into_parts();
+        let (inner, _unwritten) = writer.into_parts();
+        (inner, Err(err))
+      }
+    }
+  }
+}
+
+impl<W: Write> Write for TrackedBufWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> Result<usize> {
+    let written = self.inner.write(buf)?;
+    self.position += written as u64;
+    Ok(written)
+  }
+
+  fn flush(&mut self) -> Result<()> {
+    self.inner.flush()
+  }
+}
+
+impl<W: Write> BufWrite for TrackedBufWriter<W> {
+  type Inner = W;
+
+  fn flush_and_get_inner(&mut self) -> Result<&mut W> {
+    self.inner.flush_and_get_inner()
+  }
+}
+
+impl<W: Write + Seek> Seek for TrackedBufWriter<W> {
+  /// Flushes the buffer before seeking.
+  fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+    self.position = self.inner.seek(pos)?;
+    Ok(self.position)
+  }
+
+  fn stream_position(&mut self) -> Result<u64> {
+    Ok(self.position)
+  }
+}
+
+impl<W: Write + Resize> Resize for TrackedBufWriter<W> {
+  fn set_len(&mut self, new_size: u64) -> Result<()> {
+    self.with_inner(|inner| inner.set_len(new_size))
+  }
+}
+
+/// Several streams read one after another as if they were one.
+///
+/// Reading moves on to the next part when one runs out. Seeking needs the
+/// length of every part, which is given with [`ConcatReader::with_lens`] or
+/// measured the first time the reader seeks.
+#[derive(Debug)]
+pub struct ConcatReader<R> {
+  parts: Vec<R>,
+  /// The offset of each part, followed by the total length, once known.
+  boundaries: Option<Vec<u64>>,
+  /// The part being read, which is `parts.len()` past the end.
+  index: usize,
+  /// The furthest part that's been read or sought to, so that the parts
+  /// from there back to `index` can be rewound when seeking back.
+  furthest: usize,
+  position: u64,
+}
+
+impl<R> ConcatReader<R> {
+  /// Reads `parts` in order, each from its current position.
+  pub fn new(parts: Vec<R>) -> Self {
+    Self { parts, boundaries: None, index: 0, furthest: 0, position: 0 }
+  }
+
+  /// Reads `parts` in order, where each part's length is the corresponding
+  /// element of `lens`.
+  ///
+  /// # Panics
+  ///
+  /// If there isn't a length for each part.
+  pub fn with_lens(parts: Vec<R>, lens: impl IntoIterator<Item = u64>) -> Self {
+    let boundaries: Vec<u64> = [0]
+      .into_iter()
+      .chain(lens.into_iter().scan(0, |end, len| {
+        *end += len;
+        Some(*end)
+      }))
+      .collect();
+    assert_eq!(boundaries.len(), parts.len() + 1);
+    Self { boundaries: Some(boundaries), ..Self::new(parts) }
+  }
+
+  pub fn into_inner(self) -> Vec<R> {
+    self.parts
+  }
+}
+
+impl<R: Read> Read for ConcatReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+    while let Some(part) = self.parts.get_mut(self.index) {
+      let len = part.read(buf)?;
+      if len > 0 || buf.is_empty() {
+        self.position += len as u64;
+        return Ok(len);
+      }
+      self.index += 1;
+      self.furthest = self.furthest.max(self.index);
+    }
+    Ok(0)
+  }
+}
+
+impl<R: Seek> Seek for ConcatReader<R> {
+  fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+    if self.boundaries.is_none() {
+      let mut boundaries = vec![0];
+      for part in &mut self.parts {
+        let len = part.seek(SeekFrom::End(0))?;
+        boundaries.push(boundaries.last().unwrap() + len);
+      }
+      // Every part was moved to its end.
+      self.furthest = self.parts.len();
+      self.boundaries = Some(boundaries);
+    }
+    let boundaries = self.boundaries.as_deref().unwrap();
+    let position = match pos {
+      SeekFrom::Start(offset) => Some(offset),
+      SeekFrom::End(offset) => boundaries.last().unwrap().checked_add_signed(offset),
+      SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+    }
+    .ok_or_else(|| Error::from(ErrorKind::InvalidInput))?;
+    // Empty parts start where the next one does, so this is the last part
+    // that starts at or before `position`, or `parts.len()` past the end.
+    let index = (boundaries.partition_point(|&start| start <= position) - 1).min(self.parts.len());
+    if let Some(part) = self.parts.get_mut(index) {
+      part.seek(SeekFrom::Start(position - boundaries[index]))?;
+    }
+    let rewind_end = (self.furthest + 1).min(self.parts.len());
+    for part in self.parts.get_mut(index + 1..rewind_end).unwrap_or_default() {
+      part.seek(SeekFrom::Start(0))?;
+    }
+    self.index = index;
+    self.furthest = index;
+    self.position = position;
+    Ok(position)
+  }
+
+  fn stream_position(&mut self) -> Result<u64> {
+    Ok(self.position)
+  }
+}
+
+impl<R> KnownLen for ConcatReader<R> {
+  /// The total length of the parts, which is only known once the reader has
+  /// been given or measured them.
+  fn known_len(&self) -> Result<u64> {
+    (self.boundaries.as_ref())
+      .and_then(|boundaries| boundaries.last().copied())
+      .ok_or_else(|| Error::from(ErrorKind::Unsupported))
+  }
+}
+
+/// A reader for a part of a stream whose length is declared up front, such
+/// as a section of a patch.
+///
+/// Reading stops at the declared length, like [`Take`], but fails with a
+/// [`LengthMismatch`] if the stream ends before it. Once the part has been
+/// parsed, [`finish`](ExactSizeRead::finish) checks that it took up every
+/// declared byte.
+#[derive(Debug)]
+pub struct ExactSizeRead<R> {
+  inner: R,
+  len: u64,
+  remaining: u64,
+}
+
+impl<R> ExactSizeRead<R> {
+  /// Reads the next `len` bytes of `inner`.
+  pub fn new(inner: R, len: u64) -> Self {
+    Self { inner, len, remaining: len }
+  }
+
+  /// Returns the inner stream, or fails with a [`LengthMismatch`] if some of
+  /// the declared bytes haven't been read, meaning that the stream continues
+  /// past what was parsed.
+  pub fn finish(self) -> Result<R> {
+    match self.remaining {
+      0 => Ok(self.inner),
+      _ => Err(self.mismatch(ErrorKind::InvalidData)),
+    }
+  }
+
+  fn mismatch(&self, kind: ErrorKind) -> Error {
+    Error::new(
+      kind,
+      LengthMismatch { expected: self.len, actual: self.len - self.remaining },
+    )
+  }
+}
+
+impl<R: Read> Read for ExactSizeRead<R> {
+  fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+    if self.remaining == 0 || buf.is_empty() {
+      return Ok(0);
+    }
+    let len = buf.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+    match self.inner.read(&mut buf[..len])? {
+      0 => Err(self.mismatch(ErrorKind::UnexpectedEof)),
+      read => {
+        self.remaining -= read as u64;
+        Ok(read)
+      }
+    }
+  }
+}
+
+/// The error [`ExactSizeRead`] fails with when a part of a stream isn't the
+/// length it was declared to be.
+///
+/// It's returned as an [`ErrorKind::UnexpectedEof`] error if the stream ended
+/// early, and as an [`ErrorKind::InvalidData`] error if the part was parsed
+/// before all of it was read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LengthMismatch {
+  /// The declared length.
+  pub expected: u64,
+  /// How many bytes were read.
+  pub actual: u64,
+}
+
+impl fmt::Display for LengthMismatch {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&i18n::format(
+      "romhacks::io::length_mismatch",
+      &[("expected", &self.expected), ("actual", &self.actual)],
+    ))
+  }
+}
+
+impl std::error::Error for LengthMismatch {}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  mod concat_reader {
+    use super::*;
+
+    /// Parts that are joined into "abcdefgh", with an empty part between
+    /// "abc" and "defg", so there are boundaries at 3 (twice), 7 and 8.
+    const PARTS: [&[u8]; 4] = [b"abc", b"", b"defg", b"h"];
+    const JOINED: &[u8] = b"abcdefgh";
+
+    /// A reader given the lengths of its parts and one that measures them.
+    fn readers() -> [ConcatReader<Cursor<&'static [u8]>>; 2] {
+      let parts = || PARTS.into_iter().map(Cursor::new).collect::<Vec<_>>();
+      let lens = PARTS.iter().map(|part| part.len() as u64);
+      [ConcatReader::with_lens(parts(), lens), ConcatReader::new(parts())]
+    }
+
+    fn rest(reader: &mut impl Read) -> Vec<u8> {
+      let mut rest = Vec::new();
+      reader.read_to_end(&mut rest).unwrap();
+      rest
+    }
+
+    #[test]
+    fn reads_parts_in_order() {
+      for mut reader in readers() {
+        assert_eq!(rest(&mut reader), JOINED);
+      }
+    }
+
+    #[test]
+    fn seeks_around_boundaries() {
+      for boundary in [3, 7, 8] {
+        for position in [boundary - 1, boundary, boundary + 1] {
+          for mut reader in readers() {
+            assert_eq!(reader.seek(SeekFrom::Start(position)).unwrap(), position);
+            let expected = JOINED.get(position as usize..).unwrap_or_default();
+            assert_eq!(rest(&mut reader), expected, "position {position}");
+          }
+        }
+      }
+    }
+
+    #[test]
+    fn seeks_back_across_boundaries() {
+      for mut reader in readers() {
+        assert_eq!(rest(&mut reader), JOINED);
+        for position in [7, 3, 2, 0] {
+          reader.seek(SeekFrom::Start(position)).unwrap();
+          assert_eq!(rest(&mut reader), &JOINED[position as usize..]);
+        }
+      }
+    }
+
+    #[test]
+    fn seeks_from_end() {
+      for mut reader in readers() {
+        assert_eq!(reader.seek(SeekFrom::End(0)).unwrap(), 8);
+        assert_eq!(rest(&mut reader), b"");
+        assert_eq!(reader.seek(SeekFrom::End(-5)).unwrap(), 3);
+        assert_eq!(rest(&mut reader), b"defgh");
+        assert_eq!(reader.seek(SeekFrom::End(-8)).unwrap(), 0);
+        assert_eq!(rest(&mut reader), JOINED);
+        assert_eq!(reader.known_len().unwrap(), 8);
+      }
+    }
+
+    #[test]
+    fn seeks_back_from_current_position() {
+      for mut reader in readers() {
+        let mut buf = [0u8; 5];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(reader.seek(SeekFrom::Current(-3)).unwrap(), 2);
+        assert_eq!(rest(&mut reader), b"cdefgh");
+        assert_eq!(reader.seek(SeekFrom::Current(-1)).unwrap(), 7);
+        assert_eq!(rest(&mut reader), b"h");
+      }
+    }
+
+    #[test]
+    fn refuses_seeks_before_start() {
+      for mut reader in readers() {
+        reader.seek(SeekFrom::Start(4)).unwrap();
+        let err = reader.seek(SeekFrom::Current(-5)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let err = reader.seek(SeekFrom::End(-9)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        // A failed seek doesn't move the reader.
+        assert_eq!(rest(&mut reader), b"efgh");
+      }
+    }
+  }
+
+  mod exact_size_read {
+    use super::*;
+
+    fn read_all(reader: &mut impl Read) -> Result<Vec<u8>> {
+      let mut data = Vec::new();
+      reader.read_to_end(&mut data)?;
+      Ok(data)
+    }
+
+    fn mismatch(err: &Error) -> LengthMismatch {
+      *err.get_ref().unwrap().downcast_ref::<LengthMismatch>().unwrap()
+    }
+
+    #[test]
+    fn empty_part_of_empty_stream() {
+      let mut part = ExactSizeRead::new(Cursor::new(b""), 0);
+      assert_eq!(read_all(&mut part).unwrap(), b"");
+      part.finish().unwrap();
+    }
+
+    #[test]
+    fn empty_stream_ends_early() {
+      let mut part = ExactSizeRead::new(Cursor::new(b""), 4);
+      let err = read_all(&mut part).unwrap_err();
+      assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+      assert_eq!(mismatch(&err), LengthMismatch { expected: 4, actual: 0 });
+    }
+
+    #[test]
+    fn part_as_long_as_stream() {
+      let mut part = ExactSizeRead::new(Cursor::new(b"abcd"), 4);
+      assert_eq!(read_all(&mut part).unwrap(), b"abcd");
+      assert_eq!(part.finish().unwrap().position(), 4);
+    }
+
+    #[test]
+    fn part_stops_at_declared_length() {
+      let mut part = ExactSizeRead::new(Cursor::new(b"abcdef"), 4);
+      assert_eq!(read_all(&mut part).unwrap(), b"abcd");
+      let mut stream = part.finish().unwrap();
+      assert_eq!(read_all(&mut stream).unwrap(), b"ef");
+    }
+
+    #[test]
+    fn stream_one_byte_short() {
+      let mut part = ExactSizeRead::new(Cursor::new(b"abc"), 4);
+      let err = read_all(&mut part).unwrap_err();
+      assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+      assert_eq!(mismatch(&err), LengthMismatch { expected: 4, actual: 3 });
+    }
+
+    #[test]
+    fn finish_before_end_of_part() {
+      let mut part = ExactSizeRead::new(Cursor::new(b"abcd"), 4);
+      part.read_exact(&mut [0u8; 3]).unwrap();
+      let err = part.finish().unwrap_err();
+      assert_eq!(err.kind(), ErrorKind::InvalidData);
+      assert_eq!(mismatch(&err), LengthMismatch { expected: 4, actual: 3 });
+    }
+
+    #[test]
+    fn errors_of_stream_are_passed_on() {
+      struct Failing;
+      impl Read for Failing {
+        fn read(&mut self, _: &mut [u8]) -> Result<usize> {
+          Err(Error::new(ErrorKind::PermissionDenied, "denied"))
+        }
+      }
+      let mut part = ExactSizeRead::new(Failing, 4);
+      let err = read_all(&mut part).unwrap_err();
+      assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+      assert_eq!(err.to_string(), "denied");
+    }
+
+    #[test]
+    fn length_mismatch_is_bad_patch() {
+      let mut part = ExactSizeRead::new(Cursor::new(b"abc"), 4);
+      let err = crate::patch::Error::from(read_all(&mut part).unwrap_err());
+      assert!(matches!(err, crate::patch::Error::BadPatch));
+    }
+  }
+
+  mod reflink_or_copy {
+    use super::*;
+
+    #[test]
+    fn copies_into_new_file_only() {
+      let dir = std::env::temp_dir().join(format!("romhacks-{}", ulid::Ulid::new()));
+      fs::create_dir(&dir).unwrap();
+      let (source, dest) = (dir.join("source"), dir.join("dest"));
+      fs::write(&source, b"abcd").unwrap();
+      reflink_or_copy(&source, &dest).unwrap();
+      assert_eq!(fs::read(&dest).unwrap(), b"abcd");
+      let err = reflink_or_copy(&source, &dest).unwrap_err();
+      assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+      assert_eq!(fs::read(&dest).unwrap(), b"abcd");
+      fs::remove_dir_all(&dir).unwrap();
+    }
+  }
+}
@@ -0,0 +1,284 @@
+//! A small JSON reader and writer for exchanging data with other tools.
+//!
+//! Only integer numbers are supported, since nothing this crate exports needs
+//! fractions. Objects keep their keys in order.
+
+use crate::error::prelude::*;
+use crate::i18n;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+  Null,
+  Bool(bool),
+  Integer(i128),
+  String(String),
+  Array(Vec<Value>),
+  Object(Vec<(String, Value)>),
+}
+
+impl Value {
+  /// Returns the value of `key` if this is an object that contains it.
+  pub fn get(&self, key: &str) -> Option<&Value> {
+    match self {
+      Value::Object(entries) => (entries.iter())
+        .find(|(k, _)| k == key)
+        .map(|(_, value)| value),
+      _ => None,
+    }
+  }
+
+  pub fn as_str(&self) -> Option<&str> {
+    match self {
+      Value::String(str) => Some(str),
+      _ => None,
+    }
+  }
+
+  pub fn as_integer(&self) -> Option<i128> {
+    match self {
+      Value::Integer(value) => Some(*value),
+      _ => None,
+    }
+  }
+
+  pub fn as_array(&self) -> Option<&[Value]> {
+    match self {
+      Value::Array(values) => Some(values),
+      _ => None,
+    }
+  }
+
+  /// Writes the value with each array element and object entry on its own line.
+  fn write_indented(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+    const INDENT: &str = "  ";
+    let indent = INDENT.repeat(depth + 1);
+    let close = INDENT.repeat(depth);
+    match self {
+      Value::Null => f.write_str("null"),
+      Value::Bool(value) => write!(f, "{value}"),
+      Value::Integer(value) => write!(f, "{value}"),
+      Value::String(str) => write_string(f, str),
+      Value::Array(values) if values.is_empty() => f.write_str("[]"),
+      Value::Array(values) => {
+        f.write_str("[\n")?;
+        for (i, value) in values.iter().enumerate() {
+          f.write_str(&indent)?;
+          value.write_indented(f, depth + 1)?;
+          f.write_str(if i + 1 < values.len() { ",\n" } else { "\n" })?;
+        }
+        write!(f, "{close}]")
+      }
+      Value::Object(entries) if entries.is_empty() => f.write_str("{}"),
+      Value::Object(entries) => {
+        f.write_str("{\n")?;
+        for (i, (key, value)) in entries.iter().enumerate() {
+          f.write_str(&indent)?;
+          write_string(f, key)?;
+          f.write_str(": ")?;
+          value.write_indented(f, depth + 1)?;
+          f.write_str(if i + 1 < entries.len() { ",\n" } else { "\n" })?;
+        }
+        write!(f, "{close}}}")
+      }
+    }
+  }
+}
+
+impl fmt::Display for Value {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    self.write_indented(f, 0)
+  }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, str: &str) -> fmt::Result {
+  f.write_str("\"")?;
+  for c in str.chars() {
+    match c {
+      '"' => f.write_str("\\\"")?,
+      '\\' => f.write_str("\\\\")?,
+      '\n' => f.write_str("\\n")?,
+      '\r' => f.write_str("\\r")?,
+      '\t' => f.write_str("\\t")?,
+      c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+      c => write!(f, "{c}")?,
+    }
+  }
+  f.write_str("\"")
+}
+
+impl std::str::FromStr for Value {
+  type Err = ParseError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut parser = Parser { bytes: s.as_bytes(), pos: 0 };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    match parser.pos == parser.bytes.len() {
+      true => Ok(value),
+      false => Err(parser.error()),
+    }
+  }
+}
+
+struct Parser<'a> {
+  bytes: &'a [u8],
+  pos: usize,
+}
+
+impl Parser<'_> {
+  fn error(&self) -> ParseError {
+    ParseError { offset: self.pos }
+  }
+
+  fn skip_whitespace(&mut self) {
+    while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.bytes.get(self.pos) {
+      self.pos += 1;
+    }
+  }
+
+  fn peek(&mut self) -> Option<u8> {
+    self.skip_whitespace();
+    self.bytes.get(self.pos).copied()
+  }
+
+  fn expect(&mut self, literal: &[u8]) -> Result<(), ParseError> {
+    match self.bytes[self.pos..].starts_with(literal) {
+      true => {
+        self.pos += literal.len();
+        Ok(())
+      }
+      false => Err(self.error()),
+    }
+  }
+
+  fn value(&mut self) -> Result<Value, ParseError> {
+    match self.peek().ok_or_else(|| self.error())? {
+      b'n' => self.expect(b"null").map(|_| Value::Null),
+      b't' => self.expect(b"true").map(|_| Value::Bool(true)),
+      b'f' => self.expect(b"false").map(|_| Value::Bool(false)),
+      b'"' => self.string().map(Value::String),
+      b'[' => {
+        self.pos += 1;
+        let mut values = Vec::new();
+        if self.peek() == Some(b']') {
+          self.pos += 1;
+          return Ok(Value::Array(values));
+        }
+        loop {
+          values.push(self.value()?);
+          match self.peek() {
+            Some(b',') => self.pos += 1,
+            Some(b']') => {
+              self.pos += 1;
+              return Ok(Value::Array(values));
+            }
+            _ => return Err(self.error()),
+          }
+        }
+      }
+      b'{' => {
+        self.pos += 1;
+        let mut entries = Vec::new();
+        if self.peek() == Some(b'}') {
+          self.pos += 1;
+          return Ok(Value::Object(entries));
+        }
+        loop {
+          if self.peek() != Some(b'"') {
+            return Err(self.error());
+          }
+          let key = self.string()?;
+          if self.peek() != Some(b':') {
+            return Err(self.error());
+          }
+          self.pos += 1;
+          entries.push((key, self.value()?));
+          match self.peek() {
+            Some(b',') => self.pos += 1,
+            Some(b'}') => {
+              self.pos += 1;
+              return Ok(Value::Object(entries));
+            }
+            _ => return Err(self.error()),
+          }
+        }
+      }
+      b'-' | b'0'..=b'9' => {
+        let start = self.pos;
+        self.pos += 1;
+        while let Some(b'0'..=b'9') = self.bytes.get(self.pos) {
+          self.pos += 1;
+        }
+        (std::str::from_utf8(&self.bytes[start..self.pos]).ok())
+          .and_then(|digits| digits.parse().ok())
+          .map(Value::Integer)
+          .ok_or(ParseError { offset: start })
+      }
+      _ => Err(self.error()),
+    }
+  }
+
+  fn string(&mut self) -> Result<String, ParseError> {
+    self.expect(b"\"")?;
+    let mut buf = Vec::new();
+    loop {
+      let byte = *self.bytes.get(self.pos).ok_or_else(|| self.error())?;
+      self.pos += 1;
+      match byte {
+        b'"' => break,
+        b'\\' => {
+          let escape = *self.bytes.get(self.pos).ok_or_else(|| self.error())?;
+          self.pos += 1;
+          let c = match escape {
+            b'"' => '"',
+            b'\\' => '\\',
+            b'/' => '/',
+            b'b' => '\u{8}',
+            b'f' => '\u{c}',
+            b'n' => '\n',
+            b'r' => '\r',
+            b't' => '\t',
+            b'u' => {
+              let high = self.hex4()?;
+              let code = match high {
+                0xD800..=0xDBFF => {
+                  self.expect(b"\\u")?;
+                  let low = self.hex4()?;
+                  if !(0xDC00..=0xDFFF).contains(&low) {
+                    return Err(self.error());
+                  }
+                  0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+                }
+                _ => high,
+              };
+              char::from_u32(code).ok_or_else(|| self.error())?
+            }
+            _ => return Err(self.error()),
+          };
+          buf.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+        }
+        byte => buf.push(byte),
+      }
+    }
+    String::from_utf8(buf).map_err(|_| self.error())
+  }
+
+  fn hex4(&mut self) -> Result<u32, ParseError> {
+    let digits = self
+      .bytes
+      .get(self.pos..self.pos + 4)
+      .ok_or_else(|| self.error())?;
+    let digits = std::str::from_utf8(digits).map_err(|_| self.error())?;
+    let value = u32::from_str_radix(digits, 16).map_err(|_| self.error())?;
+    self.pos += 4;
+    Ok(value)
+  }
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("{}", i18n::format("romhacks::json::malformed", &[("offset", offset)]))]
+#[diagnostic(code(romhacks::json::malformed))]
+pub struct ParseError {
+  offset: usize,
+}
@@ -1,4 +1,5 @@
-use crate::{crc, mem};
+use crate::error::prelude::*;
+use crate::{crc, i18n, mem};
 pub use kdl::*;
 pub use kdl_schema::Schema;
 pub use kdl_schema_check::CheckFailure;
@@ -8,6 +9,59 @@ pub mod prelude {
   pub use kdl_schema_check::CheckExt;
 }
 
+/// Every way a document fails to match a schema.
+#[derive(Debug, Error, Diagnostic)]
+#[error("{}", i18n::format("romhacks::kdl::check_failures", &[("count", &.failures.len())]))]
+#[diagnostic(code(romhacks::kdl::check_failures))]
+pub struct CheckFailures {
+  #[related]
+  pub failures: Vec<CheckFailure>,
+}
+
+/// Checks `text` against `schema`, reporting every violation instead of just
+/// the first.
+///
+/// The checker stops at the first problem, so after a failure each top-level
+/// node is checked again in isolation: the other nodes that share its name are
+/// blanked out with spaces, which keeps every span pointing into `text`.
+/// Problems with the document as a whole are reported once.
+pub fn check_all(schema: &Schema, name: &str, text: &str) -> Result<(), CheckFailures> {
+  use self::prelude::*;
+
+  let first = match schema.check_text_matches(name, text) {
+    Ok(()) => return Ok(()),
+    Err(failure) => failure,
+  };
+  let mut failures = vec![first];
+  let Ok(doc) = text.parse::<KdlDocument>() else {
+    return Err(CheckFailures { failures });
+  };
+  let mut seen: Vec<String> = vec![format!("{:?}", failures[0])];
+  for (i, node) in doc.nodes().iter().enumerate() {
+    let siblings = (doc.nodes().iter().enumerate())
+      .filter(|(j, other)| *j != i && other.name().value() == node.name().value())
+      .map(|(_, other)| other.span());
+    let mut isolated = text.as_bytes().to_vec();
+    for span in siblings {
+      for byte in &mut isolated[span.offset()..span.offset() + span.len()] {
+        if !byte.is_ascii_whitespace() {
+          *byte = b' ';
+        }
+      }
+    }
+    // Blanking whole nodes can't split a UTF-8 sequence.
+    let isolated = String::from_utf8(isolated).unwrap();
+    if let Err(failure) = schema.check_text_matches(name, &isolated) {
+      let key = format!("{failure:?}");
+      if !seen.contains(&key) {
+        seen.push(key);
+        failures.push(failure);
+      }
+    }
+  }
+  Err(CheckFailures { failures })
+}
+
 pub fn unwrap_children(node: &KdlNode) -> &[KdlNode] {
   node.children().unwrap().nodes()
 }
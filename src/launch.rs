@@ -0,0 +1,236 @@
+//! Opening the newest patched file of a game in an emulator.
+//!
+//! Emulators are set up in "emulators.kdl" in the configuration directory,
+//! with one node per emulator holding the program and its arguments, and the
+//! extensions of the files it opens:
+//!
+//! ```kdl
+//! emulator "snes9x" "-fullscreen" extensions="sfc smc"
+//! emulator "mupen64plus" extensions="z64 n64 v64"
+//! emulator "retroarch" "-L" "/usr/lib/libretro/mgba_libretro.so"
+//! ```
+//!
+//! The path of the patched file is added after the arguments. An emulator
+//! without extensions opens files that no other emulator does.
+
+use crate::error::prelude::*;
+use crate::manifest::{self, index};
+use crate::{audit, dirs, fs, i18n, io, kdl};
+use std::{ffi, path, process};
+
+/// The name of the file emulators are read from.
+const EMULATORS_FILE_NAME: &str = "emulators.kdl";
+
+/// The name of the nodes in [`EMULATORS_FILE_NAME`].
+const EMULATOR: &str = "emulator";
+
+// props
+const EXTENSIONS: &str = "extensions";
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct Args {
+  /// The name of the game, as in the name of its manifest before
+  /// " (patched)", or the path of the manifest.
+  pub game: String,
+  /// The directory the game's manifest and patched files are in.
+  #[arg(long, value_name = "DIR", default_value = ".")]
+  pub dir: path::PathBuf,
+  /// Open the newest version of this hack rather than the last file
+  /// patched.
+  #[arg(long, value_name = "URL")]
+  pub hack: Option<String>,
+  /// The emulator to run rather than the one set up for the file's
+  /// extension.
+  #[arg(long, value_name = "PROGRAM")]
+  pub emulator: Option<String>,
+  /// Print the command that would be run without running it.
+  #[arg(short = 'n', long)]
+  pub dry_run: bool,
+}
+
+impl Args {
+  pub fn call(self) -> Result<(), Error> {
+    let manifest_path = self.manifest_path();
+    let doc = manifest::read(&manifest_path)?;
+    let mut outputs = (doc.files.iter())
+      .flat_map(|file| file.patches.iter())
+      .filter(|patch| self.hack.as_ref().is_none_or(|url| patch.hack_url == *url))
+      .filter_map(|patch| Some((patch, manifest_path.with_file_name(patch.output.as_ref()?))))
+      .filter(|(_, output)| output.is_file());
+    // Patches are recorded in the order they're applied, so the last one is
+    // the newest unless a hack's versions are being compared. Versions that
+    // can't be compared count as older than any that can.
+    let newest = match self.hack {
+      Some(_) => {
+        outputs.max_by(|(a, _), (b, _)| (a.hack_version.parsed()).cmp(&b.hack_version.parsed()))
+      }
+      None => outputs.next_back(),
+    };
+    let Some((_, file)) = newest else {
+      return Err(Error::NoPatchedFile { manifest: manifest_path });
+    };
+
+    let mut command = match self.emulator {
+      Some(program) => vec![program],
+      None => Emulators::load()?.find(&file)?.to_vec(),
+    };
+    command.push(file.display().to_string());
+    let command_line = command.join(" ");
+    if self.dry_run {
+      println!("{command_line}");
+      return Ok(());
+    }
+
+    audit::record(
+      "launch",
+      &[
+        ("game", &self.game),
+        ("file", &file.to_string_lossy()),
+        ("emulator", &command[0]),
+      ],
+    )?;
+    log::info!(
+      "{}",
+      i18n::format("romhacks::launch::running", &[("command", &command_line)])
+    );
+    run(&command).map_err(|source| Error::Spawn { command: command_line, source })
+  }
+
+  /// The path of the game's manifest.
+  fn manifest_path(&self) -> path::PathBuf {
+    match self.game.ends_with(index::MANIFEST_SUFFIX) {
+      true => path::PathBuf::from(&self.game),
+      false => {
+        let mut name = ffi::OsString::from(&self.game);
+        name.push(" (patched)");
+        name.push(index::MANIFEST_SUFFIX);
+        self.dir.join(name)
+      }
+    }
+  }
+}
+
+/// The path emulators are read from.
+pub fn path() -> path::PathBuf {
+  dirs::config_dir().join(EMULATORS_FILE_NAME)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Emulator {
+  /// The program to run, followed by its arguments.
+  command: Vec<String>,
+  /// The extensions of the files it opens, in lowercase. If there are none,
+  /// it opens any file.
+  extensions: Vec<String>,
+}
+
+/// The emulators set up in the configuration directory.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Emulators {
+  emulators: Vec<Emulator>,
+}
+
+impl Emulators {
+  /// Reads the emulators from [`path`]. There are none if it doesn't exist.
+  fn load() -> Result<Self, Error> {
+    let path = path();
+    let text = match fs::read_to_string(&path) {
+      Ok(text) => text,
+      Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+      Err(err) => return Err(err.into()),
+    };
+    let doc: kdl::KdlDocument = text.parse()?;
+    let emulators = (doc.nodes().iter())
+      .map(|node| parse_emulator(node).ok_or_else(|| Error::Malformed { path: path.clone() }))
+      .collect::<Result<_, _>>()?;
+    Ok(Self { emulators })
+  }
+
+  /// Finds the command of the first emulator that opens files with the
+  /// extension of `file`, or else of the first that opens any file.
+  fn find(&self, file: &path::Path) -> Result<&[String], Error> {
+    let extension = (file.extension())
+      .map(|extension| extension.to_string_lossy().to_lowercase())
+      .unwrap_or_default();
+    (self.emulators.iter())
+      .find(|emulator| emulator.extensions.contains(&extension))
+      .or_else(|| (self.emulators.iter()).find(|emulator| emulator.extensions.is_empty()))
+      .map(|emulator| emulator.command.as_slice())
+      .ok_or(Error::NoEmulator { extension })
+  }
+}
+
+fn parse_emulator(node: &kdl::KdlNode) -> Option<Emulator> {
+  if node.name().value() != EMULATOR {
+    return None;
+  }
+  let command = (node.entries().iter())
+    .filter(|entry| entry.name().is_none())
+    .map(|entry| entry.value().as_string().map(str::to_owned))
+    .collect::<Option<Vec<String>>>()
+    .filter(|command| !command.is_empty())?;
+  let extensions = match node.get(EXTENSIONS) {
+    Some(value) => (value.as_string()?.split_whitespace())
+      .map(|extension| extension.trim_start_matches('.').to_lowercase())
+      .collect(),
+    None => Vec::new(),
+  };
+  Some(Emulator { command, extensions })
+}
+
+/// Replaces this process with the emulator, so that it gets the terminal and
+/// its exit status is the launch's. Only returns if it couldn't be run.
+#[cfg(unix)]
+fn run(command: &[String]) -> io::Result<()> {
+  use std::os::unix::process::CommandExt;
+  Err(
+    process::Command::new(&command[0])
+      .args(&command[1..])
+      .exec(),
+  )
+}
+
+/// Runs the emulator and exits with its exit status once it exits.
+#[cfg(not(unix))]
+fn run(command: &[String]) -> io::Result<()> {
+  let status = process::Command::new(&command[0])
+    .args(&command[1..])
+    .status()?;
+  process::exit(status.code().unwrap_or(1))
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error(transparent)]
+  Kdl(#[from] kdl::KdlError),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Manifest(#[from] manifest::Error),
+  #[error("{}", i18n::format("romhacks::launch::malformed", &[("path", &path.display())]))]
+  #[diagnostic(
+    code(romhacks::launch::malformed),
+    help("{}", i18n::text("romhacks::launch::malformed::help"))
+  )]
+  Malformed { path: path::PathBuf },
+  #[error("{}", i18n::format(
+    "romhacks::launch::no_patched_file",
+    &[("manifest", &manifest.display())]
+  ))]
+  #[diagnostic(code(romhacks::launch::no_patched_file))]
+  NoPatchedFile { manifest: path::PathBuf },
+  #[error("{}", i18n::format("romhacks::launch::no_emulator", &[("extension", extension)]))]
+  #[diagnostic(
+    code(romhacks::launch::no_emulator),
+    help("{}", i18n::format("romhacks::launch::no_emulator::help", &[("path", &path().display())]))
+  )]
+  NoEmulator { extension: String },
+  #[error("{}", i18n::format(
+    "romhacks::launch::spawn",
+    &[("command", command), ("error", source)]
+  ))]
+  #[diagnostic(code(romhacks::launch::spawn))]
+  Spawn { command: String, source: io::Error },
+}
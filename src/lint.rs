@@ -0,0 +1,46 @@
+//! Warnings for hack developers about patches that change parts of a ROM
+//! that differ between dumps of the same game, such as copier headers and
+//! checksums. Such patches break when they're applied to a differently
+//! headered dump.
+
+use crate::console::{Console, Rom};
+use crate::i18n;
+use std::fmt;
+use std::ops::Range;
+
+/// A header or checksum that a patch changes.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct HeaderChange {
+  pub console: Console,
+  region: &'static str,
+  pub range: Range<usize>,
+}
+
+impl fmt::Display for HeaderChange {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&i18n::format(
+      "romhacks::lint::header_change",
+      &[
+        ("region", &i18n::text(self.region)),
+        ("console", &self.console),
+        ("start", &format!("{:#X}", self.range.start)),
+        ("end", &format!("{:#X}", self.range.end - 1)),
+      ],
+    ))
+  }
+}
+
+/// Returns the headers and checksums of `source` that differ in `target`.
+pub fn header_changes(source: &[u8], target: &[u8]) -> Vec<HeaderChange> {
+  let Some(rom) = Rom::recognize(source, source.len() as u64, None) else {
+    return Vec::new();
+  };
+  (rom.regions().into_iter())
+    .filter(|region| source.get(region.range.clone()) != target.get(region.range.clone()))
+    .map(|region| HeaderChange {
+      console: rom.console,
+      region: region.name,
+      range: region.range,
+    })
+    .collect()
+}
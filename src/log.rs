@@ -1,7 +1,20 @@
+use crate::render::{Stream, Style};
+
 pub fn init() {
   use std::io::Write;
   pretty_env_logger::formatted_builder()
-    .format(|buf, record| writeln!(buf, "{}: {}", record.level(), record.args()))
+    .format(|buf, record| {
+      let level = record.level();
+      match Style::for_level(level) {
+        Some(style) => writeln!(
+          buf,
+          "{}: {}",
+          Stream::Stderr.paint(style, level),
+          record.args()
+        ),
+        None => writeln!(buf, "{}: {}", level, record.args()),
+      }
+    })
     .filter_level(log::LevelFilter::Trace)
     .init();
 }
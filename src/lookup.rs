@@ -0,0 +1,266 @@
+use crate::cache::DigestCache;
+use crate::crc::Crc32;
+use crate::error::prelude::*;
+use crate::io::prelude::*;
+use crate::patch::header::Header;
+use crate::render::{Stream, Style};
+use crate::source::SourceCache;
+use crate::{cache, dirs, fs, i18n, io, manifest, patch, profile};
+use rayon::prelude::*;
+use std::sync::Arc;
+use std::{fmt, path};
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct Args {
+  #[arg(short, long)]
+  pub rom: path::PathBuf,
+  #[arg(short = 'd', long)]
+  pub patch_dir: path::PathBuf,
+  /// Don't read or update the cache of previously computed checksums.
+  #[arg(long)]
+  pub no_cache: bool,
+  /// An index written by `manifest scan`, used to recognize a ROM that was
+  /// already patched.
+  #[arg(long)]
+  pub index: Option<path::PathBuf>,
+  /// Also apply each patch that might apply to a copy of the ROM, without
+  /// writing the result, to find out whether it applies without errors. The
+  /// ROM is read into memory once and the patches are tried in parallel.
+  #[arg(long)]
+  pub try_apply: bool,
+}
+
+impl Args {
+  pub fn call(self) -> Result<(), Error> {
+    // Patches are matched against the ROM's name, so it must have one.
+    let Some(rom_name) = self.rom.file_name() else {
+      return Err(Error::NoFileName { path: self.rom.clone() });
+    };
+    let rom_name = rom_name.to_string_lossy();
+    let mut digest_cache = (!self.no_cache)
+      .then(|| DigestCache::load(cache::path()))
+      .transpose()?;
+    let (source, rom_size, rom_digest) = match self.try_apply {
+      true => {
+        let source = SourceCache::load(digest_cache.as_mut(), &self.rom)?;
+        let (rom_size, rom_digest) = (source.len(), source.digest());
+        (Some(source), rom_size, rom_digest)
+      }
+      false => {
+        let mut rom = fs::File::open(&self.rom)?;
+        let rom_size: u64 = rom.seek(io::SeekFrom::End(0))?;
+        let rom_digest = cache::read_and_hash(digest_cache.as_mut(), &mut rom)?;
+        (None, rom_size, rom_digest)
+      }
+    };
+    if let Some(digest_cache) = &digest_cache {
+      digest_cache.save()?;
+    }
+
+    if let Some(index_path) = &self.index {
+      let index: manifest::index::Index = fs::read_to_string(index_path)?.parse()?;
+      for (entry, file) in index.find_result(rom_digest) {
+        let patches: Vec<&str> = file
+          .patches
+          .iter()
+          .map(|patch| patch.name.as_str())
+          .collect();
+        println!(
+          "{}",
+          i18n::format(
+            "romhacks::match::known_result",
+            &[
+              ("file", &file.name),
+              ("patches", &patches.join(", ")),
+              ("manifest", &entry.path.display()),
+            ]
+          )
+        );
+      }
+    }
+
+    let found = scan(&self.patch_dir)?;
+    let mut candidates: Vec<(&path::Path, patch::Kind)> = Vec::new();
+    for Found { path: patch_path, kind, header } in &found {
+      let kind = *kind;
+      let verdict = Verdict::new(header, &rom_name, rom_size, rom_digest);
+      match verdict {
+        Verdict::Applies | Verdict::NamesRom | Verdict::Unverifiable => {
+          let style = match verdict {
+            Verdict::Applies => Style::Ok,
+            _ => Style::Warning,
+          };
+          println!(
+            "{}: {} ({kind})",
+            Stream::Stdout.paint(style, verdict),
+            patch_path.display()
+          );
+          candidates.push((patch_path, kind));
+        }
+        Verdict::AlreadyApplied => {
+          log::debug!("{verdict}: {} ({kind})", patch_path.display())
+        }
+        Verdict::WrongInput => match header.source_crc32 {
+          Some(expected) => log::debug!(
+            "{verdict}: {} ({kind}, {})",
+            patch_path.display(),
+            Stream::Stderr.checksum_diff(expected, rom_digest)
+          ),
+          None => log::debug!("{verdict}: {} ({kind})", patch_path.display()),
+        },
+      }
+    }
+
+    if let Some(source) = &source {
+      let results: Vec<_> = candidates
+        .par_iter()
+        .map(|&(patch_path, kind)| try_apply(source, patch_path, kind))
+        .collect();
+      for ((patch_path, kind), result) in candidates.iter().zip(results) {
+        match result {
+          Ok(()) => println!(
+            "{}: {} ({kind})",
+            Stream::Stdout.paint(Style::Ok, i18n::text("romhacks::match::trial_applied")),
+            patch_path.display()
+          ),
+          Err(err) => println!(
+            "{}: {} ({kind}, {err})",
+            Stream::Stdout.paint(Style::Failure, i18n::text("romhacks::match::trial_failed")),
+            patch_path.display()
+          ),
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+/// A patch found by [`scan`] and its header.
+#[derive(Clone, Debug)]
+pub struct Found {
+  pub path: path::PathBuf,
+  pub kind: patch::Kind,
+  pub header: Header,
+}
+
+/// Reads the header of each patch in `patch_dir`, in the order of their
+/// paths. Files that aren't patches are skipped, and so are patches whose
+/// headers can't be read, with a warning.
+pub fn scan(patch_dir: &path::Path) -> io::Result<Vec<Found>> {
+  let mut patch_paths = fs::read_dir(patch_dir)?
+    .map(|entry| entry.map(|entry| entry.path()))
+    .collect::<Result<Vec<path::PathBuf>, io::Error>>()?;
+  patch_paths.sort();
+
+  let mut found = Vec::new();
+  for patch_path in patch_paths.into_iter().filter(|path| path.is_file()) {
+    let mut patch = match patch::compression::open(&patch_path) {
+      Ok(patch) => patch,
+      Err(patch::Error::Compression(patch::compression::Error::IO(err))) => return Err(err),
+      Err(err) => {
+        log::warn!(
+          "{}",
+          i18n::format(
+            "romhacks::match::skipped",
+            &[("path", &patch_path.display()), ("error", &err)]
+          )
+        );
+        continue;
+      }
+    };
+    let Some(kind) = patch::Kind::detect(&mut patch)? else {
+      continue;
+    };
+    match patch::header::read(kind, &mut patch) {
+      Ok(header) => found.push(Found { path: patch_path, kind, header }),
+      Err(err) => {
+        log::warn!(
+          "{}",
+          i18n::format(
+            "romhacks::match::skipped",
+            &[("path", &patch_path.display()), ("error", &err)]
+          )
+        );
+      }
+    }
+  }
+  Ok(found)
+}
+
+/// Applies a patch to a copy of the ROM in `source` and discards the result.
+fn try_apply(
+  source: &Arc<SourceCache>,
+  patch_path: &path::Path,
+  kind: patch::Kind,
+) -> Result<(), patch::job::Error> {
+  let mut patch = patch::compression::open(patch_path)?;
+  let mut rom = source.reader();
+  let mut output = io::SpooledTempBuffer::new(profile::get().spool_threshold, dirs::temp_dir());
+  patch::job::PatchJob::new()
+    .source(&mut rom)
+    .source_crc32(source.digest())
+    .patch(&mut patch)
+    .format(kind)
+    .output(&mut output)
+    .run()?;
+  Ok(())
+}
+
+/// Whether a patch can be applied to a ROM, as far as can be told from its header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Verdict {
+  /// The patch's expected source checksum matches the ROM.
+  Applies,
+  /// The patch's target checksum matches the ROM.
+  AlreadyApplied,
+  /// The patch's expected source size or checksum doesn't match the ROM.
+  WrongInput,
+  /// The patch has no checksums, but its application header names the ROM.
+  NamesRom,
+  /// The patch doesn't record anything about its source.
+  Unverifiable,
+}
+
+impl Verdict {
+  pub fn new(header: &Header, rom_name: &str, rom_size: u64, rom_digest: Crc32) -> Self {
+    if header.target_crc32 == Some(rom_digest) {
+      return Verdict::AlreadyApplied;
+    }
+    if header.source_size.is_some_and(|size| size != rom_size) {
+      return Verdict::WrongInput;
+    }
+    match header.source_crc32 {
+      Some(crc32) if crc32 == rom_digest => Verdict::Applies,
+      Some(_) => Verdict::WrongInput,
+      None => match &header.app_header {
+        Some(app_header) if app_header.split('/').any(|name| name == rom_name) => Verdict::NamesRom,
+        _ => Verdict::Unverifiable,
+      },
+    }
+  }
+}
+
+impl fmt::Display for Verdict {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(i18n::text(match self {
+      Verdict::Applies => "romhacks::match::applies",
+      Verdict::AlreadyApplied => "romhacks::match::already_applied",
+      Verdict::WrongInput => "romhacks::match::wrong_input",
+      Verdict::NamesRom => "romhacks::match::names_rom",
+      Verdict::Unverifiable => "romhacks::match::unverifiable",
+    }))
+  }
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Index(#[from] manifest::index::ParseError),
+  #[error("{}", i18n::format("romhacks::lookup::no_file_name", &[("path", &path.display())]))]
+  #[diagnostic(code(romhacks::lookup::no_file_name))]
+  NoFileName { path: path::PathBuf },
+}
@@ -4,31 +4,117 @@ use crate::error::prelude::*;
 use std::process;
 
 mod apply;
+mod audit;
+mod blockmap;
+mod buffers;
+mod cache;
+mod chd;
+mod clean;
 mod cli;
+mod compare;
+mod console;
 mod convert;
 mod crc;
+mod create;
+mod csv;
+mod cue;
+mod dat;
+mod dirs;
+mod disc;
+mod doctor;
 mod error;
+mod explain;
 mod filename;
+mod fingerprint;
+mod fs;
+mod genpatch;
 mod hack;
+mod hooks;
+mod i18n;
+mod identify;
+mod info;
 mod io;
+mod json;
 mod kdl;
+mod launch;
+mod lint;
 mod log;
+mod lookup;
 mod manifest;
 mod mem;
+mod metadata;
+mod parts;
 mod patch;
+mod precheck;
+mod preview;
+mod profile;
+mod rebase;
+mod render;
+mod report;
+mod sandbox;
+mod signature;
+mod source;
+mod split;
+mod temp;
+mod trim;
+mod unpack;
+mod upgrade;
 mod validate;
+mod zip;
 
-fn main() -> miette::Result<()> {
+fn main() -> process::ExitCode {
   use cli::CommandKind::*;
 
+  let args: cli::Args = match clap::Parser::try_parse() {
+    Ok(args) => args,
+    // Help and the version aren't errors, and clap prints them itself.
+    Err(err) if !err.use_stderr() => err.exit(),
+    Err(err) => return fail(Error::from(err)),
+  };
+  render::init(args.color);
+  profile::init(profile::Profile::from(&args.profile));
+  dirs::init(args.dirs.clone());
+  patch::options::init(patch::options::FormatOptions::from(&args.format_options));
+  io::init_retry_policy(io::RetryPolicy::from(&args.retry));
   log::init();
-  let args: cli::Args = clap::Parser::try_parse().map_err(|err| Error::from(err))?;
-  match args.command {
-    Apply(args) => args.call().map_err(|err| Error::from(err).into()),
-    Validate(args) => args.call().map_err(|err| Error::ValidateError(err).into()),
+  #[cfg(feature = "plugins")]
+  patch::plugin::init();
+  let result = match args.command {
+    Apply(args) => args.call().map_err(Error::from),
+    Blockmap(args) => args.call().map_err(Error::from),
+    Clean(args) => args.call().map_err(Error::from),
+    Compare(args) => args.call().map_err(Error::from),
+    Create(args) => args.call().map_err(Error::from),
+    Doctor(args) => args.call().map_err(Error::from),
+    Explain(args) => args.call().map_err(Error::from),
+    Genpatch(args) => args.call().map_err(Error::from),
+    Identify(args) => args.call().map_err(Error::from),
+    Info(args) => args.call().map_err(Error::from),
+    Launch(args) => args.call().map_err(Error::from),
+    Manifest(args) => args.call().map_err(Error::from),
+    Match(args) => args.call().map_err(Error::from),
+    Precheck(args) => args.call().map_err(Error::from),
+    Preview(args) => args.call().map_err(Error::from),
+    Rebase(args) => args.call().map_err(Error::from),
+    Report(args) => args.call().map_err(Error::from),
+    Split(args) => args.call().map_err(Error::from),
+    Unpack(args) => args.call().map_err(Error::from),
+    Upgrade(args) => args.call().map_err(Error::from),
+    Validate(args) => args.call().map_err(Error::from),
+  };
+  match result {
+    Ok(()) => process::ExitCode::SUCCESS,
+    Err(err) => fail(err),
   }
 }
 
+/// Prints `err` and returns the status to exit with.
+fn fail(err: Error) -> process::ExitCode {
+  let status = err.status();
+  eprintln!("Error: {:?}", miette::Report::new(err));
+  process::ExitCode::from(status)
+}
+
 #[non_exhaustive]
 #[derive(Debug, Error, Diagnostic)]
 enum Error {
@@ -39,22 +125,183 @@ enum Error {
   ApplyPatchError(#[from] apply::Error),
   #[error(transparent)]
   #[diagnostic(transparent)]
-  ValidateError(#[from] kdl_schema_check::CheckFailure),
+  BlockmapError(#[from] blockmap::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  CleanError(#[from] clean::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  CompareError(#[from] compare::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  CreateError(#[from] create::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  DoctorError(#[from] doctor::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  ExplainError(#[from] explain::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  GenpatchError(#[from] genpatch::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  IdentifyError(#[from] identify::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  InfoError(#[from] info::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  LaunchError(#[from] launch::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  ManifestError(#[from] manifest::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  MatchError(#[from] lookup::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  PrecheckError(#[from] precheck::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  PreviewError(#[from] preview::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  RebaseError(#[from] rebase::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  ReportError(#[from] report::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  SplitError(#[from] split::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  UnpackError(#[from] unpack::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  UpgradeError(#[from] upgrade::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  ValidateError(#[from] validate::Error),
+}
+
+/// The exit statuses of errors. Each one means the same thing for every
+/// command, so that scripts can tell failures apart. They stay below 64,
+/// where the statuses `identify` exits with start.
+mod status {
+  pub const BAD_ARGUMENT: u8 = 1;
+  pub const IO: u8 = 2;
+  pub const BAD_MANIFEST: u8 = 3;
+  pub const ALREADY_PATCHED: u8 = 4;
+  pub const MANIFEST_OUTDATED: u8 = 5;
+  pub const PATCHING: u8 = 6;
+  pub const SOURCE_MODIFIED: u8 = 7;
+  pub const BAD_SIGNATURE: u8 = 8;
+  pub const HOOK_FAILED: u8 = 9;
+  pub const OUTPUT_CORRUPTED: u8 = 10;
+  pub const PROBLEMS_FOUND: u8 = 11;
+  pub const BLOCK_MAP_MISMATCH: u8 = 12;
+  pub const FILES_DIFFER: u8 = 13;
+  pub const MANIFEST_DRIFT: u8 = 14;
+}
+
+impl Error {
+  /// The status the program exits with when it fails with this error.
+  fn status(&self) -> u8 {
+    use status::*;
+    match self {
+      Error::CliError(_) => BAD_ARGUMENT,
+      Error::ApplyPatchError(err) => apply_status(err.get_kind()),
+      Error::BlockmapError(err) => match err {
+        blockmap::Error::Mismatch(_) => BLOCK_MAP_MISMATCH,
+        _ => IO,
+      },
+      Error::CleanError(_) => IO,
+      Error::CompareError(err) => match err {
+        compare::Error::IO(_) => IO,
+        _ => FILES_DIFFER,
+      },
+      Error::CreateError(err) => match err {
+        create::Error::IO(_) => IO,
+        _ => PATCHING,
+      },
+      Error::DoctorError(err) => match err {
+        doctor::Error::ProblemsFound { .. } => PROBLEMS_FOUND,
+        _ => IO,
+      },
+      Error::ExplainError(err) => match err {
+        explain::Error::UnknownFormat { .. } => BAD_ARGUMENT,
+        _ => IO,
+      },
+      Error::GenpatchError(err) => match err {
+        genpatch::Error::IO(_) | genpatch::Error::Create(create::Error::IO(_)) => IO,
+        _ => PATCHING,
+      },
+      Error::IdentifyError(_) => IO,
+      Error::InfoError(_) => IO,
+      Error::LaunchError(err) => match err {
+        launch::Error::IO(_) | launch::Error::Manifest(manifest::Error::IO(_)) => IO,
+        _ => BAD_MANIFEST,
+      },
+      Error::ManifestError(err) => match err {
+        manifest::Error::IO(_) => IO,
+        manifest::Error::Drift { .. } => MANIFEST_DRIFT,
+        _ => BAD_MANIFEST,
+      },
+      Error::MatchError(err) => match err {
+        lookup::Error::NoFileName { .. } => BAD_ARGUMENT,
+        _ => IO,
+      },
+      Error::PrecheckError(err) => match err {
+        precheck::Error::Patch(patch::Error::IO(_))
+        | precheck::Error::Patch(patch::Error::Compression(patch::compression::Error::IO(_)))
+        | precheck::Error::IO(_) => IO,
+        _ => PATCHING,
+      },
+      Error::PreviewError(err) => match err {
+        preview::Error::IO(_)
+        | preview::Error::Patch(patch::Error::Compression(patch::compression::Error::IO(_))) => IO,
+        _ => PATCHING,
+      },
+      Error::RebaseError(err) => match err {
+        rebase::Error::IO(_)
+        | rebase::Error::Patch(patch::Error::Compression(patch::compression::Error::IO(_))) => IO,
+        _ => PATCHING,
+      },
+      Error::ReportError(err) => match err {
+        report::Error::IO(_) => IO,
+        _ => BAD_MANIFEST,
+      },
+      Error::SplitError(_) => IO,
+      Error::UnpackError(err) => match err {
+        unpack::Error::IO(_) | unpack::Error::Zip(zip::Error::IO(_)) => IO,
+        unpack::Error::Exists { .. } => BAD_ARGUMENT,
+        _ => BAD_MANIFEST,
+      },
+      Error::UpgradeError(err) => match err {
+        upgrade::Error::Manifest(manifest::Error::IO(_)) => IO,
+        upgrade::Error::Manifest(_) => BAD_MANIFEST,
+        upgrade::Error::Apply(err) => apply_status(err.get_kind()),
+        upgrade::Error::NotNewer { .. } => ALREADY_PATCHED,
+      },
+      Error::ValidateError(_) => IO,
+    }
+  }
 }
 
-impl process::Termination for Error {
-  fn report(self) -> process::ExitCode {
-    use apply::ErrorKind as K;
-    process::ExitCode::from(match self {
-      Error::CliError(_) => 1,
-      Error::ApplyPatchError(err) => match err.get_kind() {
-        K::IOError => 2,
-        K::BadManifest => 3,
-        K::AlreadyPatched => 4,
-        K::ManifestOutdated => 5,
-        K::Patching => 6,
-      },
-      Error::ValidateError(_) => 2,
-    })
+fn apply_status(kind: apply::ErrorKind) -> u8 {
+  use apply::ErrorKind as K;
+  use status::*;
+  match kind {
+    K::IOError => IO,
+    K::BadManifest => BAD_MANIFEST,
+    K::AlreadyPatched => ALREADY_PATCHED,
+    K::ManifestOutdated => MANIFEST_OUTDATED,
+    K::Patching => PATCHING,
+    K::BadArgument => BAD_ARGUMENT,
+    K::SourceModified => SOURCE_MODIFIED,
+    K::BadSignature => BAD_SIGNATURE,
+    K::HookFailed => HOOK_FAILED,
+    K::OutputCorrupted => OUTPUT_CORRUPTED,
   }
 }
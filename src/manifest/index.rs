@@ -0,0 +1,167 @@
+//! An index of every manifest under a directory, so that other commands can
+//! look up patched files without searching for manifests again.
+
+use super::model::{File, Manifest, ModelError};
+use super::{SCHEMA, VERSION};
+use crate::crc::Crc32;
+use crate::error::prelude::*;
+use crate::{fs, i18n, io, kdl, mem};
+use std::str::FromStr;
+use std::{fmt, path};
+
+/// The suffix of manifest file names.
+pub const MANIFEST_SUFFIX: &str = ".romhacks.kdl";
+
+/// The default name of an index file. It doesn't end with [`MANIFEST_SUFFIX`],
+/// so scanning a directory never picks up its own index.
+pub const DEFAULT_FILE_NAME: &str = "romhacks-index.kdl";
+
+/// The version of the index layout this program writes. Version 2.0 holds
+/// files in the layout of [`MANIFEST_VERSION`](super::MANIFEST_VERSION) 2.0.
+/// Indexes of version 1.0 are still read.
+const INDEX_VERSION: &str = "2.0";
+
+// nodes
+const ROMHACKS_INDEX: &str = "romhacks-index";
+const MANIFEST: &str = "manifest";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Index {
+  pub entries: Vec<Entry>,
+}
+
+/// A manifest and where it was found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+  pub path: path::PathBuf,
+  pub manifest: Manifest,
+}
+
+impl Index {
+  /// Reads every manifest under `dir`, in order of their paths. Manifests
+  /// that can't be read or don't match the schema are skipped with a warning.
+  pub fn scan(dir: &path::Path) -> io::Result<Self> {
+    let mut paths = Vec::new();
+    find_manifests(dir, &mut paths)?;
+    paths.sort();
+    let schema = kdl::Schema::parse(SCHEMA).unwrap();
+    let entries = (paths.into_iter())
+      .filter_map(|path| {
+        let read = || -> Result<Manifest, ScanError> {
+          let text = fs::read_to_string(&path)?;
+          kdl::check_all(&schema, &path.to_string_lossy(), &text)?;
+          Ok(Manifest::from_kdl(
+            &kdl::KdlDocument::from_str(&text).unwrap(),
+          )?)
+        };
+        match read() {
+          Ok(manifest) => Some(Entry { path, manifest }),
+          Err(err) => {
+            log::warn!(
+              "{}",
+              i18n::format(
+                "romhacks::manifest::scan_skipped",
+                &[("path", &path.display()), ("error", &err)]
+              )
+            );
+            None
+          }
+        }
+      })
+      .collect();
+    Ok(Self { entries })
+  }
+
+  /// Finds the files with a recorded result that has the checksum `crc32`.
+  pub fn find_result(&self, crc32: Crc32) -> impl Iterator<Item = (&Entry, &File)> {
+    (self.entries.iter())
+      .flat_map(|entry| entry.manifest.files.iter().map(move |file| (entry, file)))
+      .filter(move |(_, file)| file.has_result(crc32))
+  }
+}
+
+fn find_manifests(dir: &path::Path, paths: &mut Vec<path::PathBuf>) -> io::Result<()> {
+  for entry in fs::read_dir(dir)? {
+    let entry = entry?;
+    let path = entry.path();
+    if entry.file_type()?.is_dir() {
+      find_manifests(&path, paths)?;
+    } else if (path.file_name())
+      .is_some_and(|name| name.to_string_lossy().ends_with(MANIFEST_SUFFIX))
+    {
+      paths.push(path);
+    }
+  }
+  Ok(())
+}
+
+impl fmt::Display for Index {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let mut doc = kdl::KdlDocument::new();
+    let nodes = doc.nodes_mut();
+    nodes.push(mem::init(kdl::KdlNode::new(ROMHACKS_INDEX), |node| {
+      node.insert(VERSION, INDEX_VERSION);
+    }));
+    for entry in &self.entries {
+      nodes.push(mem::init(kdl::KdlNode::new(MANIFEST), |node| {
+        node.insert(0, entry.path.to_string_lossy().into_owned());
+        node.insert(VERSION, entry.manifest.version.as_str());
+        let children = node.ensure_children().nodes_mut();
+        children.extend(entry.manifest.files.iter().map(File::to_kdl));
+      }));
+    }
+    doc.autoformat();
+    write!(f, "{doc}")
+  }
+}
+
+impl FromStr for Index {
+  type Err = ParseError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let doc: kdl::KdlDocument = s.parse()?;
+    if doc.get(ROMHACKS_INDEX).is_none() {
+      return Err(ModelError::Malformed.into());
+    }
+    let entries = (doc.nodes().iter())
+      .filter(|node| node.name().value() == MANIFEST)
+      .map(|node| {
+        let string = |key: kdl::NodeKey| {
+          (node.get(key).and_then(|value| value.as_string())).ok_or(ModelError::Malformed)
+        };
+        let files: &[kdl::KdlNode] = node.children().map_or(&[], |children| children.nodes());
+        Ok(Entry {
+          path: string(0.into())?.into(),
+          manifest: Manifest {
+            version: string(VERSION.into())?.to_owned(),
+            files: (files.iter())
+              .map(File::from_kdl)
+              .collect::<Result<_, ModelError>>()?,
+          },
+        })
+      })
+      .collect::<Result<_, ParseError>>()?;
+    Ok(Self { entries })
+  }
+}
+
+/// Why a manifest was left out of an index.
+#[derive(Debug, Error, Diagnostic)]
+enum ScanError {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error(transparent)]
+  Kdl(#[from] kdl::CheckFailures),
+  #[error(transparent)]
+  Model(#[from] ModelError),
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum ParseError {
+  #[error(transparent)]
+  Kdl(#[from] kdl::KdlError),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Model(#[from] ModelError),
+}
@@ -0,0 +1,539 @@
+use crate::error::prelude::*;
+use crate::{crc, filename, fs, hack, i18n, info, io, json, kdl, mem, signature};
+use std::borrow::Cow;
+use std::str::FromStr;
+use std::{ffi, path};
+
+pub mod index;
+pub mod model;
+pub mod verify;
+
+pub const SCHEMA: &str = include_str!("romhacks.schema.kdl");
+
+/// The version of the manifest layout this program writes. Version 2.0 added
+/// the names of patched files and several results per patch, superseded
+/// results, signatures and checked hack versions. Manifests of version 1.0
+/// are still read, and are rewritten as 2.0 when they're next updated,
+/// which only adds to them.
+pub const MANIFEST_VERSION: &str = "2.0";
+
+// nodes
+const ROMHACKS_MANIFEST: &str = "romhacks-manifest";
+const FILE: &str = "file";
+const PATCH: &str = "patch";
+const RESULT: &str = "result";
+const HACK: &str = "hack";
+const SIGNATURE: &str = "signature";
+const SUPERSEDED: &str = "superseded";
+
+// props
+const URL: &str = "url";
+const CRC_32: &str = "crc32";
+const VERSION: &str = "version";
+const KEY_ID: &str = "key-id";
+const COMMENT: &str = "comment";
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct Args {
+  #[command(subcommand)]
+  pub command: Command,
+}
+
+#[derive(Clone, Debug, clap::Subcommand)]
+pub enum Command {
+  /// Convert a manifest to JSON or CSV.
+  Export {
+    manifest: path::PathBuf,
+    #[arg(short, long, value_enum)]
+    format: ExchangeFormat,
+    /// Where to write the converted manifest, or "-" for standard output.
+    #[arg(short, long, default_value = io::STDIO)]
+    output: path::PathBuf,
+  },
+  /// Convert a manifest exported as JSON or CSV back to KDL.
+  Import {
+    file: path::PathBuf,
+    #[arg(short, long, value_enum)]
+    format: ExchangeFormat,
+    /// Where to write the manifest.
+    #[arg(short, long)]
+    output: path::PathBuf,
+  },
+  /// Report on every manifest under a directory.
+  Scan {
+    dir: path::PathBuf,
+    /// Also write an index of the manifests, which `match --index` can use
+    /// to recognize patched files.
+    #[arg(long, num_args = 0..=1, default_missing_value = index::DEFAULT_FILE_NAME)]
+    index: Option<path::PathBuf>,
+  },
+  /// Check that the ROMs and patched files a manifest records still have
+  /// the recorded checksums. Files aren't changed.
+  Verify {
+    manifest: path::PathBuf,
+    /// The directory the ROMs are in. By default, they're looked for next to
+    /// the manifest, like the patched files.
+    #[arg(long)]
+    rom_dir: Option<path::PathBuf>,
+    /// Check patched files that have a block map, as written by
+    /// `apply --blockmap`, by hashing only the chunks the patch changed and
+    /// this percentage of the others. Matching files are listed as probable
+    /// matches, since the rest of them wasn't hashed.
+    #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(0..=100))]
+    sample: Option<u8>,
+  },
+}
+
+/// The formats manifests can be exported to and imported from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, clap::ValueEnum)]
+pub enum ExchangeFormat {
+  Json,
+  Csv,
+}
+
+impl Args {
+  pub fn call(self) -> Result<(), Error> {
+    match self.command {
+      Command::Export { manifest, format, output } => {
+        let model = read(&manifest)?;
+        let exported = match format {
+          ExchangeFormat::Json => format!("{}\n", model.to_json()),
+          ExchangeFormat::Csv => model.to_csv(),
+        };
+        io::write_file_or_stdout(&output, exported.as_bytes())?;
+      }
+      Command::Import { file, format, output } => {
+        let text = fs::read_to_string(&file)?;
+        let model = match format {
+          ExchangeFormat::Json => model::Manifest::from_json(
+            &text
+              .parse::<json::Value>()
+              .map_err(model::ModelError::from)?,
+          )?,
+          ExchangeFormat::Csv => model::Manifest::from_csv(&text)?,
+        };
+        let imported = model.to_kdl().to_string();
+        kdl::check_all(
+          &kdl::Schema::parse(SCHEMA).unwrap(),
+          &output.to_string_lossy(),
+          &imported,
+        )?;
+        fs::write(&output, imported)?;
+      }
+      Command::Scan { dir, index } => {
+        let scanned = index::Index::scan(&dir)?;
+        info::print_table(&scan_table(&scanned));
+        if let Some(index_path) = index {
+          fs::write(index_path, scanned.to_string())?;
+        }
+      }
+      Command::Verify { manifest, rom_dir, sample } => {
+        let model = read(&manifest)?;
+        let output_dir = manifest.parent().unwrap_or(path::Path::new(""));
+        let rom_dir = rom_dir.as_deref().unwrap_or(output_dir);
+        let entries = verify::verify(&model, rom_dir, output_dir, sample);
+        info::print_table(&verify::table(&entries));
+        let count = entries
+          .iter()
+          .filter(|entry| entry.status.is_drift())
+          .count();
+        if count > 0 {
+          return Err(Error::Drift { count });
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Lists every applied patch in the index, one per row.
+fn scan_table(index: &index::Index) -> Vec<Vec<String>> {
+  let header = [
+    "romhacks::manifest::scan_manifest",
+    "romhacks::manifest::scan_file",
+    "romhacks::manifest::scan_patch",
+    "romhacks::manifest::scan_hack",
+    "romhacks::manifest::scan_version",
+    "romhacks::manifest::scan_result",
+  ];
+  let rows = (index.entries.iter()).flat_map(|entry| {
+    (entry.manifest.files.iter()).flat_map(move |file| {
+      (file.patches.iter()).map(move |patch| {
+        vec![
+          entry.path.display().to_string(),
+          file.name.clone(),
+          patch.name.clone(),
+          patch.hack_url.clone(),
+          patch.hack_version.to_string(),
+          format!("{:08X}", patch.result.value()),
+        ]
+      })
+    })
+  });
+  std::iter::once(header.map(|key| i18n::text(key).to_owned()).to_vec())
+    .chain(rows)
+    .collect()
+}
+
+/// The path of the manifest that records the patches applied to a ROM,
+/// which is shared by every file of the same game. It's in `output_dir`, the
+/// directory the patched files are written to.
+pub fn path_for(rom_path: &path::Path, output_dir: &path::Path) -> path::PathBuf {
+  let mut buf = ffi::OsString::from(filename::infer_game_name(rom_path));
+  buf.push(" (patched)");
+  buf.push(index::MANIFEST_SUFFIX);
+  output_dir.join(buf)
+}
+
+/// Reads a manifest and checks it against the schema.
+pub fn read(manifest_path: &path::Path) -> Result<model::Manifest, Error> {
+  let text = fs::read_to_string(manifest_path)?;
+  kdl::check_all(
+    &kdl::Schema::parse(SCHEMA).unwrap(),
+    &manifest_path.to_string_lossy(),
+    &text,
+  )?;
+  Ok(model::Manifest::from_kdl(
+    &kdl::KdlDocument::from_str(&text).unwrap(),
+  )?)
+}
+
+/// Finds the name of the file that the patch with the checksum `patch_digest`
+/// was applied to in order to write a file with the checksum
+/// `patched_digest`, in the manifests directly inside `dir`. Manifests that
+/// can't be read are skipped.
+pub fn find_source(
+  dir: &path::Path,
+  patched_digest: crc::Crc32,
+  patch_digest: crc::Crc32,
+) -> io::Result<Option<String>> {
+  for entry in fs::read_dir(dir)? {
+    let path = entry?.path();
+    if !(path.file_name())
+      .is_some_and(|name| name.to_string_lossy().ends_with(index::MANIFEST_SUFFIX))
+    {
+      continue;
+    }
+    let Ok(manifest) = read(&path) else {
+      continue;
+    };
+    let source = (manifest.files.into_iter()).find(|file| {
+      (file.patches.iter())
+        .any(|patch| patch.crc32 == patch_digest && patch.result == patched_digest)
+    });
+    if let Some(file) = source {
+      return Ok(Some(file.name));
+    }
+  }
+  Ok(None)
+}
+
+/// Reads the manifest for a ROM, or creates one, and checks that the patch
+/// can be applied to the ROM to write the patched file named `output_name`.
+///
+/// If `upgrade_url` is given and the last patch recorded for the ROM is a
+/// version of that hack applied to this same file, the patch is removed so
+/// that the newer version can be recorded in its place.
+///
+/// If `reset_outdated` is `true` and the ROM isn't a file the manifest
+/// records, its patches are kept under a `superseded` node and the ROM's
+/// current checksum is recorded, rather than failing with
+/// [`GetOrCreateError::ManifestOutdated`].
+pub fn get_or_create(
+  manifest_path: &impl AsRef<path::Path>,
+  rom_path: &impl AsRef<path::Path>,
+  rom_digest: crc::Crc32,
+  patch_digest: crc::Crc32,
+  output_name: &str,
+  upgrade_url: Option<&str>,
+  reset_outdated: bool,
+) -> Result<kdl::KdlDocument, GetOrCreateError> {
+  monomorphic_get_or_create(
+    manifest_path.as_ref(),
+    rom_path.as_ref(),
+    rom_digest,
+    patch_digest,
+    output_name,
+    upgrade_url,
+    reset_outdated,
+  )
+}
+
+fn monomorphic_get_or_create(
+  manifest_path: &path::Path,
+  rom_path: &path::Path,
+  rom_digest: crc::Crc32,
+  patch_digest: crc::Crc32,
+  output_name: &str,
+  upgrade_url: Option<&str>,
+  reset_outdated: bool,
+) -> Result<kdl::KdlDocument, GetOrCreateError> {
+  let str = match fs::read_to_string(manifest_path) {
+    Ok(str) => str,
+    Err(err) => {
+      return if err.kind() == io::ErrorKind::NotFound {
+        log::info!(
+          "{}",
+          i18n::format(
+            "romhacks::manifest::created",
+            &[("path", &manifest_path.display())]
+          )
+        );
+        Ok(create())
+      } else {
+        Err(err.into())
+      };
+    }
+  };
+
+  kdl::check_all(
+    &kdl::Schema::parse(SCHEMA).unwrap(),
+    &manifest_path.to_string_lossy(),
+    &str,
+  )?;
+
+  let mut manifest = mem::init(kdl::KdlDocument::from_str(&str).unwrap(), |doc| {
+    doc.nodes_mut().sort_by(|a, b| {
+      fn ord(node: &kdl::KdlNode) -> i32 {
+        (node.name().value() != ROMHACKS_MANIFEST) as i32
+      }
+      ord(a).cmp(&ord(b))
+    });
+    // The entry about to be added may use anything the current layout has.
+    doc.nodes_mut()[0].insert(VERSION, MANIFEST_VERSION);
+  });
+
+  let file_name: Cow<'_, str> = rom_path.file_name().unwrap().to_string_lossy();
+  let existing_file_node: Option<&mut kdl::KdlNode> =
+    manifest.nodes_mut()[1..]
+      .iter_mut()
+      .find(|node: &&mut kdl::KdlNode| {
+        let node_id = kdl::NodeId::new(FILE, (0, file_name.as_ref()));
+        node_id == **node
+      });
+  let existing_file_node: &mut kdl::KdlNode = match existing_file_node {
+    Some(node) => node,
+    None => return Ok(manifest),
+  };
+
+  if let Some(url) = upgrade_url {
+    if remove_upgraded_patch(existing_file_node, rom_digest, url) {
+      return Ok(manifest);
+    }
+  }
+  match validate_file(existing_file_node, rom_digest, patch_digest, output_name) {
+    Err(GetOrCreateError::ManifestOutdated) if reset_outdated => {
+      log::warn!(
+        "{}",
+        i18n::format(
+          "romhacks::manifest::superseded",
+          &[("file", &file_name), ("path", &manifest_path.display())]
+        )
+      );
+      supersede(existing_file_node, rom_digest);
+    }
+    result => result?,
+  }
+
+  Ok(manifest)
+}
+
+#[rustfmt::skip]
+fn create() -> kdl::KdlDocument {
+  mem::init(kdl::KdlDocument::new(), |doc| {
+    doc.nodes_mut().push(mem::init(kdl::KdlNode::new(ROMHACKS_MANIFEST), |node| {
+      node.insert(VERSION, MANIFEST_VERSION);
+    }));
+  })
+}
+
+/// Checks that the file is the ROM the manifest's patches started from or
+/// one of their results, and that the patch hasn't already been applied to
+/// write a file with the same name. The same ROM can be patched with several
+/// hacks, or with one hack and different options, to write several files.
+fn validate_file(
+  file_node: &kdl::KdlNode,
+  file_crc32: crc::Crc32,
+  patch_crc32: crc::Crc32,
+  output_name: &str,
+) -> Result<(), GetOrCreateError> {
+  let patches: &[kdl::KdlNode] = kdl::unwrap_children(file_node);
+  let is_known = get_crc32(file_node) == Some(file_crc32)
+    || (patches.iter())
+      .flat_map(results)
+      .any(|result| get_crc32(result) == Some(file_crc32));
+  if !is_known {
+    Err(GetOrCreateError::ManifestOutdated)?;
+  }
+  let patch_id = kdl::NodeId::new(PATCH, (CRC_32, patch_crc32));
+  if let Some(patch) = patches.iter().find(|patch| patch_id == **patch) {
+    // Results recorded before outputs were named could be for any file.
+    if (results(patch)).any(|result| output_of(result).is_none_or(|name| name == output_name)) {
+      Err(GetOrCreateError::AlreadyPatched)?;
+    }
+  }
+  Ok(())
+}
+
+/// Removes the last patch applied to a file that's a version of the hack at
+/// `url`, if `file_crc32` is the checksum of the file it was applied to:
+/// the file itself, or a result of the patch before it.
+fn remove_upgraded_patch(file_node: &mut kdl::KdlNode, file_crc32: crc::Crc32, url: &str) -> bool {
+  let patches: &[kdl::KdlNode] = kdl::unwrap_children(file_node);
+  let hack_url = |patch: &kdl::KdlNode| {
+    (child(patch, HACK))
+      .and_then(|hack| hack.get(URL))
+      .and_then(|value| value.as_string())
+      .map(str::to_owned)
+  };
+  let Some(index) = patches
+    .iter()
+    .rposition(|patch| hack_url(patch).as_deref() == Some(url))
+  else {
+    return false;
+  };
+  let applied_to_file = get_crc32(file_node) == Some(file_crc32)
+    || (index.checked_sub(1))
+      .is_some_and(|i| results(&patches[i]).any(|result| get_crc32(result) == Some(file_crc32)));
+  if !applied_to_file {
+    return false;
+  }
+  file_node
+    .children_mut()
+    .as_mut()
+    .unwrap()
+    .nodes_mut()
+    .remove(index);
+  true
+}
+
+/// Moves the patches recorded for a file under a `superseded` node that
+/// keeps the file's old checksum, and records `file_crc32` as its checksum,
+/// so that patches are recorded from the file as it is now.
+fn supersede(file_node: &mut kdl::KdlNode, file_crc32: crc::Crc32) {
+  let old_crc32 = get_crc32(file_node);
+  let children = file_node.ensure_children().nodes_mut();
+  let (patches, mut kept): (Vec<kdl::KdlNode>, Vec<kdl::KdlNode>) =
+    (children.drain(..)).partition(|node| node.name().value() == PATCH);
+  kept.push(mem::init(kdl::KdlNode::new(SUPERSEDED), |node| {
+    if let Some(old_crc32) = old_crc32 {
+      node.insert(CRC_32, old_crc32);
+    }
+    node.ensure_children().nodes_mut().extend(patches);
+  }));
+  *children = kept;
+  file_node.insert(CRC_32, file_crc32);
+}
+
+fn child<'a>(node: &'a kdl::KdlNode, name: &str) -> Option<&'a kdl::KdlNode> {
+  (kdl::unwrap_children(node).iter()).find(|node| node.name().value() == name)
+}
+
+/// The `result` nodes of a patch, one for each file it was applied to write.
+fn results(patch: &kdl::KdlNode) -> impl Iterator<Item = &kdl::KdlNode> {
+  (kdl::unwrap_children(patch).iter()).filter(|node| node.name().value() == RESULT)
+}
+
+/// The name of the patched file a `result` node is for, if it was recorded.
+fn output_of(result: &kdl::KdlNode) -> Option<&str> {
+  result.get(0).and_then(|value| value.as_string())
+}
+
+fn get_crc32(node: &kdl::KdlNode) -> Option<crc::Crc32> {
+  (node.get(CRC_32))
+    .and_then(|value| value.as_integer().map(|x: i128| x as u32))
+    .map(crc::Crc32::new)
+}
+
+/// Records that `patch` was applied to `rom` to write `patched`. If the patch
+/// was already applied to the ROM to write a file with another name, the
+/// result is added to it.
+pub fn update(
+  doc: &mut kdl::KdlDocument,
+  rom: &path::Path,
+  patch: &path::Path,
+  patched: &path::Path,
+  hack: hack::RomHack,
+  file_digest: crc::Crc32,
+  patch_digest: crc::Crc32,
+  patched_digest: crc::Crc32,
+  signature: Option<&signature::Verified>,
+) {
+  let result = mem::init(kdl::KdlNode::new(RESULT), |node| {
+    node.insert(
+      0,
+      patched.file_name().unwrap().to_string_lossy().into_owned(),
+    );
+    node.insert(CRC_32, patched_digest);
+  });
+  let file_nodes = doc.nodes_mut();
+  let patch_nodes = kdl::NodeId::new(
+    FILE,
+    (0, rom.file_name().unwrap().to_string_lossy().as_ref()),
+  )
+  .get_or_insert(file_nodes, |node| {
+    node.insert(CRC_32, file_digest);
+  })
+  .ensure_children()
+  .nodes_mut();
+  let patch_id = kdl::NodeId::new(PATCH, (CRC_32, patch_digest));
+  if let Some(patch_node) = patch_nodes.iter_mut().find(|node| patch_id == **node) {
+    let children = patch_node.ensure_children().nodes_mut();
+    let after_results = (children.iter())
+      .rposition(|node| node.name().value() == RESULT)
+      .map_or(children.len(), |i| i + 1);
+    children.insert(after_results, result);
+    return;
+  }
+  patch_nodes.push(mem::init(kdl::KdlNode::new(PATCH), |node| {
+    node.insert(0, patch.file_name().unwrap().to_string_lossy().into_owned());
+    node.insert(CRC_32, patch_digest);
+    let children = node.ensure_children().nodes_mut();
+    children.push(mem::init(kdl::KdlNode::new(HACK), |node| {
+      node.insert(URL, hack.url.as_str());
+      node.insert(VERSION, hack.version.as_str());
+    }));
+    children.push(result);
+    if let Some(signature) = signature {
+      children.push(mem::init(kdl::KdlNode::new(SIGNATURE), |node| {
+        node.insert(KEY_ID, signature.key_id.to_string());
+        node.insert(COMMENT, signature.trusted_comment.as_str());
+      }));
+    }
+  }));
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum GetOrCreateError {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Kdl(#[from] kdl::CheckFailures),
+  #[error("{}", i18n::text("romhacks::manifest::already_patched"))]
+  #[diagnostic(code(romhacks::manifest::already_patched))]
+  AlreadyPatched,
+  #[error("{}", i18n::text("romhacks::manifest::outdated"))]
+  #[diagnostic(
+    code(romhacks::manifest::outdated),
+    help("{}", i18n::text("romhacks::manifest::outdated::help"))
+  )]
+  ManifestOutdated,
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Kdl(#[from] kdl::CheckFailures),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Model(#[from] model::ModelError),
+  #[error("{}", i18n::format("romhacks::manifest::drift", &[("count", count)]))]
+  #[diagnostic(code(romhacks::manifest::drift))]
+  Drift { count: usize },
+}
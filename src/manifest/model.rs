@@ -0,0 +1,467 @@
+//! A typed view of a manifest, for exchanging its contents with other tools.
+//!
+//! Converting a manifest to JSON or CSV and back produces the same data, but
+//! comments and formatting in the KDL document aren't kept. Neither are
+//! patches under `superseded` nodes, which were applied to a file that's
+//! since been replaced.
+
+use super::{
+  COMMENT, CRC_32, FILE, HACK, KEY_ID, PATCH, RESULT, ROMHACKS_MANIFEST, SIGNATURE, URL, VERSION,
+};
+use crate::crc::Crc32;
+use crate::error::prelude::*;
+use crate::{csv, hack, i18n, json, kdl, mem};
+
+/// The column headers of a manifest exported as CSV. Each row is one applied patch.
+const CSV_HEADER: [&str; 10] = [
+  "file",
+  "file_crc32",
+  "patch",
+  "patch_crc32",
+  "hack_url",
+  "hack_version",
+  "result_crc32",
+  "signature_key_id",
+  "signature_comment",
+  "output",
+];
+
+/// The columns of manifests exported before the names of patched files were
+/// recorded, which can still be imported.
+const OLD_CSV_COLUMNS: usize = 9;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Manifest {
+  pub version: String,
+  pub files: Vec<File>,
+}
+
+/// A ROM and the patches that were applied to it, in order. A patch that was
+/// applied more than once to write files with different names is listed once
+/// for each of them, next to each other.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct File {
+  pub name: String,
+  pub crc32: Crc32,
+  pub patches: Vec<AppliedPatch>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AppliedPatch {
+  pub name: String,
+  pub crc32: Crc32,
+  pub hack_url: String,
+  pub hack_version: hack::RecordedVersion,
+  /// The checksum of the file after the patch was applied.
+  pub result: Crc32,
+  /// The name of the patched file. Manifests written before it was recorded
+  /// don't have it.
+  pub output: Option<String>,
+  /// The signature the patch was verified with, if any.
+  pub signature: Option<Signature>,
+}
+
+/// A minisign signature that a patch was verified with before it was applied.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Signature {
+  pub key_id: String,
+  pub comment: String,
+}
+
+impl Manifest {
+  /// Reads a manifest that matches the schema.
+  pub fn from_kdl(doc: &kdl::KdlDocument) -> Result<Self, ModelError> {
+    let header = doc.get(ROMHACKS_MANIFEST).ok_or(ModelError::Malformed)?;
+    let version = get_str(header, VERSION)?.to_owned();
+    let files = (doc.nodes().iter())
+      .filter(|node| node.name().value() == FILE)
+      .map(File::from_kdl)
+      .collect::<Result<_, ModelError>>()?;
+    Ok(Self { version, files })
+  }
+
+  pub fn to_kdl(&self) -> kdl::KdlDocument {
+    mem::init(kdl::KdlDocument::new(), |doc| {
+      let nodes = doc.nodes_mut();
+      nodes.push(mem::init(kdl::KdlNode::new(ROMHACKS_MANIFEST), |node| {
+        node.insert(VERSION, self.version.as_str());
+      }));
+      nodes.extend(self.files.iter().map(File::to_kdl));
+      doc.autoformat();
+    })
+  }
+
+  pub fn to_json(&self) -> json::Value {
+    use json::Value as V;
+    let crc32 = |crc32: Crc32| V::Integer(crc32.value().into());
+    let string = |str: &str| V::String(str.to_owned());
+    V::Object(vec![
+      ("version".to_owned(), string(&self.version)),
+      (
+        "files".to_owned(),
+        V::Array(
+          (self.files.iter())
+            .map(|file| {
+              V::Object(vec![
+                ("name".to_owned(), string(&file.name)),
+                ("crc32".to_owned(), crc32(file.crc32)),
+                (
+                  "patches".to_owned(),
+                  V::Array(
+                    (file.patches.iter())
+                      .map(|patch| {
+                        V::Object(vec![
+                          ("name".to_owned(), string(&patch.name)),
+                          ("crc32".to_owned(), crc32(patch.crc32)),
+                          ("hack_url".to_owned(), string(&patch.hack_url)),
+                          (
+                            "hack_version".to_owned(),
+                            string(patch.hack_version.as_str()),
+                          ),
+                          ("result_crc32".to_owned(), crc32(patch.result)),
+                          (
+                            "output".to_owned(),
+                            (patch.output.as_deref()).map_or(V::Null, string),
+                          ),
+                          (
+                            "signature".to_owned(),
+                            (patch.signature.as_ref()).map_or(V::Null, |signature| {
+                              V::Object(vec![
+                                ("key_id".to_owned(), string(&signature.key_id)),
+                                ("comment".to_owned(), string(&signature.comment)),
+                              ])
+                            }),
+                          ),
+                        ])
+                      })
+                      .collect(),
+                  ),
+                ),
+              ])
+            })
+            .collect(),
+        ),
+      ),
+    ])
+  }
+
+  pub fn from_json(value: &json::Value) -> Result<Self, ModelError> {
+    Ok(Self {
+      version: json_str(value, "version")?,
+      files: json_array(value, "files")?
+        .iter()
+        .map(|file| {
+          Ok(File {
+            name: json_str(file, "name")?,
+            crc32: json_crc32(file, "crc32")?,
+            patches: json_array(file, "patches")?
+              .iter()
+              .map(|patch| {
+                Ok(AppliedPatch {
+                  name: json_str(patch, "name")?,
+                  crc32: json_crc32(patch, "crc32")?,
+                  hack_url: json_str(patch, "hack_url")?,
+                  hack_version: hack::RecordedVersion::new(&json_str(patch, "hack_version")?),
+                  result: json_crc32(patch, "result_crc32")?,
+                  output: match patch.get("output") {
+                    None | Some(json::Value::Null) => None,
+                    Some(_) => Some(json_str(patch, "output")?),
+                  },
+                  signature: match patch.get("signature") {
+                    None | Some(json::Value::Null) => None,
+                    Some(signature) => Some(Signature {
+                      key_id: json_str(signature, "key_id")?,
+                      comment: json_str(signature, "comment")?,
+                    }),
+                  },
+                })
+              })
+              .collect::<Result<_, ModelError>>()?,
+          })
+        })
+        .collect::<Result<_, ModelError>>()?,
+    })
+  }
+
+  /// Writes a header row followed by one row per applied patch. The manifest
+  /// version isn't included.
+  pub fn to_csv(&self) -> String {
+    mem::init(String::new(), |out| {
+      csv::write_record(out, &CSV_HEADER);
+      for file in &self.files {
+        for patch in &file.patches {
+          csv::write_record(
+            out,
+            &[
+              file.name.clone(),
+              file.crc32.value().to_string(),
+              patch.name.clone(),
+              patch.crc32.value().to_string(),
+              patch.hack_url.clone(),
+              patch.hack_version.to_string(),
+              patch.result.value().to_string(),
+              (patch.signature.as_ref())
+                .map_or_else(String::new, |signature| signature.key_id.clone()),
+              (patch.signature.as_ref())
+                .map_or_else(String::new, |signature| signature.comment.clone()),
+              patch.output.clone().unwrap_or_default(),
+            ],
+          );
+        }
+      }
+    })
+  }
+
+  /// Reads rows written by [`Manifest::to_csv`]. Rows for the same file are
+  /// grouped together in the order the file first appears.
+  pub fn from_csv(text: &str) -> Result<Self, ModelError> {
+    let records = csv::parse(text)?;
+    let (header, rows) = records.split_first().ok_or(ModelError::Malformed)?;
+    if *header != CSV_HEADER && *header != CSV_HEADER[..OLD_CSV_COLUMNS] {
+      return Err(ModelError::Malformed);
+    }
+    let columns = header.len();
+    let crc32 = |field: &str| {
+      field
+        .parse()
+        .map(Crc32::new)
+        .map_err(|_| ModelError::Malformed)
+    };
+    let mut files: Vec<File> = Vec::new();
+    for row in rows {
+      if row.len() != columns {
+        return Err(ModelError::Malformed);
+      }
+      let [
+        file,
+        file_crc32,
+        patch,
+        patch_crc32,
+        hack_url,
+        hack_version,
+        result,
+        signature_key_id,
+        signature_comment,
+      ] = &row[..OLD_CSV_COLUMNS]
+      else {
+        return Err(ModelError::Malformed);
+      };
+      let output = row.get(OLD_CSV_COLUMNS).filter(|output| !output.is_empty());
+      let patch = AppliedPatch {
+        name: patch.clone(),
+        crc32: crc32(patch_crc32)?,
+        hack_url: hack_url.clone(),
+        hack_version: hack::RecordedVersion::new(hack_version),
+        result: crc32(result)?,
+        output: output.cloned(),
+        signature: (!signature_key_id.is_empty()).then(|| Signature {
+          key_id: signature_key_id.clone(),
+          comment: signature_comment.clone(),
+        }),
+      };
+      match files.iter_mut().find(|existing| existing.name == *file) {
+        Some(existing) if existing.crc32 != crc32(file_crc32)? => {
+          return Err(ModelError::Malformed);
+        }
+        Some(existing) => existing.patches.push(patch),
+        None => files.push(File {
+          name: file.clone(),
+          crc32: crc32(file_crc32)?,
+          patches: vec![patch],
+        }),
+      }
+    }
+    // CSV doesn't record the manifest version, so the current one is used.
+    Ok(Self { version: super::MANIFEST_VERSION.to_owned(), files })
+  }
+
+  /// Finds the newest version of the hack at `url` that was applied to any
+  /// of the files. Versions that can't be compared are skipped.
+  pub fn newest_version(&self, url: &str) -> Option<&hack::Version> {
+    (self.files.iter())
+      .flat_map(|file| file.patches.iter())
+      .filter(|patch| patch.hack_url == url)
+      .filter_map(|patch| patch.hack_version.parsed())
+      .max()
+  }
+}
+
+impl File {
+  /// Reads a `file` node and the patches under it.
+  pub fn from_kdl(node: &kdl::KdlNode) -> Result<Self, ModelError> {
+    Ok(File {
+      name: get_str(node, 0)?.to_owned(),
+      crc32: get_crc32(node, CRC_32)?,
+      patches: children(node)
+        .iter()
+        .filter(|node| node.name().value() == PATCH)
+        .map(|node| {
+          let hack = child(node, HACK)?;
+          let name = get_str(node, 0)?;
+          let crc32 = get_crc32(node, CRC_32)?;
+          let hack_url = get_str(hack, URL)?;
+          let hack_version = hack::RecordedVersion::new(get_str(hack, VERSION)?);
+          let signature = match child(node, SIGNATURE) {
+            Ok(signature) => Some(Signature {
+              key_id: get_str(signature, KEY_ID)?.to_owned(),
+              comment: get_str(signature, COMMENT)?.to_owned(),
+            }),
+            Err(_) => None,
+          };
+          // A patch has a result for each file it was applied to write.
+          (children(node).iter())
+            .filter(|node| node.name().value() == RESULT)
+            .map(|result| {
+              Ok(AppliedPatch {
+                name: name.to_owned(),
+                crc32,
+                hack_url: hack_url.to_owned(),
+                hack_version: hack_version.clone(),
+                result: get_crc32(result, CRC_32)?,
+                output: get_str(result, 0).ok().map(str::to_owned),
+                signature: signature.clone(),
+              })
+            })
+            .collect::<Result<Vec<_>, ModelError>>()
+        })
+        .collect::<Result<Vec<_>, ModelError>>()?
+        .into_iter()
+        .flatten()
+        .collect(),
+    })
+  }
+
+  pub fn to_kdl(&self) -> kdl::KdlNode {
+    mem::init(kdl::KdlNode::new(FILE), |node| {
+      node.insert(0, self.name.as_str());
+      node.insert(CRC_32, self.crc32);
+      let children = node.ensure_children().nodes_mut();
+      let same_patch = |a: &AppliedPatch, b: &AppliedPatch| a.name == b.name && a.crc32 == b.crc32;
+      for applications in self.patches.chunk_by(same_patch) {
+        let patch = &applications[0];
+        children.push(mem::init(kdl::KdlNode::new(PATCH), |node| {
+          node.insert(0, patch.name.as_str());
+          node.insert(CRC_32, patch.crc32);
+          let children = node.ensure_children().nodes_mut();
+          children.push(mem::init(kdl::KdlNode::new(HACK), |node| {
+            node.insert(URL, patch.hack_url.as_str());
+            node.insert(VERSION, patch.hack_version.as_str());
+          }));
+          for application in applications {
+            children.push(mem::init(kdl::KdlNode::new(RESULT), |node| {
+              if let Some(output) = &application.output {
+                node.insert(0, output.as_str());
+              }
+              node.insert(CRC_32, application.result);
+            }));
+          }
+          if let Some(signature) = &patch.signature {
+            children.push(mem::init(kdl::KdlNode::new(SIGNATURE), |node| {
+              node.insert(KEY_ID, signature.key_id.as_str());
+              node.insert(COMMENT, signature.comment.as_str());
+            }));
+          }
+        }));
+      }
+    })
+  }
+
+  /// Returns `true` if one of the recorded patches was applied to write a
+  /// file with the checksum `crc32`.
+  pub fn has_result(&self, crc32: Crc32) -> bool {
+    self.patches.iter().any(|patch| patch.result == crc32)
+  }
+}
+
+fn json_str(value: &json::Value, key: &str) -> Result<String, ModelError> {
+  (value.get(key).and_then(json::Value::as_str))
+    .map(str::to_owned)
+    .ok_or(ModelError::Malformed)
+}
+
+fn json_crc32(value: &json::Value, key: &str) -> Result<Crc32, ModelError> {
+  (value.get(key).and_then(json::Value::as_integer))
+    .and_then(|value| u32::try_from(value).ok())
+    .map(Crc32::new)
+    .ok_or(ModelError::Malformed)
+}
+
+fn json_array<'a>(value: &'a json::Value, key: &str) -> Result<&'a [json::Value], ModelError> {
+  (value.get(key).and_then(json::Value::as_array)).ok_or(ModelError::Malformed)
+}
+
+fn children(node: &kdl::KdlNode) -> &[kdl::KdlNode] {
+  node.children().map_or(&[], |children| children.nodes())
+}
+
+fn child<'a>(node: &'a kdl::KdlNode, name: &str) -> Result<&'a kdl::KdlNode, ModelError> {
+  (children(node).iter())
+    .find(|child| child.name().value() == name)
+    .ok_or(ModelError::Malformed)
+}
+
+fn get_str(node: &kdl::KdlNode, key: impl Into<kdl::NodeKey>) -> Result<&str, ModelError> {
+  (node.get(key).and_then(|value| value.as_string())).ok_or(ModelError::Malformed)
+}
+
+fn get_crc32(node: &kdl::KdlNode, key: impl Into<kdl::NodeKey>) -> Result<Crc32, ModelError> {
+  (node.get(key).and_then(|value| value.as_integer()))
+    .and_then(|value| u32::try_from(value).ok())
+    .map(Crc32::new)
+    .ok_or(ModelError::Malformed)
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum ModelError {
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Json(#[from] json::ParseError),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Csv(#[from] csv::ParseError),
+  #[error("{}", i18n::text("romhacks::manifest::malformed"))]
+  #[diagnostic(code(romhacks::manifest::malformed))]
+  Malformed,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn manifest(versions: &[&str]) -> Manifest {
+    let patches: String = (versions.iter().enumerate())
+      .map(|(i, version)| {
+        format!(
+          "patch \"hack-{i}.ips\" crc32={i} {{\n\
+           hack url=\"https://example.org/\" version=\"{version}\"\n\
+           result \"game-{i}.sfc\" crc32={}\n\
+           }}\n",
+          i + 100
+        )
+      })
+      .collect();
+    let doc =
+      format!("romhacks-manifest version=\"1.0\"\nfile \"game.sfc\" crc32=1 {{\n{patches}}}\n");
+    Manifest::from_kdl(&doc.parse().unwrap()).unwrap()
+  }
+
+  #[test]
+  fn keeps_versions_that_cannot_be_compared() {
+    let manifest = manifest(&["Beta 2", "1.0a", "final"]);
+    let versions: Vec<&hack::RecordedVersion> = (manifest.files[0].patches.iter())
+      .map(|patch| &patch.hack_version)
+      .collect();
+    assert!(versions.iter().all(|version| version.parsed().is_none()));
+    let written = Manifest::from_kdl(&manifest.to_kdl()).unwrap();
+    assert_eq!(written, manifest);
+    assert_eq!(manifest.newest_version("https://example.org/"), None);
+  }
+
+  #[test]
+  fn newest_version_skips_versions_that_cannot_be_compared() {
+    let manifest = manifest(&["1.2", "Beta 2", "1.10", "final"]);
+    let newest = manifest.newest_version("https://example.org/").unwrap();
+    assert_eq!(newest.as_str(), "1.10");
+  }
+}
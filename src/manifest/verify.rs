@@ -0,0 +1,171 @@
+//! Checking the files a manifest records against the files on disk, without
+//! changing either.
+
+use super::model;
+use crate::blockmap::{self, BlockMap};
+use crate::crc::Crc32;
+use crate::{fs, i18n, io};
+use std::collections::HashSet;
+use std::path;
+
+/// How a file on disk compares to what the manifest records for it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Status {
+  /// A ROM that's still the file that was patched.
+  Original,
+  /// A ROM that was patched in place and has the last recorded result.
+  Patched,
+  /// A patched file that has the recorded result.
+  Matches,
+  /// A patched file whose hashed chunks match its block map, of which only
+  /// those the patch changed and a sample of the others were hashed.
+  SampledMatches {
+    chunks: usize,
+    total: usize,
+  },
+  /// A file that doesn't have any checksum the manifest records for it.
+  Modified {
+    crc32: Crc32,
+  },
+  Missing,
+  /// A file that couldn't be read.
+  Unknown {
+    error: String,
+  },
+}
+
+impl Status {
+  /// Whether the file has drifted from what the manifest records.
+  pub fn is_drift(&self) -> bool {
+    !matches!(
+      self,
+      Status::Original | Status::Patched | Status::Matches | Status::SampledMatches { .. }
+    )
+  }
+}
+
+/// A file the manifest records, and how it compares to the file on disk.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+  pub path: path::PathBuf,
+  /// The checksums the file may have.
+  pub expected: Vec<Crc32>,
+  pub status: Status,
+}
+
+/// Checks each ROM the manifest records, which is looked for in `rom_dir`,
+/// and each patched file, which is looked for in `output_dir`. With
+/// `sample`, patched files with a block map are only sampled; see
+/// [`BlockMap::verify_sampled`].
+pub fn verify(
+  manifest: &model::Manifest,
+  rom_dir: &path::Path,
+  output_dir: &path::Path,
+  sample: Option<u8>,
+) -> Vec<Entry> {
+  let mut entries = Vec::new();
+  for file in &manifest.files {
+    let latest = file.patches.last().map(|patch| patch.result);
+    let path = rom_dir.join(&file.name);
+    let expected: Vec<Crc32> = [Some(file.crc32), latest].into_iter().flatten().collect();
+    let status = match hash(&path) {
+      Ok(Some(crc32)) if crc32 == file.crc32 => Status::Original,
+      Ok(Some(crc32)) if Some(crc32) == latest => Status::Patched,
+      Ok(Some(crc32)) => Status::Modified { crc32 },
+      Ok(None) => Status::Missing,
+      Err(err) => Status::Unknown { error: err.to_string() },
+    };
+    entries.push(Entry { path, expected, status });
+
+    // A file that was written again by a later patch only has its result.
+    let mut seen = HashSet::new();
+    let outputs: Vec<(&String, Crc32)> = (file.patches.iter().rev())
+      .filter_map(|patch| Some((patch.output.as_ref()?, patch.result)))
+      .filter(|(output, _)| seen.insert(*output))
+      .collect();
+    for (output, result) in outputs.into_iter().rev() {
+      let path = output_dir.join(output);
+      let sampled = sample.and_then(|percent| verify_sampled(&path, percent));
+      let status = match sampled {
+        Some(status) => status,
+        None => match hash(&path) {
+          Ok(Some(crc32)) if crc32 == result => Status::Matches,
+          Ok(Some(crc32)) => Status::Modified { crc32 },
+          Ok(None) => Status::Missing,
+          Err(err) => Status::Unknown { error: err.to_string() },
+        },
+      };
+      entries.push(Entry { path, expected: vec![result], status });
+    }
+  }
+  entries
+}
+
+/// Checks the patched file at `path` against its block map, if it has one.
+/// Returns `None` if it can't be checked that way or any chunk doesn't match,
+/// since the block map may be older than the file, which should then be
+/// hashed in full to find out.
+fn verify_sampled(path: &path::Path, percent: u8) -> Option<Status> {
+  let block_map: BlockMap = fs::read_to_string(blockmap::sidecar_path(path))
+    .ok()?
+    .parse()
+    .ok()?;
+  let sampled = block_map
+    .verify_sampled(&mut fs::File::open(path).ok()?, percent)
+    .ok()?;
+  sampled
+    .mismatches
+    .is_empty()
+    .then(|| Status::SampledMatches {
+      chunks: sampled.chunks,
+      total: block_map.chunks().len(),
+    })
+}
+
+/// Returns the checksum of the file at `path`, or `None` if it doesn't exist.
+fn hash(path: &path::Path) -> io::Result<Option<Crc32>> {
+  let file = match fs::File::open(path) {
+    Ok(file) => file,
+    Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+    Err(err) => return Err(err),
+  };
+  Crc32::read_and_hash(&mut io::BufReader::new(file)).map(Some)
+}
+
+/// Lists each entry with its status, one per row.
+pub fn table(entries: &[Entry]) -> Vec<Vec<String>> {
+  let header = [
+    "romhacks::manifest::verify_file",
+    "romhacks::manifest::verify_expected",
+    "romhacks::manifest::verify_status",
+  ];
+  let hex = |crc32: Crc32| format!("{:08X}", crc32.value());
+  let rows = entries.iter().map(|entry| {
+    let expected: Vec<String> = entry.expected.iter().copied().map(hex).collect();
+    let status = match &entry.status {
+      Status::Original => i18n::text("romhacks::manifest::verify::original").to_owned(),
+      Status::Patched => i18n::text("romhacks::manifest::verify::patched").to_owned(),
+      Status::Matches => i18n::text("romhacks::manifest::verify::matches").to_owned(),
+      Status::SampledMatches { chunks, total } => i18n::format(
+        "romhacks::manifest::verify::matches_sampled",
+        &[("chunks", chunks), ("total", total)],
+      ),
+      Status::Modified { crc32 } => i18n::format(
+        "romhacks::manifest::verify::modified",
+        &[("crc32", &hex(*crc32))],
+      ),
+      Status::Missing => i18n::text("romhacks::manifest::verify::missing").to_owned(),
+      Status::Unknown { error } => {
+        i18n::format("romhacks::manifest::verify::unknown", &[("error", error)])
+      }
+    };
+    vec![
+      entry.path.display().to_string(),
+      expected.join(", "),
+      status,
+    ]
+  });
+  std::iter::once(header.map(|key| i18n::text(key).to_owned()).to_vec())
+    .chain(rows)
+    .collect()
+}
@@ -0,0 +1,131 @@
+//! Copying file metadata from a ROM to the file patched from it.
+
+use crate::{fs, io};
+use std::path;
+
+/// Which metadata to copy in addition to timestamps and, on Unix, the
+/// executable bits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Options {
+  /// Copy extended attributes on Unix, or alternate data streams on Windows.
+  pub extended_attributes: bool,
+}
+
+/// Copies the metadata of the file at `source` to the file at `dest`.
+///
+/// The modification and access times are always copied, as is the creation
+/// time on platforms that support setting it.
+pub fn copy(source: &path::Path, dest: &path::Path, options: Options) -> io::Result<()> {
+  let source_metadata = fs::metadata(source)?;
+  if options.extended_attributes {
+    copy_extended_attributes(source, dest)?;
+  }
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt;
+    const EXECUTABLE: u32 = 0o111;
+    let mut permissions = fs::metadata(dest)?.permissions();
+    let mode =
+      (permissions.mode() & !EXECUTABLE) | (source_metadata.permissions().mode() & EXECUTABLE);
+    permissions.set_mode(mode);
+    fs::set_permissions(dest, permissions)?;
+  }
+  // Set the times last, since writing the other metadata can update them.
+  let times = std::fs::FileTimes::new()
+    .set_accessed(source_metadata.accessed()?)
+    .set_modified(source_metadata.modified()?);
+  #[cfg(windows)]
+  let times = {
+    use std::os::windows::fs::FileTimesExt;
+    times.set_created(source_metadata.created()?)
+  };
+  #[cfg(target_os = "macos")]
+  let times = {
+    use std::os::macos::fs::FileTimesExt;
+    times.set_created(source_metadata.created()?)
+  };
+  fs::OpenOptions::new()
+    .write(true)
+    .open(dest)?
+    .file()
+    .set_times(times)
+    .map_err(fs::with_path(fs::Operation::SetTimes, dest))
+}
+
+#[cfg(unix)]
+fn copy_extended_attributes(source: &path::Path, dest: &path::Path) -> io::Result<()> {
+  for name in xattr::list(source).map_err(fs::with_path(fs::Operation::ReadAttributes, source))? {
+    let value =
+      xattr::get(source, &name).map_err(fs::with_path(fs::Operation::ReadAttributes, source))?;
+    if let Some(value) = value {
+      xattr::set(dest, &name, &value)
+        .map_err(fs::with_path(fs::Operation::WriteAttributes, dest))?;
+    }
+  }
+  Ok(())
+}
+
+#[cfg(windows)]
+fn copy_extended_attributes(source: &path::Path, dest: &path::Path) -> io::Result<()> {
+  use std::ffi::{OsString, c_void};
+  use std::os::windows::ffi::{OsStrExt, OsStringExt};
+  use windows_sys::Win32::Foundation::{ERROR_HANDLE_EOF, INVALID_HANDLE_VALUE};
+  use windows_sys::Win32::Storage::FileSystem::{
+    FindClose, FindFirstStreamW, FindNextStreamW, FindStreamInfoStandard, WIN32_FIND_STREAM_DATA,
+  };
+
+  // The unnamed stream holds the file's contents, which were already written.
+  const DEFAULT_STREAM: &str = "::$DATA";
+
+  let wide_source: Vec<u16> = source.as_os_str().encode_wide().chain([0]).collect();
+  let mut data: WIN32_FIND_STREAM_DATA = unsafe { std::mem::zeroed() };
+  // SAFETY: `wide_source` is NUL-terminated and `data` is the struct that
+  // FindStreamInfoStandard requires.
+  let handle = unsafe {
+    FindFirstStreamW(
+      wide_source.as_ptr(),
+      FindStreamInfoStandard,
+      &mut data as *mut _ as *mut c_void,
+      0,
+    )
+  };
+  if handle == INVALID_HANDLE_VALUE {
+    let err = io::Error::last_os_error();
+    return match err.raw_os_error() == Some(ERROR_HANDLE_EOF as i32) {
+      true => Ok(()),
+      false => Err(fs::with_path(fs::Operation::ReadAttributes, source)(err)),
+    };
+  }
+  let result = (|| loop {
+    let len = data
+      .cStreamName
+      .iter()
+      .position(|&c| c == 0)
+      .unwrap_or(data.cStreamName.len());
+    // Stream names have the form ":name:$DATA".
+    let stream_name = OsString::from_wide(&data.cStreamName[..len]);
+    if stream_name != DEFAULT_STREAM {
+      let mut source_stream = source.as_os_str().to_owned();
+      source_stream.push(&stream_name);
+      let mut dest_stream = dest.as_os_str().to_owned();
+      dest_stream.push(&stream_name);
+      fs::copy(source_stream, dest_stream)?;
+    }
+    // SAFETY: `handle` is a valid search handle and `data` is the struct it expects.
+    if unsafe { FindNextStreamW(handle, &mut data as *mut _ as *mut c_void) } == 0 {
+      let err = io::Error::last_os_error();
+      return match err.raw_os_error() == Some(ERROR_HANDLE_EOF as i32) {
+        true => Ok(()),
+        false => Err(fs::with_path(fs::Operation::ReadAttributes, source)(err)),
+      };
+    }
+  })();
+  // SAFETY: `handle` is a valid search handle that isn't used afterwards.
+  unsafe { FindClose(handle) };
+  result
+}
+
+#[cfg(not(any(unix, windows)))]
+fn copy_extended_attributes(_source: &path::Path, _dest: &path::Path) -> io::Result<()> {
+  Ok(())
+}
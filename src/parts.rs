@@ -0,0 +1,207 @@
+//! ROMs that are split into numbered parts, like the ".1", ".2", ".3" files
+//! that backup units such as the Super Wild Card read from floppy disks.
+//!
+//! A directory holding the parts is patched like a single file: the parts
+//! are read one after another as if they were joined, into a temporary file
+//! that the patch is applied to. The patched file is written whole, or split
+//! again into parts of the same sizes.
+
+use crate::error::prelude::*;
+use crate::io::prelude::*;
+use crate::{dirs, fs, i18n, io, temp};
+use std::path;
+
+/// The extension of a joined file when the parts don't include one with a
+/// regular extension, such as "game.smc" before "game.1".
+const DEFAULT_EXTENSION: &str = "bin";
+
+/// The parts of a ROM read one after another, as if they were joined.
+pub type ConcatenatedReader = io::ConcatReader<fs::File>;
+
+/// The files a ROM is split into, in order, and their lengths, which are
+/// the boundaries between them in the joined file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Parts {
+  paths: Vec<path::PathBuf>,
+  lens: Vec<u64>,
+}
+
+impl Parts {
+  /// Finds the parts of the ROM in `dir`: files with the same name and
+  /// consecutive numbers as extensions, optionally preceded by one with
+  /// their name and another extension. Other files in `dir` are ignored.
+  pub fn find(dir: &path::Path) -> Result<Self, Error> {
+    let not_split = || Error::NotSplit { path: dir.to_owned() };
+    let mut numbered: Vec<(u32, path::PathBuf)> = Vec::new();
+    let mut others: Vec<path::PathBuf> = Vec::new();
+    for entry in fs::read_dir(dir)? {
+      let entry = entry?;
+      if !entry.file_type()?.is_file() {
+        continue;
+      }
+      let path = entry.path();
+      let number = (path.extension())
+        .and_then(|ext| ext.to_str())
+        .filter(|ext| ext.bytes().all(|byte| byte.is_ascii_digit()))
+        .and_then(|ext| ext.parse::<u32>().ok());
+      match number {
+        Some(number) => numbered.push((number, path)),
+        None => others.push(path),
+      }
+    }
+    numbered.sort();
+    let stem = match numbered.first() {
+      Some((_, path)) => path.file_stem().unwrap_or_default().to_owned(),
+      None => return Err(not_split()),
+    };
+    if numbered
+      .iter()
+      .any(|(_, path)| path.file_stem() != Some(&stem))
+    {
+      return Err(not_split());
+    }
+    if let Some(pair) = numbered.windows(2).find(|pair| pair[1].0 != pair[0].0 + 1) {
+      let missing = pair[0].1.with_extension((pair[0].0 + 1).to_string());
+      return Err(Error::MissingPart { path: missing });
+    }
+    let mut bases = others
+      .into_iter()
+      .filter(|path| path.file_stem() == Some(&stem));
+    let base = bases.next();
+    if bases.next().is_some() {
+      return Err(not_split());
+    }
+    let paths: Vec<path::PathBuf> = base
+      .into_iter()
+      .chain(numbered.into_iter().map(|(_, path)| path))
+      .collect();
+    let lens = (paths.iter())
+      .map(|path| Ok(fs::metadata(path)?.len()))
+      .collect::<io::Result<Vec<u64>>>()?;
+    Ok(Self { paths, lens })
+  }
+
+  /// Opens the parts for reading as one stream.
+  pub fn open(&self) -> io::Result<ConcatenatedReader> {
+    let files = (self.paths.iter())
+      .map(fs::File::open)
+      .collect::<io::Result<Vec<_>>>()?;
+    Ok(io::ConcatReader::with_lens(
+      files,
+      self.lens.iter().copied(),
+    ))
+  }
+
+  /// Joins the parts into a file named after `dir`, the directory they're
+  /// in, in a temporary directory.
+  pub fn join(&self, dir: &path::Path) -> Result<Joined, Error> {
+    let temp_dir = dirs::temp_dir().join(format!("romhacks-{}", ulid::Ulid::new()));
+    fs::create_dir(&temp_dir)?;
+    // The game's name is inferred from the name of the file being patched,
+    // and the directory is usually named after the game.
+    let name = match dir.file_name() {
+      Some(name) => path::PathBuf::from(name),
+      None => path::PathBuf::from(self.paths[0].file_stem().unwrap_or_default()),
+    };
+    let extension = (self.paths[0].extension())
+      .filter(|ext| {
+        !ext
+          .to_string_lossy()
+          .bytes()
+          .all(|byte| byte.is_ascii_digit())
+      })
+      .map_or(DEFAULT_EXTENSION.into(), |ext| ext.to_owned());
+    let joined = Joined {
+      path: temp_dir.join(name.with_extension(extension)),
+      dir: temp_dir,
+    };
+    io::copy(&mut self.open()?, &mut fs::File::create(&joined.path)?)?;
+    log::info!(
+      "{}",
+      i18n::format(
+        "romhacks::parts::joined",
+        &[("count", &self.paths.len()), ("dir", &dir.display())]
+      )
+    );
+    Ok(joined)
+  }
+
+  /// Splits `file` into parts of the same sizes as these, named like `file`
+  /// with the extension of the corresponding part, then deletes it. The last
+  /// part holds whatever is left, so a file that's grown has a larger last
+  /// part and one that's shrunk may have fewer parts.
+  pub fn split(&self, file: &path::Path) -> Result<Vec<path::PathBuf>, Error> {
+    // The first part may have the same name as the file. Renaming replaces
+    // the empty temporary file, which reserves the name.
+    let file_name = file.file_name().unwrap_or_default().to_string_lossy();
+    let whole = temp::create(&dirs::temp_dir_for(file), &file_name)?
+      .path()
+      .to_path_buf();
+    fs::rename(file, &whole)?;
+    let mut reader = io::BufReader::new(fs::File::open(&whole)?);
+    let mut remaining = reader.get_ref().known_len()?;
+    let mut written = Vec::new();
+    for (index, (part, &part_len)) in self.paths.iter().zip(&self.lens).enumerate() {
+      let is_last = index + 1 == self.paths.len();
+      if remaining == 0 && index > 0 {
+        break;
+      }
+      let len = match is_last {
+        true => remaining,
+        false => part_len.min(remaining),
+      };
+      let path = file.with_extension(part.extension().unwrap_or_default());
+      io::copy(&mut (&mut reader).take(len), &mut fs::File::create(&path)?)?;
+      remaining -= len;
+      written.push(path);
+    }
+    drop(reader);
+    fs::remove_file(&whole)?;
+    log::info!(
+      "{}",
+      i18n::format(
+        "romhacks::parts::split",
+        &[("count", &written.len()), ("path", &file.display())]
+      )
+    );
+    Ok(written)
+  }
+}
+
+/// The parts of a ROM joined into a file in a temporary directory, which is
+/// deleted when this is dropped.
+#[derive(Debug)]
+pub struct Joined {
+  dir: path::PathBuf,
+  path: path::PathBuf,
+}
+
+impl Joined {
+  pub fn path(&self) -> &path::Path {
+    &self.path
+  }
+}
+
+impl Drop for Joined {
+  fn drop(&mut self) {
+    if let Err(err) = fs::remove_dir_all(&self.dir) {
+      log::warn!("{err}");
+    }
+  }
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error("{}", i18n::format("romhacks::parts::not_split", &[("path", &path.display())]))]
+  #[diagnostic(
+    code(romhacks::parts::not_split),
+    help("{}", i18n::text("romhacks::parts::not_split::help"))
+  )]
+  NotSplit { path: path::PathBuf },
+  #[error("{}", i18n::format("romhacks::parts::missing_part", &[("path", &path.display())]))]
+  #[diagnostic(code(romhacks::parts::missing_part))]
+  MissingPart { path: path::PathBuf },
+}
@@ -0,0 +1,186 @@
+//! APS patches for Game Boy Advance ROMs, which share little with the N64
+//! format but the name.
+//!
+//! A patch declares the sizes of the ROM and the patched file, followed by
+//! records that each XOR a 64 KiB block of the ROM. A record holds the CRC16
+//! of the block before and after patching, which are checked unless the
+//! [`Patcher`](crate::patch::Patcher) isn't strict, in which case blocks that
+//! don't match are only reported with a warning.
+
+use crate::io::prelude::*;
+use crate::patch::header::Header;
+use crate::patch::{Error, OutputFile};
+use crate::{i18n, io, profile};
+
+pub const MAGIC: &[u8] = b"APS1";
+
+/// The size of the blocks records XOR.
+const BLOCK_SIZE: usize = 0x10000;
+
+pub fn patch(
+  rom: &mut impl OutputFile,
+  patch: &mut (impl Read + Seek),
+  strict: bool,
+) -> Result<(), Error> {
+  patch.seek(io::SeekFrom::Start(0))?;
+  let mut patch = io::BufReader::with_capacity(profile::get().buf_size, patch);
+  let (source_size, target_size) = read_sizes(&mut patch)?;
+
+  // Blocks are read before the file is truncated, since the source CRCs
+  // cover bytes the patched file may not keep.
+  rom.set_len(source_size.max(target_size))?;
+  let mut xor = vec![0u8; BLOCK_SIZE];
+  let mut block = vec![0u8; BLOCK_SIZE];
+  let (mut source_mismatches, mut target_mismatches) = (0usize, 0usize);
+  while !patch.fill_buf()?.is_empty() {
+    let offset = u64::from(patch.read_u32::<LE>()?);
+    let source_crc = patch.read_u16::<LE>()?;
+    let target_crc = patch.read_u16::<LE>()?;
+    patch.read_exact(&mut xor)?;
+
+    let len = read_block(rom, offset, &mut block)?;
+    let crc = crc16(&block);
+    if crc != source_crc {
+      match strict {
+        false => source_mismatches += 1,
+        true if crc == target_crc => return Err(Error::AlreadyPatched),
+        true => return Err(Error::WrongInputFile),
+      }
+    }
+    for (byte, xor) in block.iter_mut().zip(&xor) {
+      *byte ^= *xor;
+    }
+    if crc16(&block) != target_crc {
+      match strict {
+        false => target_mismatches += 1,
+        true => return Err(Error::BadPatch),
+      }
+    }
+    rom.seek(io::SeekFrom::Start(offset))?;
+    rom.write_all(&block[..len])?;
+  }
+  rom.set_len(target_size)?;
+  rom.flush()?;
+
+  if source_mismatches > 0 {
+    log::warn!(
+      "{}",
+      i18n::format(
+        "romhacks::patch::aps_source_mismatch",
+        &[("count", &source_mismatches)]
+      )
+    );
+  }
+  if target_mismatches > 0 {
+    log::warn!(
+      "{}",
+      i18n::format(
+        "romhacks::patch::aps_target_mismatch",
+        &[("count", &target_mismatches)]
+      )
+    );
+  }
+  Ok(())
+}
+
+/// Reads the sizes a GBA APS patch declares.
+pub fn read_header(patch: &mut (impl Read + Seek)) -> Result<Header, Error> {
+  patch.seek(io::SeekFrom::Start(0))?;
+  let (source_size, target_size) = read_sizes(&mut io::BufReader::new(patch))?;
+  Ok(Header {
+    source_size: Some(source_size),
+    target_size: Some(target_size),
+    ..Header::default()
+  })
+}
+
+/// Reads the magic string and the sizes of the ROM and the patched file,
+/// leaving `patch` at the first record.
+fn read_sizes(patch: &mut impl Read) -> Result<(u64, u64), Error> {
+  if patch.read_array::<4>()? != MAGIC {
+    return Err(Error::BadPatch);
+  }
+  let source_size = u64::from(patch.read_u32::<LE>()?);
+  let target_size = u64::from(patch.read_u32::<LE>()?);
+  Ok((source_size, target_size))
+}
+
+/// Reads the block at `offset` into `block`, filling whatever is past the
+/// end of the file with zeroes, and returns how much of it is in the file.
+fn read_block(rom: &mut (impl Read + Seek), offset: u64, block: &mut [u8]) -> io::Result<usize> {
+  rom.seek(io::SeekFrom::Start(offset))?;
+  let mut len = 0;
+  while len < block.len() {
+    match rom.read(&mut block[len..])? {
+      0 => break,
+      read => len += read,
+    }
+  }
+  block[len..].fill(0);
+  Ok(len)
+}
+
+/// The CRC16 that records are checked with: CRC-16/CCITT-FALSE, with the
+/// polynomial 0x1021, an initial value of 0xFFFF and no reflection.
+fn crc16(bytes: &[u8]) -> u16 {
+  let mut crc: u16 = 0xFFFF;
+  for &byte in bytes {
+    crc ^= u16::from(byte) << 8;
+    for _ in 0..8 {
+      crc = match crc & 0x8000 {
+        0 => crc << 1,
+        _ => (crc << 1) ^ 0x1021,
+      };
+    }
+  }
+  crc
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::patch::Kind;
+  use crate::patch::tests::apply;
+  use romhacks_testkit::{ApsGbaBuilder, rom};
+
+  /// A patch for `source` that XORs 1 into the first byte of its one block.
+  fn patch_for(source: &[u8]) -> Vec<u8> {
+    ApsGbaBuilder::new(source).xor(0, &[1]).build()
+  }
+
+  #[test]
+  fn xors_matching_block() {
+    let rom = vec![0u8; BLOCK_SIZE];
+    let patched = apply(Kind::APSGBA, &rom, patch_for(&rom)).unwrap();
+    assert_eq!(patched[0], 1);
+    assert!(patched[1..].iter().all(|&byte| byte == 0));
+  }
+
+  #[test]
+  fn xors_blocks_past_end_of_rom() {
+    let rom = rom::random(0x18000, 1);
+    for len in [0x20000, 0x14000] {
+      let builder = ApsGbaBuilder::new(&rom)
+        .xor(0, b"romhacks")
+        .xor(0x10000, &rom::random(0x10000, 2))
+        .resize(len);
+      let expected = builder.target();
+      assert!(apply(Kind::APSGBA, &rom, builder.build()).unwrap() == expected);
+    }
+  }
+
+  #[test]
+  fn strict_refuses_mismatched_block() {
+    let rom = vec![0u8; BLOCK_SIZE];
+    let err = apply(Kind::APSGBA, &rom, patch_for(&[2u8; BLOCK_SIZE])).unwrap_err();
+    assert!(matches!(err, Error::WrongInputFile));
+  }
+
+  #[test]
+  fn lenient_patches_mismatched_block() {
+    let mut rom = io::Cursor::new(vec![0u8; BLOCK_SIZE]);
+    let mut patch = io::Cursor::new(patch_for(&[2u8; BLOCK_SIZE]));
+    super::patch(&mut rom, &mut patch, false).unwrap();
+    assert_eq!(rom.into_inner()[0], 1);
+  }
+}
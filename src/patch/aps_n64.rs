@@ -0,0 +1,185 @@
+//! APS patches for Nintendo 64 ROMs, as written by the APS patcher.
+//!
+//! A patch starts with a header holding a description and, for N64 patches,
+//! the cartridge ID and CRC from the ROM's internal header, which must match
+//! for the patch to be applied. The size of the patched file follows, and then
+//! records, each of which writes either bytes stored in the patch or one byte
+//! repeated, at an offset in the file.
+
+use crate::io::prelude::*;
+use crate::patch::header::Header;
+use crate::patch::{Error, OutputFile};
+use crate::{io, profile};
+
+pub const MAGIC: &[u8] = b"APS10";
+
+/// The header types of patches that don't record which ROM they apply to,
+/// and of those that do.
+const SIMPLE_HEADER: u8 = 0;
+const N64_HEADER: u8 = 1;
+
+/// The only encoding method, in which records are stored as they are.
+const SIMPLE_ENCODING: u8 = 0;
+
+/// The length of the description after the header type and encoding method.
+const DESCRIPTION_LEN: usize = 50;
+
+/// Where the cartridge ID is in a ROM's internal header, and its length.
+const CART_ID_OFFSET: u64 = 0x3C;
+const CART_ID_LEN: usize = 3;
+
+/// Where the two CRCs are in a ROM's internal header, and their total length.
+const CRC_OFFSET: u64 = 0x10;
+const CRC_LEN: usize = 8;
+
+/// A record's length if it repeats one byte rather than storing its bytes.
+const RLE: u8 = 0;
+
+/// The ROM a patch with an N64 header applies to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Cart {
+  id: [u8; CART_ID_LEN],
+  crc: [u8; CRC_LEN],
+}
+
+pub fn patch(rom: &mut impl OutputFile, patch: &mut (impl Read + Seek)) -> Result<(), Error> {
+  patch.seek(io::SeekFrom::Start(0))?;
+  let mut patch = io::BufReader::with_capacity(profile::get().buf_size, patch);
+  let (cart, target_size) = read_start(&mut patch)?;
+  if let Some(cart) = cart {
+    check_cart(rom, &cart)?;
+  }
+  rom.set_len(target_size)?;
+
+  let mut buf = [0u8; u8::MAX as usize];
+  while !patch.fill_buf()?.is_empty() {
+    let offset = u64::from(patch.read_u32::<LE>()?);
+    let len = match patch.read_u8()? {
+      RLE => {
+        let byte = patch.read_u8()?;
+        let len = usize::from(patch.read_u8()?);
+        buf[..len].fill(byte);
+        len
+      }
+      len => {
+        let len = usize::from(len);
+        patch.read_exact(&mut buf[..len])?;
+        len
+      }
+    };
+    if offset + len as u64 > target_size {
+      return Err(Error::BadPatch);
+    }
+    rom.seek(io::SeekFrom::Start(offset))?;
+    rom.write_all(&buf[..len])?;
+  }
+
+  rom.flush()?;
+  Ok(())
+}
+
+/// Reads the size of the patched file, which every APS patch declares.
+pub fn read_header(patch: &mut (impl Read + Seek)) -> Result<Header, Error> {
+  patch.seek(io::SeekFrom::Start(0))?;
+  let (_, target_size) = read_start(&mut io::BufReader::new(patch))?;
+  Ok(Header {
+    target_size: Some(target_size),
+    ..Header::default()
+  })
+}
+
+/// Reads the header and the size of the patched file, leaving `patch` at the
+/// first record.
+fn read_start(patch: &mut impl Read) -> Result<(Option<Cart>, u64), Error> {
+  if patch.read_array::<5>()? != MAGIC {
+    return Err(Error::BadPatch);
+  }
+  let header_type = patch.read_u8()?;
+  if patch.read_u8()? != SIMPLE_ENCODING {
+    return Err(Error::UnsupportedPatchFeature);
+  }
+  let _description = patch.read_array::<DESCRIPTION_LEN>()?;
+  let cart = match header_type {
+    N64_HEADER => {
+      // Whether the ROM was byte-swapped, which the checks below already
+      // account for, since they compare its bytes as they're stored.
+      let _format = patch.read_u8()?;
+      let id = patch.read_array::<CART_ID_LEN>()?;
+      let crc = patch.read_array::<CRC_LEN>()?;
+      let _padding = patch.read_array::<5>()?;
+      Some(Cart { id, crc })
+    }
+    SIMPLE_HEADER => None,
+    _ => return Err(Error::BadPatch),
+  };
+  let target_size = u64::from(patch.read_u32::<LE>()?);
+  Ok((cart, target_size))
+}
+
+/// Checks that `rom` is the ROM the patch was made for.
+fn check_cart(rom: &mut (impl Read + Seek), cart: &Cart) -> Result<(), Error> {
+  let mut id = [0u8; CART_ID_LEN];
+  let mut crc = [0u8; CRC_LEN];
+  rom.seek(io::SeekFrom::Start(CART_ID_OFFSET))?;
+  let read_id = rom.read_exact(&mut id);
+  rom.seek(io::SeekFrom::Start(CRC_OFFSET))?;
+  let read_crc = rom.read_exact(&mut crc);
+  match (read_id, read_crc) {
+    (Ok(()), Ok(())) if id == cart.id && crc == cart.crc => Ok(()),
+    (Err(err), _) | (_, Err(err)) if err.kind() != io::ErrorKind::UnexpectedEof => Err(err.into()),
+    _ => Err(Error::WrongInputFile),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::patch::Kind;
+  use crate::patch::tests::apply;
+  use romhacks_testkit::{ApsN64Builder, rom};
+
+  #[test]
+  fn writes_records() {
+    let rom = rom::sequential(0x1000);
+    let patch = ApsN64Builder::new(0x1000)
+      .description("romhacks")
+      .hunk(0x10, b"romhacks")
+      .hunk(0xFF8, b"extended")
+      .build();
+    let expected = rom::with_bytes(&rom, 0x10, b"romhacks");
+    let expected = rom::with_bytes(&expected, 0xFF8, b"extended");
+    assert!(apply(Kind::APS, &rom, patch).unwrap() == expected);
+  }
+
+  #[test]
+  fn repeats_rle_byte() {
+    let rom = rom::sequential(0x1000);
+    let patch = ApsN64Builder::new(0x10FF)
+      .rle(0x800, 0xFF, 0x80)
+      .rle(0x1000, 0xAA, 0xFF)
+      .build();
+    let expected = rom::with_bytes(&rom, 0x800, &rom::filled(0x80, 0xFF));
+    let expected = rom::with_bytes(&expected, 0x1000, &rom::filled(0xFF, 0xAA));
+    assert!(apply(Kind::APS, &rom, patch).unwrap() == expected);
+  }
+
+  #[test]
+  fn checks_cart() {
+    let rom = rom::random(0x1000, 1);
+    let patch = ApsN64Builder::new(0x1000)
+      .cart(&rom)
+      .hunk(0x800, b"romhacks")
+      .build();
+    let expected = rom::with_bytes(&rom, 0x800, b"romhacks");
+    assert!(apply(Kind::APS, &rom, patch.clone()).unwrap() == expected);
+
+    let other_id = rom::with_bytes(&rom, CART_ID_OFFSET as usize, b"XYZ");
+    let other_crc = rom::with_bytes(&rom, CRC_OFFSET as usize + 4, &[0; 4]);
+    for other in [other_id, other_crc, rom[..0x20].to_vec()] {
+      assert!(matches!(
+        apply(Kind::APS, &other, patch.clone()),
+        Err(Error::WrongInputFile)
+      ));
+    }
+  }
+}
@@ -1,15 +1,35 @@
-use crate::crc;
-use crate::patch::Error;
+use crate::patch::header::{Footer, Header};
+use crate::patch::varint::ReadByuuVarInt;
+use crate::patch::{Error, OutputFile};
+use crate::{crc, profile};
 use std::{io, mem};
 
 use crate::io::prelude::*;
 
 pub const MAGIC: &[u8] = b"BPS";
 
+/// Reads the sizes and checksums declared by a BPS patch.
+pub fn read_header(patch: &mut (impl Read + Seek + KnownLen)) -> Result<Header, Error> {
+  let footer = Footer::read(patch)?;
+  patch.seek(io::SeekFrom::Start(0))?;
+  let mut patch = io::BufReader::with_capacity(profile::get().buf_size, patch);
+  if &patch.read_array::<4>()? != b"BPS1" {
+    return Err(Error::BadPatch);
+  }
+  Ok(Header {
+    source_size: Some(patch.read_varint()?),
+    target_size: Some(patch.read_varint()?),
+    source_crc32: Some(footer.source),
+    target_crc32: Some(footer.target),
+    app_header: None,
+    version: None,
+  })
+}
+
 pub fn patch(
   rom: &mut (impl Read + Seek),
   patch: &mut (impl Read + Seek),
-  output: &mut (impl Read + Write + Seek),
+  output: &mut impl OutputFile,
   rom_checksum: crc::Crc32,
   patch_checksum: crc::Crc32,
   patch_eof: u64,
@@ -0,0 +1,225 @@
+//! bsdiff patches in the BSDIFF40 container, as written by Colin Percival's
+//! bsdiff.
+//!
+//! After a 32-byte header, a patch holds three bzip2-compressed blocks: the
+//! controls, the diff bytes and the extra bytes. Each control is three
+//! numbers: how many diff bytes to add to the ROM's bytes, how many extra
+//! bytes to copy after them, and how far to move in the ROM before the next
+//! control. The blocks are read side by side, so their compressed bytes are
+//! held in memory while the output is written.
+
+use crate::io::prelude::*;
+use crate::patch::header::Header;
+use crate::patch::{Error, OutputFile};
+use crate::{buffers, io};
+use bzip2::read::BzDecoder;
+
+pub const MAGIC: &[u8] = b"BSDIFF40";
+
+/// The length of the header: the magic string, the lengths of the
+/// compressed controls and diff bytes, and the size of the patched file.
+const HEADER_LEN: u64 = 32;
+
+/// The size of the chunks diff and extra bytes are applied in.
+const BUF_SIZE: usize = 8 * 1024;
+
+pub fn patch(
+  rom: &mut (impl Read + Seek),
+  patch: &mut (impl Read + Seek),
+  output: &mut impl OutputFile,
+) -> Result<(), Error> {
+  let rom_len = rom.seek(io::SeekFrom::End(0))?;
+  let patch_len = patch.seek(io::SeekFrom::End(0))?;
+  patch.seek(io::SeekFrom::Start(0))?;
+  let (controls_len, diff_len, target_size) = read_start(patch)?;
+  let extra_len = (patch_len - HEADER_LEN)
+    .checked_sub(controls_len)
+    .and_then(|len| len.checked_sub(diff_len))
+    .ok_or(Error::BadPatch)?;
+  let mut read_block = |len: u64| -> Result<Vec<u8>, Error> {
+    let mut block = Vec::new();
+    io::ExactSizeRead::new(&mut *patch, len).read_to_end(&mut block)?;
+    Ok(block)
+  };
+  let controls = read_block(controls_len)?;
+  let diff = read_block(diff_len)?;
+  let extra = read_block(extra_len)?;
+  let mut controls = BzDecoder::new(controls.as_slice());
+  let mut diff = BzDecoder::new(diff.as_slice());
+  let mut extra = BzDecoder::new(extra.as_slice());
+
+  output.seek(io::SeekFrom::Start(0))?;
+  output.set_len(0)?;
+  let mut output =
+    io::TrackedBufWriter::with_capacity(buffers::output_writer(target_size), output)?;
+  let mut rom_buf = [0u8; BUF_SIZE];
+  let mut buf = [0u8; BUF_SIZE];
+  let (mut written, mut rom_pos) = (0u64, 0i64);
+  while written < target_size {
+    let diff_len = to_len(read_offtin(&mut controls)?)?;
+    let extra_len = to_len(read_offtin(&mut controls)?)?;
+    let seek = read_offtin(&mut controls)?;
+    if diff_len
+      .checked_add(extra_len)
+      .is_none_or(|len| len > target_size - written)
+    {
+      return Err(Error::BadPatch);
+    }
+
+    // The diff bytes are added to the ROM's bytes, and to zeroes wherever the
+    // ROM has no bytes.
+    let mut remaining = diff_len;
+    while remaining > 0 {
+      let len = remaining.min(BUF_SIZE as u64) as usize;
+      diff.read_exact(&mut buf[..len])?;
+      let start = rom_pos.clamp(0, rom_len as i64);
+      let end = rom_pos.saturating_add(len as i64).clamp(0, rom_len as i64);
+      if start < end {
+        let in_buf = (start - rom_pos) as usize..(end - rom_pos) as usize;
+        rom.seek(io::SeekFrom::Start(start as u64))?;
+        rom.read_exact(&mut rom_buf[in_buf.clone()])?;
+        for (byte, rom_byte) in buf[in_buf.clone()].iter_mut().zip(&rom_buf[in_buf]) {
+          *byte = byte.wrapping_add(*rom_byte);
+        }
+      }
+      output.write_all(&buf[..len])?;
+      rom_pos = rom_pos.checked_add(len as i64).ok_or(Error::BadPatch)?;
+      remaining -= len as u64;
+    }
+
+    let mut remaining = extra_len;
+    while remaining > 0 {
+      let len = remaining.min(BUF_SIZE as u64) as usize;
+      extra.read_exact(&mut buf[..len])?;
+      output.write_all(&buf[..len])?;
+      remaining -= len as u64;
+    }
+
+    written += diff_len + extra_len;
+    rom_pos = rom_pos.checked_add(seek).ok_or(Error::BadPatch)?;
+  }
+
+  let (_, result) = output.into_inner();
+  result?;
+  Ok(())
+}
+
+/// Reads the size of the patched file, which every bsdiff patch declares.
+pub fn read_header(patch: &mut (impl Read + Seek)) -> Result<Header, Error> {
+  patch.seek(io::SeekFrom::Start(0))?;
+  let (_, _, target_size) = read_start(patch)?;
+  Ok(Header {
+    target_size: Some(target_size),
+    ..Header::default()
+  })
+}
+
+/// Reads the header, returning the lengths of the compressed controls and
+/// diff bytes and the size of the patched file.
+fn read_start(patch: &mut impl Read) -> Result<(u64, u64, u64), Error> {
+  if patch.read_array::<8>()? != MAGIC {
+    return Err(Error::BadPatch);
+  }
+  let controls_len = to_len(read_offtin(patch)?)?;
+  let diff_len = to_len(read_offtin(patch)?)?;
+  let target_size = to_len(read_offtin(patch)?)?;
+  Ok((controls_len, diff_len, target_size))
+}
+
+/// Reads a number as bsdiff stores it: 8 bytes in little-endian order, with
+/// the sign in the highest bit rather than in two's complement.
+fn read_offtin(patch: &mut impl Read) -> Result<i64, Error> {
+  let value = patch.read_u64::<LE>()?;
+  let magnitude = i64::try_from(value & !(1 << 63)).unwrap();
+  Ok(match value >> 63 {
+    0 => magnitude,
+    _ => -magnitude,
+  })
+}
+
+/// Checks that a number read from a patch is a length, which can't be
+/// negative.
+fn to_len(value: i64) -> Result<u64, Error> {
+  u64::try_from(value).map_err(|_| Error::BadPatch)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::patch::Kind;
+  use crate::patch::tests::apply;
+  use romhacks_testkit::{BsdiffBuilder, rom};
+
+  #[test]
+  fn applies_controls() {
+    let rom = rom::random(0x1000, 1);
+    let target = rom::with_bytes(&rom[..0x800], 0x10, b"romhacks");
+    let builder = BsdiffBuilder::new(&rom)
+      .control(&target[..0x400], b"inserted", 0x200)
+      .control(&target[0x600..], b"", -0x100)
+      .control(b"read past the end", b"extended", 0x1000);
+    let expected = builder.target().to_vec();
+    assert!(apply(Kind::BSDIFF, &rom, builder.build()).unwrap() == expected);
+  }
+
+  #[test]
+  fn refuses_negative_lengths() {
+    let rom = rom::random(0x100, 2);
+    for (diff_len, extra_len) in [(-1, 0), (0, -1)] {
+      let patch = BsdiffBuilder::new(&rom)
+        .control(&rom[..0x10], b"", 0)
+        .raw_control(diff_len, extra_len, 0)
+        .target_size(0x20)
+        .build();
+      assert!(matches!(
+        apply(Kind::BSDIFF, &rom, patch),
+        Err(Error::BadPatch)
+      ));
+    }
+    let patch = BsdiffBuilder::new(&rom).target_size(-1).build();
+    assert!(matches!(
+      apply(Kind::BSDIFF, &rom, patch),
+      Err(Error::BadPatch)
+    ));
+  }
+
+  #[test]
+  fn refuses_overflowing_controls() {
+    let rom = rom::random(0x100, 3);
+    // Lengths that add up to more than the rest of the patched file, including
+    // a sum that doesn't fit in an i64.
+    for (diff_len, extra_len) in [(0x11, 0), (0, 0x11), (i64::MAX, i64::MAX)] {
+      let patch = BsdiffBuilder::new(&rom)
+        .control(&rom[..0x10], b"", 0)
+        .raw_control(diff_len, extra_len, 0)
+        .target_size(0x20)
+        .build();
+      assert!(matches!(
+        apply(Kind::BSDIFF, &rom, patch),
+        Err(Error::BadPatch)
+      ));
+    }
+    // A seek past i64::MAX.
+    let patch = BsdiffBuilder::new(&rom)
+      .control(&rom[..0x10], b"", i64::MAX)
+      .build();
+    assert!(matches!(
+      apply(Kind::BSDIFF, &rom, patch),
+      Err(Error::BadPatch)
+    ));
+  }
+
+  #[test]
+  fn refuses_truncated_block() {
+    let rom = rom::random(0x100, 4);
+    let patch = BsdiffBuilder::new(&rom)
+      .control(&rom[..0x80], &rom::random(0x80, 5), 0)
+      .build();
+    // The extra bytes' block comes last.
+    let truncated = patch[..patch.len() - 16].to_vec();
+    assert!(matches!(
+      apply(Kind::BSDIFF, &rom, truncated),
+      Err(Error::BadPatch)
+    ));
+  }
+}
@@ -0,0 +1,216 @@
+//! Patches that are distributed compressed, which saves more space than the
+//! secondary compression some formats have built in.
+//!
+//! Every format seeks within the patch while applying it, so a compressed
+//! patch is decompressed into a temporary file before it's read. Only zstd
+//! is supported, and only in builds with the `zstd` feature; other builds
+//! recognize zstd patches and say how to decompress them by hand.
+
+use crate::error::prelude::*;
+use crate::io::prelude::*;
+use crate::{dirs, fs, i18n, io, patch, temp};
+use std::{fmt, ops, path};
+
+/// The first bytes of a zstd frame.
+pub const ZSTD_MAGIC: &[u8; 4] = b"\x28\xB5\x2F\xFD";
+
+/// The compression levels zstd accepts, from fastest to smallest.
+pub const ZSTD_LEVELS: ops::RangeInclusive<i32> = 1..=22;
+
+/// The level used when none is given, which is also the zstd tool's default.
+#[cfg(feature = "zstd")]
+const ZSTD_DEFAULT_LEVEL: i32 = 3;
+
+/// The longest magic number in [`Compression::SIGNATURES`].
+const MAX_MAGIC_LEN: usize = ZSTD_MAGIC.len();
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Compression {
+  Zstd,
+}
+
+impl Compression {
+  /// The magic number at the start of each format's compressed files.
+  pub const SIGNATURES: [(&'static [u8], Compression); 1] = [(ZSTD_MAGIC, Compression::Zstd)];
+
+  /// Reads the magic number at the start of `file` and identifies how it's
+  /// compressed, if it is. Leaves `file` at its start either way.
+  pub fn detect(file: &mut (impl Read + Seek)) -> io::Result<Option<Self>> {
+    file.seek(io::SeekFrom::Start(0))?;
+    let mut magic = Vec::with_capacity(MAX_MAGIC_LEN);
+    (&mut *file)
+      .take(MAX_MAGIC_LEN as u64)
+      .read_to_end(&mut magic)?;
+    file.seek(io::SeekFrom::Start(0))?;
+    Ok(Self::from_magic(&magic))
+  }
+
+  /// Identifies how a stream is compressed from its first bytes, without
+  /// consuming them.
+  pub fn sniff(stream: &mut impl io::BufReadExt) -> io::Result<Option<Self>> {
+    Ok(Self::from_magic(stream.peek(MAX_MAGIC_LEN)?))
+  }
+
+  fn from_magic(magic: &[u8]) -> Option<Self> {
+    (Self::SIGNATURES.iter())
+      .find(|(signature, _)| magic.starts_with(signature))
+      .map(|(_, compression)| *compression)
+  }
+
+  /// Returns `true` if this build can compress and decompress this format.
+  pub fn is_supported(self) -> bool {
+    match self {
+      Compression::Zstd => cfg!(feature = "zstd"),
+    }
+  }
+
+  /// Decompresses everything `reader` reads into `writer`.
+  pub fn decompress(self, reader: &mut impl Read, writer: &mut impl Write) -> Result<(), Error> {
+    match self {
+      #[cfg(feature = "zstd")]
+      Compression::Zstd => Ok(zstd::stream::copy_decode(reader, writer)?),
+      #[cfg(not(feature = "zstd"))]
+      compression => {
+        let _ = (reader, writer);
+        Err(Error::Unsupported { compression })
+      }
+    }
+  }
+
+  /// Compresses `data` at `level`, or at the format's default level.
+  pub fn compress(self, data: &[u8], level: Option<i32>) -> Result<Vec<u8>, Error> {
+    match self {
+      #[cfg(feature = "zstd")]
+      Compression::Zstd => Ok(zstd::stream::encode_all(
+        data,
+        level.unwrap_or(ZSTD_DEFAULT_LEVEL),
+      )?),
+      #[cfg(not(feature = "zstd"))]
+      compression => {
+        let _ = (data, level);
+        Err(Error::Unsupported { compression })
+      }
+    }
+  }
+}
+
+impl fmt::Display for Compression {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Compression::Zstd => write!(f, "zstd"),
+    }
+  }
+}
+
+/// A compression format and the level to compress at, given as "zstd" or
+/// "zstd:19".
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Setting {
+  pub compression: Compression,
+  pub level: Option<i32>,
+}
+
+impl Setting {
+  pub fn compress(self, data: &[u8]) -> Result<Vec<u8>, Error> {
+    self.compression.compress(data, self.level)
+  }
+}
+
+/// Parses a [`Setting`] from the command line.
+pub fn parse_setting(arg: &str) -> Result<Setting, String> {
+  let (name, level) = match arg.split_once(':') {
+    Some((name, level)) => (name, Some(level)),
+    None => (arg, None),
+  };
+  let (compression, levels) = match name.to_ascii_lowercase().as_str() {
+    "zstd" => (Compression::Zstd, ZSTD_LEVELS),
+    _ => {
+      return Err(i18n::format(
+        "romhacks::compression::unknown",
+        &[("name", &name)],
+      ));
+    }
+  };
+  let level = match level.map(str::parse::<i32>) {
+    None => None,
+    Some(Ok(level)) if levels.contains(&level) => Some(level),
+    Some(_) => {
+      return Err(i18n::format(
+        "romhacks::compression::bad_level",
+        &[
+          ("compression", &compression),
+          ("min", levels.start()),
+          ("max", levels.end()),
+        ],
+      ));
+    }
+  };
+  Ok(Setting { compression, level })
+}
+
+/// Opens a patch, decompressing it first if it's compressed. The
+/// decompressed copy is deleted once the returned file is closed.
+///
+/// Failing to read the patch is reported as [`Error::IO`], so callers can
+/// tell it apart from errors in applying it.
+pub fn open(path: &path::Path) -> Result<fs::File, patch::Error> {
+  Ok(open_decompressed(path)?)
+}
+
+fn open_decompressed(path: &path::Path) -> Result<fs::File, Error> {
+  let mut file = io::retry(|| fs::File::open(path))?;
+  let Some(compression) = Compression::detect(&mut file)? else {
+    return Ok(file);
+  };
+  decompress_to_temp_file(compression, &mut io::BufReader::new(file))
+}
+
+/// Decompresses a patch read from a stream, such as standard input, into a
+/// file like [`open`] does. Since a compressed patch's format can't be told
+/// from its first bytes, it can't be identified without this.
+pub fn spool(compression: Compression, stream: &mut impl Read) -> Result<fs::File, patch::Error> {
+  Ok(decompress_to_temp_file(compression, stream)?)
+}
+
+fn decompress_to_temp_file(
+  compression: Compression,
+  reader: &mut impl Read,
+) -> Result<fs::File, Error> {
+  if !compression.is_supported() {
+    return Err(Error::Unsupported { compression });
+  }
+  let mut decompressed = anonymous_temp_file(&dirs::temp_dir())?;
+  compression.decompress(reader, &mut decompressed)?;
+  decompressed.seek(io::SeekFrom::Start(0))?;
+  Ok(decompressed)
+}
+
+/// Creates a file in `dir` that's deleted when it's closed, even if the
+/// program is killed first where the platform allows it.
+fn anonymous_temp_file(dir: &path::Path) -> io::Result<fs::File> {
+  let mut options = fs::OpenOptions::new();
+  options.read(true).write(true).create_new(true);
+  #[cfg(windows)]
+  {
+    use crate::fs::os::windows::fs::OpenOptionsExt;
+    options.custom_flags(windows_sys::Win32::Storage::FileSystem::FILE_FLAG_DELETE_ON_CLOSE);
+  }
+  let file = temp::create_with(dir, temp::DEFAULT_NAME, |path| options.open(path))?;
+  // Unix keeps an unlinked file's contents until its last handle is closed.
+  #[cfg(not(windows))]
+  fs::remove_file(file.path())?;
+  Ok(file)
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error("{}", i18n::format("romhacks::compression::unsupported", &[("compression", compression)]))]
+  #[diagnostic(
+    code(romhacks::compression::unsupported),
+    help("{}", i18n::text("romhacks::compression::unsupported::help"))
+  )]
+  Unsupported { compression: Compression },
+}
@@ -0,0 +1,74 @@
+//! A dyn-compatible interface to the patch formats.
+//!
+//! [`Patcher::patch`] is generic over the types of its streams, so every
+//! combination of stream types gets its own copy of each format's code.
+//! Code that only has `&mut dyn` streams, such as a
+//! [`PatchJob`](super::job::PatchJob), applies patches through
+//! [`PatchFormat`] instead, so each format is compiled once for them.
+//! Plugins implement it too, since the streams they're given can be of any
+//! type.
+
+use super::{Capabilities, Error, Kind, OutputFile, Patcher};
+use crate::crc::Crc32;
+use crate::io::prelude::*;
+use std::fmt;
+
+/// A stream that a ROM can be read from.
+pub trait Source: Read + Seek {}
+
+impl<T: Read + Seek + ?Sized> Source for T {}
+
+/// A stream that a patch can be read from. Its length must be known, since
+/// some formats store checksums at the end.
+pub trait PatchSource: Read + Seek + KnownLen {}
+
+impl<T: Read + Seek + KnownLen + ?Sized> PatchSource for T {}
+
+/// A patch format whose patches can be applied to streams of any type.
+pub trait PatchFormat: fmt::Debug + Send + Sync {
+  fn kind(&self) -> Kind;
+
+  fn capabilities(&self) -> Capabilities {
+    self.kind().capabilities()
+  }
+
+  /// Applies `patch` to `rom`, writing the patched file to `output`. Formats
+  /// that patch in place expect `output` to already hold a copy of `rom`.
+  /// The arguments are the same as those of [`Patcher::patch`].
+  fn apply(
+    &self,
+    rom: &mut dyn Source,
+    patch: &mut dyn PatchSource,
+    output: &mut dyn OutputFile,
+    rom_checksum: Crc32,
+    patch_checksum: Crc32,
+    patch_eof: u64,
+  ) -> Result<(), Error>;
+}
+
+impl PatchFormat for Patcher {
+  fn kind(&self) -> Kind {
+    self.kind
+  }
+
+  fn apply(
+    &self,
+    rom: &mut dyn Source,
+    patch: &mut dyn PatchSource,
+    output: &mut dyn OutputFile,
+    rom_checksum: Crc32,
+    patch_checksum: Crc32,
+    patch_eof: u64,
+  ) -> Result<(), Error> {
+    // The generic code needs sized types, which mutable references to trait
+    // objects are.
+    self.patch(
+      &mut &mut *rom,
+      &mut &mut *patch,
+      &mut &mut *output,
+      rom_checksum,
+      patch_checksum,
+      patch_eof,
+    )
+  }
+}
@@ -0,0 +1,221 @@
+//! Patches in the Generic Diff Format (GDIFF), as described in the W3C's
+//! note of 1997.
+//!
+//! After the magic number and a version byte, a patch is a list of
+//! commands, each of which appends either bytes stored in the patch or a
+//! range of the ROM's bytes to the patched file. A command's byte says which
+//! it is and how wide its numbers are, which are stored in big-endian order.
+//! Numbers wider than two bytes are signed, so negative ones are refused.
+
+use crate::io::prelude::*;
+use crate::patch::{Error, OutputFile};
+use crate::{buffers, io};
+
+pub const MAGIC: &[u8] = &[0xD1, 0xFF, 0xD1, 0xFF];
+
+/// The size of the chunks data and copies are written in.
+const BUF_SIZE: usize = 8 * 1024;
+
+mod command {
+  pub const EOF: u8 = 0;
+  /// Commands up to this one are followed by that many bytes of data.
+  pub const MAX_INLINE_DATA: u8 = 246;
+  pub const DATA_USHORT: u8 = 247;
+  pub const DATA_INT: u8 = 248;
+  pub const COPY_USHORT_UBYTE: u8 = 249;
+  pub const COPY_USHORT_USHORT: u8 = 250;
+  pub const COPY_USHORT_INT: u8 = 251;
+  pub const COPY_INT_UBYTE: u8 = 252;
+  pub const COPY_INT_USHORT: u8 = 253;
+  pub const COPY_INT_INT: u8 = 254;
+  pub const COPY_LONG_INT: u8 = 255;
+}
+
+/// What a command appends to the patched file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Command {
+  /// The next bytes of the patch.
+  Data {
+    len: u64,
+  },
+  /// Bytes of the ROM.
+  Copy {
+    position: u64,
+    len: u64,
+  },
+  Eof,
+}
+
+pub fn patch(
+  rom: &mut (impl Read + Seek),
+  patch: &mut (impl Read + Seek),
+  output: &mut impl OutputFile,
+) -> Result<(), Error> {
+  let rom_len = rom.seek(io::SeekFrom::End(0))?;
+  let patch_len = patch.seek(io::SeekFrom::End(0))?;
+  patch.seek(io::SeekFrom::Start(0))?;
+  let mut patch = io::BufReader::with_capacity(buffers::patch_reader(patch_len), patch);
+  if patch.read_array::<4>()? != MAGIC {
+    return Err(Error::BadPatch);
+  }
+  // The version was checked by `header::read_version`.
+  let _version = patch.read_u8()?;
+
+  output.seek(io::SeekFrom::Start(0))?;
+  output.set_len(0)?;
+  let mut output = io::TrackedBufWriter::with_capacity(buffers::hunk_writer(patch_len), output)?;
+  let mut buf = [0u8; BUF_SIZE];
+  loop {
+    let (source, len): (&mut dyn Read, u64) = match read_command(&mut patch)? {
+      Command::Data { len } => (&mut patch, len),
+      Command::Copy { position, len } => {
+        if position.checked_add(len).is_none_or(|end| end > rom_len) {
+          return Err(Error::BadPatch);
+        }
+        rom.seek(io::SeekFrom::Start(position))?;
+        (rom, len)
+      }
+      Command::Eof => break,
+    };
+    let mut remaining = len;
+    while remaining > 0 {
+      let len = remaining.min(BUF_SIZE as u64) as usize;
+      source.read_exact(&mut buf[..len])?;
+      output.write_all(&buf[..len])?;
+      remaining -= len as u64;
+    }
+  }
+
+  let (_, result) = output.into_inner();
+  result?;
+  Ok(())
+}
+
+fn read_command(patch: &mut impl Read) -> Result<Command, Error> {
+  use command::*;
+  let copy = |position: u64, len: u64| Command::Copy { position, len };
+  Ok(match patch.read_u8()? {
+    EOF => Command::Eof,
+    len @ 1..=MAX_INLINE_DATA => Command::Data { len: len.into() },
+    DATA_USHORT => Command::Data { len: patch.read_u16::<BE>()?.into() },
+    DATA_INT => Command::Data { len: read_int(patch)? },
+    COPY_USHORT_UBYTE => copy(patch.read_u16::<BE>()?.into(), patch.read_u8()?.into()),
+    COPY_USHORT_USHORT => copy(
+      patch.read_u16::<BE>()?.into(),
+      patch.read_u16::<BE>()?.into(),
+    ),
+    COPY_USHORT_INT => copy(patch.read_u16::<BE>()?.into(), read_int(patch)?),
+    COPY_INT_UBYTE => copy(read_int(patch)?, patch.read_u8()?.into()),
+    COPY_INT_USHORT => copy(read_int(patch)?, patch.read_u16::<BE>()?.into()),
+    COPY_INT_INT => copy(read_int(patch)?, read_int(patch)?),
+    COPY_LONG_INT => copy(read_long(patch)?, read_int(patch)?),
+  })
+}
+
+/// Reads an `int`, which is signed, as a position or length.
+fn read_int(patch: &mut impl Read) -> Result<u64, Error> {
+  u64::try_from(patch.read_i32::<BE>()?).map_err(|_| Error::BadPatch)
+}
+
+/// Reads a `long`, which is signed, as a position.
+fn read_long(patch: &mut impl Read) -> Result<u64, Error> {
+  u64::try_from(patch.read_i64::<BE>()?).map_err(|_| Error::BadPatch)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::patch::Kind;
+  use crate::patch::tests::apply;
+  use romhacks_testkit::{GdiffBuilder, rom};
+
+  #[test]
+  fn appends_data() {
+    let rom = rom::random(0x100, 1);
+    let builder = GdiffBuilder::new(&rom)
+      .data(b"romhacks")
+      .data(&rom::random(246, 2))
+      .data(&rom::random(0x1000, 3))
+      .data(&rom::random(0x10000, 4));
+    let expected = builder.target().to_vec();
+    assert!(apply(Kind::GDIFF, &rom, builder.build()).unwrap() == expected);
+  }
+
+  #[test]
+  fn copies_each_width() {
+    let rom = rom::random(0x30000, 5);
+    let builder = GdiffBuilder::new(&rom)
+      .copy(0x10, 0x20)
+      .copy(0x100, 0x1000)
+      .copy(0, 0x20000)
+      .copy(0x10010, 0x20)
+      .copy(0x10100, 0x1000)
+      .copy(0x10000, 0x20000)
+      .data(b"romhacks");
+    let expected = builder.target().to_vec();
+    assert!(apply(Kind::GDIFF, &rom, builder.build()).unwrap() == expected);
+
+    // A long position, which the builder only writes past i32::MAX.
+    let patch = GdiffBuilder::new(&rom)
+      .raw(&[command::COPY_LONG_INT])
+      .raw(&0x100i64.to_be_bytes())
+      .raw(&8i32.to_be_bytes())
+      .build();
+    assert!(apply(Kind::GDIFF, &rom, patch).unwrap() == rom[0x100..0x108]);
+  }
+
+  #[test]
+  fn refuses_negative_numbers() {
+    let rom = rom::random(0x100, 6);
+    let commands = [
+      [&[command::DATA_INT][..], &(-1i32).to_be_bytes()].concat(),
+      [&[command::COPY_INT_UBYTE][..], &(-1i32).to_be_bytes(), &[1]].concat(),
+      [
+        &[command::COPY_USHORT_INT][..],
+        &[0, 0],
+        &(-1i32).to_be_bytes(),
+      ]
+      .concat(),
+      [
+        &[command::COPY_LONG_INT][..],
+        &(-1i64).to_be_bytes(),
+        &[0, 0, 0, 1],
+      ]
+      .concat(),
+    ];
+    for command in commands {
+      let patch = GdiffBuilder::new(&rom).raw(&command).build();
+      assert!(matches!(
+        apply(Kind::GDIFF, &rom, patch),
+        Err(Error::BadPatch)
+      ));
+    }
+  }
+
+  #[test]
+  fn refuses_copies_past_rom() {
+    let rom = rom::random(0x100, 7);
+    let commands = [
+      [&[command::COPY_USHORT_UBYTE][..], &[0, 0xF9], &[8]].concat(),
+      [
+        &[command::COPY_INT_UBYTE][..],
+        &i32::MAX.to_be_bytes(),
+        &[1],
+      ]
+      .concat(),
+      [
+        &[command::COPY_LONG_INT][..],
+        &i64::MAX.to_be_bytes(),
+        &[0, 0, 0, 1],
+      ]
+      .concat(),
+    ];
+    for command in commands {
+      let patch = GdiffBuilder::new(&rom).raw(&command).build();
+      assert!(matches!(
+        apply(Kind::GDIFF, &rom, patch),
+        Err(Error::BadPatch)
+      ));
+    }
+  }
+}
@@ -0,0 +1,129 @@
+use crate::crc::Crc32;
+use crate::io;
+use crate::io::prelude::*;
+use crate::patch::{Error, Kind, aps_gba, aps_n64, bps, bsdiff, ninja2, ups, vcd};
+
+/// Metadata that can be read from a patch's header and footer without applying it.
+///
+/// Each field is `None` if the patch format doesn't record that information.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Header {
+  pub source_size: Option<u64>,
+  pub target_size: Option<u64>,
+  pub source_crc32: Option<Crc32>,
+  pub target_crc32: Option<Crc32>,
+  /// The xdelta3 application header, which typically names the source and target files.
+  pub app_header: Option<String>,
+  /// The version of the format the patch declares, such as "3.0" for a PPF3
+  /// patch.
+  pub version: Option<String>,
+}
+
+/// Reads the metadata of a patch of the given kind.
+pub fn read(kind: Kind, patch: &mut (impl Read + Seek + KnownLen)) -> Result<Header, Error> {
+  let version = read_version(kind, patch)?;
+  let header = match kind {
+    Kind::UPS => ups::read_header(patch)?,
+    Kind::BPS => bps::read_header(patch)?,
+    Kind::RUP => ninja2::read_header(patch)?,
+    Kind::APS => aps_n64::read_header(patch)?,
+    Kind::APSGBA => aps_gba::read_header(patch)?,
+    Kind::BSDIFF => bsdiff::read_header(patch)?,
+    Kind::VCD => Header {
+      app_header: vcd::read_app_header(patch)?
+        .map(|bytes| String::from_utf8_lossy(&bytes).into_owned()),
+      ..Header::default()
+    },
+    Kind::IPS | Kind::PPF | Kind::GDIFF | Kind::IPS32 => Header::default(),
+    #[cfg(feature = "plugins")]
+    Kind::Plugin(_) => Header::default(),
+  };
+  Ok(Header { version, ..header })
+}
+
+/// Where a format declares its version in a patch, and the versions that
+/// can be applied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct VersionField {
+  /// The offset of the version from the start of the patch.
+  pub offset: u64,
+  pub len: usize,
+  pub supported: &'static [&'static str],
+}
+
+impl VersionField {
+  /// Returns where patches of `kind` declare their version, if they do.
+  pub fn of(kind: Kind) -> Option<Self> {
+    // UPS and BPS end their magic strings with a digit, PPF with two, and
+    // Vcdiff and GDIFF have a version byte after their magic numbers. NINJA's
+    // magic string is followed by its major version, as a digit.
+    match kind {
+      Kind::IPS | Kind::IPS32 => None,
+      Kind::UPS | Kind::BPS => Some(Self { offset: 3, len: 1, supported: &["1"] }),
+      Kind::PPF => Some(Self {
+        offset: 3,
+        len: 2,
+        supported: &["1.0", "2.0", "3.0"],
+      }),
+      Kind::VCD => Some(Self { offset: 3, len: 1, supported: &["0"] }),
+      Kind::RUP => Some(Self { offset: 5, len: 1, supported: &["2"] }),
+      Kind::GDIFF => Some(Self { offset: 4, len: 1, supported: &["4"] }),
+      Kind::APS | Kind::APSGBA | Kind::BSDIFF => None,
+      #[cfg(feature = "plugins")]
+      Kind::Plugin(_) => None,
+    }
+  }
+}
+
+/// Reads the version of the format a patch declares, for formats that
+/// declare one, and leaves the patch where it was. Fails with
+/// [`Error::UnsupportedVersion`] if it's a version that can't be applied,
+/// such as a newer one.
+pub fn read_version(kind: Kind, patch: &mut (impl Read + Seek)) -> Result<Option<String>, Error> {
+  let Some(field) = VersionField::of(kind) else {
+    return Ok(None);
+  };
+  let position = patch.stream_position()?;
+  patch.seek(io::SeekFrom::Start(field.offset))?;
+  let mut bytes = Vec::with_capacity(field.len);
+  io::ExactSizeRead::new(&mut *patch, field.len as u64).read_to_end(&mut bytes)?;
+  patch.seek(io::SeekFrom::Start(position))?;
+  let found = match kind {
+    Kind::PPF => format!("{}.{}", char::from(bytes[0]), char::from(bytes[1])),
+    Kind::VCD | Kind::GDIFF => bytes[0].to_string(),
+    _ => char::from(bytes[0]).to_string(),
+  };
+  match field.supported.contains(&found.as_str()) {
+    true => Ok(Some(found)),
+    false => Err(Error::UnsupportedVersion { found, supported: field.supported.join(", ") }),
+  }
+}
+
+/// The checksums stored in the last 12 bytes of UPS and BPS patches.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Footer {
+  pub source: Crc32,
+  pub target: Crc32,
+  pub patch: Crc32,
+}
+
+impl Footer {
+  pub const SIZE: usize = 3 * size_of::<u32>();
+
+  /// Returns the position of the footer in a UPS or BPS patch.
+  pub fn position(patch: &impl KnownLen) -> io::Result<u64> {
+    (patch.known_len()?)
+      .checked_sub(Self::SIZE as u64)
+      .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))
+  }
+
+  /// Seeks to the footer of a UPS or BPS patch and reads it.
+  pub fn read(patch: &mut (impl Read + Seek + KnownLen)) -> io::Result<Self> {
+    patch.seek(io::SeekFrom::Start(Self::position(patch)?))?;
+    Ok(Self {
+      source: Crc32::new(patch.read_u32::<LE>()?),
+      target: Crc32::new(patch.read_u32::<LE>()?),
+      patch: Crc32::new(patch.read_u32::<LE>()?),
+    })
+  }
+}
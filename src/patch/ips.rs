@@ -1,45 +1,89 @@
 use crate::io::prelude::*;
-use crate::{io, mem, patch};
+use crate::patch::ops::Op;
+use crate::{buffers, io, mem, patch, profile};
+use std::borrow::Cow;
 use std::num;
 
 pub const MAGIC: &[u8] = b"PAT";
 
+/// IPS or [IPS32](super::ips32), which differ only in the string before the
+/// records, the one after them and how wide offsets are.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Variant {
+  Ips,
+  Ips32,
+}
+
+impl Variant {
+  fn header(self) -> &'static [u8] {
+    match self {
+      Variant::Ips => b"PATCH",
+      Variant::Ips32 => b"IPS32",
+    }
+  }
+
+  /// The marker after the records, which is as long as an offset.
+  fn eof(self) -> &'static [u8] {
+    match self {
+      Variant::Ips => b"EOF",
+      Variant::Ips32 => b"EEOF",
+    }
+  }
+
+  /// The width of offsets and of the truncation size, in bytes.
+  fn offset_len(self) -> usize {
+    self.eof().len()
+  }
+
+  /// Checks that a record at `offset` starts within the format's
+  /// [`max_file_size`](patch::Capabilities::max_file_size). A record that
+  /// starts within it can write past it, into a larger ROM.
+  fn check_record(self, offset: u64) -> Result<(), patch::Error> {
+    let kind = match self {
+      Variant::Ips => patch::Kind::IPS,
+      Variant::Ips32 => patch::Kind::IPS32,
+    };
+    match offset < kind.capabilities().max_file_size {
+      true => Ok(()),
+      false => Err(patch::Error::FileTooLarge),
+    }
+  }
+}
+
 pub fn patch(
-  rom: &mut (impl Write + Seek + Resize),
+  rom: &mut impl patch::OutputFile,
   patch: &mut (impl Read + Seek),
 ) -> Result<(), patch::Error> {
-  const FOOTER_LEN: usize = 6;
-  let patch_eof = patch.seek(io::SeekFrom::End(-(FOOTER_LEN as i64)))? + FOOTER_LEN as u64;
-  let (end_of_records, new_file_size) = match (&patch.read_array::<FOOTER_LEN>()?).split_at(3) {
-    (_, b"EOF") => (patch_eof - 3, None),
-    (b"EOF", new_size) => {
-      let buf = mem::init([0u8; 4], |buf| {
-        (&mut buf[1..]).copy_from_slice(new_size);
-      });
-      let new_file_size: u32 = u32::from_be_bytes(buf);
-      let new_size = num::NonZeroU32::new(new_file_size).ok_or(patch::Error::BadPatch)?;
-      (patch_eof - 6, Some(new_size))
-    }
-    _ => return Err(patch::Error::BadPatch),
-  };
+  apply(Variant::Ips, rom, patch)
+}
+
+pub(super) fn apply(
+  variant: Variant,
+  rom: &mut impl patch::OutputFile,
+  patch: &mut (impl Read + Seek),
+) -> Result<(), patch::Error> {
+  let (end_of_records, new_file_size) = read_footer(variant, patch)?;
 
   patch.seek(io::SeekFrom::Start(0))?;
-  let mut patch = io::BufReader::new(patch).take(end_of_records);
-  if &patch.read_array::<5>()? != MAGIC {
+  let mut patch =
+    io::BufReader::with_capacity(buffers::patch_reader(end_of_records), patch).take(end_of_records);
+  if patch.read_array::<5>()? != variant.header() {
     return Err(patch::Error::BadPatch);
   }
 
   loop {
-    let offset: u32 = patch.read_u24::<BE>()?;
-    rom.seek(io::SeekFrom::Start(offset.into()))?;
+    let offset = patch.read_uint::<BE>(variant.offset_len())?;
+    rom.seek(io::SeekFrom::Start(offset))?;
     match num::NonZeroU16::new(patch.read_u16::<BE>()?) {
       Some(hunk_size) => {
-        let mut hunk = (&mut patch).take(hunk_size.get().into());
+        variant.check_record(offset)?;
+        let mut hunk = io::ExactSizeRead::new(&mut patch, hunk_size.get().into());
         io::copy(&mut hunk, rom)?;
       }
       None => {
         let size = num::NonZeroU16::new(patch.read_u16::<BE>()?).ok_or(patch::Error::BadPatch)?;
         let value: u8 = patch.read_u8()?;
+        variant.check_record(offset)?;
         io::copy(&mut io::repeat(value).take(size.get().into()), rom)?;
       }
     }
@@ -55,3 +99,149 @@ pub fn patch(
   rom.flush()?;
   Ok(())
 }
+
+/// Passes each record in an IPS patch to `visitor`.
+pub fn decode(
+  patch: &mut (impl Read + Seek),
+  visitor: &mut impl patch::ops::Visitor,
+) -> Result<(), patch::Error> {
+  decode_records(Variant::Ips, patch, visitor)
+}
+
+pub(super) fn decode_records(
+  variant: Variant,
+  patch: &mut (impl Read + Seek),
+  visitor: &mut impl patch::ops::Visitor,
+) -> Result<(), patch::Error> {
+  let (end_of_records, new_file_size) = read_footer(variant, patch)?;
+
+  patch.seek(io::SeekFrom::Start(0))?;
+  let mut patch =
+    io::BufReader::with_capacity(buffers::patch_reader(end_of_records), patch).take(end_of_records);
+  if patch.read_array::<5>()? != variant.header() {
+    return Err(patch::Error::BadPatch);
+  }
+
+  let mut data = Vec::new();
+  while patch.limit() > 0 {
+    let offset = patch.read_uint::<BE>(variant.offset_len())?;
+    match num::NonZeroU16::new(patch.read_u16::<BE>()?) {
+      Some(hunk_size) => {
+        variant.check_record(offset)?;
+        data.clear();
+        io::ExactSizeRead::new(&mut patch, hunk_size.get().into()).read_to_end(&mut data)?;
+        visitor.visit(&Op::Write { offset, data: Cow::Borrowed(&data) })?;
+      }
+      None => {
+        let len = num::NonZeroU16::new(patch.read_u16::<BE>()?).ok_or(patch::Error::BadPatch)?;
+        let byte: u8 = patch.read_u8()?;
+        variant.check_record(offset)?;
+        visitor.visit(&Op::Fill { offset, len: len.get().into(), byte })?;
+      }
+    }
+  }
+
+  if let Some(new_size) = new_file_size {
+    visitor.visit(&Op::Resize { len: new_size.get().into() })?;
+  }
+  Ok(())
+}
+
+/// Writes `ops` as an IPS patch.
+///
+/// IPS can't express expected contents or XORs, so [`Op::Expect`] and
+/// [`Op::Xor`] fail with [`patch::Error::UnsupportedPatchFeature`].
+pub fn encode(ops: &[Op<'_>], output: &mut impl Write) -> Result<(), patch::Error> {
+  encode_records(Variant::Ips, ops, output)
+}
+
+pub(super) fn encode_records(
+  variant: Variant,
+  ops: &[Op<'_>],
+  output: &mut impl Write,
+) -> Result<(), patch::Error> {
+  const MAX_HUNK_SIZE: usize = u16::MAX as usize;
+  let offset_len = variant.offset_len();
+  let max_offset: u64 = (1 << (8 * offset_len)) - 1;
+  // A record at this offset would be mistaken for the "EOF" marker.
+  let eof_offset = (variant.eof().iter()).fold(0u64, |offset, &byte| offset << 8 | u64::from(byte));
+  let be_bytes = |value: u64| value.to_be_bytes()[size_of::<u64>() - offset_len..].to_vec();
+
+  let write_offset = |output: &mut dyn Write, offset: u64| -> Result<(), patch::Error> {
+    match offset {
+      _ if offset == eof_offset => Err(patch::Error::UnsupportedPatchFeature),
+      _ if offset <= max_offset => Ok(output.write_all(&be_bytes(offset))?),
+      _ => Err(patch::Error::FileTooLarge),
+    }
+  };
+
+  let mut output = io::BufWriter::with_capacity(profile::get().buf_size, output);
+  output.write_all(variant.header())?;
+  let mut new_size = None;
+  for op in ops {
+    match op {
+      Op::Write { offset, data } => {
+        for (i, hunk) in data.chunks(MAX_HUNK_SIZE).enumerate() {
+          write_offset(&mut output, offset + (i * MAX_HUNK_SIZE) as u64)?;
+          output.write_all(&(hunk.len() as u16).to_be_bytes())?;
+          output.write_all(hunk)?;
+        }
+      }
+      Op::Fill { offset, len, byte } => {
+        let mut pos = 0;
+        while pos < *len {
+          let run = (len - pos).min(MAX_HUNK_SIZE as u64);
+          write_offset(&mut output, offset + pos)?;
+          output.write_all(&0u16.to_be_bytes())?;
+          output.write_all(&(run as u16).to_be_bytes())?;
+          output.write_all(&[*byte])?;
+          pos += run;
+        }
+      }
+      Op::Resize { len } => new_size = Some(*len),
+      Op::Expect { .. } | Op::Xor { .. } => return Err(patch::Error::UnsupportedPatchFeature),
+    }
+  }
+  output.write_all(variant.eof())?;
+  if let Some(new_size) = new_size {
+    if new_size == 0 || new_size > max_offset {
+      return Err(patch::Error::FileTooLarge);
+    }
+    output.write_all(&be_bytes(new_size))?;
+  }
+  output.flush()?;
+  Ok(())
+}
+
+/// Finds the end of the records and the truncation size, if any, from the
+/// "EOF" marker at the end of an IPS patch. With `ips.lenient`, the records
+/// of a patch without the marker run to its end.
+fn read_footer(
+  variant: Variant,
+  patch: &mut (impl Read + Seek),
+) -> Result<(u64, Option<num::NonZeroU32>), patch::Error> {
+  // The marker, followed by the truncation size if there is one.
+  let footer_len = 2 * variant.offset_len();
+  let lenient = patch::options::get().ips.lenient;
+  let patch_eof = patch.seek(io::SeekFrom::End(-(footer_len as i64)))? + footer_len as u64;
+  let mut footer = [0u8; 8];
+  patch.read_exact(&mut footer[..footer_len])?;
+  let eof = variant.eof();
+  let (end_of_records, new_file_size) = match footer[..footer_len].split_at(eof.len()) {
+    (_, marker) if marker == eof => (patch_eof - eof.len() as u64, None),
+    (marker, new_size) if marker == eof => {
+      let buf = mem::init([0u8; 4], |buf| {
+        (&mut buf[4 - new_size.len()..]).copy_from_slice(new_size);
+      });
+      let new_file_size: u32 = u32::from_be_bytes(buf);
+      let new_size = match num::NonZeroU32::new(new_file_size) {
+        None if !lenient => return Err(patch::Error::BadPatch),
+        new_size => new_size,
+      };
+      (patch_eof - footer_len as u64, new_size)
+    }
+    _ if lenient => (patch_eof, None),
+    _ => return Err(patch::Error::BadPatch),
+  };
+  Ok((end_of_records, new_file_size))
+}
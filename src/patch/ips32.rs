@@ -0,0 +1,50 @@
+//! IPS32, which some tools write for ROMs larger than the 16 MiB IPS can
+//! address. It's IPS with "IPS32" in place of "PATCH", 32-bit offsets and
+//! truncation size, and "EEOF" in place of "EOF".
+
+use crate::io::prelude::*;
+use crate::patch::ips::{self, Variant};
+use crate::patch::ops::{Op, Visitor};
+use crate::patch::{Error, OutputFile};
+
+pub const MAGIC: &[u8] = b"IPS32";
+
+pub fn patch(rom: &mut impl OutputFile, patch: &mut (impl Read + Seek)) -> Result<(), Error> {
+  ips::apply(Variant::Ips32, rom, patch)
+}
+
+/// Passes each record in an IPS32 patch to `visitor`.
+pub fn decode(patch: &mut (impl Read + Seek), visitor: &mut impl Visitor) -> Result<(), Error> {
+  ips::decode_records(Variant::Ips32, patch, visitor)
+}
+
+/// Writes `ops` as an IPS32 patch, which can't express what
+/// [IPS](ips::encode) can't either.
+pub fn encode(ops: &[Op<'_>], output: &mut impl Write) -> Result<(), Error> {
+  ips::encode_records(Variant::Ips32, ops, output)
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::patch::Kind;
+  use crate::patch::tests::apply;
+  use romhacks_testkit::ips::MAX_OFFSET;
+  use romhacks_testkit::{IpsBuilder, rom};
+
+  #[test]
+  fn records_past_ips_offsets() {
+    let len = MAX_OFFSET as usize + 0x101;
+    let rom = rom::filled(len, 0);
+    let patch = IpsBuilder::ips32()
+      .hunk(0x10, b"romhacks")
+      .hunk(MAX_OFFSET + 1, b"past 16 MiB")
+      .rle(MAX_OFFSET + 0x80, 0xFF, 0x80)
+      .truncate(len as u32 + 0x10)
+      .build();
+    let expected = rom::with_bytes(&rom, 0x10, b"romhacks");
+    let expected = rom::with_bytes(&expected, MAX_OFFSET as usize + 1, b"past 16 MiB");
+    let expected = rom::with_bytes(&expected, MAX_OFFSET as usize + 0x80, &[0xFF; 0x80]);
+    let expected = rom::with_bytes(&expected, len, &[0; 0x10]);
+    assert!(apply(Kind::IPS32, &rom, patch).unwrap() == expected);
+  }
+}
@@ -0,0 +1,388 @@
+//! A builder for applying a patch, for commands that only need the patched
+//! file, such as `rebase` and `match --try-apply`.
+//!
+//! [`Patcher::patch`] takes the streams and the checksums it needs as
+//! positional arguments, and leaves hashing, copying the ROM for formats that
+//! patch in place and checking the result to its caller. A [`PatchJob`] does
+//! all of that and returns a [`Report`] of what happened:
+//!
+//! ```ignore
+//! let report = PatchJob::new()
+//!   .source(&mut rom)
+//!   .patch(&mut patch)
+//!   .output(&mut output)
+//!   .expect_output(crc32)
+//!   .strict(false)
+//!   .run()?;
+//! ```
+//!
+//! Options are set with methods, so new ones don't break existing callers.
+
+use super::dynamic::{PatchFormat, PatchSource, Source};
+use super::{Kind, OutputFile, Patcher};
+use crate::crc::Crc32;
+use crate::error::prelude::*;
+use crate::io::prelude::*;
+use crate::report::{Phase, Timing};
+use crate::{i18n, io, patch};
+use std::{fmt, time};
+
+/// The size and checksum of one of the files in a job.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Summary {
+  pub size: u64,
+  pub crc32: Crc32,
+}
+
+/// What happened when a [`PatchJob`] ran. The same facts as in a
+/// [`Report`](crate::report::Report), without the paths and the hack, which a job doesn't
+/// know about.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Report {
+  pub format: Kind,
+  pub source: Summary,
+  pub patch: Summary,
+  pub output: Summary,
+  /// Unmet expectations of a job that isn't strict, in order.
+  pub warnings: Vec<String>,
+  /// How long each phase took, in order.
+  pub timings: Vec<Timing>,
+}
+
+/// Applying a patch to a source, configured with builder methods. The
+/// source, the patch and the output must be set before it's run.
+#[must_use]
+#[derive(Default)]
+pub struct PatchJob<'a> {
+  source: Option<&'a mut dyn Source>,
+  source_crc32: Option<Crc32>,
+  patch: Option<&'a mut dyn PatchSource>,
+  format: Option<Kind>,
+  output: Option<&'a mut dyn OutputFile>,
+  strict: bool,
+  expected_source: Option<Crc32>,
+  expected_output: Option<Crc32>,
+}
+
+impl<'a> PatchJob<'a> {
+  /// Creates a strict job with no limits or expectations beyond the patch
+  /// format's.
+  pub fn new() -> Self {
+    Self { strict: true, ..Default::default() }
+  }
+
+  /// The file to apply the patch to.
+  pub fn source(mut self, source: &'a mut dyn Source) -> Self {
+    self.source = Some(source);
+    self
+  }
+
+  /// The checksum of the source, if it's already known, so that it isn't
+  /// hashed again.
+  pub fn source_crc32(mut self, crc32: Crc32) -> Self {
+    self.source_crc32 = Some(crc32);
+    self
+  }
+
+  pub fn patch(mut self, patch: &'a mut dyn PatchSource) -> Self {
+    self.patch = Some(patch);
+    self
+  }
+
+  /// The patch's format. By default, it's detected from the patch.
+  pub fn format(mut self, format: Kind) -> Self {
+    self.format = Some(format);
+    self
+  }
+
+  /// Where to write the patched file. Anything it holds is replaced.
+  pub fn output(mut self, output: &'a mut dyn OutputFile) -> Self {
+    self.output = Some(output);
+    self
+  }
+
+  /// Whether unmet expectations fail the job. Otherwise, they're added to
+  /// the report's warnings. Jobs are strict by default.
+  pub fn strict(mut self, strict: bool) -> Self {
+    self.strict = strict;
+    self
+  }
+
+  /// The checksum the source should have, for formats that don't record it.
+  pub fn expect_source(mut self, crc32: Crc32) -> Self {
+    self.expected_source = Some(crc32);
+    self
+  }
+
+  /// The checksum the patched file should have.
+  pub fn expect_output(mut self, crc32: Crc32) -> Self {
+    self.expected_output = Some(crc32);
+    self
+  }
+
+  /// Applies the patch.
+  ///
+  /// # Panics
+  ///
+  /// If the source, the patch or the output wasn't set.
+  pub fn run(self) -> Result<Report, Error> {
+    let Self {
+      source,
+      source_crc32,
+      patch,
+      format,
+      output,
+      strict,
+      expected_source,
+      expected_output,
+    } = self;
+    let source = source.expect("the source of a patch job must be set");
+    let patch = patch.expect("the patch of a patch job must be set");
+    let output = output.expect("the output of a patch job must be set");
+    let mut timings: Vec<Timing> = Vec::new();
+    let mut warnings: Vec<String> = Vec::new();
+    let mut check = |expected: Option<Crc32>, actual: Crc32, error: Error| match expected {
+      Some(expected) if expected != actual => match strict {
+        true => Err(error),
+        false => {
+          warnings.push(error.to_string());
+          Ok(())
+        }
+      },
+      _ => Ok(()),
+    };
+
+    let start = time::Instant::now();
+    let format = match format {
+      Some(format) => format,
+      None => Kind::detect(&mut &mut *patch)?.ok_or(Error::UnknownFormat)?,
+    };
+    let patch_size = patch.known_len()?;
+    let patch_crc32 = format.digest(&mut &mut *patch)?;
+    patch.seek(io::SeekFrom::Start(0))?;
+    timings.push(Timing::since(Phase::PatchParse, start, patch_size));
+
+    let source_size = source.seek(io::SeekFrom::End(0))?;
+    source.seek(io::SeekFrom::Start(0))?;
+    let source_crc32 = match source_crc32 {
+      Some(crc32) => crc32,
+      None => {
+        let start = time::Instant::now();
+        let crc32 = Crc32::read_and_hash(&mut &mut *source)?;
+        source.seek(io::SeekFrom::Start(0))?;
+        timings.push(Timing::since(Phase::SourceHash, start, source_size));
+        crc32
+      }
+    };
+    check(
+      expected_source,
+      source_crc32,
+      Error::UnexpectedSource {
+        expected: expected_source.unwrap_or(source_crc32),
+        actual: source_crc32,
+      },
+    )?;
+
+    let start = time::Instant::now();
+    output.set_len(0)?;
+    output.seek(io::SeekFrom::Start(0))?;
+    let patcher = Patcher::from_patch_kind(format).strict(strict);
+    if patcher.capabilities().in_place {
+      io::copy(&mut *source, &mut *output)?;
+      output.seek(io::SeekFrom::Start(0))?;
+      source.seek(io::SeekFrom::Start(0))?;
+    }
+    patcher.apply(
+      &mut *source,
+      &mut *patch,
+      &mut *output,
+      source_crc32,
+      patch_crc32,
+      patch_size,
+    )?;
+    let output_size = output.seek(io::SeekFrom::End(0))?;
+    timings.push(Timing::since(Phase::Apply, start, output_size));
+
+    let start = time::Instant::now();
+    output.seek(io::SeekFrom::Start(0))?;
+    let output_crc32 = Crc32::read_and_hash(&mut &mut *output)?;
+    timings.push(Timing::since(Phase::OutputHash, start, output_size));
+    check(
+      expected_output,
+      output_crc32,
+      Error::UnexpectedOutput {
+        expected: expected_output.unwrap_or(output_crc32),
+        actual: output_crc32,
+      },
+    )?;
+
+    Ok(Report {
+      format,
+      source: Summary { size: source_size, crc32: source_crc32 },
+      patch: Summary { size: patch_size, crc32: patch_crc32 },
+      output: Summary { size: output_size, crc32: output_crc32 },
+      warnings,
+      timings,
+    })
+  }
+}
+
+impl fmt::Debug for PatchJob<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("PatchJob")
+      .field("format", &self.format)
+      .field("strict", &self.strict)
+      .field("expected_source", &self.expected_source)
+      .field("expected_output", &self.expected_output)
+      .finish_non_exhaustive()
+  }
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Patch(#[from] patch::Error),
+  #[error("{}", i18n::text("romhacks::patch::unknown_kind"))]
+  #[diagnostic(code(romhacks::job::unknown_format))]
+  UnknownFormat,
+  #[error("{}", i18n::format(
+    "romhacks::job::unexpected_source",
+    &[("expected", &format!("{:08X}", expected.value())), ("actual", &format!("{:08X}", actual.value()))]
+  ))]
+  #[diagnostic(code(romhacks::job::unexpected_source))]
+  UnexpectedSource { expected: Crc32, actual: Crc32 },
+  #[error("{}", i18n::format(
+    "romhacks::job::unexpected_output",
+    &[("expected", &format!("{:08X}", expected.value())), ("actual", &format!("{:08X}", actual.value()))]
+  ))]
+  #[diagnostic(code(romhacks::job::unexpected_output))]
+  UnexpectedOutput { expected: Crc32, actual: Crc32 },
+}
+
+impl From<io::Error> for Error {
+  fn from(err: io::Error) -> Self {
+    Error::Patch(err.into())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use romhacks_testkit::{IpsBuilder, rom};
+  use std::io::Cursor;
+
+  /// Runs a job for an IPS patch that writes "hack" at offset 16 of `rom`,
+  /// with the options `configure` sets, and returns its result with the
+  /// output.
+  fn run(
+    rom: &[u8],
+    configure: impl for<'a> FnOnce(PatchJob<'a>) -> PatchJob<'a>,
+  ) -> (Result<Report, Error>, Vec<u8>) {
+    let patch = IpsBuilder::new().hunk(16, b"hack").build();
+    let mut source = Cursor::new(rom);
+    let mut patch = Cursor::new(patch);
+    let mut output = Cursor::new(Vec::new());
+    let job = PatchJob::new()
+      .source(&mut source)
+      .patch(&mut patch)
+      .output(&mut output);
+    let result = configure(job).run();
+    (result, output.into_inner())
+  }
+
+  #[test]
+  fn reports_what_happened() {
+    let source = rom::sequential(64);
+    let patch = IpsBuilder::new().hunk(16, b"hack").build();
+    let expected = rom::with_bytes(&source, 16, b"hack");
+    let (report, output) = run(&source, |job| job);
+    let report = report.unwrap();
+    assert_eq!(output, expected);
+    assert_eq!(report.format, Kind::IPS);
+    assert_eq!(
+      report.source,
+      Summary { size: 64, crc32: Crc32::of(&source) }
+    );
+    assert_eq!(
+      report.patch,
+      Summary { size: patch.len() as u64, crc32: Crc32::of(&patch) }
+    );
+    assert_eq!(
+      report.output,
+      Summary { size: 64, crc32: Crc32::of(&expected) }
+    );
+    assert!(report.warnings.is_empty());
+    let phases: Vec<Phase> = report.timings.iter().map(|timing| timing.phase).collect();
+    assert_eq!(
+      phases,
+      [
+        Phase::PatchParse,
+        Phase::SourceHash,
+        Phase::Apply,
+        Phase::OutputHash
+      ]
+    );
+  }
+
+  #[test]
+  fn known_source_checksum_is_not_hashed_again() {
+    let source = rom::sequential(64);
+    let known = Crc32::new(0x1234_5678);
+    let (report, _) = run(&source, |job| job.source_crc32(known));
+    let report = report.unwrap();
+    assert_eq!(report.source.crc32, known);
+    assert!(
+      report
+        .timings
+        .iter()
+        .all(|timing| timing.phase != Phase::SourceHash)
+    );
+  }
+
+  #[test]
+  fn strict_job_refuses_unexpected_output() {
+    let source = rom::sequential(64);
+    let (result, _) = run(&source, |job| job.expect_output(Crc32::new(0)));
+    let actual = Crc32::of(&rom::with_bytes(&source, 16, b"hack"));
+    assert!(matches!(
+      result,
+      Err(Error::UnexpectedOutput { expected, actual: a }) if expected == Crc32::new(0) && a == actual
+    ));
+  }
+
+  #[test]
+  fn strict_job_refuses_unexpected_source_before_patching() {
+    let source = rom::sequential(64);
+    let (result, output) = run(&source, |job| job.expect_source(Crc32::new(0)));
+    assert!(matches!(result, Err(Error::UnexpectedSource { .. })));
+    assert!(output.is_empty());
+  }
+
+  #[test]
+  fn lenient_job_warns_about_unmet_expectations() {
+    let source = rom::sequential(64);
+    let (report, output) = run(&source, |job| {
+      job
+        .strict(false)
+        .expect_source(Crc32::new(0))
+        .expect_output(Crc32::new(0))
+    });
+    let report = report.unwrap();
+    assert_eq!(output, rom::with_bytes(&source, 16, b"hack"));
+    assert_eq!(report.warnings.len(), 2);
+  }
+
+  #[test]
+  fn met_expectations_pass() {
+    let source = rom::sequential(64);
+    let expected = rom::with_bytes(&source, 16, b"hack");
+    let (report, _) = run(&source, |job| {
+      job
+        .expect_source(Crc32::of(&source))
+        .expect_output(Crc32::of(&expected))
+    });
+    assert!(report.unwrap().warnings.is_empty());
+  }
+}
@@ -1,17 +1,55 @@
 use crate::error::prelude::*;
-use crate::io::Resize;
-use crate::{crc, error, io};
+use crate::io::{BufReadExt, KnownLen, Resize};
+use crate::{crc, error, i18n, io, profile};
 use std::io::{ErrorKind, Read, Seek, Write};
 use std::{fmt, path};
 
+pub mod aps_gba;
+pub mod aps_n64;
 pub mod bps;
+pub mod bsdiff;
+pub mod compression;
+pub mod dynamic;
+pub mod gdiff;
+pub mod header;
 pub mod ips;
+pub mod ips32;
+pub mod job;
+pub mod ninja2;
+pub mod ops;
+pub mod options;
+#[cfg(feature = "plugins")]
+pub mod plugin;
 pub mod ppf;
+pub mod precheck;
+pub mod stats;
+pub mod trace;
 pub mod ups;
 mod varint;
 pub mod vcd;
+pub mod xdelta1;
 
 pub use self::err::*;
+#[cfg(feature = "plugins")]
+use dynamic::PatchFormat;
+
+/// A file that patched output is written to. Some formats patch the output in
+/// place, so it must also be readable and resizable.
+///
+/// This trait is implemented for every type with those capabilities, including
+/// `&mut dyn OutputFile` and `Box<dyn OutputFile>`. Concrete types such as
+/// files and `Cursor<Vec<u8>>` are patched through monomorphized code, while
+/// embedders can use trait objects to choose an output at runtime.
+pub trait OutputFile: Read + Write + Seek + Resize {}
+
+impl<T: Read + Write + Seek + Resize + ?Sized> OutputFile for T {}
+
+/// Converts a length or offset read from a patch to `usize`. On 32-bit
+/// targets, a value that doesn't fit couldn't be held in memory anyway, so
+/// the patch is refused as too large instead of the value being truncated.
+pub(crate) fn to_usize(value: impl TryInto<usize>) -> Result<usize, Error> {
+  value.try_into().map_err(|_| Error::FileTooLarge)
+}
 
 #[derive(Clone, Debug)]
 pub struct Patch<P> {
@@ -25,13 +63,287 @@ impl<P> Patch<P> {
   }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Kind {
   IPS,
   UPS,
   BPS,
   PPF,
   VCD,
+  /// NINJA 2.0, whose patches are usually named ".rup".
+  RUP,
+  /// APS for Nintendo 64 ROMs.
+  APS,
+  /// APS for Game Boy Advance ROMs, a different format.
+  APSGBA,
+  /// bsdiff's BSDIFF40 format.
+  BSDIFF,
+  /// The W3C's Generic Diff Format.
+  GDIFF,
+  /// IPS with 32-bit offsets.
+  IPS32,
+  /// A format added by a plugin.
+  #[cfg(feature = "plugins")]
+  Plugin(plugin::Id),
+}
+
+impl Kind {
+  /// Every built-in patch format.
+  pub const ALL: [Kind; 11] = [
+    Kind::IPS,
+    Kind::UPS,
+    Kind::BPS,
+    Kind::PPF,
+    Kind::VCD,
+    Kind::RUP,
+    Kind::APS,
+    Kind::APSGBA,
+    Kind::BSDIFF,
+    Kind::GDIFF,
+    Kind::IPS32,
+  ];
+
+  /// Every supported patch format, including those added by plugins.
+  pub fn available() -> Vec<Kind> {
+    let kinds = Self::ALL.into_iter();
+    #[cfg(feature = "plugins")]
+    let kinds = kinds.chain(plugin::ids().map(Kind::Plugin));
+    kinds.collect()
+  }
+
+  /// Describes what this format records and what it needs from the files it patches.
+  pub fn capabilities(self) -> Capabilities {
+    match self {
+      Kind::IPS => Capabilities {
+        source_checksum: false,
+        target_checksum: false,
+        patch_checksum: false,
+        creation: true,
+        in_place: true,
+        // Offsets are 24-bit big-endian integers.
+        max_file_size: 1 << 24,
+        seeks_source: false,
+        seeks_patch: true,
+        seeks_output: true,
+      },
+      Kind::UPS => Capabilities {
+        source_checksum: true,
+        target_checksum: true,
+        patch_checksum: true,
+        creation: false,
+        in_place: true,
+        max_file_size: u64::MAX,
+        seeks_source: false,
+        seeks_patch: true,
+        seeks_output: true,
+      },
+      Kind::BPS => Capabilities {
+        source_checksum: true,
+        target_checksum: true,
+        patch_checksum: true,
+        creation: true,
+        in_place: false,
+        max_file_size: u64::MAX,
+        seeks_source: true,
+        seeks_patch: true,
+        seeks_output: false,
+      },
+      Kind::PPF => Capabilities {
+        source_checksum: false,
+        target_checksum: false,
+        patch_checksum: false,
+        creation: false,
+        in_place: true,
+        max_file_size: u64::MAX,
+        seeks_source: false,
+        seeks_patch: true,
+        seeks_output: true,
+      },
+      Kind::VCD => Capabilities {
+        source_checksum: false,
+        target_checksum: false,
+        patch_checksum: false,
+        creation: true,
+        in_place: false,
+        max_file_size: u64::MAX,
+        seeks_source: true,
+        seeks_patch: true,
+        // VCD_TARGET copies read back previously written output.
+        seeks_output: true,
+      },
+      Kind::RUP => Capabilities {
+        // The patch records MD5s of the files it patches, not CRC32s.
+        source_checksum: false,
+        target_checksum: false,
+        patch_checksum: false,
+        creation: false,
+        in_place: true,
+        max_file_size: u64::MAX,
+        seeks_source: false,
+        seeks_patch: true,
+        seeks_output: true,
+      },
+      Kind::APS => Capabilities {
+        // N64 patches only record the ROM's cartridge ID and internal CRCs.
+        source_checksum: false,
+        target_checksum: false,
+        patch_checksum: false,
+        creation: false,
+        in_place: true,
+        max_file_size: 1 << 32,
+        seeks_source: false,
+        seeks_patch: false,
+        seeks_output: true,
+      },
+      Kind::APSGBA => Capabilities {
+        // Each record has CRC16s of its block, but not of the whole file.
+        source_checksum: false,
+        target_checksum: false,
+        patch_checksum: false,
+        creation: false,
+        in_place: true,
+        max_file_size: 1 << 32,
+        seeks_source: false,
+        seeks_patch: false,
+        seeks_output: true,
+      },
+      Kind::BSDIFF => Capabilities {
+        source_checksum: false,
+        target_checksum: false,
+        patch_checksum: false,
+        creation: false,
+        in_place: false,
+        max_file_size: i64::MAX as u64,
+        // Controls move anywhere in the ROM, and the patch's three blocks
+        // are read from where they start.
+        seeks_source: true,
+        seeks_patch: true,
+        seeks_output: false,
+      },
+      Kind::GDIFF => Capabilities {
+        source_checksum: false,
+        target_checksum: false,
+        patch_checksum: false,
+        creation: false,
+        in_place: false,
+        max_file_size: u64::MAX,
+        // Copies read from anywhere in the ROM.
+        seeks_source: true,
+        seeks_patch: false,
+        seeks_output: false,
+      },
+      Kind::IPS32 => Capabilities {
+        source_checksum: false,
+        target_checksum: false,
+        patch_checksum: false,
+        creation: false,
+        in_place: true,
+        // Offsets are 32-bit big-endian integers.
+        max_file_size: 1 << 32,
+        seeks_source: false,
+        seeks_patch: true,
+        seeks_output: true,
+      },
+      #[cfg(feature = "plugins")]
+      Kind::Plugin(id) => id.plugin().capabilities(),
+    }
+  }
+
+  /// The magic string at the start of each format's patches. N64 APS
+  /// patches start with the GBA format's magic string, so they come first.
+  pub const SIGNATURES: [(&'static [u8], Kind); 11] = [
+    (ips::MAGIC, Kind::IPS),
+    (ups::MAGIC, Kind::UPS),
+    (bps::MAGIC, Kind::BPS),
+    (ppf::MAGIC, Kind::PPF),
+    (vcd::MAGIC, Kind::VCD),
+    (ninja2::MAGIC, Kind::RUP),
+    (aps_n64::MAGIC, Kind::APS),
+    (aps_gba::MAGIC, Kind::APSGBA),
+    (bsdiff::MAGIC, Kind::BSDIFF),
+    (gdiff::MAGIC, Kind::GDIFF),
+    (ips32::MAGIC, Kind::IPS32),
+  ];
+
+  /// The magic string at the start of this format's patches.
+  pub fn magic(self) -> &'static [u8] {
+    match self {
+      #[cfg(feature = "plugins")]
+      Kind::Plugin(id) => id.plugin().magic(),
+      _ => (Self::SIGNATURES.iter())
+        .find(|(_, kind)| *kind == self)
+        .map_or(&[], |(signature, _)| signature),
+    }
+  }
+
+  /// How many bytes at the start of a patch identify its format.
+  fn magic_len() -> usize {
+    (Self::available().into_iter())
+      .map(|kind| kind.magic().len())
+      .max()
+      .unwrap_or(0)
+  }
+
+  /// Identifies a patch format from the first bytes of a patch file. Plugin
+  /// formats are only considered if no built-in format matches.
+  pub fn from_magic(magic: &[u8]) -> Option<Self> {
+    (Self::available().into_iter()).find(|kind| magic.starts_with(kind.magic()))
+  }
+
+  /// Computes the CRC32 that identifies a patch of this format. A patch's own
+  /// checksum doesn't cover the footer it's stored in, so neither does this.
+  pub fn digest(self, patch: &mut (impl Read + Seek + KnownLen)) -> io::Result<crc::Crc32> {
+    let patch_eof = patch.known_len()?;
+    let checksum_limit = match self.capabilities().patch_checksum {
+      true => patch_eof.saturating_sub(4),
+      false => patch_eof,
+    };
+    patch.seek(io::SeekFrom::Start(0))?;
+    crc::Crc32::read_and_hash(&mut patch.take(checksum_limit))
+  }
+
+  /// Identifies a patch's format from the magic string at its start, without
+  /// consuming it, for streams that can't seek back.
+  pub fn sniff(patch: &mut impl BufReadExt) -> io::Result<Option<Self>> {
+    Ok(Self::from_magic(patch.peek(Self::magic_len())?))
+  }
+
+  /// Reads the magic string at the start of `patch` and identifies its format.
+  /// Files too short to contain a magic string are reported as unknown.
+  pub fn detect(patch: &mut (impl Read + Seek)) -> io::Result<Option<Self>> {
+    patch.seek(io::SeekFrom::Start(0))?;
+    let mut magic = Vec::with_capacity(Self::magic_len());
+    (&mut *patch)
+      .take(Self::magic_len() as u64)
+      .read_to_end(&mut magic)?;
+    Ok(Self::from_magic(&magic))
+  }
+}
+
+/// The properties of a patch format. See [`Kind::capabilities`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Capabilities {
+  /// The patch records the checksum of the file it applies to.
+  pub source_checksum: bool,
+  /// The patch records the checksum of the patched file.
+  pub target_checksum: bool,
+  /// The patch ends with a CRC32 of everything before it.
+  pub patch_checksum: bool,
+  /// Patches in this format can be created.
+  pub creation: bool,
+  /// The patch modifies a copy of the source file rather than building the
+  /// output from scratch.
+  pub in_place: bool,
+  /// How far into a file the format's records can start, in bytes. Records
+  /// that start past it are refused, but the files patched can be larger,
+  /// and a record that starts before it can write past it.
+  pub max_file_size: u64,
+  /// Applying the patch seeks within the source file.
+  pub seeks_source: bool,
+  /// Applying the patch seeks within the patch file.
+  pub seeks_patch: bool,
+  /// Applying the patch seeks within the output file.
+  pub seeks_output: bool,
 }
 
 impl fmt::Display for Kind {
@@ -42,6 +354,14 @@ impl fmt::Display for Kind {
       Kind::BPS => write!(f, "BPS"),
       Kind::PPF => write!(f, "PPF"),
       Kind::VCD => write!(f, "Vcdiff (a.k.a. xdelta)"),
+      Kind::RUP => write!(f, "NINJA 2.0"),
+      Kind::APS => write!(f, "APS (N64)"),
+      Kind::APSGBA => write!(f, "APS (GBA)"),
+      Kind::BSDIFF => write!(f, "bsdiff"),
+      Kind::GDIFF => write!(f, "GDIFF"),
+      Kind::IPS32 => write!(f, "IPS32"),
+      #[cfg(feature = "plugins")]
+      Kind::Plugin(id) => write!(f, "{}", id.plugin().display_name()),
     }
   }
 }
@@ -51,18 +371,30 @@ pub struct UnknownPatchKindError(pub(crate) ());
 
 impl fmt::Display for UnknownPatchKindError {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    write!(f, "Unknown patch type.")
+    write!(f, "{}", i18n::text("romhacks::patch::unknown_kind"))
   }
 }
 
 impl error::Error for UnknownPatchKindError {}
 
 #[derive(Clone, Copy, Debug)]
-pub struct Patcher(Kind);
+pub struct Patcher {
+  kind: Kind,
+  strict: bool,
+}
 
 impl Patcher {
   pub fn from_patch_kind(patch_kind: Kind) -> Self {
-    Self(patch_kind)
+    Self { kind: patch_kind, strict: true }
+  }
+
+  /// Whether the checksums a patch records for each part of the file, which
+  /// only GBA APS patches have, refuse a ROM that doesn't match them.
+  /// Otherwise, mismatches are only warned about. Patchers are strict by
+  /// default.
+  pub fn strict(mut self, strict: bool) -> Self {
+    self.strict = strict;
+    self
   }
 
   pub fn patch<R, P, O>(
@@ -76,27 +408,47 @@ impl Patcher {
   ) -> Result<(), Error>
   where
     R: Read + Seek,
-    P: Read + Seek,
-    O: Read + Write + Seek + Resize,
+    P: Read + Seek + KnownLen,
+    O: OutputFile,
   {
-    match self.0 {
+    header::read_version(self.kind, patch)?;
+    match self.kind {
       Kind::IPS => Patcher::ips(output, patch),
       Kind::UPS => Patcher::ups(output, patch, rom_checksum, patch_checksum),
       Kind::BPS => Patcher::bps(rom, patch, output, rom_checksum, patch_checksum, patch_eof),
       Kind::PPF => Patcher::ppf(output, patch),
       Kind::VCD => Patcher::vcdiff(rom, patch, output),
+      Kind::RUP => Patcher::ninja2(output, patch),
+      Kind::APS => Patcher::aps_n64(output, patch),
+      Kind::APSGBA => Patcher::aps_gba(output, patch, self.strict),
+      Kind::BSDIFF => Patcher::bsdiff(rom, patch, output),
+      Kind::GDIFF => Patcher::gdiff(rom, patch, output),
+      Kind::IPS32 => Patcher::ips32(output, patch),
+      #[cfg(feature = "plugins")]
+      Kind::Plugin(id) => {
+        id.plugin()
+          .apply(rom, patch, output, rom_checksum, patch_checksum, patch_eof)
+      }
     }
   }
 
   fn ips<R, P>(rom: &mut R, patch: &mut P) -> Result<(), Error>
   where
-    R: Write + Seek + Resize,
+    R: OutputFile,
     P: Read + Seek,
   {
     ips::patch(rom, patch)?;
     Ok(())
   }
 
+  fn ips32<R, P>(rom: &mut R, patch: &mut P) -> Result<(), Error>
+  where
+    R: OutputFile,
+    P: Read + Seek,
+  {
+    ips32::patch(rom, patch)
+  }
+
   fn ups<R, P>(
     rom: &mut R,
     patch: &mut P,
@@ -104,8 +456,8 @@ impl Patcher {
     patch_checksum: crc::Crc32,
   ) -> Result<(), crate::patch::Error>
   where
-    R: Read + Write + Seek + Resize,
-    P: Read + Seek,
+    R: OutputFile,
+    P: Read + Seek + KnownLen,
   {
     ups::patch(rom, patch, rom_checksum, patch_checksum)?;
     Ok(())
@@ -122,7 +474,7 @@ impl Patcher {
   where
     R: Read + Seek,
     P: Read + Seek,
-    O: Read + Write + Seek + Resize,
+    O: OutputFile,
   {
     // bps::patch(rom, patch, file_checksum, patch_checksum, patch_eof)?
     let mut file_contents = vec![];
@@ -147,23 +499,68 @@ impl Patcher {
           F::Canceled => P::IO(io::Error::from(ErrorKind::Interrupted)),
         }
       })?;
-    io::copy(&mut bps_output.as_bytes(), output)?;
+    let mut output = io::TrackedBufWriter::with_capacity(profile::get().buf_size, output)?;
+    output.write_all(bps_output.as_bytes())?;
+    let (_, result) = output.into_inner();
+    result?;
     Ok(())
   }
 
+  fn bsdiff<R, P, O>(rom: &mut R, patch: &mut P, output: &mut O) -> Result<(), Error>
+  where
+    R: Read + Seek,
+    P: Read + Seek,
+    O: OutputFile,
+  {
+    bsdiff::patch(rom, patch, output)
+  }
+
+  fn gdiff<R, P, O>(rom: &mut R, patch: &mut P, output: &mut O) -> Result<(), Error>
+  where
+    R: Read + Seek,
+    P: Read + Seek,
+    O: OutputFile,
+  {
+    gdiff::patch(rom, patch, output)
+  }
+
   fn ppf<R, P>(rom: &mut R, ppf: &mut P) -> Result<(), Error>
   where
-    R: Read + Write + Seek + Resize,
+    R: OutputFile,
     P: Read + Seek,
   {
     ppf::patch(rom, ppf).map_err(|err| err.into())
   }
 
+  fn aps_gba<R, P>(rom: &mut R, patch: &mut P, strict: bool) -> Result<(), Error>
+  where
+    R: OutputFile,
+    P: Read + Seek,
+  {
+    aps_gba::patch(rom, patch, strict)
+  }
+
+  fn aps_n64<R, P>(rom: &mut R, patch: &mut P) -> Result<(), Error>
+  where
+    R: OutputFile,
+    P: Read + Seek,
+  {
+    aps_n64::patch(rom, patch)
+  }
+
+  fn ninja2<R, P>(rom: &mut R, patch: &mut P) -> Result<(), Error>
+  where
+    R: OutputFile,
+    P: Read + Seek,
+  {
+    ninja2::patch(rom, patch)
+  }
+
   fn vcdiff<R, P, O>(rom: &mut R, patch: &mut P, output: &mut O) -> Result<(), Error>
   where
     R: Read + Seek,
     P: Read + Seek,
-    O: Read + Write + Seek + Resize,
+    O: OutputFile,
   {
     vcd::patch(rom, patch, output)?;
     Ok(())
@@ -181,23 +578,62 @@ type PatchFn<P> = fn(&path::Path, &Patch<P>) -> Result<(), Error>;
 
 mod err {
   use crate::error::prelude::*;
+  use crate::i18n;
   use std::io;
 
-  #[derive(Debug, Error)]
+  #[derive(Debug, Error, Diagnostic)]
   #[error(transparent)]
   pub enum Error {
     #[error(transparent)]
     IO(io::Error),
-    #[error("The patch file is corrupt.")]
+    #[error("{}", i18n::text("romhacks::patch::bad_patch"))]
+    #[diagnostic(code(romhacks::patch::bad_patch))]
     BadPatch,
-    #[error("Unsupported patch.")]
+    #[error("{}", i18n::text("romhacks::patch::unsupported_feature"))]
+    #[diagnostic(code(romhacks::patch::unsupported_feature))]
     UnsupportedPatchFeature,
-    #[error("The patch or ROM file is too large.")]
+    #[error("{}", i18n::format(
+      "romhacks::patch::unsupported_version",
+      &[("found", found), ("supported", supported)]
+    ))]
+    #[diagnostic(
+      code(romhacks::patch::unsupported_version),
+      help("{}", i18n::text("romhacks::patch::unsupported_version::help"))
+    )]
+    UnsupportedVersion { found: String, supported: String },
+    #[error("{}", i18n::text("romhacks::patch::file_too_large"))]
+    #[diagnostic(code(romhacks::patch::file_too_large))]
     FileTooLarge,
-    #[error("The patch is not intended for the input file.")]
+    #[error("{}", i18n::text("romhacks::patch::wrong_input_file"))]
+    #[diagnostic(code(romhacks::patch::wrong_input_file))]
     WrongInputFile,
-    #[error("This patch has already been applied to the input file.")]
+    #[error("{}", i18n::format(
+      "romhacks::patch::wrong_source_size",
+      &[("expected", expected), ("actual", actual)]
+    ))]
+    #[diagnostic(
+      code(romhacks::patch::wrong_source_size),
+      help("{}", i18n::text("romhacks::patch::wrong_source_size::help"))
+    )]
+    WrongSourceSize { expected: u64, actual: u64 },
+    #[error("{}", i18n::text("romhacks::patch::xdelta1"))]
+    #[diagnostic(
+      code(romhacks::patch::xdelta1),
+      help("{}", i18n::text("romhacks::patch::xdelta1::help"))
+    )]
+    Xdelta1,
+    #[error("{}", i18n::text("romhacks::patch::already_patched"))]
+    #[diagnostic(code(romhacks::patch::already_patched))]
     AlreadyPatched,
+    #[error("{}", i18n::text("romhacks::patch::wrong_output"))]
+    #[diagnostic(code(romhacks::patch::wrong_output))]
+    WrongOutput,
+    #[error("{}", i18n::text("romhacks::patch::no_undo_data"))]
+    #[diagnostic(code(romhacks::patch::no_undo_data))]
+    NoUndoData,
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Compression(#[from] super::compression::Error),
   }
 
   impl From<io::Error> for Error {
@@ -220,3 +656,94 @@ mod err {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use romhacks_testkit::ppf::ImageType;
+  use romhacks_testkit::{BpsBuilder, IpsBuilder, PpfBuilder, rom};
+  use std::io::Cursor;
+
+  /// Applies `patch` to `rom` as `apply` does, after checking that it's
+  /// detected as a `kind` patch.
+  pub(crate) fn apply(kind: Kind, rom: &[u8], patch: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let patch_eof = patch.len() as u64;
+    let mut patch = Cursor::new(patch);
+    assert_eq!(Kind::detect(&mut patch).unwrap(), Some(kind));
+    let patch_digest = kind.digest(&mut patch)?;
+    patch.seek(io::SeekFrom::Start(0))?;
+    let mut output = Cursor::new(match kind.capabilities().in_place {
+      true => rom.to_vec(),
+      false => Vec::new(),
+    });
+    Patcher::from_patch_kind(kind).patch(
+      &mut Cursor::new(rom),
+      &mut patch,
+      &mut output,
+      crc::Crc32::of(rom),
+      patch_digest,
+      patch_eof,
+    )?;
+    Ok(output.into_inner())
+  }
+
+  #[test]
+  fn ips_records() {
+    let rom = rom::sequential(0x1000);
+    let patch = IpsBuilder::new()
+      .hunk(0x10, b"romhacks")
+      .rle(0x800, 0xFF, 0x100)
+      .hunk(0xFFE, b"extended")
+      .build();
+    let expected = rom::with_bytes(&rom, 0x10, b"romhacks");
+    let expected = rom::with_bytes(&expected, 0x800, &rom::filled(0x100, 0xFF));
+    let expected = rom::with_bytes(&expected, 0xFFE, b"extended");
+    assert!(apply(Kind::IPS, &rom, patch).unwrap() == expected);
+  }
+
+  #[test]
+  fn ips_truncation() {
+    let rom = rom::sequential(0x1000);
+    let patch = IpsBuilder::new()
+      .hunk(0, b"romhacks")
+      .truncate(0x800)
+      .build();
+    let expected = rom::with_bytes(&rom, 0, b"romhacks");
+    assert!(apply(Kind::IPS, &rom, patch).unwrap() == expected[..0x800]);
+  }
+
+  #[test]
+  fn bps_actions() {
+    let rom = rom::random(0x1000, 1);
+    let builder = BpsBuilder::new(&rom)
+      .metadata("<patch/>")
+      .source_read(0x100)
+      .target_read(b"romhacks")
+      .source_copy(0x800, 0x200)
+      .target_copy(0x100, 0x40)
+      .target_copy(0x300, 0x60);
+    let expected = builder.target().to_vec();
+    assert!(apply(Kind::BPS, &rom, builder.build()).unwrap() == expected);
+  }
+
+  #[test]
+  fn ppf_records_and_block_check() {
+    let image = rom::random(0x10000, 2);
+    let patch = PpfBuilder::new()
+      .description("romhacks")
+      .image_type(ImageType::Bin)
+      .block_check(&image)
+      .hunk(0x20, b"romhacks")
+      .hunk(0xFFFC, b"extended")
+      .build();
+    let expected = rom::with_bytes(&image, 0x20, b"romhacks");
+    let expected = rom::with_bytes(&expected, 0xFFFC, b"extended");
+    assert!(apply(Kind::PPF, &image, patch.clone()).unwrap() == expected);
+
+    let other = rom::random(0x10000, 3);
+    assert!(matches!(
+      apply(Kind::PPF, &other, patch),
+      Err(Error::BadPatch)
+    ));
+  }
+}
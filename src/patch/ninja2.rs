@@ -0,0 +1,250 @@
+//! NINJA 2.0 patches, which the NINJA patcher names ".rup".
+//!
+//! A patch starts with a 2048-byte header of text fields, such as the hack's
+//! author and title, followed by commands. An "open file" command records the
+//! size and MD5 of a file before and after patching, and the XOR records after
+//! it apply to that file. A patch can hold several files, such as each
+//! release of a game; the one whose source MD5 matches the ROM is applied,
+//! and the patched file must then have its target MD5.
+
+use crate::io::prelude::*;
+use crate::patch::header::Header;
+use crate::patch::{Error, OutputFile};
+use crate::{io, profile};
+use md5::{Digest, Md5};
+
+pub const MAGIC: &[u8] = b"NINJA2";
+
+/// Where the commands start, after the header.
+const COMMANDS_START: u64 = 0x800;
+
+/// The size of the chunks XOR records are applied in.
+const BUF_SIZE: usize = 8 * 1024;
+
+mod command {
+  pub const END: u8 = 0x00;
+  pub const OPEN_FILE: u8 = 0x01;
+  pub const XOR: u8 = 0x02;
+}
+
+/// A file recorded by an "open file" command.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct File {
+  source_size: u64,
+  target_size: u64,
+  source_md5: [u8; 16],
+  target_md5: [u8; 16],
+  /// Where the file's XOR records start in the patch.
+  records: u64,
+  /// Where the bytes past the end of the source are in the patch, and how
+  /// many there are, if patching makes the file larger.
+  appended: Option<(u64, u64)>,
+}
+
+pub fn patch(rom: &mut impl OutputFile, patch: &mut (impl Read + Seek)) -> Result<(), Error> {
+  let files = read_files(patch)?;
+
+  // The output starts as a copy of the ROM, so that's what's hashed.
+  rom.seek(io::SeekFrom::Start(0))?;
+  let md5 = md5_of(rom)?;
+  let Some(file) = files.iter().find(|file| file.source_md5 == md5) else {
+    return Err(match files.iter().any(|file| file.target_md5 == md5) {
+      true => Error::AlreadyPatched,
+      false => Error::WrongInputFile,
+    });
+  };
+
+  if file.source_size != file.target_size {
+    rom.set_len(file.target_size)?;
+  }
+
+  patch.seek(io::SeekFrom::Start(file.records))?;
+  let mut patch = io::BufReader::with_capacity(profile::get().buf_size, patch);
+  let mut patch_buf = [0u8; BUF_SIZE];
+  let mut rom_buf = [0u8; BUF_SIZE];
+  while let Some(command::XOR) = read_command(&mut patch)? {
+    let offset = read_vlv(&mut patch)?;
+    let mut remaining = read_vlv(&mut patch)?;
+    rom.seek(io::SeekFrom::Start(offset))?;
+    while remaining > 0 {
+      let len = remaining.min(BUF_SIZE as u64) as usize;
+      let (patch_chunk, rom_chunk) = (&mut patch_buf[..len], &mut rom_buf[..len]);
+      patch.read_exact(patch_chunk)?;
+      rom.read_exact(rom_chunk)?;
+      for (rom_byte, patch_byte) in rom_chunk.iter_mut().zip(patch_chunk) {
+        *rom_byte ^= *patch_byte;
+      }
+      rom.seek_relative(-(len as i64))?;
+      rom.write_all(rom_chunk)?;
+      remaining -= len as u64;
+    }
+  }
+
+  // The bytes past the end of the source are stored inverted.
+  if let Some((position, len)) = file.appended {
+    let patch = patch.into_inner();
+    patch.seek(io::SeekFrom::Start(position))?;
+    rom.seek(io::SeekFrom::Start(file.source_size))?;
+    let mut remaining = len;
+    while remaining > 0 {
+      let len = remaining.min(BUF_SIZE as u64) as usize;
+      let chunk = &mut patch_buf[..len];
+      patch.read_exact(chunk)?;
+      chunk.iter_mut().for_each(|byte| *byte = !*byte);
+      rom.write_all(chunk)?;
+      remaining -= len as u64;
+    }
+  }
+
+  rom.flush()?;
+  rom.seek(io::SeekFrom::Start(0))?;
+  if md5_of(rom)? != file.target_md5 {
+    return Err(Error::WrongOutput);
+  }
+  Ok(())
+}
+
+fn md5_of(file: &mut impl Read) -> io::Result<[u8; 16]> {
+  let mut hasher = Md5::new();
+  io::copy(file, &mut hasher)?;
+  Ok(hasher.finalize().into())
+}
+
+/// Reads the sizes a NINJA 2.0 patch declares. Patches that hold several
+/// files declare no single size, so none are read from them.
+pub fn read_header(patch: &mut (impl Read + Seek)) -> Result<Header, Error> {
+  let files = read_files(patch)?;
+  Ok(match files.as_slice() {
+    [file] => Header {
+      source_size: Some(file.source_size),
+      target_size: Some(file.target_size),
+      ..Header::default()
+    },
+    _ => Header::default(),
+  })
+}
+
+/// Reads every "open file" command in `patch`, skipping over their records.
+fn read_files(patch: &mut (impl Read + Seek)) -> Result<Vec<File>, Error> {
+  patch.seek(io::SeekFrom::Start(0))?;
+  let mut patch = io::BufReader::with_capacity(profile::get().buf_size, patch);
+  if patch.read_array::<6>()? != MAGIC {
+    return Err(Error::BadPatch);
+  }
+  patch.seek(io::SeekFrom::Start(COMMANDS_START))?;
+
+  let mut files: Vec<File> = Vec::new();
+  loop {
+    match read_command(&mut patch)? {
+      Some(command::OPEN_FILE) => {
+        let name_len = read_vlv(&mut patch)?;
+        // The file's name and its type, which is only used to display it.
+        patch.seek_relative(
+          i64::try_from(name_len)
+            .ok()
+            .and_then(|len| len.checked_add(1))
+            .ok_or(Error::BadPatch)?,
+        )?;
+        let source_size = read_vlv(&mut patch)?;
+        let target_size = read_vlv(&mut patch)?;
+        let source_md5 = patch.read_array::<16>()?;
+        let target_md5 = patch.read_array::<16>()?;
+        let mut appended = None;
+        if source_size != target_size {
+          // "M" if the file is made smaller, in which case the removed bytes
+          // are only kept to undo the patch, and "A" if it's made larger.
+          let mode = patch.read_u8()?;
+          let len = read_vlv(&mut patch)?;
+          let position = patch.stream_position()?;
+          match mode {
+            b'A' if target_size > source_size => appended = Some((position, len)),
+            b'M' if target_size < source_size => {}
+            _ => return Err(Error::BadPatch),
+          }
+          skip(&mut patch, len)?;
+        }
+        let records = patch.stream_position()?;
+        files.push(File {
+          source_size,
+          target_size,
+          source_md5,
+          target_md5,
+          records,
+          appended,
+        });
+      }
+      Some(command::XOR) if !files.is_empty() => {
+        let _offset = read_vlv(&mut patch)?;
+        let len = read_vlv(&mut patch)?;
+        skip(&mut patch, len)?;
+      }
+      Some(command::END) | None if !files.is_empty() => return Ok(files),
+      _ => return Err(Error::BadPatch),
+    }
+  }
+}
+
+/// Reads the next command, or returns `None` at the end of the patch.
+fn read_command(patch: &mut impl BufRead) -> io::Result<Option<u8>> {
+  let command = patch.fill_buf()?.first().copied();
+  if command.is_some() {
+    patch.consume(1);
+  }
+  Ok(command)
+}
+
+/// Reads a variable-length value: a byte holding how many bytes the value
+/// has, followed by the value in little-endian order.
+fn read_vlv(patch: &mut impl Read) -> Result<u64, Error> {
+  let len = usize::from(patch.read_u8()?);
+  if len > size_of::<u64>() {
+    return Err(Error::FileTooLarge);
+  }
+  let mut bytes = [0u8; size_of::<u64>()];
+  patch.read_exact(&mut bytes[..len])?;
+  Ok(u64::from_le_bytes(bytes))
+}
+
+fn skip(patch: &mut (impl Read + Seek), len: u64) -> Result<(), Error> {
+  patch.seek_relative(i64::try_from(len).map_err(|_| Error::BadPatch)?)?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::patch::Kind;
+  use crate::patch::tests::apply;
+  use romhacks_testkit::{Ninja2Builder, rom};
+
+  #[test]
+  fn xors_records_into_rom() {
+    let rom = rom::random(0x1000, 1);
+    let builder = Ninja2Builder::new(&rom)
+      .xor(0x100, &[0xFF; 0x10])
+      .xor(0xFF0, b"romhacks");
+    let expected = builder.target().to_vec();
+    assert!(apply(Kind::RUP, &rom, builder.build()).unwrap() == expected);
+  }
+
+  #[test]
+  fn refuses_output_without_target_md5() {
+    let rom = rom::random(0x1000, 1);
+    let patch = Ninja2Builder::new(&rom)
+      .xor(0x100, &[0xFF; 0x10])
+      .target_md5([0; 16])
+      .build();
+    assert!(matches!(
+      apply(Kind::RUP, &rom, patch),
+      Err(Error::WrongOutput)
+    ));
+  }
+
+  #[test]
+  fn requires_version_in_magic() {
+    let rom = rom::random(0x1000, 1);
+    let mut patch = Ninja2Builder::new(&rom).xor(0, &[1]).build();
+    patch[5] = b'1';
+    assert_eq!(Kind::detect(&mut io::Cursor::new(patch)).unwrap(), None);
+  }
+}
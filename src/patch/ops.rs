@@ -0,0 +1,313 @@
+//! A format-agnostic view of the operations in a patch.
+//!
+//! Decoders walk a patch and pass each operation to a [`Visitor`], which can
+//! apply it, collect it or inspect it. Formats whose output is built from
+//! copies of the source file, such as BPS and VCDIFF, can't be decoded yet.
+
+use crate::crc::Crc32;
+use crate::io::prelude::*;
+use crate::patch::header::{self, Header};
+use crate::patch::{Error, Kind, OutputFile, ips, ips32, ppf, ups};
+use std::borrow::Cow;
+use std::io;
+use std::ops::Range;
+
+/// A single change that a patch makes to the file it's applied to.
+///
+/// Offsets are absolute positions in the output file.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Op<'a> {
+  /// The file must contain `data` at `offset` before it's patched.
+  Expect { offset: u64, data: Cow<'a, [u8]> },
+  /// Overwrites the bytes at `offset` with `data`.
+  Write { offset: u64, data: Cow<'a, [u8]> },
+  /// Overwrites `len` bytes at `offset` with `byte`.
+  Fill { offset: u64, len: u64, byte: u8 },
+  /// XORs the bytes at `offset` with `data`. Bytes beyond the end of the file
+  /// are treated as zeroes.
+  Xor { offset: u64, data: Cow<'a, [u8]> },
+  /// Truncates or extends the file to `len` bytes.
+  Resize { len: u64 },
+}
+
+impl Op<'_> {
+  /// Copies any borrowed data so the operation can outlive the decoder's buffers.
+  pub fn into_owned(self) -> Op<'static> {
+    match self {
+      Op::Expect { offset, data } => Op::Expect { offset, data: Cow::Owned(data.into_owned()) },
+      Op::Write { offset, data } => Op::Write { offset, data: Cow::Owned(data.into_owned()) },
+      Op::Fill { offset, len, byte } => Op::Fill { offset, len, byte },
+      Op::Xor { offset, data } => Op::Xor { offset, data: Cow::Owned(data.into_owned()) },
+      Op::Resize { len } => Op::Resize { len },
+    }
+  }
+
+  /// Returns the part of this operation that falls within `range`, or `None`
+  /// if it doesn't touch it. [`Op::Resize`] has no extent and is never clipped.
+  pub fn clip(&self, range: &Range<u64>) -> Option<Op<'_>> {
+    match self {
+      Op::Expect { offset, data } => clip_slice(*offset, data, range)
+        .map(|(offset, data)| Op::Expect { offset, data: Cow::Borrowed(data) }),
+      Op::Write { offset, data } => clip_slice(*offset, data, range)
+        .map(|(offset, data)| Op::Write { offset, data: Cow::Borrowed(data) }),
+      Op::Xor { offset, data } => clip_slice(*offset, data, range)
+        .map(|(offset, data)| Op::Xor { offset, data: Cow::Borrowed(data) }),
+      Op::Fill { offset, len, byte } => {
+        let start = (*offset).max(range.start);
+        let end = (offset + len).min(range.end);
+        (start < end).then(|| Op::Fill { offset: start, len: end - start, byte: *byte })
+      }
+      Op::Resize { len } => Some(Op::Resize { len: *len }),
+    }
+  }
+}
+
+/// Returns the part of `data`, which starts at `offset`, that falls within
+/// `range`, and the offset of that part.
+fn clip_slice<'a>(offset: u64, data: &'a [u8], range: &Range<u64>) -> Option<(u64, &'a [u8])> {
+  let start = offset.max(range.start);
+  let end = (offset + data.len() as u64).min(range.end);
+  (start < end).then(|| {
+    (
+      start,
+      &data[(start - offset) as usize..(end - offset) as usize],
+    )
+  })
+}
+
+/// Receives the operations of a patch in the order they're applied.
+pub trait Visitor {
+  fn visit(&mut self, op: &Op<'_>) -> Result<(), Error>;
+}
+
+impl<F: FnMut(&Op<'_>) -> Result<(), Error>> Visitor for F {
+  fn visit(&mut self, op: &Op<'_>) -> Result<(), Error> {
+    self(op)
+  }
+}
+
+/// Collects operations into a list.
+impl Visitor for Vec<Op<'static>> {
+  fn visit(&mut self, op: &Op<'_>) -> Result<(), Error> {
+    self.push(op.clone().into_owned());
+    Ok(())
+  }
+}
+
+/// Whether patches of this format can be decoded into operations.
+pub fn decodes(kind: Kind) -> bool {
+  matches!(kind, Kind::IPS | Kind::UPS | Kind::PPF | Kind::IPS32)
+}
+
+/// Decodes every operation in `patch` and passes it to `visitor`.
+///
+/// The patch's own checksum is validated, if it has one, but checksums of
+/// the file being patched aren't; see [`header::read`] for those.
+pub fn decode(
+  kind: Kind,
+  patch: &mut (impl Read + Seek + KnownLen),
+  visitor: &mut impl Visitor,
+) -> Result<(), Error> {
+  header::read_version(kind, patch)?;
+  match kind {
+    Kind::IPS => ips::decode(patch, visitor),
+    Kind::IPS32 => ips32::decode(patch, visitor),
+    Kind::UPS => ups::decode(patch, visitor),
+    Kind::PPF => ppf::decode(patch, visitor),
+    Kind::BPS | Kind::VCD | Kind::RUP | Kind::APS | Kind::APSGBA | Kind::BSDIFF | Kind::GDIFF => {
+      Err(Error::UnsupportedPatchFeature)
+    }
+    #[cfg(feature = "plugins")]
+    Kind::Plugin(_) => Err(Error::UnsupportedPatchFeature),
+  }
+}
+
+/// Writes `ops` as a patch of the given format.
+///
+/// Formats that record checksums of the files they patch can't be encoded
+/// from operations alone, and neither can operations a format has no
+/// equivalent for; both return [`Error::UnsupportedPatchFeature`].
+pub fn encode(kind: Kind, ops: &[Op<'_>], output: &mut impl Write) -> Result<(), Error> {
+  match kind {
+    Kind::IPS => ips::encode(ops, output),
+    Kind::IPS32 => ips32::encode(ops, output),
+    Kind::PPF => ppf::encode(ops, output),
+    Kind::UPS
+    | Kind::BPS
+    | Kind::VCD
+    | Kind::RUP
+    | Kind::APS
+    | Kind::APSGBA
+    | Kind::BSDIFF
+    | Kind::GDIFF => Err(Error::UnsupportedPatchFeature),
+    #[cfg(feature = "plugins")]
+    Kind::Plugin(_) => Err(Error::UnsupportedPatchFeature),
+  }
+}
+
+/// Applies each operation it visits to a file.
+#[derive(Debug)]
+pub struct Applier<'a, O: ?Sized> {
+  output: &'a mut O,
+  buf: Vec<u8>,
+  overlap: Option<Overlap>,
+}
+
+impl<'a, O: OutputFile + ?Sized> Applier<'a, O> {
+  pub fn new(output: &'a mut O) -> Self {
+    Self { output, buf: Vec::new(), overlap: None }
+  }
+
+  /// Creates an applier that also reads the bytes each write is about to
+  /// replace, and counts how many of them already had their new value.
+  pub fn verifying(output: &'a mut O) -> Self {
+    Self {
+      overlap: Some(Overlap::default()),
+      ..Self::new(output)
+    }
+  }
+
+  /// How much of the file was already patched. Only available if the applier
+  /// was created with [`Applier::verifying`].
+  pub fn overlap(&self) -> Option<Overlap> {
+    self.overlap
+  }
+
+  /// Reads the `len` bytes at `offset` into `self.buf`. Bytes past the end of
+  /// the file are left out.
+  fn read_existing(&mut self, offset: u64, len: u64) -> io::Result<()> {
+    self.output.seek(io::SeekFrom::Start(offset))?;
+    self.buf.clear();
+    (&mut *self.output).take(len).read_to_end(&mut self.buf)?;
+    Ok(())
+  }
+}
+
+impl<O: OutputFile + ?Sized> Visitor for Applier<'_, O> {
+  fn visit(&mut self, op: &Op<'_>) -> Result<(), Error> {
+    match op {
+      Op::Expect { offset, data } => {
+        self.read_existing(*offset, data.len() as u64)?;
+        if self.buf[..] != data[..] {
+          return Err(Error::BadPatch);
+        }
+      }
+      Op::Write { offset, data } => {
+        if let Some(mut overlap) = self.overlap {
+          self.read_existing(*offset, data.len() as u64)?;
+          overlap.count(&self.buf, data.iter().copied(), data.len() as u64);
+          self.overlap = Some(overlap);
+        }
+        self.output.seek(io::SeekFrom::Start(*offset))?;
+        self.output.write_all(data)?;
+      }
+      Op::Fill { offset, len, byte } => {
+        if let Some(mut overlap) = self.overlap {
+          self.read_existing(*offset, *len)?;
+          overlap.count(&self.buf, std::iter::repeat(*byte), *len);
+          self.overlap = Some(overlap);
+        }
+        self.output.seek(io::SeekFrom::Start(*offset))?;
+        io::copy(&mut io::repeat(*byte).take(*len), self.output)?;
+      }
+      Op::Xor { offset, data } => {
+        self.read_existing(*offset, data.len() as u64)?;
+        self.buf.resize(data.len(), 0);
+        for (byte, mask) in self.buf.iter_mut().zip(data.iter()) {
+          *byte ^= mask;
+        }
+        self.output.seek(io::SeekFrom::Start(*offset))?;
+        self.output.write_all(&self.buf)?;
+      }
+      Op::Resize { len } => self.output.set_len(*len)?,
+    }
+    Ok(())
+  }
+}
+
+/// How many of the bytes written by a patch already had the value it wrote.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Overlap {
+  pub written: u64,
+  pub unchanged: u64,
+}
+
+impl Overlap {
+  /// The share of unchanged bytes at which a file is assumed to be patched already.
+  const PATCHED_PERCENT: u64 = 99;
+
+  fn count(&mut self, existing: &[u8], new: impl Iterator<Item = u8>, len: u64) {
+    self.written += len;
+    self.unchanged += existing.iter().zip(new).filter(|(a, b)| **a == *b).count() as u64;
+  }
+
+  /// Whether nearly every written byte already had its new value, which
+  /// suggests the patch was applied to this file before.
+  pub fn looks_patched(&self) -> bool {
+    self.written > 0 && self.unchanged * 100 >= self.written * Self::PATCHED_PERCENT
+  }
+
+  /// The share of written bytes that were unchanged, as a whole percentage.
+  pub fn percent(&self) -> u64 {
+    match self.written {
+      0 => 0,
+      written => self.unchanged * 100 / written,
+    }
+  }
+}
+
+/// A patch whose operations were decoded ahead of time, so that it can be
+/// applied to many files without parsing or validating it again.
+#[derive(Clone, Debug)]
+pub struct DecodedPatch {
+  kind: Kind,
+  header: Header,
+  ops: Vec<Op<'static>>,
+}
+
+impl DecodedPatch {
+  pub fn decode(kind: Kind, patch: &mut (impl Read + Seek + KnownLen)) -> Result<Self, Error> {
+    let header = header::read(kind, patch)?;
+    let mut ops = Vec::new();
+    decode(kind, patch, &mut ops)?;
+    Ok(Self { kind, header, ops })
+  }
+
+  pub fn kind(&self) -> Kind {
+    self.kind
+  }
+
+  pub fn header(&self) -> &Header {
+    &self.header
+  }
+
+  pub fn ops(&self) -> &[Op<'static>] {
+    &self.ops
+  }
+
+  /// Patches `file` in place. `file_checksum` is checked against the source
+  /// checksum in the patch's header, if there is one.
+  pub fn apply(&self, file: &mut impl OutputFile, file_checksum: Crc32) -> Result<(), Error> {
+    self.check_source(file_checksum)?;
+    self.replay(&mut Applier::new(file))?;
+    file.flush()?;
+    Ok(())
+  }
+
+  /// Checks `file_checksum` against the source checksum in the patch's
+  /// header, if there is one.
+  pub fn check_source(&self, file_checksum: Crc32) -> Result<(), Error> {
+    if (self.header.source_crc32).is_some_and(|crc32| crc32 != file_checksum) {
+      return Err(match self.header.target_crc32 == Some(file_checksum) {
+        true => Error::AlreadyPatched,
+        false => Error::WrongInputFile,
+      });
+    }
+    Ok(())
+  }
+
+  /// Passes every operation to `visitor` without checking the header.
+  pub fn replay(&self, visitor: &mut impl Visitor) -> Result<(), Error> {
+    self.ops.iter().try_for_each(|op| visitor.visit(op))
+  }
+}
@@ -0,0 +1,144 @@
+//! Options that change how particular patch formats are read, set with
+//! `--opt <format>.<option>=<value>`.
+//!
+//! Like the [`profile`](crate::profile), the options are set once at startup
+//! and read by the format modules with [`get`], so they don't have to be
+//! passed through every function that applies a patch.
+
+use crate::i18n;
+use std::sync::OnceLock;
+
+static OPTIONS: OnceLock<FormatOptions> = OnceLock::new();
+
+/// The suffixes of sizes, and the number of bytes each stands for.
+const SIZE_UNITS: [(&str, u64); 4] = [
+  ("GiB", 1 << 30),
+  ("MiB", 1 << 20),
+  ("KiB", 1 << 10),
+  ("B", 1),
+];
+
+#[derive(Clone, Debug, Default, clap::Args)]
+#[group(id = "format_options")]
+pub struct Args {
+  /// Change how a patch format is read. Can be given more than once.
+  /// "ips.lenient=true" applies IPS patches without an "EOF" marker;
+  /// "ppf.validate_blockcheck=warn" or "off" applies PPF patches whose block
+  /// check doesn't match the ROM, with or without a warning; and
+  /// "vcd.max_window=64MiB" refuses Vcdiff patches with larger windows.
+  #[arg(long = "opt", global = true, value_name = "KEY=VALUE", value_parser = parse_option)]
+  pub options: Vec<FormatOption>,
+}
+
+/// One option set on the command line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FormatOption {
+  IpsLenient(bool),
+  PpfValidateBlockCheck(BlockCheckValidation),
+  VcdMaxWindow(u64),
+}
+
+/// The options of every format.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct FormatOptions {
+  pub ips: IpsOptions,
+  pub ppf: PpfOptions,
+  pub vcd: VcdOptions,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct IpsOptions {
+  /// Whether a patch without an "EOF" marker is applied up to its end, and
+  /// a truncation size of zero is ignored, rather than the patch refused.
+  pub lenient: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct PpfOptions {
+  pub validate_block_check: BlockCheckValidation,
+}
+
+/// What happens to a PPF patch whose block check doesn't match the ROM.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum BlockCheckValidation {
+  /// The ROM's bytes are compared with the block check, and the patch is
+  /// refused if they differ.
+  #[default]
+  Bytes,
+  /// The bytes are compared, but a mismatch is only a warning.
+  Warn,
+  /// The block check is ignored.
+  Off,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct VcdOptions {
+  /// The most bytes a window may need for its source segment and target
+  /// window together.
+  pub max_window: Option<u64>,
+}
+
+impl From<&Args> for FormatOptions {
+  fn from(args: &Args) -> Self {
+    let mut options = Self::default();
+    for option in &args.options {
+      match *option {
+        FormatOption::IpsLenient(lenient) => options.ips.lenient = lenient,
+        FormatOption::PpfValidateBlockCheck(validation) => {
+          options.ppf.validate_block_check = validation
+        }
+        FormatOption::VcdMaxWindow(len) => options.vcd.max_window = Some(len),
+      }
+    }
+    options
+  }
+}
+
+/// Sets the options returned by [`get`]. Until this is called, the defaults
+/// are used.
+pub fn init(options: FormatOptions) {
+  let _ = OPTIONS.set(options);
+}
+
+pub fn get() -> &'static FormatOptions {
+  OPTIONS.get_or_init(FormatOptions::default)
+}
+
+fn parse_option(arg: &str) -> Result<FormatOption, String> {
+  let bad_value = || i18n::format("romhacks::patch::options::bad_value", &[("option", &arg)]);
+  let Some((key, value)) = arg.split_once('=') else {
+    return Err(i18n::text("romhacks::patch::options::malformed").to_owned());
+  };
+  match key {
+    "ips.lenient" => match value {
+      "true" => Ok(FormatOption::IpsLenient(true)),
+      "false" => Ok(FormatOption::IpsLenient(false)),
+      _ => Err(bad_value()),
+    },
+    "ppf.validate_blockcheck" => {
+      let validation = match value {
+        "bytes" => BlockCheckValidation::Bytes,
+        "warn" => BlockCheckValidation::Warn,
+        "off" => BlockCheckValidation::Off,
+        _ => return Err(bad_value()),
+      };
+      Ok(FormatOption::PpfValidateBlockCheck(validation))
+    }
+    "vcd.max_window" => parse_size(value)
+      .map(FormatOption::VcdMaxWindow)
+      .ok_or_else(bad_value),
+    _ => Err(i18n::format(
+      "romhacks::patch::options::unknown",
+      &[("key", &key)],
+    )),
+  }
+}
+
+/// Parses a number of bytes, optionally followed by "B", "KiB", "MiB" or
+/// "GiB".
+fn parse_size(value: &str) -> Option<u64> {
+  let (number, unit) = (SIZE_UNITS.iter())
+    .find_map(|&(suffix, unit)| Some((value.strip_suffix(suffix)?, unit)))
+    .unwrap_or((value, 1));
+  number.trim().parse::<u64>().ok()?.checked_mul(unit)
+}
@@ -0,0 +1,720 @@
+//! Patch formats added by plugins: shared libraries in the `plugins`
+//! directory of the configuration directory, loaded when the program starts.
+//!
+//! A plugin exports `romhacks_plugin`, a C function that takes no arguments
+//! and returns a pointer to a [`Descriptor`] that lives as long as the
+//! library. The descriptor starts with the version of the interface the
+//! plugin was built against, and plugins built against another version
+//! aren't loaded. `include/romhacks_plugin.h` declares the interface for
+//! plugins written in C.
+//!
+//! Plugin formats are identified by their magic strings after the built-in
+//! formats, so a plugin can't take over a built-in format's patches.
+
+use super::dynamic::{PatchFormat, PatchSource, Source};
+use super::{Capabilities, Error, Kind, OutputFile};
+use crate::crc::Crc32;
+use crate::error::prelude::*;
+use crate::io::prelude::*;
+use crate::{dirs, fs, i18n, identify, io};
+use std::ffi::{CStr, c_char, c_void};
+use std::sync::OnceLock;
+use std::{env, path, slice};
+
+/// The version of the interface this program implements. It's raised
+/// whenever [`Descriptor`] or [`Streams`] change.
+pub const ABI_VERSION: u32 = 1;
+
+/// The name of the function plugins export.
+const ENTRY_POINT: &[u8] = b"romhacks_plugin\0";
+
+/// The longest magic string a plugin format can have.
+pub const MAX_MAGIC_LEN: usize = 16;
+
+/// Capability flags for [`Descriptor::flags`], with the meanings of the
+/// fields of [`Capabilities`] with the same names.
+pub mod flags {
+  pub const SOURCE_CHECKSUM: u32 = 1 << 0;
+  pub const TARGET_CHECKSUM: u32 = 1 << 1;
+  pub const PATCH_CHECKSUM: u32 = 1 << 2;
+  pub const IN_PLACE: u32 = 1 << 3;
+  pub const SEEKS_SOURCE: u32 = 1 << 4;
+  pub const SEEKS_PATCH: u32 = 1 << 5;
+  pub const SEEKS_OUTPUT: u32 = 1 << 6;
+}
+
+/// The statuses a plugin's `apply` function returns, with the values
+/// `include/romhacks_plugin.h` gives them. Failures other than
+/// [`STREAM_ERROR`](status::STREAM_ERROR) stand for a [`patch::Error`](Error),
+/// and [`status::error`] is the one place they're mapped to it.
+pub mod status {
+  use super::Error;
+
+  pub const OK: i32 = 0;
+  /// A stream function failed, and the error it failed with is reported.
+  pub const STREAM_ERROR: i32 = 1;
+  pub const BAD_PATCH: i32 = 2;
+  pub const WRONG_INPUT_FILE: i32 = 3;
+  pub const ALREADY_PATCHED: i32 = 4;
+  pub const UNSUPPORTED_FEATURE: i32 = 5;
+  pub const FILE_TOO_LARGE: i32 = 6;
+
+  /// The error a failure other than [`STREAM_ERROR`] is reported as.
+  /// Statuses this version doesn't know are reported as a bad patch.
+  pub fn error(status: i32) -> Error {
+    match status {
+      WRONG_INPUT_FILE => Error::WrongInputFile,
+      ALREADY_PATCHED => Error::AlreadyPatched,
+      UNSUPPORTED_FEATURE => Error::UnsupportedPatchFeature,
+      FILE_TOO_LARGE => Error::FileTooLarge,
+      _ => Error::BadPatch,
+    }
+  }
+}
+
+/// What a plugin tells the program about its format.
+#[repr(C)]
+#[derive(Debug)]
+pub struct Descriptor {
+  /// The [`ABI_VERSION`] the plugin was built against.
+  pub abi_version: u32,
+  /// The name of the format on the command line, such as "aps": a
+  /// NUL-terminated string of lowercase ASCII letters and digits.
+  pub name: *const c_char,
+  /// The name of the format shown to users, as NUL-terminated UTF-8.
+  pub display_name: *const c_char,
+  /// The magic string at the start of the format's patches.
+  pub magic: *const u8,
+  pub magic_len: usize,
+  /// A combination of [`flags`].
+  pub flags: u32,
+  /// The largest file the format can address, in bytes, or 0 if there's
+  /// no limit.
+  pub max_file_size: u64,
+  /// Applies a patch, returning one of the [`status`] codes. Plugins whose
+  /// descriptor leaves it null aren't loaded.
+  pub apply: Option<unsafe extern "C" fn(streams: *const Streams) -> i32>,
+}
+
+/// A stream the program passes to a plugin. Each function returns -1 if it
+/// fails, in which case `apply` should return [`status::STREAM_ERROR`].
+#[repr(C)]
+#[derive(Debug)]
+pub struct Stream {
+  pub context: *mut c_void,
+  /// Reads up to `len` bytes, returning how many were read, or 0 at the end.
+  /// `buf` may only be null if `len` is 0.
+  pub read: unsafe extern "C" fn(context: *mut c_void, buf: *mut u8, len: usize) -> isize,
+  /// Writes up to `len` bytes, returning how many were written. Only the
+  /// output can be written to, and `buf` may only be null if `len` is 0.
+  pub write: unsafe extern "C" fn(context: *mut c_void, buf: *const u8, len: usize) -> isize,
+  /// Seeks like `fseek`, with `whence` 0 for the start, 1 for the current
+  /// position and 2 for the end, and returns the new position.
+  pub seek: unsafe extern "C" fn(context: *mut c_void, offset: i64, whence: i32) -> i64,
+  /// Truncates or extends the stream to `len` bytes, returning 0. Only the
+  /// output can be resized.
+  pub set_len: unsafe extern "C" fn(context: *mut c_void, len: u64) -> i32,
+}
+
+/// The arguments of a plugin's `apply` function. Formats that patch in
+/// place get an output that already holds a copy of the ROM.
+#[repr(C)]
+#[derive(Debug)]
+pub struct Streams {
+  pub rom: Stream,
+  pub patch: Stream,
+  pub output: Stream,
+  pub rom_crc32: u32,
+  pub patch_crc32: u32,
+  /// The length of the patch.
+  pub patch_len: u64,
+}
+
+/// A loaded plugin, whose format patches are applied through
+/// [`PatchFormat`].
+#[derive(Debug)]
+pub struct Plugin {
+  id: Id,
+  name: &'static str,
+  display_name: &'static str,
+  magic: &'static [u8],
+  capabilities: Capabilities,
+  apply: unsafe extern "C" fn(streams: *const Streams) -> i32,
+  /// Keeps the library loaded for as long as its functions can be called.
+  /// Plugins whose descriptor is part of the program, as in tests, have
+  /// none.
+  _library: Option<libloading::Library>,
+}
+
+/// Identifies a loaded plugin, so that [`Kind`] can stay `Copy`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Id(u16);
+
+impl Id {
+  pub fn plugin(self) -> &'static Plugin {
+    &all()[usize::from(self.0)]
+  }
+}
+
+static PLUGINS: OnceLock<Vec<Plugin>> = OnceLock::new();
+
+/// Loads the plugins in [`dir`], if they haven't been loaded yet. Plugins
+/// that can't be loaded are skipped with a warning.
+///
+/// Loading happens before commands restrict themselves with a sandbox, which
+/// wouldn't let the libraries be read.
+pub fn init() {
+  all();
+}
+
+/// Every loaded plugin, sorted by the name of its library.
+pub fn all() -> &'static [Plugin] {
+  PLUGINS.get_or_init(|| load_dir(&dir()))
+}
+
+/// The directory plugins are loaded from.
+pub fn dir() -> path::PathBuf {
+  dirs::config_dir().join("plugins")
+}
+
+fn load_dir(dir: &path::Path) -> Vec<Plugin> {
+  let mut paths: Vec<path::PathBuf> = match fs::read_dir(dir) {
+    Ok(entries) => (entries.filter_map(Result::ok))
+      .map(|entry| entry.path())
+      .filter(|path| {
+        path
+          .extension()
+          .is_some_and(|ext| ext == env::consts::DLL_EXTENSION)
+      })
+      .collect(),
+    Err(err) if err.kind() == io::ErrorKind::NotFound => return Vec::new(),
+    Err(err) => {
+      log::warn!("{err}");
+      return Vec::new();
+    }
+  };
+  paths.sort();
+  let mut plugins: Vec<Plugin> = Vec::new();
+  for path in paths {
+    let Ok(id) = u16::try_from(plugins.len()).map(Id) else {
+      log::warn!(
+        "{}",
+        i18n::format(
+          "romhacks::plugin::not_loaded",
+          &[("path", &path.display()), ("error", &LoadError::TooMany)]
+        )
+      );
+      continue;
+    };
+    let loaded = Plugin::load(&path, id).and_then(|plugin| {
+      let taken = Kind::ALL
+        .iter()
+        .any(|&kind| identify::name(kind) == plugin.name)
+        || plugins.iter().any(|other| other.name == plugin.name);
+      match taken {
+        true => Err(LoadError::DuplicateName { name: plugin.name.to_owned() }),
+        false => Ok(plugin),
+      }
+    });
+    match loaded {
+      Ok(plugin) => {
+        log::debug!(
+          "{}",
+          i18n::format(
+            "romhacks::plugin::loaded",
+            &[("name", &plugin.name), ("path", &path.display())]
+          )
+        );
+        plugins.push(plugin);
+      }
+      Err(err) => log::warn!(
+        "{}",
+        i18n::format(
+          "romhacks::plugin::not_loaded",
+          &[("path", &path.display()), ("error", &err)]
+        )
+      ),
+    }
+  }
+  plugins
+}
+
+impl Plugin {
+  /// Loads the library at `path` and reads its descriptor, for the plugin
+  /// `id` will identify.
+  fn load(path: &path::Path, id: Id) -> Result<Plugin, LoadError> {
+    // SAFETY: loading a library runs its initializers, which is what
+    // installing it in the plugins directory asks for.
+    let library = unsafe { libloading::Library::new(path) }?;
+    // SAFETY: the plugin interface gives the entry point this signature.
+    let descriptor = unsafe {
+      let entry_point: libloading::Symbol<unsafe extern "C" fn() -> *const Descriptor> =
+        library.get(ENTRY_POINT)?;
+      entry_point()
+    };
+    // SAFETY: the plugin interface requires the descriptor and the strings
+    // it points to to live as long as the library, which is never unloaded.
+    unsafe { Plugin::from_descriptor(descriptor, id, Some(library)) }
+  }
+
+  /// Reads a plugin's descriptor, for the plugin `id` will identify.
+  ///
+  /// # Safety
+  ///
+  /// `descriptor` must be null or point to a descriptor whose ABI version
+  /// can be read. If that's [`ABI_VERSION`], it must have this version's
+  /// layout, and the descriptor, the strings it points to and its
+  /// functions must be valid for as long as the program runs.
+  unsafe fn from_descriptor(
+    descriptor: *const Descriptor,
+    id: Id,
+    library: Option<libloading::Library>,
+  ) -> Result<Plugin, LoadError> {
+    if descriptor.is_null() {
+      return Err(LoadError::NoDescriptor);
+    }
+    // SAFETY: every version of the descriptor starts with the ABI version,
+    // so it can be read before the rest of the layout is known.
+    let abi_version = unsafe { descriptor.cast::<u32>().read() };
+    if abi_version != ABI_VERSION {
+      return Err(LoadError::AbiVersion { found: abi_version });
+    }
+    // SAFETY: the descriptor has the layout of this version.
+    let descriptor: &'static Descriptor = unsafe { &*descriptor };
+    let name = unsafe { static_str(descriptor.name) }.ok_or(LoadError::BadName)?;
+    if name.is_empty()
+      || !(name.bytes()).all(|byte| byte.is_ascii_lowercase() || byte.is_ascii_digit())
+    {
+      return Err(LoadError::BadName);
+    }
+    let display_name = unsafe { static_str(descriptor.display_name) }.unwrap_or(name);
+    if descriptor.magic.is_null() || !(1..=MAX_MAGIC_LEN).contains(&descriptor.magic_len) {
+      return Err(LoadError::BadMagic);
+    }
+    let magic = unsafe { slice::from_raw_parts(descriptor.magic, descriptor.magic_len) };
+    let apply = descriptor.apply.ok_or(LoadError::NoApply)?;
+    let has = |flag: u32| descriptor.flags & flag != 0;
+    Ok(Plugin {
+      id,
+      name,
+      display_name,
+      magic,
+      capabilities: Capabilities {
+        source_checksum: has(flags::SOURCE_CHECKSUM),
+        target_checksum: has(flags::TARGET_CHECKSUM),
+        patch_checksum: has(flags::PATCH_CHECKSUM),
+        creation: false,
+        in_place: has(flags::IN_PLACE),
+        max_file_size: match descriptor.max_file_size {
+          0 => u64::MAX,
+          size => size,
+        },
+        seeks_source: has(flags::SEEKS_SOURCE),
+        seeks_patch: has(flags::SEEKS_PATCH),
+        seeks_output: has(flags::SEEKS_OUTPUT),
+      },
+      apply,
+      _library: library,
+    })
+  }
+
+  /// The name of the format on the command line.
+  pub fn name(&self) -> &'static str {
+    self.name
+  }
+
+  pub fn display_name(&self) -> &'static str {
+    self.display_name
+  }
+
+  pub fn magic(&self) -> &'static [u8] {
+    self.magic
+  }
+}
+
+impl PatchFormat for Plugin {
+  fn kind(&self) -> Kind {
+    Kind::Plugin(self.id)
+  }
+
+  fn capabilities(&self) -> Capabilities {
+    self.capabilities
+  }
+
+  fn apply(
+    &self,
+    rom: &mut dyn Source,
+    patch: &mut dyn PatchSource,
+    output: &mut dyn OutputFile,
+    rom_checksum: Crc32,
+    patch_checksum: Crc32,
+    patch_eof: u64,
+  ) -> Result<(), Error> {
+    // A patch stream is a source too, but only a sized one can be passed as
+    // such.
+    let mut patch_source = patch;
+    let mut rom = Context::new(Access::Read(rom));
+    let mut patch = Context::new(Access::Read(&mut patch_source));
+    let mut output = Context::new(Access::Write(output));
+    let streams = Streams {
+      rom: rom.stream(),
+      patch: patch.stream(),
+      output: output.stream(),
+      rom_crc32: rom_checksum.value(),
+      patch_crc32: patch_checksum.value(),
+      patch_len: patch_eof,
+    };
+    // SAFETY: the streams point to contexts that outlive the call.
+    let status = unsafe { (self.apply)(&streams) };
+    match status {
+      status::OK => Ok(()),
+      status::STREAM_ERROR => {
+        let error = [rom.error, patch.error, output.error]
+          .into_iter()
+          .flatten()
+          .next();
+        Err(error.map_or(Error::BadPatch, Error::from))
+      }
+      failure => Err(status::error(failure)),
+    }
+  }
+}
+
+/// Borrows a NUL-terminated UTF-8 string that lives as long as the program.
+///
+/// # Safety
+///
+/// `ptr` must be null or point to a NUL-terminated string that's never
+/// freed.
+unsafe fn static_str(ptr: *const c_char) -> Option<&'static str> {
+  match ptr.is_null() {
+    true => None,
+    false => unsafe { CStr::from_ptr(ptr) }.to_str().ok(),
+  }
+}
+
+/// The ID of each loaded plugin.
+pub fn ids() -> impl Iterator<Item = Id> {
+  (0..all().len()).map(|index| Id(index as u16))
+}
+
+enum Access<'a> {
+  Read(&'a mut dyn Source),
+  Write(&'a mut dyn OutputFile),
+}
+
+/// What a [`Stream`]'s context points to: the stream, and the error its last
+/// failed call returned.
+struct Context<'a> {
+  access: Access<'a>,
+  error: Option<io::Error>,
+}
+
+impl<'a> Context<'a> {
+  fn new(access: Access<'a>) -> Self {
+    Self { access, error: None }
+  }
+
+  fn stream(&mut self) -> Stream {
+    Stream {
+      context: (self as *mut Self).cast(),
+      read: read_stream,
+      write: write_stream,
+      seek: seek_stream,
+      set_len: set_stream_len,
+    }
+  }
+
+  /// Calls `f` with the context of a stream, keeping its error if it fails.
+  ///
+  /// # Safety
+  ///
+  /// `context` must come from [`Context::stream`], and the context must
+  /// still be alive.
+  unsafe fn call<T>(
+    context: *mut c_void,
+    f: impl FnOnce(&mut Access) -> io::Result<T>,
+  ) -> Option<T> {
+    let context = unsafe { &mut *context.cast::<Context<'_>>() };
+    match f(&mut context.access) {
+      Ok(value) => Some(value),
+      Err(err) => {
+        context.error = Some(err);
+        None
+      }
+    }
+  }
+}
+
+fn read_only() -> io::Error {
+  io::Error::from(io::ErrorKind::PermissionDenied)
+}
+
+/// Fails a read or write of a null buffer that isn't empty.
+fn null_buffer() -> io::Error {
+  io::Error::from(io::ErrorKind::InvalidInput)
+}
+
+unsafe extern "C" fn read_stream(context: *mut c_void, buf: *mut u8, len: usize) -> isize {
+  if len == 0 {
+    return 0;
+  }
+  let read = unsafe {
+    Context::call(context, |access| {
+      if buf.is_null() {
+        return Err(null_buffer());
+      }
+      // SAFETY: the plugin passes a buffer of at least `len` bytes.
+      let buf = slice::from_raw_parts_mut(buf, len);
+      match access {
+        Access::Read(stream) => stream.read(buf),
+        Access::Write(stream) => stream.read(buf),
+      }
+    })
+  };
+  read.map_or(-1, |read| read as isize)
+}
+
+unsafe extern "C" fn write_stream(context: *mut c_void, buf: *const u8, len: usize) -> isize {
+  if len == 0 {
+    return 0;
+  }
+  let written = unsafe {
+    Context::call(context, |access| {
+      if buf.is_null() {
+        return Err(null_buffer());
+      }
+      // SAFETY: the plugin passes a buffer of at least `len` bytes.
+      let buf = slice::from_raw_parts(buf, len);
+      match access {
+        Access::Read(_) => Err(read_only()),
+        Access::Write(stream) => stream.write(buf),
+      }
+    })
+  };
+  written.map_or(-1, |written| written as isize)
+}
+
+unsafe extern "C" fn seek_stream(context: *mut c_void, offset: i64, whence: i32) -> i64 {
+  let pos = match whence {
+    0 => u64::try_from(offset).map(io::SeekFrom::Start),
+    1 => Ok(io::SeekFrom::Current(offset)),
+    2 => Ok(io::SeekFrom::End(offset)),
+    _ => return -1,
+  };
+  let Ok(pos) = pos else {
+    return -1;
+  };
+  let position = unsafe {
+    Context::call(context, |access| match access {
+      Access::Read(stream) => stream.seek(pos),
+      Access::Write(stream) => stream.seek(pos),
+    })
+  };
+  position
+    .and_then(|position| i64::try_from(position).ok())
+    .unwrap_or(-1)
+}
+
+unsafe extern "C" fn set_stream_len(context: *mut c_void, len: u64) -> i32 {
+  let resized = unsafe {
+    Context::call(context, |access| match access {
+      Access::Read(_) => Err(read_only()),
+      Access::Write(stream) => stream.set_len(len),
+    })
+  };
+  resized.map_or(-1, |()| 0)
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum LoadError {
+  #[error(transparent)]
+  Library(#[from] libloading::Error),
+  #[error("{}", i18n::text("romhacks::plugin::no_descriptor"))]
+  NoDescriptor,
+  #[error("{}", i18n::format(
+    "romhacks::plugin::abi_version",
+    &[("found", found), ("expected", &ABI_VERSION)]
+  ))]
+  AbiVersion { found: u32 },
+  #[error("{}", i18n::text("romhacks::plugin::no_apply"))]
+  NoApply,
+  #[error("{}", i18n::text("romhacks::plugin::bad_name"))]
+  BadName,
+  #[error("{}", i18n::format("romhacks::plugin::bad_magic", &[("max", &MAX_MAGIC_LEN)]))]
+  BadMagic,
+  #[error("{}", i18n::format("romhacks::plugin::duplicate_name", &[("name", name)]))]
+  DuplicateName { name: String },
+  #[error("{}", i18n::text("romhacks::plugin::too_many"))]
+  TooMany,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+
+  const MAGIC: &[u8] = b"TEST";
+
+  /// Applies a patch of a format whose patches are the magic string
+  /// followed by the patched file.
+  unsafe extern "C" fn copy_patch(streams: *const Streams) -> i32 {
+    let Streams { patch, output, .. } = unsafe { &*streams };
+    if unsafe { (patch.seek)(patch.context, MAGIC.len() as i64, 0) } < 0 {
+      return status::STREAM_ERROR;
+    }
+    let mut buf = [0; 16];
+    loop {
+      let read = unsafe { (patch.read)(patch.context, buf.as_mut_ptr(), buf.len()) };
+      if read <= 0 {
+        return if read == 0 { status::OK } else { status::STREAM_ERROR };
+      }
+      if unsafe { (output.write)(output.context, buf.as_ptr(), read as usize) } != read {
+        return status::STREAM_ERROR;
+      }
+    }
+  }
+
+  /// Tries to write to the ROM, which is read-only.
+  unsafe extern "C" fn write_rom(streams: *const Streams) -> i32 {
+    let rom = unsafe { &(*streams).rom };
+    match unsafe { (rom.write)(rom.context, MAGIC.as_ptr(), MAGIC.len()) } {
+      -1 => status::STREAM_ERROR,
+      _ => status::OK,
+    }
+  }
+
+  unsafe extern "C" fn wrong_input_file(_streams: *const Streams) -> i32 {
+    status::WRONG_INPUT_FILE
+  }
+
+  fn descriptor(apply: Option<unsafe extern "C" fn(*const Streams) -> i32>) -> Descriptor {
+    Descriptor {
+      abi_version: ABI_VERSION,
+      name: c"test".as_ptr(),
+      display_name: c"Test".as_ptr(),
+      magic: MAGIC.as_ptr(),
+      magic_len: MAGIC.len(),
+      flags: flags::TARGET_CHECKSUM | flags::SEEKS_PATCH,
+      max_file_size: 0,
+      apply,
+    }
+  }
+
+  /// Reads `descriptor` like the descriptor of the first plugin in a
+  /// library.
+  fn load(descriptor: Descriptor) -> Result<Plugin, LoadError> {
+    let descriptor: &'static Descriptor = Box::leak(Box::new(descriptor));
+    // SAFETY: the descriptor is never freed, and its strings and its magic
+    // string are literals.
+    unsafe { Plugin::from_descriptor(descriptor, Id(0), None) }
+  }
+
+  fn apply(plugin: &Plugin, patch: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut output = Cursor::new(Vec::new());
+    plugin.apply(
+      &mut Cursor::new(b"rom"),
+      &mut Cursor::new(patch),
+      &mut output,
+      Crc32::new(0),
+      Crc32::new(0),
+      patch.len() as u64,
+    )?;
+    Ok(output.into_inner())
+  }
+
+  #[test]
+  fn loads_descriptor() {
+    let plugin = load(descriptor(Some(copy_patch))).unwrap();
+    assert_eq!(plugin.kind(), Kind::Plugin(Id(0)));
+    assert_eq!(plugin.name(), "test");
+    assert_eq!(plugin.display_name(), "Test");
+    assert_eq!(plugin.magic(), MAGIC);
+    let capabilities = plugin.capabilities();
+    assert!(capabilities.target_checksum && capabilities.seeks_patch);
+    assert!(!capabilities.source_checksum && !capabilities.in_place);
+    assert_eq!(capabilities.max_file_size, u64::MAX);
+  }
+
+  #[test]
+  fn applies_patch_through_streams() {
+    let plugin = load(descriptor(Some(copy_patch))).unwrap();
+    let patch = [MAGIC, b"a file longer than the buffer"].concat();
+    assert_eq!(
+      apply(&plugin, &patch).unwrap(),
+      b"a file longer than the buffer"
+    );
+  }
+
+  #[test]
+  fn reports_error_of_failed_stream() {
+    let plugin = load(descriptor(Some(write_rom))).unwrap();
+    assert!(matches!(
+      apply(&plugin, MAGIC),
+      Err(Error::IO(err)) if err.kind() == io::ErrorKind::PermissionDenied
+    ));
+  }
+
+  #[test]
+  fn maps_statuses_to_errors() {
+    let plugin = load(descriptor(Some(wrong_input_file))).unwrap();
+    assert!(matches!(apply(&plugin, MAGIC), Err(Error::WrongInputFile)));
+  }
+
+  #[test]
+  fn statuses_match_header() {
+    let header = include_str!("../../include/romhacks_plugin.h");
+    let defined = |name: &str| -> i32 {
+      let prefix = format!("#define ROMHACKS_{name} ");
+      let line = header.lines().find_map(|line| line.strip_prefix(&prefix));
+      let value = line.and_then(|line| line.split_whitespace().next());
+      value.unwrap().parse().unwrap()
+    };
+    assert_eq!(defined("OK"), status::OK);
+    assert_eq!(defined("STREAM_ERROR"), status::STREAM_ERROR);
+    assert_eq!(defined("BAD_PATCH"), status::BAD_PATCH);
+    assert_eq!(defined("WRONG_INPUT_FILE"), status::WRONG_INPUT_FILE);
+    assert_eq!(defined("ALREADY_PATCHED"), status::ALREADY_PATCHED);
+    assert_eq!(defined("UNSUPPORTED_FEATURE"), status::UNSUPPORTED_FEATURE);
+    assert_eq!(defined("FILE_TOO_LARGE"), status::FILE_TOO_LARGE);
+  }
+
+  #[test]
+  fn each_failure_is_a_different_error() {
+    let errors: Vec<String> = (status::BAD_PATCH..=status::FILE_TOO_LARGE)
+      .map(|failure| format!("{:?}", status::error(failure)))
+      .collect();
+    let mut distinct = errors.clone();
+    distinct.sort();
+    distinct.dedup();
+    assert_eq!(distinct.len(), errors.len(), "{errors:?}");
+    assert!(matches!(status::error(i32::MAX), Error::BadPatch));
+  }
+
+  #[test]
+  fn refuses_descriptor_without_apply() {
+    assert!(matches!(load(descriptor(None)), Err(LoadError::NoApply)));
+  }
+
+  #[test]
+  fn refuses_other_abi_version() {
+    let descriptor = Descriptor {
+      abi_version: ABI_VERSION + 1,
+      ..descriptor(Some(copy_patch))
+    };
+    assert!(matches!(
+      load(descriptor),
+      Err(LoadError::AbiVersion { found }) if found == ABI_VERSION + 1
+    ));
+  }
+
+  #[test]
+  fn refuses_bad_name() {
+    let descriptor = Descriptor {
+      name: c"Test".as_ptr(),
+      ..descriptor(Some(copy_patch))
+    };
+    assert!(matches!(load(descriptor), Err(LoadError::BadName)));
+  }
+}
@@ -1,6 +1,8 @@
 use crate::convert::prelude::*;
 use crate::io::prelude::*;
-use crate::{io, mem, patch};
+use crate::patch::ops::{Op, Visitor};
+use crate::patch::options::BlockCheckValidation;
+use crate::{buffers, i18n, io, mem, patch, profile};
 use std::borrow::Cow;
 use std::fmt::Formatter;
 use std::num;
@@ -11,39 +13,176 @@ const BLOCK_CHECK_LENGTH: usize = 1024;
 
 /// Applies a PPF patch to a ROM.
 pub fn patch(
-  rom: &mut (impl Read + Write + Seek),
+  rom: &mut impl patch::OutputFile,
   patch: &mut (impl Read + Seek),
 ) -> Result<(), patch::Error> {
   // This value isn't needed yet, but it's better to obtain it now since doing
   // so later might discard the internal buffer of the BufReader.
   let eof: u64 = patch.seek(io::SeekFrom::End(0))?;
   patch.seek(io::SeekFrom::Start(0))?;
-  let mut patch = io::BufReader::new(patch);
+  let mut patch = io::BufReader::with_capacity(buffers::patch_reader(eof), patch);
+
+  let format = Format::parse(&mut patch, eof)?;
+  if let Some(block_check) = &format.block_check {
+    match patch::options::get().ppf.validate_block_check {
+      BlockCheckValidation::Bytes => block_check.validate(rom)?,
+      BlockCheckValidation::Warn => match block_check.validate(rom) {
+        Err(patch::Error::BadPatch) => {
+          log::warn!(
+            "{}",
+            i18n::text("romhacks::patch::ppf_block_check_mismatch")
+          )
+        }
+        validated => validated?,
+      },
+      BlockCheckValidation::Off => {}
+    }
+  }
+  format.apply_patch(&mut patch, rom, false)?;
+  Ok(())
+}
+
+/// Restores the ROM a PPF3 patch was applied to from the undo data the patch
+/// holds, given the patched file.
+///
+/// The block check isn't validated, since it's a copy of the ROM before
+/// patching. Patches without undo data fail with
+/// [`patch::Error::NoUndoData`].
+pub fn unpatch(
+  rom: &mut impl patch::OutputFile,
+  patch: &mut (impl Read + Seek),
+) -> Result<(), patch::Error> {
+  let eof: u64 = patch.seek(io::SeekFrom::End(0))?;
+  patch.seek(io::SeekFrom::Start(0))?;
+  let mut patch = io::BufReader::with_capacity(buffers::patch_reader(eof), patch);
 
-  let format = Format::parse_and_validate(&mut patch, rom, eof)?;
-  format.apply_patch(&mut patch, rom)?;
+  let format = Format::parse(&mut patch, eof)?;
+  if !format.has_undo_data {
+    return Err(patch::Error::NoUndoData);
+  }
+  format.apply_patch(&mut patch, rom, true)?;
   Ok(())
 }
 
+/// Passes the block check and each record in a PPF patch to `visitor`.
+pub fn decode(
+  patch: &mut (impl Read + Seek),
+  visitor: &mut impl Visitor,
+) -> Result<(), patch::Error> {
+  let eof: u64 = patch.seek(io::SeekFrom::End(0))?;
+  patch.seek(io::SeekFrom::Start(0))?;
+  let mut patch = io::BufReader::with_capacity(buffers::patch_reader(eof), patch);
+
+  let format = Format::parse(&mut patch, eof)?;
+  // Ops can only expect contents, so a block check that isn't strictly
+  // validated is left out.
+  let validation = patch::options::get().ppf.validate_block_check;
+  let block_check =
+    (format.block_check.as_ref()).filter(|_| validation == BlockCheckValidation::Bytes);
+  if let Some(block_check) = block_check {
+    visitor.visit(&Op::Expect {
+      offset: block_check.image_type.block_check_offset().get().into(),
+      data: Cow::Borrowed(&block_check.block[..]),
+    })?;
+  }
+  format.decode_records(&mut patch, visitor)
+}
+
+/// Writes `ops` as a PPF3 patch without undo data.
+///
+/// A leading [`Op::Expect`] of a whole block at one of the block check offsets
+/// becomes the patch's block check. Any other expected contents, XORs and
+/// resizes can't be expressed in PPF and fail with
+/// [`patch::Error::UnsupportedPatchFeature`].
+pub fn encode(ops: &[Op<'_>], output: &mut impl Write) -> Result<(), patch::Error> {
+  const MAX_HUNK_SIZE: usize = u8::MAX as usize;
+
+  let (block_check, ops) = match ops.split_first() {
+    Some((Op::Expect { offset, data }, rest)) => {
+      let image_type = [ImageType::BIN, ImageType::GI]
+        .into_iter()
+        .find(|image_type| u64::from(image_type.block_check_offset().get()) == *offset)
+        .filter(|_| data.len() == BLOCK_CHECK_LENGTH)
+        .ok_or(patch::Error::UnsupportedPatchFeature)?;
+      (Some((image_type, data)), rest)
+    }
+    _ => (None, ops),
+  };
+
+  let mut output = io::BufWriter::with_capacity(profile::get().buf_size, output);
+  output.write_all(b"PPF30")?;
+  output.write_all(&[2])?;
+  output.write_all(&[b' '; 50])?;
+  let image_type = block_check.map_or(ImageType::BIN, |(image_type, _)| image_type);
+  output.write_all(&[
+    image_type as u8,
+    block_check.is_some() as u8,
+    false as u8, // undo data
+    0,           // unused
+  ])?;
+  if let Some((_, block)) = block_check {
+    output.write_all(block)?;
+  }
+
+  let mut write_record = |offset: u64, hunk: &[u8]| -> io::Result<()> {
+    output.write_all(&offset.to_le_bytes())?;
+    output.write_all(&[hunk.len() as u8])?;
+    output.write_all(hunk)
+  };
+  for op in ops {
+    match op {
+      Op::Write { offset, data } => {
+        for (i, hunk) in data.chunks(MAX_HUNK_SIZE).enumerate() {
+          write_record(offset + (i * MAX_HUNK_SIZE) as u64, hunk)?;
+        }
+      }
+      Op::Fill { offset, len, byte } => {
+        let hunk = [*byte; MAX_HUNK_SIZE];
+        let mut pos = 0;
+        while pos < *len {
+          let run = (len - pos).min(MAX_HUNK_SIZE as u64);
+          write_record(offset + pos, &hunk[..run as usize])?;
+          pos += run;
+        }
+      }
+      Op::Expect { .. } | Op::Xor { .. } | Op::Resize { .. } => {
+        return Err(patch::Error::UnsupportedPatchFeature);
+      }
+    }
+  }
+  output.flush()?;
+  Ok(())
+}
+
+/// Reads the version from the magic string at the start of a PPF patch
+/// without consuming it.
+pub fn sniff_version(patch: &mut impl io::BufReadExt) -> Result<Version, patch::Error> {
+  let magic: &[u8; 5] = (patch.peek(5)?)
+    .try_into()
+    .map_err(|_| patch::Error::BadPatch)?;
+  Version::try_from(magic)
+}
+
 /// Details about the format of a PPF file.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 struct Format {
   patch_range: std::ops::Range<u64>,
   rom_offset_type: RomOffsetType,
   has_undo_data: bool,
+  block_check: Option<BlockCheck>,
 }
 
 impl Format {
-  /// Parses the PPF header and footer and performs block check validation.
+  /// Parses the PPF header and footer, including the block check if there is
+  /// one. The block check isn't compared against the ROM.
   ///
   /// `patch`'s cursor must be at the start of the file, and `eof` must be the
   /// length of the PPF file.
   ///
   /// If this method returns `Ok`, `patch` will be positioned at the start of
   /// the patch data. No guarantees are made about its cursor position otherwise.
-  pub fn parse_and_validate(
+  pub fn parse(
     patch: &mut io::BufReader<impl Read + Seek>,
-    rom: &mut (impl Read + Seek),
     eof: u64,
   ) -> Result<Format, patch::Error> {
     // applyppf3 parses the magic string to obtain the version number and
@@ -71,18 +210,20 @@ impl Format {
         patch_range: 56..eof,
         rom_offset_type: RomOffsetType::U32,
         has_undo_data: false,
+        block_check: None,
       },
       Version::V2 => {
         // File size checks were deprecated in V3 because they were unreliable,
         // but an absent file size might indicate an invalid PPF file.
         num::NonZeroU32::try_from(patch.read_u32::<LE>()?).map_err(|_| patch::Error::BadPatch)?;
-        BlockCheck(ImageType::BIN).validate(patch, rom)?;
+        let block_check = BlockCheck::read(ImageType::BIN, patch)?;
         let pos: u64 = 60 + BLOCK_CHECK_LENGTH as u64;
         let end_of_patch = Self::find_end_of_patch(patch, FooterBodyLengthType::U32, pos..eof)?;
         Format {
           patch_range: pos..end_of_patch,
           rom_offset_type: RomOffsetType::U32,
           has_undo_data: false,
+          block_check: Some(block_check),
         }
       }
       Version::V3 => {
@@ -95,14 +236,16 @@ impl Format {
           .map_err(|_| patch::Error::BadPatch)?;
         patch.seek_relative(1)?; // Unused in V3
         let pos: u64 = 60 + (has_block_check as u64 * BLOCK_CHECK_LENGTH as u64);
-        if has_block_check {
-          BlockCheck(image_type).validate(patch, rom)?;
-        }
+        let block_check = match has_block_check {
+          true => Some(BlockCheck::read(image_type, patch)?),
+          false => None,
+        };
         let end_of_patch = Self::find_end_of_patch(patch, FooterBodyLengthType::U16, pos..eof)?;
         Format {
           patch_range: pos..end_of_patch,
           rom_offset_type: RomOffsetType::U64,
           has_undo_data,
+          block_check,
         }
       }
     })
@@ -188,7 +331,7 @@ impl Format {
     // If there's no footer, seek back to the start of the patch data and return
     // EOF. This is the most common case.
     if buf != END_MAGIC {
-      seek_to_start(patch, end_magic_pos)?;
+      seek_to_start(patch, end_magic_pos + END_MAGIC.len() as u64)?;
       return Ok(range.end);
     }
 
@@ -225,14 +368,17 @@ impl Format {
     Ok(footer_pos)
   }
 
+  /// Writes each record's bytes to `rom`, or its undo data if `undo` is set.
   pub fn apply_patch(
     self: Format,
     patch: &mut io::BufReader<impl Read + Seek>,
     rom: &mut (impl Write + Seek),
+    undo: bool,
   ) -> Result<(), patch::Error> {
-    let Format { patch_range, rom_offset_type, has_undo_data } = self;
-    let mut patch = patch.take(patch_range.end - patch_range.start);
-    let mut rom = io::BufWriter::new(rom);
+    let Format { patch_range, rom_offset_type, has_undo_data, .. } = self;
+    let patch_len = patch_range.end - patch_range.start;
+    let mut patch = patch.take(patch_len);
+    let mut rom = io::BufWriter::with_capacity(buffers::hunk_writer(patch_len), rom);
     let mut rom_offset: u64 = 0;
 
     loop {
@@ -253,11 +399,18 @@ impl Format {
         rom_offset = offset;
       }
 
-      io::copy(&mut ((&mut patch).take(hunk_length)), &mut rom)?;
+      // The Take adapter doesn't implement Seek, so the half of the record
+      // that isn't written is discarded into Sink.
+      if undo {
+        io::copy(&mut (&mut patch).take(hunk_length), &mut io::sink())?;
+      }
+      io::copy(
+        &mut io::ExactSizeRead::new(&mut patch, hunk_length),
+        &mut rom,
+      )?;
       rom_offset += hunk_length;
 
-      if has_undo_data {
-        // The Take adapter doesn't implement Seek, so discard the bytes into Sink.
+      if has_undo_data && !undo {
         io::copy(&mut (&mut patch).take(hunk_length), &mut io::sink())?;
       }
 
@@ -269,24 +422,64 @@ impl Format {
     rom.flush()?;
     Ok(())
   }
+
+  /// Passes each record in the patch data to `visitor`, as
+  /// [`apply_patch`](Format::apply_patch) would apply them.
+  pub fn decode_records(
+    self: Format,
+    patch: &mut io::BufReader<impl Read + Seek>,
+    visitor: &mut impl Visitor,
+  ) -> Result<(), patch::Error> {
+    let Format { patch_range, rom_offset_type, has_undo_data, .. } = self;
+    let mut patch = patch.take(patch_range.end - patch_range.start);
+    let mut data = Vec::new();
+
+    loop {
+      let offset = u64::from_le_bytes(mem::try_init([0u8; mem::size_of::<u64>()], |buf| {
+        patch.read_exact(&mut buf[..rom_offset_type.size()])
+      })?);
+
+      let hunk_length: u64 = match num::NonZeroU8::new(patch.read_u8()?) {
+        Some(x) => x.get() as u64,
+        None => Err(patch::Error::BadPatch)?,
+      };
+
+      data.clear();
+      io::ExactSizeRead::new(&mut patch, hunk_length).read_to_end(&mut data)?;
+      visitor.visit(&Op::Write { offset, data: Cow::Borrowed(&data) })?;
+
+      if has_undo_data {
+        io::copy(&mut (&mut patch).take(hunk_length), &mut io::sink())?;
+      }
+
+      if patch.limit() == 0 {
+        break;
+      }
+    }
+    Ok(())
+  }
 }
 
-/// A PPF2 or PPF3 block check.
-#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
-pub struct BlockCheck(ImageType);
+/// A PPF2 or PPF3 block check: a copy of 1 KiB of the ROM the patch is for.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BlockCheck {
+  image_type: ImageType,
+  block: Box<[u8; BLOCK_CHECK_LENGTH]>,
+}
 
 impl BlockCheck {
-  pub fn validate(
-    &self,
-    patch: &mut impl Read,
-    file: &mut (impl Read + Seek),
-  ) -> Result<(), patch::Error> {
+  /// Reads the block check from the patch header.
+  pub fn read(image_type: ImageType, patch: &mut impl Read) -> Result<Self, patch::Error> {
+    Ok(Self { image_type, block: Box::new(patch.read_array()?) })
+  }
+
+  /// Checks that `file` contains the block.
+  pub fn validate(&self, file: &mut (impl Read + Seek)) -> Result<(), patch::Error> {
     file.seek(io::SeekFrom::Start(
-      self.0.block_check_offset().get().into(),
+      self.image_type.block_check_offset().get().into(),
     ))?;
     let file_block: [u8; BLOCK_CHECK_LENGTH] = file.read_array()?;
-    let validation_block: [u8; BLOCK_CHECK_LENGTH] = patch.read_array()?;
-    if file_block != validation_block {
+    if file_block != *self.block {
       Err(patch::Error::BadPatch)?;
     }
     Ok(())
@@ -398,3 +591,57 @@ impl RomOffsetType {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::patch::Kind;
+  use crate::patch::tests::apply;
+  use romhacks_testkit::{PpfBuilder, ppf as builder, rom};
+  use std::io::Cursor;
+
+  /// Records overwriting bytes in the middle of the image, including two
+  /// that follow each other.
+  fn records(image: &[u8]) -> PpfBuilder {
+    PpfBuilder::new()
+      .block_check(image)
+      .hunk(0x20, b"romhacks")
+      .hunk(0x9400, &[0xFF; 255])
+      .hunk(0x94FF, &[0xEE; 4])
+      .hunk(0xFFF8, b"extended")
+  }
+
+  fn unpatch_bytes(patched: Vec<u8>, patch: Vec<u8>) -> Result<Vec<u8>, patch::Error> {
+    let mut rom = Cursor::new(patched);
+    unpatch(&mut rom, &mut Cursor::new(patch))?;
+    Ok(rom.into_inner())
+  }
+
+  #[test]
+  fn undo_data_restores_image() {
+    let image = rom::random(0x10000, 1);
+    let patch = records(&image).undo_data(&image).build();
+    let patched = apply(Kind::PPF, &image, patch.clone()).unwrap();
+    assert!(patched == apply(Kind::PPF, &image, records(&image).build()).unwrap());
+    assert!(patched != image);
+    assert!(unpatch_bytes(patched, patch).unwrap() == image);
+  }
+
+  #[test]
+  fn unpatch_needs_undo_data() {
+    let image = rom::random(0x10000, 2);
+    let v1 = PpfBuilder::new()
+      .version(builder::Version::V1)
+      .hunk(0x20, b"romhacks")
+      .build();
+    let v2 = records(&image).version(builder::Version::V2).build();
+    let v3 = records(&image).build();
+    for patch in [v1, v2, v3] {
+      let patched = apply(Kind::PPF, &image, patch.clone()).unwrap();
+      assert!(matches!(
+        unpatch_bytes(patched, patch),
+        Err(patch::Error::NoUndoData)
+      ));
+    }
+  }
+}
@@ -0,0 +1,97 @@
+//! Checking everything about a patch that can be known without applying it,
+//! so that a patch that can't be applied fails before any files are written
+//! or ROMs are hashed.
+
+use crate::crc::Crc32;
+use crate::io::prelude::*;
+use crate::patch::header::{self, Header};
+use crate::patch::{Error, Kind};
+use std::io;
+
+/// A patch that passed [`patch`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Prechecked {
+  pub kind: Kind,
+  pub header: Header,
+  /// The patch's checksum, as [`Kind::digest`] computes it.
+  pub digest: Crc32,
+}
+
+/// Checks a patch of the given kind on its own: that its header can be read
+/// and declares a version that can be applied, that it matches its own
+/// checksum if it ends with one, and that the patched file isn't too large
+/// for the format.
+pub fn patch(kind: Kind, patch: &mut (impl Read + Seek + KnownLen)) -> Result<Prechecked, Error> {
+  let header = header::read(kind, patch)?;
+  let digest = kind.digest(patch)?;
+  let capabilities = kind.capabilities();
+  if capabilities.patch_checksum {
+    patch.seek(io::SeekFrom::End(-4))?;
+    if Crc32::new(patch.read_u32::<LE>()?) != digest {
+      return Err(Error::BadPatch);
+    }
+  }
+  if (header.target_size).is_some_and(|size| size > capabilities.max_file_size) {
+    return Err(Error::FileTooLarge);
+  }
+  patch.seek(io::SeekFrom::Start(0))?;
+  Ok(Prechecked { kind, header, digest })
+}
+
+impl Prechecked {
+  /// Checks that a ROM of `rom_len` bytes is the size the patch was made for,
+  /// if `as_is`. ROMs that are padded or converted before they're patched
+  /// aren't patched as they are.
+  ///
+  /// A ROM larger than the format's [`max_file_size`](crate::patch::Capabilities::max_file_size)
+  /// isn't refused: the patch can still modify the part its records reach.
+  pub fn rom(&self, rom_len: u64, as_is: bool) -> Result<(), Error> {
+    match self.header.source_size {
+      Some(expected) if as_is && expected != rom_len => {
+        Err(Error::WrongSourceSize { expected, actual: rom_len })
+      }
+      _ => Ok(()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::patch::ips;
+  use romhacks_testkit::ips::MAX_OFFSET;
+  use romhacks_testkit::{IpsBuilder, rom};
+  use std::io::Cursor;
+
+  /// Larger than IPS offsets can address, like a 32 MiB GBA ROM.
+  const ROM_LEN: usize = 32 << 20;
+
+  #[test]
+  fn ips_applies_to_rom_larger_than_its_offsets() {
+    let patch = IpsBuilder::new()
+      .hunk(0x100, b"romhacks")
+      .hunk(MAX_OFFSET, &[0xAB])
+      .build();
+    let prechecked = super::patch(Kind::IPS, &mut Cursor::new(patch.clone())).unwrap();
+    prechecked.rom(ROM_LEN as u64, true).unwrap();
+
+    let mut rom = Cursor::new(rom::filled(ROM_LEN, 0xFF));
+    ips::patch(&mut rom, &mut Cursor::new(patch)).unwrap();
+    let expected = rom::with_bytes(&rom::filled(ROM_LEN, 0xFF), 0x100, b"romhacks");
+    let expected = rom::with_bytes(&expected, MAX_OFFSET as usize, &[0xAB]);
+    assert!(rom.into_inner() == expected);
+  }
+
+  #[test]
+  fn ips_record_at_last_offset_writes_past_it() {
+    let patch = IpsBuilder::new().hunk(MAX_OFFSET, &[0xAB, 0xCD]).build();
+    let mut rom = Cursor::new(rom::filled(ROM_LEN, 0xFF));
+    ips::patch(&mut rom, &mut Cursor::new(patch)).unwrap();
+    let expected = rom::with_bytes(
+      &rom::filled(ROM_LEN, 0xFF),
+      MAX_OFFSET as usize,
+      &[0xAB, 0xCD],
+    );
+    assert!(rom.into_inner() == expected);
+  }
+}
@@ -0,0 +1,105 @@
+//! Statistics about the hunks of a patch, gathered by decoding it with
+//! [`ops::decode`](super::ops::decode).
+
+use super::Error;
+use super::ops::{Op, Visitor};
+use std::ops::Range;
+
+/// Counts the hunks of a patch and the bytes they change.
+///
+/// Expected contents, such as the block check of a PPF patch, aren't hunks.
+/// XOR hunks only change the bytes they XOR with something other than zero.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Statistics {
+  pub hunks: u64,
+  pub bytes_changed: u64,
+  /// The offsets of the longest hunk, or the first of them if there's a tie.
+  pub largest_hunk: Option<Range<u64>>,
+  /// The number of hunks that fill a run with one byte.
+  pub rle_hunks: u64,
+  pub rle_bytes: u64,
+  /// The size the patch resizes the file to, if it does.
+  pub resize: Option<u64>,
+  /// The offsets of every hunk, to find how they're spread across the file.
+  ranges: Vec<Range<u64>>,
+}
+
+/// The bytes changed within a range of offsets.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Bucket {
+  pub range: Range<u64>,
+  pub bytes: u64,
+}
+
+impl Statistics {
+  /// The end of the last byte that the patch changes, or the size it resizes
+  /// the file to if that's larger.
+  pub fn extent(&self) -> u64 {
+    let end = self.ranges.iter().map(|range| range.end).max().unwrap_or(0);
+    end.max(self.resize.unwrap_or(0))
+  }
+
+  /// The offsets of every hunk, in the order they're applied.
+  pub fn ranges(&self) -> &[Range<u64>] {
+    &self.ranges
+  }
+
+  /// Splits the patch's extent into `buckets` ranges of equal size and counts
+  /// the bytes the hunks cover in each.
+  pub fn coverage(&self, buckets: u64) -> Vec<Bucket> {
+    let extent = self.extent();
+    if extent == 0 || buckets == 0 {
+      return Vec::new();
+    }
+    let bucket_len = extent.div_ceil(buckets);
+    let mut histogram: Vec<Bucket> = (0..extent.div_ceil(bucket_len))
+      .map(|i| Bucket {
+        range: i * bucket_len..((i + 1) * bucket_len).min(extent),
+        bytes: 0,
+      })
+      .collect();
+    for range in &self.ranges {
+      let first = (range.start / bucket_len) as usize;
+      let last = ((range.end - 1) / bucket_len) as usize;
+      for bucket in &mut histogram[first..=last] {
+        bucket.bytes += range.end.min(bucket.range.end) - range.start.max(bucket.range.start);
+      }
+    }
+    histogram
+  }
+
+  fn count(&mut self, range: Range<u64>, bytes_changed: u64) {
+    if range.is_empty() {
+      return;
+    }
+    self.hunks += 1;
+    self.bytes_changed += bytes_changed;
+    let len = range.end - range.start;
+    if (self.largest_hunk.as_ref()).is_none_or(|largest| largest.end - largest.start < len) {
+      self.largest_hunk = Some(range.clone());
+    }
+    self.ranges.push(range);
+  }
+}
+
+impl Visitor for Statistics {
+  fn visit(&mut self, op: &Op<'_>) -> Result<(), Error> {
+    match op {
+      Op::Expect { .. } => {}
+      Op::Write { offset, data } => {
+        self.count(*offset..offset + data.len() as u64, data.len() as u64);
+      }
+      Op::Fill { offset, len, .. } => {
+        self.count(*offset..offset + len, *len);
+        self.rle_hunks += 1;
+        self.rle_bytes += len;
+      }
+      Op::Xor { offset, data } => {
+        let changed = data.iter().filter(|byte| **byte != 0).count() as u64;
+        self.count(*offset..offset + data.len() as u64, changed);
+      }
+      Op::Resize { len } => self.resize = Some(*len),
+    }
+    Ok(())
+  }
+}
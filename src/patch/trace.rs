@@ -0,0 +1,52 @@
+//! Logging each operation of a patch as it's applied, so that patch authors
+//! can compare what this program does with what other patchers do.
+
+use super::Error;
+use super::ops::{Op, Visitor};
+use std::io::Write;
+
+/// Writes a line for each operation to `out` before passing it to `inner`.
+///
+/// Each line has the operation's index, its command, the offset and number
+/// of bytes it affects, and the position in the output after it.
+#[derive(Debug)]
+pub struct Tracer<'a, V: ?Sized, W> {
+  inner: &'a mut V,
+  out: W,
+  index: u64,
+  position: u64,
+}
+
+impl<'a, V: Visitor + ?Sized, W: Write> Tracer<'a, V, W> {
+  pub fn new(inner: &'a mut V, out: W) -> Self {
+    Self { inner, out, index: 0, position: 0 }
+  }
+}
+
+impl<V: Visitor + ?Sized, W: Write> Visitor for Tracer<'_, V, W> {
+  fn visit(&mut self, op: &Op<'_>) -> Result<(), Error> {
+    let (command, offset, len) = match op {
+      Op::Expect { offset, data } => ("expect", *offset, data.len() as u64),
+      Op::Write { offset, data } => ("write", *offset, data.len() as u64),
+      Op::Fill { offset, len, .. } => ("fill", *offset, *len),
+      Op::Xor { offset, data } => ("xor", *offset, data.len() as u64),
+      Op::Resize { len } => ("resize", 0, *len),
+    };
+    match op {
+      Op::Expect { .. } => {}
+      Op::Resize { len } => self.position = self.position.min(*len),
+      _ => self.position = offset + len,
+    }
+    write!(
+      self.out,
+      "{} {command} offset={offset:#X} len={len}",
+      self.index
+    )?;
+    if let Op::Fill { byte, .. } = op {
+      write!(self.out, " byte={byte:#04X}")?;
+    }
+    writeln!(self.out, " position={:#X}", self.position)?;
+    self.index += 1;
+    self.inner.visit(op)
+  }
+}
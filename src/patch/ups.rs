@@ -1,27 +1,31 @@
-use crate::crc;
 use crate::io::prelude::*;
-use crate::patch::Error;
+use crate::patch::header::{Footer, Header};
+use crate::patch::ops::{Op, Visitor};
 use crate::patch::varint::{ReadByuuVarInt, overflow_err};
+use crate::patch::{Error, OutputFile};
+use crate::{buffers, crc, profile};
 use ::rayon::prelude::*;
+use std::borrow::Cow;
 use std::ops::{Deref, DerefMut};
 use std::{io, iter};
 use wide::u8x16;
 
 pub const MAGIC: &[u8] = b"UPS";
 
-const FOOTER_SIZE: usize = 3 * size_of::<u32>();
-const BUF_SIZE: usize = 8 * 1024; // default buffer size used by std::io
+/// The size of the chunks of the ROM that are XORed at once.
+const BUF_SIZE: usize = 8 * 1024;
 const SIMD_SIZE: usize = u8x16::LANES as usize;
 
 pub fn patch(
-  rom: &mut (impl Read + Write + Seek + Resize),
-  patch: &mut (impl Read + Seek),
+  rom: &mut impl OutputFile,
+  patch: &mut (impl Read + Seek + KnownLen),
   file_checksum: crc::Crc32,
   patch_checksum: crc::Crc32,
 ) -> Result<(), Error> {
-  let mut patch = io::BufReader::with_capacity(BUF_SIZE, patch);
+  let start_of_checksums: u64 = Footer::position(patch)?;
+  let mut patch = io::BufReader::with_capacity(buffers::patch_reader(start_of_checksums), patch);
 
-  let start_of_checksums = patch.seek(io::SeekFrom::End(-(FOOTER_SIZE as i64)))? as i64;
+  patch.seek(io::SeekFrom::Start(start_of_checksums))?;
   validate_checksums(&mut patch, file_checksum, patch_checksum)?;
 
   patch.seek(io::SeekFrom::Start(0))?;
@@ -36,23 +40,84 @@ pub fn patch(
   if input_rom_size != output_rom_size {
     rom.set_len(output_rom_size)?;
   }
-  rom.seek(io::SeekFrom::Start(0))?;
 
   let mut rom_buf = CacheAlignedBuffer([0u8; BUF_SIZE]);
-  let mut hunks = patch.take(start_of_checksums as u64 - MAGIC.len() as u64);
-  loop {
-    let offset = i64::try_from(hunks.read_varint()?) //
-      .map_err(|_| overflow_err())?;
-    rom.seek_relative(offset)?;
-    apply_hunk(rom, &mut hunks, &mut rom_buf)?;
-    if hunks.limit() == 0 {
-      break;
-    }
+  let mut hunks = Hunks::after_header(&mut patch)?;
+  while let Some(offset) = hunks.next_offset()? {
+    rom.seek(io::SeekFrom::Start(offset))?;
+    while !hunks.read_chunk(|chunk| {
+      // The patch's buffer can be larger than the ROM's.
+      for patch_hunk in chunk.chunks(BUF_SIZE) {
+        let rom_hunk = &mut rom_buf[..patch_hunk.len()];
+        rom.read_exact(rom_hunk)?;
+        xor_hunks(patch_hunk, rom_hunk);
+        rom.seek_relative(-(rom_hunk.len() as i64))?;
+        rom.write_all(rom_hunk)?;
+      }
+      Ok(())
+    })? {}
+  }
+
+  Ok(())
+}
+
+/// Passes each hunk in a UPS patch to `visitor`, after checking the patch's
+/// own checksum.
+pub fn decode(
+  patch: &mut (impl Read + Seek + KnownLen),
+  visitor: &mut impl Visitor,
+) -> Result<(), Error> {
+  let footer = Footer::read(patch)?;
+  let start_of_checksums: u64 = Footer::position(patch)?;
+  patch.seek(io::SeekFrom::Start(0))?;
+  // The patch checksum covers everything but itself.
+  let patch_checksum = crc::Crc32::read_and_hash(&mut (&mut *patch).take(start_of_checksums + 8))?;
+  if patch_checksum != footer.patch {
+    return Err(Error::BadPatch);
+  }
+
+  patch.seek(io::SeekFrom::Start(0))?;
+  let mut patch = io::BufReader::with_capacity(buffers::patch_reader(start_of_checksums), patch);
+  if &patch.read_array::<4>()? != b"UPS1" {
+    return Err(Error::BadPatch);
+  }
+  let input_rom_size: u64 = patch.read_varint()?;
+  let output_rom_size: u64 = patch.read_varint()?;
+  if input_rom_size != output_rom_size {
+    visitor.visit(&Op::Resize { len: output_rom_size })?;
   }
 
+  let mut hunks = Hunks::after_header(&mut patch)?;
+  let mut data = Vec::new();
+  while let Some(offset) = hunks.next_offset()? {
+    data.clear();
+    while !hunks.read_chunk(|chunk| {
+      data.extend_from_slice(chunk);
+      Ok(())
+    })? {}
+    visitor.visit(&Op::Xor { offset, data: Cow::Borrowed(&data) })?;
+  }
   Ok(())
 }
 
+/// Reads the sizes and checksums declared by a UPS patch.
+pub fn read_header(patch: &mut (impl Read + Seek + KnownLen)) -> Result<Header, Error> {
+  let footer = Footer::read(patch)?;
+  patch.seek(io::SeekFrom::Start(0))?;
+  let mut patch = io::BufReader::with_capacity(profile::get().buf_size, patch);
+  if &patch.read_array::<4>()? != b"UPS1" {
+    return Err(Error::BadPatch);
+  }
+  Ok(Header {
+    source_size: Some(patch.read_varint()?),
+    target_size: Some(patch.read_varint()?),
+    source_crc32: Some(footer.source),
+    target_crc32: Some(footer.target),
+    app_header: None,
+    version: None,
+  })
+}
+
 fn validate_checksums(
   patch: &mut io::BufReader<&mut (impl Read + Seek + Sized)>,
   file_checksum: crc::Crc32,
@@ -78,32 +143,64 @@ fn validate_checksums(
   Ok(())
 }
 
-fn apply_hunk(
-  rom: &mut (impl Read + Write + Seek + Resize + Sized),
-  hunks: &mut io::Take<io::BufReader<&mut (impl Read + Seek + Sized)>>,
-  rom_buf: &mut CacheAlignedBuffer,
-) -> Result<(), Error> {
-  loop {
-    let hunks_buf: &[u8] = hunks.fill_buf()?;
-    if hunks_buf.is_empty() {
+/// The hunks of a UPS patch, which extend from the end of its header to the
+/// start of its footer.
+///
+/// Each hunk is a varint of how many bytes of the file to skip since the end
+/// of the previous hunk, then the bytes to XOR with the file, then a NUL
+/// byte. The NUL byte ends the hunk's bytes but also stands for a byte of the
+/// file, which is left as it is, so the next hunk's skip counts from after
+/// it.
+struct Hunks<R> {
+  patch: io::Take<R>,
+  /// Where in the file the next hunk's skip counts from.
+  position: u64,
+}
+
+impl<R: BufRead> Hunks<R> {
+  /// Reads the hunks after the header that `patch` was just read past.
+  fn after_header(mut patch: R) -> Result<Self, Error>
+  where
+    R: Seek + KnownLen,
+  {
+    let len: u64 = (patch.remaining()?)
+      .checked_sub(Footer::SIZE as u64)
+      .ok_or(Error::BadPatch)?;
+    Ok(Self { patch: patch.take(len), position: 0 })
+  }
+
+  /// Reads the skip of the next hunk and returns where its bytes start in
+  /// the file, or `None` after the last hunk.
+  fn next_offset(&mut self) -> Result<Option<u64>, Error> {
+    if self.patch.limit() == 0 {
+      return Ok(None);
+    }
+    let offset = (self.position)
+      .checked_add(self.patch.read_varint()?)
+      .ok_or_else(overflow_err)?;
+    self.position = offset;
+    Ok(Some(offset))
+  }
+
+  /// Passes the next of the current hunk's bytes that are buffered to
+  /// `apply`, and returns whether they were the last, in which case the NUL
+  /// byte after them has been read.
+  fn read_chunk(&mut self, apply: impl FnOnce(&[u8]) -> Result<(), Error>) -> Result<bool, Error> {
+    let buf = self.patch.fill_buf()?;
+    if buf.is_empty() {
       return Err(Error::BadPatch);
     }
     // The memchr crate uses SIMD to find the first NUL byte efficiently.
-    let (size, is_end_of_hunk) = ::memchr::memchr(0, hunks_buf)
-      .map(|i| (i, true))
-      .unwrap_or_else(|| (hunks_buf.len(), false));
-    let (patch_hunk, rom_hunk) = (&hunks_buf[..size], &mut rom_buf[..size]);
-    rom.read_exact(rom_hunk)?;
-    xor_hunks(patch_hunk, rom_hunk);
-    rom.seek_relative(-(rom_hunk.len() as i64))?;
-    rom.write_all(rom_hunk)?;
-    // Add 1 to account for the NUL byte.
-    hunks.consume(size + 1);
-    if is_end_of_hunk {
-      break;
-    }
+    let (len, is_end) = match ::memchr::memchr(0, buf) {
+      Some(len) => (len, true),
+      None => (buf.len(), false),
+    };
+    apply(&buf[..len])?;
+    let consumed = len + usize::from(is_end);
+    self.patch.consume(consumed);
+    self.position += consumed as u64;
+    Ok(is_end)
   }
-  Ok(())
 }
 
 fn xor_hunks(patch_hunk: &[u8], rom_hunk: &mut [u8]) {
@@ -147,3 +244,48 @@ impl DerefMut for CacheAlignedBuffer {
     &mut self.0[..]
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use crate::patch::Kind;
+  use crate::patch::ops::{self, Applier};
+  use crate::patch::tests::apply;
+  use romhacks_testkit::{UpsBuilder, rom};
+  use std::io::Cursor;
+
+  /// Hunks separated by a single unchanged byte, which is the one the NUL
+  /// byte ending the first hunk covers, and by longer runs.
+  fn builder(rom: &[u8]) -> UpsBuilder {
+    UpsBuilder::new(rom)
+      .hunk(0x10, &[0xAA; 4])
+      .hunk(0x15, &[0xBB; 4])
+      .hunk(0x1A, &[0xCC])
+      .hunk(0x100, &[0xDD; 0x20])
+  }
+
+  #[test]
+  fn hunks_skip_byte_after_nul() {
+    let rom = rom::filled(0x1000, 0);
+    let builder = builder(&rom);
+    let expected = builder.target().to_vec();
+    assert!(apply(Kind::UPS, &rom, builder.build()).unwrap() == expected);
+  }
+
+  #[test]
+  fn decoded_hunks_match_applied_hunks() {
+    let rom = rom::random(0x1000, 1);
+    let builder = builder(&rom).resize(0x1100).hunk(0x10F0, b"extended");
+    let expected = builder.target().to_vec();
+    let patch = builder.build();
+    assert!(apply(Kind::UPS, &rom, patch.clone()).unwrap() == expected);
+
+    let mut output = Cursor::new(rom);
+    ops::decode(
+      Kind::UPS,
+      &mut Cursor::new(patch),
+      &mut Applier::new(&mut output),
+    )
+    .unwrap();
+    assert!(output.into_inner() == expected);
+  }
+}
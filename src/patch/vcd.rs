@@ -1,11 +1,14 @@
-use crate::io;
 use crate::io::prelude::*;
-use crate::patch::Error;
 use crate::patch::vcd::cache::AddressCache;
+use crate::patch::{self, Error, OutputFile};
+use crate::{buffers, i18n, io, profile};
 use byteorder::ReadBytesExt;
 use num_traits::{CheckedMul, Num};
+use std::cell::Cell;
+use std::collections::HashMap;
 use std::io::{BufReader, Read, Seek, Write};
 use std::num::NonZeroU8;
+use std::{mem, ops};
 
 /// The magic string for Vcdiff patch files.
 ///
@@ -16,52 +19,427 @@ const VCD_DECOMPRESS: u8 = 1;
 const VCD_CODETABLE: u8 = 2;
 const HAS_APPHEADER: u8 = 4;
 
+const VCD_SOURCE: u8 = 0x01;
+const VCD_TARGET: u8 = 0x02;
+
+/// Reads the application header of a Vcdiff patch, if it has one.
+///
+/// xdelta3 uses the application header to record the names of the source and
+/// target files.
+pub fn read_app_header(patch: &mut (impl Read + Seek)) -> Result<Option<Vec<u8>>, Error> {
+  patch.seek(io::SeekFrom::Start(0))?;
+  let mut patch = BufReader::with_capacity(profile::get().buf_size, patch);
+  if &patch.read_array::<3>()? != MAGIC {
+    return Err(Error::BadPatch);
+  }
+  let _version = patch.read_u8()?;
+  let hdr_indicator = patch.read_u8()?;
+  if hdr_indicator & VCD_DECOMPRESS != 0 {
+    let _compressor_id = patch.read_u8()?;
+  }
+  if hdr_indicator & VCD_CODETABLE != 0 {
+    let code_table_len: u32 = patch.read_vcdiff_int()?;
+    patch.seek_relative(code_table_len as i64)?;
+  }
+  if hdr_indicator & HAS_APPHEADER == 0 {
+    return Ok(None);
+  }
+  let header_size: u32 = patch.read_vcdiff_int()?;
+  let mut app_header = vec![];
+  io::ExactSizeRead::new(&mut patch, header_size as u64).read_to_end(&mut app_header)?;
+  Ok(Some(app_header))
+}
+
+/// Formats an application header the way xdelta3 does, optionally followed
+/// by the name and version of this program.
+///
+/// xdelta3 writes the target's name and compression method, then the source's
+/// name and compression method, each followed by a slash. It only reads those
+/// four fields, so the program version after them doesn't affect it.
+pub fn app_header(source_name: &str, target_name: &str, program_version: bool) -> Vec<u8> {
+  let mut header = format!("{target_name}//{source_name}/");
+  if program_version {
+    header += &format!("/{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+  }
+  header.into_bytes()
+}
+
+/// The most target bytes in each window written by [`encode`]. xdelta3
+/// refuses to decode windows larger than 16 MiB.
+const ENCODED_WINDOW_LEN: usize = 1 << 23;
+
+/// Copies shorter than this are written as adds, since their instructions and
+/// addresses can take more space than the bytes.
+const MIN_COPY_LEN: usize = 4;
+
+/// Runs shorter than this are written as part of adds.
+const MIN_RUN_LEN: usize = 8;
+
+/// The spacing between the source positions that [`encode`] looks up copies
+/// from. Any copy at least twice as long is found.
+const SOURCE_INDEX_STEP: usize = 8;
+
+/// A source and the positions in it that [`encode`] looks up copies from.
+///
+/// Building the index reads the whole source, so it's built once when
+/// several targets are encoded against the same source.
+pub struct SourceIndex<'a> {
+  source: &'a [u8],
+  positions: HashMap<[u8; SOURCE_INDEX_STEP], usize>,
+}
+
+impl<'a> SourceIndex<'a> {
+  pub fn new(source: &'a [u8]) -> Self {
+    let mut positions = HashMap::new();
+    for (i, chunk) in source.chunks_exact(SOURCE_INDEX_STEP).enumerate() {
+      positions
+        .entry(chunk.try_into().unwrap())
+        .or_insert(i * SOURCE_INDEX_STEP);
+    }
+    Self { source, positions }
+  }
+}
+
+/// Writes a patch that turns the indexed source into `target`.
+///
+/// Each window copies from the whole source, using the default code table
+/// and absolute copy addresses. Matches are found greedily: at each position
+/// of the target, the encoder tries the same position of the source, since ROM
+/// hacks usually modify data in place, then any indexed source position that
+/// starts with the same bytes.
+pub fn encode(
+  index: &SourceIndex<'_>,
+  target: &[u8],
+  app_header: Option<&[u8]>,
+  output: &mut impl Write,
+) -> io::Result<()> {
+  output.write_all(MAGIC)?;
+  output.write_all(&[0])?;
+  match app_header {
+    Some(app_header) => {
+      output.write_all(&[HAS_APPHEADER])?;
+      output.write_vcdiff_int(app_header.len() as u64)?;
+      output.write_all(app_header)?;
+    }
+    None => output.write_all(&[0])?,
+  }
+
+  let source = index.source;
+  for (i, window) in target.chunks(ENCODED_WINDOW_LEN).enumerate() {
+    let mut encoder = WindowEncoder::default();
+    let window_start = i * ENCODED_WINDOW_LEN;
+    let mut pending = 0;
+    let mut pos = 0;
+    while pos < window.len() {
+      let same_position = window_start + pos;
+      let indexed = (window.get(pos..pos + SOURCE_INDEX_STEP))
+        .and_then(|key| index.positions.get(key).copied());
+      let best = [Some(same_position), indexed]
+        .into_iter()
+        .flatten()
+        .map(|address| (address, match_len(source, address, &window[pos..])))
+        .max_by_key(|&(_, len)| len)
+        .filter(|&(_, len)| len >= MIN_COPY_LEN);
+      let Some((mut address, mut len)) = best else {
+        pos += 1;
+        continue;
+      };
+      // Take back bytes that were going to be added if they also match.
+      while pos > pending && address > 0 && source[address - 1] == window[pos - 1] {
+        address -= 1;
+        pos -= 1;
+        len += 1;
+      }
+      encoder.add_or_run(&window[pending..pos]);
+      encoder.copy(address, len);
+      pos += len;
+      pending = pos;
+    }
+    encoder.add_or_run(&window[pending..]);
+    encoder.write(source.len(), window.len(), output)?;
+  }
+  Ok(())
+}
+
+/// How many bytes of `target` match the source starting at `address`.
+fn match_len(source: &[u8], address: usize, target: &[u8]) -> usize {
+  let source = source.get(address..).unwrap_or_default();
+  (source.iter().zip(target))
+    .take_while(|(a, b)| a == b)
+    .count()
+}
+
+/// The sections of a window that's being encoded.
+#[derive(Default)]
+struct WindowEncoder {
+  data: Vec<u8>,
+  instructions: Vec<u8>,
+  addresses: Vec<u8>,
+}
+
+impl WindowEncoder {
+  /// Instruction codes in the default code table.
+  const RUN: u8 = 0;
+  const ADD: u8 = 1;
+  const COPY_SELF: u8 = 19;
+
+  /// Adds `bytes`, writing long runs of the same byte as runs.
+  fn add_or_run(&mut self, mut bytes: &[u8]) {
+    while !bytes.is_empty() {
+      let run_start = (0..bytes.len()).find(|&i| run_len(&bytes[i..]) >= MIN_RUN_LEN);
+      let (added, rest) = bytes.split_at(run_start.unwrap_or(bytes.len()));
+      if !added.is_empty() {
+        self.add(added);
+      }
+      if !rest.is_empty() {
+        let len = run_len(rest);
+        self.instructions.push(Self::RUN);
+        self.instructions.write_vcdiff_int(len as u64).unwrap();
+        self.data.push(rest[0]);
+        bytes = &rest[len..];
+      } else {
+        bytes = rest;
+      }
+    }
+  }
+
+  fn add(&mut self, bytes: &[u8]) {
+    // Codes 2 to 18 add 1 to 17 bytes without a separate size.
+    match bytes.len() {
+      len @ 1..=17 => self.instructions.push(Self::ADD + len as u8),
+      len => {
+        self.instructions.push(Self::ADD);
+        self.instructions.write_vcdiff_int(len as u64).unwrap();
+      }
+    }
+    self.data.extend_from_slice(bytes);
+  }
+
+  fn copy(&mut self, address: usize, len: usize) {
+    // Codes 20 to 34 copy 4 to 18 bytes without a separate size.
+    match len {
+      4..=18 => self.instructions.push(Self::COPY_SELF + (len - 3) as u8),
+      len => {
+        self.instructions.push(Self::COPY_SELF);
+        self.instructions.write_vcdiff_int(len as u64).unwrap();
+      }
+    }
+    self.addresses.write_vcdiff_int(address as u64).unwrap();
+  }
+
+  fn write(self, source_len: usize, target_len: usize, output: &mut impl Write) -> io::Result<()> {
+    let mut delta = Vec::new();
+    delta.write_vcdiff_int(target_len as u64)?;
+    // Delta_Indicator: none of the sections are compressed.
+    delta.push(0);
+    delta.write_vcdiff_int(self.data.len() as u64)?;
+    delta.write_vcdiff_int(self.instructions.len() as u64)?;
+    delta.write_vcdiff_int(self.addresses.len() as u64)?;
+
+    match source_len {
+      0 => output.write_all(&[0])?,
+      len => {
+        output.write_all(&[VCD_SOURCE])?;
+        output.write_vcdiff_int(len as u64)?;
+        output.write_vcdiff_int(0)?;
+      }
+    }
+    let encoding_len =
+      delta.len() + self.data.len() + self.instructions.len() + self.addresses.len();
+    output.write_vcdiff_int(encoding_len as u64)?;
+    for section in [delta, self.data, self.instructions, self.addresses] {
+      output.write_all(&section)?;
+    }
+    Ok(())
+  }
+}
+
+/// The number of times the first byte of `bytes` repeats at its start.
+fn run_len(bytes: &[u8]) -> usize {
+  (bytes.iter())
+    .take_while(|&&byte| Some(&byte) == bytes.first())
+    .count()
+}
+
 pub fn patch(
   rom: &mut (impl Read + Seek),
   patch: &mut (impl Read + Seek),
-  output: &mut (impl Read + Write + Seek),
+  output: &mut impl OutputFile,
 ) -> Result<(), Error> {
-  let mut patch = BufReader::new(patch);
+  decode(rom, patch, output, false)
+}
 
-  // header
-  {
+/// Applies a patch, which is the encoding of a code table if `code_table`
+/// is set. Those must use the default code table, so that decoding one
+/// doesn't decode another.
+fn decode(
+  rom: &mut (impl Read + Seek),
+  patch: &mut (impl Read + Seek),
+  output: &mut impl OutputFile,
+  code_table: bool,
+) -> Result<(), Error> {
+  let rom_len = rom.seek(io::SeekFrom::End(0))?;
+  let patch_len = patch.seek(io::SeekFrom::End(0))?;
+  patch.seek(io::SeekFrom::Start(0))?;
+  let mut patch = BufReader::with_capacity(buffers::patch_reader(patch_len), patch);
+
+  let code_table = {
     if &patch.read_array::<3>()? != MAGIC {
       return Err(Error::BadPatch);
     }
 
-    let version = patch.read_u8()?;
-    if version != 0 {
-      return Err(Error::UnsupportedPatchFeature);
-    }
+    // The version was checked by `header::read_version`.
+    let _version = patch.read_u8()?;
 
     let hdr_indicator = patch.read_u8()?;
-    if hdr_indicator & (VCD_CODETABLE | VCD_DECOMPRESS) != 0 {
+    if hdr_indicator & VCD_DECOMPRESS != 0 {
       return Err(Error::UnsupportedPatchFeature);
     }
+    let table = match hdr_indicator & VCD_CODETABLE {
+      0 => CodeTable::DEFAULT,
+      _ if code_table => return Err(Error::BadPatch),
+      _ => CodeTable::read(&mut patch)?,
+    };
 
     if hdr_indicator & HAS_APPHEADER != 0 {
       // Skip over the app header.
       let header_size: u32 = patch.read_vcdiff_int()?;
       patch.seek_relative(header_size as i64)?;
     }
-  }
-
-  let mut patcher = Patcher::new(rom, patch, output);
+    table
+  };
+
+  let plan = Plan::scan(&mut patch, patch_len, rom_len)?;
+  log::debug!(
+    "{}",
+    i18n::format(
+      "romhacks::patch::vcd_plan",
+      &[
+        ("windows", &plan.windows),
+        ("target_len", &plan.target_len),
+        ("superstring_len", &plan.superstring_len),
+      ]
+    )
+  );
+  let mut patcher = Patcher::new(
+    rom,
+    patch,
+    io::TrackedBufWriter::with_capacity(buffers::output_writer(plan.target_len), output)?,
+    code_table,
+  );
+  patcher.buffers.reserve(&plan)?;
   // window sections
-  loop {
-    patcher.process_window()?;
+  for window in 0u64.. {
+    patcher.process_window().inspect_err(|_| {
+      log::error!(
+        "{}",
+        i18n::format(
+          "romhacks::patch::vcd_window_failed",
+          &[
+            ("window", &window),
+            ("offset", &format!("{:#X}", patcher.files.output.position())),
+          ]
+        )
+      );
+    })?;
     if patcher.reached_eof()? {
       break;
     }
     patcher.clear_buffers();
   }
+  patcher.finish()?;
 
   Ok(())
 }
 
-struct Patcher<R, P, O> {
+/// What decoding a patch's windows takes, found by reading only their
+/// headers, so that buffers can be allocated once and bad patches rejected
+/// before anything is written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Plan {
+  windows: u64,
+  /// The length of the patched file.
+  target_len: u64,
+  /// The largest source segment and target window of any window, together.
+  superstring_len: usize,
+  data_len: usize,
+  instructions_len: usize,
+  addresses_len: usize,
+}
+
+impl Plan {
+  /// Reads the headers of the windows from the patch's position to its end,
+  /// then seeks back. Fails if a window copies from past the end of the ROM
+  /// or from a part of the target that isn't written yet, if its sections
+  /// don't add up to its length or run past the end of the patch, or if it
+  /// needs more memory than `vcd.max_window` allows.
+  fn scan(
+    patch: &mut BufReader<impl Read + Seek>,
+    patch_len: u64,
+    rom_len: u64,
+  ) -> Result<Self, Error> {
+    let start = patch.stream_position()?;
+    let max_window = patch::options::get().vcd.max_window;
+    let mut plan = Self::default();
+    while !patch.reached_eof()? {
+      let source_len = match patch.read_u8()? {
+        0 => 0,
+        indicator @ (VCD_SOURCE | VCD_TARGET) => {
+          let source_len: u32 = patch.read_vcdiff_int()?;
+          let source_position: u64 = patch.read_vcdiff_int()?;
+          let source_end =
+            (source_position.checked_add(source_len as u64)).ok_or(Error::BadPatch)?;
+          match indicator {
+            VCD_SOURCE if source_end > rom_len => return Err(Error::WrongInputFile),
+            VCD_TARGET if source_end > plan.target_len => return Err(Error::BadPatch),
+            _ => {}
+          }
+          source_len
+        }
+        _ => return Err(Error::BadPatch),
+      };
+      let encoding_len: u32 = patch.read_vcdiff_int()?;
+      let mut encoding = (&mut *patch).take(encoding_len as u64);
+      let target_window_len: u32 = encoding.read_vcdiff_int()?;
+      let _delta_indicator = encoding.read_u8()?;
+      let data_len: u32 = encoding.read_vcdiff_int()?;
+      let instructions_len: u32 = encoding.read_vcdiff_int()?;
+      let addresses_len: u32 = encoding.read_vcdiff_int()?;
+      let sections_len = data_len as u64 + instructions_len as u64 + addresses_len as u64;
+      if sections_len != encoding.limit() {
+        return Err(Error::BadPatch);
+      }
+      patch.seek_relative(sections_len as i64)?;
+      if patch.stream_position()? > patch_len {
+        return Err(Error::BadPatch);
+      }
+
+      plan.windows += 1;
+      plan.target_len = (plan.target_len)
+        .checked_add(target_window_len as u64)
+        .ok_or(Error::BadPatch)?;
+      // Positions in the superstring are decoded as 32-bit integers.
+      let superstring_len =
+        (source_len.checked_add(target_window_len)).ok_or(Error::FileTooLarge)?;
+      if max_window.is_some_and(|max_window| superstring_len as u64 > max_window) {
+        return Err(Error::FileTooLarge);
+      }
+      plan.superstring_len = plan.superstring_len.max(patch::to_usize(superstring_len)?);
+      plan.data_len = plan.data_len.max(patch::to_usize(data_len)?);
+      plan.instructions_len = plan
+        .instructions_len
+        .max(patch::to_usize(instructions_len)?);
+      plan.addresses_len = plan.addresses_len.max(patch::to_usize(addresses_len)?);
+    }
+    patch.seek(io::SeekFrom::Start(start))?;
+    Ok(plan)
+  }
+}
+
+struct Patcher<R, P, O: Write> {
   files: Files<R, P, O>,
-  buffers: Buffers,
+  buffers: PooledBuffers,
+  code_table: CodeTable,
 }
 
 impl<R, P, O> Patcher<R, P, O>
@@ -73,16 +451,17 @@ where
   pub const VCD_SOURCE: u8 = 0x01;
   pub const VCD_TARGET: u8 = 0x02;
 
-  pub fn new(rom: R, patch: P, output: O) -> Self {
+  pub fn new(rom: R, patch: P, output: io::TrackedBufWriter<O>, code_table: CodeTable) -> Self {
     Self {
       files: Files { rom, patch, output },
-      buffers: Buffers::new(),
+      buffers: PooledBuffers::take(),
+      code_table,
     }
   }
 
   fn process_window(&mut self) -> Result<(), Error> {
     let Files { rom, patch, output } = &mut self.files;
-    let buffers = &mut self.buffers;
+    let buffers: &mut Buffers = &mut self.buffers;
 
     let win_indicator = patch.read_u8()?;
     let source_window_len = match win_indicator {
@@ -97,24 +476,25 @@ where
       Self::VCD_TARGET => {
         let source_len: u32 = patch.read_vcdiff_int()?;
         let source_position: u64 = patch.read_vcdiff_int()?;
-        output.seek(io::SeekFrom::Start(source_position))?;
-        io::copy(
-          &mut output.take(source_len as u64),
-          &mut buffers.superstring,
-        )?;
-        output.seek(io::SeekFrom::End(0))?;
+        let source_end = (source_position.checked_add(source_len as u64)).ok_or(Error::BadPatch)?;
+        let source_range = source_position..source_end;
+        if output.read_back(source_range, &mut buffers.superstring)? != source_len as u64 {
+          // The source segment must have already been written to the target.
+          return Err(Error::BadPatch);
+        }
         source_len
       }
       _ => return Err(Error::BadPatch),
     };
 
     let encoding_len: u32 = patch.read_vcdiff_int()?;
-    let mut patch = patch.take(encoding_len as u64);
+    let mut patch = io::ExactSizeRead::new(patch, encoding_len as u64);
 
     let target_window_len: u32 = patch.read_vcdiff_int()?;
-    buffers
-      .superstring
-      .resize(buffers.superstring.len() + target_window_len as usize, 0);
+    let superstring_len = (buffers.superstring.len())
+      .checked_add(patch::to_usize(target_window_len)?)
+      .ok_or(Error::FileTooLarge)?;
+    buffers.superstring.resize(superstring_len, 0);
 
     let delta_indicator: u8 = patch.read_u8()?;
     if delta_indicator != 0 {
@@ -130,22 +510,24 @@ where
     let instructions_len: u32 = patch.read_vcdiff_int()?;
     let addresses_len: u32 = patch.read_vcdiff_int()?;
     io::copy(
-      &mut (&mut patch).take(data_len as u64),
+      &mut io::ExactSizeRead::new(&mut patch, data_len as u64),
       &mut buffers.add_and_run_data,
     )?;
     io::copy(
-      &mut (&mut patch).take(instructions_len as u64),
+      &mut io::ExactSizeRead::new(&mut patch, instructions_len as u64),
       &mut buffers.instructions_and_sizes,
     )?;
     io::copy(
-      &mut (&mut patch).take(addresses_len as u64),
+      &mut io::ExactSizeRead::new(&mut patch, addresses_len as u64),
       &mut buffers.copy_addresses,
     )?;
+    // The sections must take up the rest of the window.
+    patch.finish()?;
 
-    let mut cursors = Cursors::new(buffers, source_window_len);
+    let mut cursors = Cursors::new(buffers, source_window_len, &self.code_table);
     loop {
       let instruction_code = cursors.instructions_and_sizes.read_u8()?;
-      let (first, second) = Self::decode_instruction_pair(instruction_code);
+      let (first, second) = self.code_table.decode_instruction_pair(instruction_code);
       Self::execute_instruction(&mut cursors, first)?;
       Self::execute_instruction(&mut cursors, second)?;
       if cursors.instructions_and_sizes.reached_eof()? {
@@ -160,9 +542,9 @@ where
   fn execute_instruction(cursors: &mut Cursors<'_>, instruction: Instruction) -> Result<(), Error> {
     match instruction {
       Instruction::Noop => {}
-      Instruction::Run => {
+      Instruction::Run { size } => {
         let byte = cursors.add_and_run_data.read_u8()?;
-        let size: u32 = cursors.instructions_and_sizes.read_vcdiff_int()?;
+        let size: u32 = cursors.read_instruction_size(size)?;
         (cursors.superstring).write_bytes(size, |_, mut dest: &mut [u8]| {
           io::copy(&mut io::repeat(byte).take(size as u64), &mut dest)
         })?;
@@ -180,9 +562,14 @@ where
         let size: u32 = cursors.read_instruction_size(size)?;
         let here: u32 = cursors.superstring.target_window_position();
         let address = cursors.copy_addresses.decode(here, mode)?;
+        let start = patch::to_usize(address)?;
+        let end = patch::to_usize(address.checked_add(size).ok_or(Error::BadPatch)?)?;
         (cursors.superstring).write_bytes(size, |source: &[u8], mut dest: &mut [u8]| {
-          let sequence_len = u32::min(address + size, source.len() as u32) as usize;
-          let periodic_sequence: &[u8] = &source[address as usize..sequence_len];
+          // A copy can overlap the bytes it writes, in which case what's
+          // already written repeats. It can't start past them.
+          let periodic_sequence: &[u8] = (source.get(start..end.min(source.len())))
+            .filter(|sequence| !sequence.is_empty())
+            .ok_or(io::Error::from(io::ErrorKind::InvalidData))?;
           loop {
             dest.write(periodic_sequence)?;
             if dest.is_empty() {
@@ -200,49 +587,21 @@ where
     self.files.patch.reached_eof()
   }
 
-  fn decode_instruction_pair(index: u8) -> (Instruction, Instruction) {
-    use Instruction::*;
-    match (index) {
-      0 => (Run, Noop),
-      1..=18 => (Add { size: NonZeroU8::new(index - 1) }, Noop),
-      19..=162 => {
-        let offset = index - 19;
-        let size = NonZeroU8::new(if offset % 16 == 0 { 0 } else { 3 + offset });
-        let mode = offset / 16;
-        (Copy { size, mode }, Noop)
-      }
-      163..=234 => {
-        let offset = index - 163;
-        let size = NonZeroU8::new(1 + (offset / 3) % 4);
-        let size2 = NonZeroU8::new(4 + offset % 3);
-        let mode = offset / 12;
-        (Add { size }, Copy { size: size2, mode })
-      }
-      235..=246 => {
-        let offset = index - 235;
-        let size = NonZeroU8::new(1 + offset % 4);
-        let mode = offset / 4;
-        (Add { size }, Copy { size: NonZeroU8::new(4), mode })
-      }
-      _ => {
-        let offset = index - 247;
-        (
-          Copy { size: NonZeroU8::new(4), mode: offset },
-          Add { size: NonZeroU8::new(1) },
-        )
-      }
-    }
-  }
-
   pub fn clear_buffers(&mut self) {
     self.buffers.clear_all();
   }
+
+  /// Flushes any buffered output.
+  pub fn finish(self) -> io::Result<()> {
+    let (_, result) = self.files.output.into_inner();
+    result
+  }
 }
 
-struct Files<R, P, O> {
+struct Files<R, P, O: Write> {
   pub rom: R,
   pub patch: P,
-  pub output: O,
+  pub output: io::TrackedBufWriter<O>,
 }
 
 // The Vcdiff standard doesn't specify maximum bounds for these buffers so it's
@@ -264,11 +623,84 @@ impl Buffers {
     }
   }
 
+  /// Allocates room for the largest window in `plan`, so that the buffers
+  /// don't grow while windows are decoded. Fails if there isn't enough
+  /// memory.
+  pub fn reserve(&mut self, plan: &Plan) -> Result<(), Error> {
+    for (buffer, len) in [
+      (&mut self.superstring, plan.superstring_len),
+      (&mut self.add_and_run_data, plan.data_len),
+      (&mut self.instructions_and_sizes, plan.instructions_len),
+      (&mut self.copy_addresses, plan.addresses_len),
+    ] {
+      (buffer.try_reserve_exact(len)).map_err(|_| Error::FileTooLarge)?;
+    }
+    Ok(())
+  }
+
   pub fn clear_all(&mut self) {
-    self.superstring.clear();
-    self.add_and_run_data.clear();
-    self.instructions_and_sizes.clear();
-    self.copy_addresses.clear();
+    for buffer in self.all() {
+      buffer.clear();
+    }
+    // Don't hold on to the memory used by an unusually large window.
+    self.shrink_to(profile::get().retained_buf_size);
+  }
+
+  pub fn shrink_to(&mut self, capacity: usize) {
+    for buffer in self.all() {
+      buffer.shrink_to(capacity);
+    }
+  }
+
+  fn all(&mut self) -> [&mut Vec<u8>; 4] {
+    [
+      &mut self.superstring,
+      &mut self.add_and_run_data,
+      &mut self.instructions_and_sizes,
+      &mut self.copy_addresses,
+    ]
+  }
+}
+
+thread_local! {
+  /// The buffers of the last patch applied on this thread, which the next
+  /// one reuses, so that applying many small patches doesn't allocate them
+  /// each time.
+  static POOL: Cell<Option<Buffers>> = const { Cell::new(None) };
+}
+
+/// Buffers taken from this thread's pool, which are put back when they're
+/// dropped. Only as much capacity as the profile's largest buffer is kept,
+/// so that a large patch doesn't hold on to its memory.
+struct PooledBuffers(Buffers);
+
+impl PooledBuffers {
+  fn take() -> Self {
+    Self(POOL.take().unwrap_or_else(Buffers::new))
+  }
+}
+
+impl ops::Deref for PooledBuffers {
+  type Target = Buffers;
+
+  fn deref(&self) -> &Buffers {
+    &self.0
+  }
+}
+
+impl ops::DerefMut for PooledBuffers {
+  fn deref_mut(&mut self) -> &mut Buffers {
+    &mut self.0
+  }
+}
+
+impl Drop for PooledBuffers {
+  fn drop(&mut self) {
+    let mut buffers = mem::replace(&mut self.0, Buffers::new());
+    buffers.clear_all();
+    buffers.shrink_to(profile::get().max_buf_size);
+    // The pool is gone if the thread is exiting.
+    let _ = POOL.try_with(|pool| pool.set(Some(buffers)));
   }
 }
 
@@ -280,12 +712,15 @@ struct Cursors<'a> {
 }
 
 impl<'a> Cursors<'a> {
-  pub fn new(buffers: &'a mut Buffers, source_window_len: u32) -> Self {
+  pub fn new(buffers: &'a mut Buffers, source_window_len: u32, code_table: &CodeTable) -> Self {
     Self {
       superstring: WindowCursor::new(&mut buffers.superstring[..], source_window_len),
       add_and_run_data: io::Cursor::new(&mut buffers.add_and_run_data[..]),
       instructions_and_sizes: io::Cursor::new(&mut buffers.instructions_and_sizes[..]),
-      copy_addresses: AddressDecoder::new(io::Cursor::new(&mut buffers.copy_addresses[..])),
+      copy_addresses: AddressDecoder::new(
+        io::Cursor::new(&mut buffers.copy_addresses[..]),
+        AddressCache::new(code_table.near_cache_size, code_table.same_cache_size),
+      ),
     }
   }
 
@@ -332,7 +767,7 @@ impl<'a> WindowCursor<'a> {
     let (written, unwritten): (&[u8], &mut [u8]) =
       self.split_for_write(size).ok_or(Error::BadPatch)?;
     let result = update_fn(written, unwritten)?;
-    self.cursor.set_position((position + size) as u64);
+    self.cursor.set_position(position as u64 + size as u64);
     Ok(result)
   }
 
@@ -350,7 +785,9 @@ impl<'a> WindowCursor<'a> {
 enum Instruction {
   #[default]
   Noop,
-  Run,
+  Run {
+    size: Option<NonZeroU8>,
+  },
   Add {
     size: Option<NonZeroU8>,
   },
@@ -360,7 +797,160 @@ enum Instruction {
   },
 }
 
-impl Instruction {}
+impl Instruction {
+  const NOOP: u8 = 0;
+  const ADD: u8 = 1;
+  const RUN: u8 = 2;
+  const COPY: u8 = 3;
+
+  /// Reads an instruction from its type, size and mode in a code table.
+  fn from_parts(kind: u8, size: u8, mode: u8) -> Result<Self, Error> {
+    let size = NonZeroU8::new(size);
+    Ok(match kind {
+      Self::NOOP => Instruction::Noop,
+      Self::ADD => Instruction::Add { size },
+      Self::RUN => Instruction::Run { size },
+      Self::COPY => Instruction::Copy { size, mode },
+      _ => return Err(Error::BadPatch),
+    })
+  }
+
+  /// Returns the instruction's type, size and mode in a code table.
+  fn to_parts(self) -> [u8; 3] {
+    let size = |size: Option<NonZeroU8>| size.map_or(0, NonZeroU8::get);
+    match self {
+      Instruction::Noop => [Self::NOOP, 0, 0],
+      Instruction::Add { size: s } => [Self::ADD, size(s), 0],
+      Instruction::Run { size: s } => [Self::RUN, size(s), 0],
+      Instruction::Copy { size: s, mode } => [Self::COPY, size(s), mode],
+    }
+  }
+}
+
+/// The length of a code table once it's written out as bytes.
+const CODE_TABLE_LEN: usize = 6 * 256;
+
+/// The pairs of instructions that instruction codes stand for, and the sizes
+/// of the address caches that copies' modes refer to.
+///
+/// A patch that sets `VCD_CODETABLE` brings its own, written out as bytes and
+/// encoded as a Vcdiff patch of the default table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct CodeTable {
+  pairs: [(Instruction, Instruction); 256],
+  near_cache_size: u8,
+  same_cache_size: u8,
+}
+
+impl CodeTable {
+  /// The table from section 5.6 of RFC 3284, which patches use unless they
+  /// bring their own.
+  const DEFAULT: CodeTable = CodeTable {
+    pairs: {
+      let mut pairs = [(Instruction::Noop, Instruction::Noop); 256];
+      let mut index = 0;
+      while index < pairs.len() {
+        pairs[index] = Self::default_pair(index as u8);
+        index += 1;
+      }
+      pairs
+    },
+    near_cache_size: 4,
+    same_cache_size: 3,
+  };
+
+  pub fn decode_instruction_pair(&self, index: u8) -> (Instruction, Instruction) {
+    self.pairs[index as usize]
+  }
+
+  /// Reads the code table in a patch's header: the sizes of the address
+  /// caches, followed by the encoded table.
+  fn read(patch: &mut impl Read) -> Result<Self, Error> {
+    let len: u32 = patch.read_vcdiff_int()?;
+    let mut data = io::ExactSizeRead::new(patch, len as u64);
+    let near_cache_size = data.read_u8()?;
+    let same_cache_size = data.read_u8()?;
+    // Modes are a byte, and two of them don't use the caches.
+    if near_cache_size as usize + same_cache_size as usize + 2 > 256 {
+      return Err(Error::BadPatch);
+    }
+    let mut encoded = Vec::new();
+    data.read_to_end(&mut encoded)?;
+    let mut bytes = io::Cursor::new(Vec::with_capacity(CODE_TABLE_LEN));
+    decode(
+      &mut io::Cursor::new(Self::DEFAULT.to_bytes()),
+      &mut io::Cursor::new(encoded),
+      &mut bytes,
+      true,
+    )?;
+    let bytes: [u8; CODE_TABLE_LEN] =
+      (bytes.into_inner().try_into()).map_err(|_| Error::BadPatch)?;
+    let mut pairs = [(Instruction::Noop, Instruction::Noop); 256];
+    for (index, pair) in pairs.iter_mut().enumerate() {
+      let part = |column: usize| bytes[column * 256 + index];
+      *pair = (
+        Instruction::from_parts(part(0), part(2), part(4))?,
+        Instruction::from_parts(part(1), part(3), part(5))?,
+      );
+    }
+    Ok(Self { pairs, near_cache_size, same_cache_size })
+  }
+
+  /// Writes the table out as the types of the first and second instructions
+  /// of each pair, then their sizes, then their modes.
+  fn to_bytes(&self) -> [u8; CODE_TABLE_LEN] {
+    let mut bytes = [0u8; CODE_TABLE_LEN];
+    for (index, (first, second)) in self.pairs.iter().enumerate() {
+      for (part, (first, second)) in first
+        .to_parts()
+        .into_iter()
+        .zip(second.to_parts())
+        .enumerate()
+      {
+        bytes[part * 512 + index] = first;
+        bytes[part * 512 + 256 + index] = second;
+      }
+    }
+    bytes
+  }
+
+  /// Returns the pair that an instruction code stands for in the default
+  /// table.
+  const fn default_pair(index: u8) -> (Instruction, Instruction) {
+    use Instruction::*;
+    match (index) {
+      0 => (Run { size: None }, Noop),
+      1..=18 => (Add { size: NonZeroU8::new(index - 1) }, Noop),
+      19..=162 => {
+        let offset = index - 19;
+        let size = NonZeroU8::new(if offset % 16 == 0 { 0 } else { 3 + offset % 16 });
+        let mode = offset / 16;
+        (Copy { size, mode }, Noop)
+      }
+      163..=234 => {
+        let offset = index - 163;
+        let size = NonZeroU8::new(1 + (offset / 3) % 4);
+        let size2 = NonZeroU8::new(4 + offset % 3);
+        let mode = offset / 12;
+        (Add { size }, Copy { size: size2, mode })
+      }
+      235..=246 => {
+        let offset = index - 235;
+        let size = NonZeroU8::new(1 + offset % 4);
+        // Only copies from the same cache, whose modes follow the first six.
+        let mode = 6 + offset / 4;
+        (Add { size }, Copy { size: NonZeroU8::new(4), mode })
+      }
+      _ => {
+        let offset = index - 247;
+        (
+          Copy { size: NonZeroU8::new(4), mode: offset },
+          Add { size: NonZeroU8::new(1) },
+        )
+      }
+    }
+  }
+}
 
 trait VcdiffRead: Read {
   /// Reads a big-endian varint. If the value overflows, returns an
@@ -385,6 +975,24 @@ trait VcdiffRead: Read {
 }
 impl<R> VcdiffRead for R where R: Read {}
 
+trait VcdiffWrite: Write {
+  /// Writes a big-endian varint.
+  fn write_vcdiff_int(&mut self, mut value: u64) -> io::Result<()> {
+    let mut bytes = [0u8; 10];
+    let mut start = bytes.len();
+    loop {
+      start -= 1;
+      bytes[start] = (value & 0x7F) as u8 | if start == bytes.len() - 1 { 0 } else { 0x80 };
+      value >>= 7;
+      if value == 0 {
+        break;
+      }
+    }
+    self.write_all(&bytes[start..])
+  }
+}
+impl<W> VcdiffWrite for W where W: Write {}
+
 trait ReadEof: BufRead {
   /// Returns `true` if the reader has reached EOF.
   ///
@@ -402,21 +1010,23 @@ struct AddressDecoder<R> {
 }
 
 impl<R: Read> AddressDecoder<R> {
-  pub fn new(addresses: R) -> Self {
-    Self { cache: AddressCache::new(), addresses }
+  pub fn new(addresses: R, cache: AddressCache) -> Self {
+    Self { cache, addresses }
   }
 
   pub fn decode(&mut self, here: u32, mode: u8) -> Result<u32, io::Error> {
-    const MAX_NEAR: u8 = 2 + cache::NearCache::SIZE;
-    const MAX_HERE: u8 = MAX_NEAR + cache::SameCache::NUM_BUCKETS;
-    let address: u32 = match mode {
+    let max_near = 2 + self.cache.near().len();
+    let max_same = max_near + self.cache.same().buckets();
+    let address: u32 = match mode as usize {
       0 => self.addresses.read_vcdiff_int()?,
       1 => here
         .checked_sub(self.addresses.read_vcdiff_int()?)
         .ok_or(io::Error::from(io::ErrorKind::InvalidData))?,
-      2..MAX_NEAR => self.cache.near()[mode - 2] + self.addresses.read_vcdiff_int::<u32>()?,
-      MAX_NEAR..MAX_HERE => {
-        let index: u16 = (mode - MAX_NEAR) as u16 * 256 + self.addresses.read_u8()? as u16;
+      mode if mode < max_near => (self.cache.near()[mode - 2])
+        .checked_add(self.addresses.read_vcdiff_int()?)
+        .ok_or(io::Error::from(io::ErrorKind::InvalidData))?,
+      mode if mode < max_same => {
+        let index = (mode - max_near) * 256 + self.addresses.read_u8()? as usize;
         self.cache.same()[index]
       }
       _ => return Err(io::Error::from(io::ErrorKind::InvalidData)),
@@ -435,8 +1045,11 @@ mod cache {
   }
 
   impl AddressCache {
-    pub const fn new() -> Self {
-      Self { near: NearCache::new(), same: SameCache::new() }
+    pub fn new(near_size: u8, same_buckets: u8) -> Self {
+      Self {
+        near: NearCache::new(near_size),
+        same: SameCache::new(same_buckets),
+      }
     }
 
     pub fn update(&mut self, addr: u32) {
@@ -454,51 +1067,61 @@ mod cache {
   }
 
   pub(crate) struct NearCache {
-    buf: [u32; NearCache::SIZE as usize],
-    next_slot: u8,
+    buf: Vec<u32>,
+    next_slot: usize,
   }
 
   impl NearCache {
-    pub const SIZE: u8 = 4;
+    pub fn new(size: u8) -> Self {
+      Self { buf: vec![0; size as usize], next_slot: 0 }
+    }
 
-    pub const fn new() -> Self {
-      Self { buf: [0; NearCache::SIZE as usize], next_slot: 0 }
+    pub fn len(&self) -> usize {
+      self.buf.len()
     }
 
     pub fn update(&mut self, addr: u32) {
-      self.buf[self.next_slot as usize] = addr;
-      self.next_slot = (self.next_slot + 1) % Self::SIZE;
+      if let Some(slot) = self.buf.get_mut(self.next_slot) {
+        *slot = addr;
+        self.next_slot = (self.next_slot + 1) % self.buf.len();
+      }
     }
   }
 
-  impl Index<u8> for NearCache {
+  impl Index<usize> for NearCache {
     type Output = u32;
 
-    fn index(&self, index: u8) -> &Self::Output {
-      &self.buf[index as usize]
+    fn index(&self, index: usize) -> &Self::Output {
+      &self.buf[index]
     }
   }
 
-  pub struct SameCache([u32; 3 * 256]);
+  /// Addresses in buckets of 256, each address in the bucket slot its value
+  /// selects.
+  pub struct SameCache(Vec<u32>);
 
   impl SameCache {
-    pub const NUM_BUCKETS: u8 = 3;
-    pub const SIZE: usize = SameCache::NUM_BUCKETS as usize * 256;
+    pub fn new(buckets: u8) -> Self {
+      Self(vec![0; buckets as usize * 256])
+    }
 
-    const fn new() -> Self {
-      Self([0; SameCache::SIZE])
+    pub fn buckets(&self) -> usize {
+      self.0.len() / 256
     }
 
     pub fn update(&mut self, addr: u32) {
-      self.0[addr as usize % Self::SIZE] = addr;
+      if !self.0.is_empty() {
+        let len = self.0.len();
+        self.0[addr as usize % len] = addr;
+      }
     }
   }
 
-  impl Index<u16> for SameCache {
+  impl Index<usize> for SameCache {
     type Output = u32;
 
-    fn index(&self, index: u16) -> &Self::Output {
-      &self.0[index as usize]
+    fn index(&self, index: usize) -> &Self::Output {
+      &self.0[index]
     }
   }
 }
@@ -507,8 +1130,98 @@ const fn set_msb<const N: usize>(arr: [u8; N]) -> [u8; N] {
   let mut result: [u8; N] = [0; N];
   let mut i = 0;
   while i < N {
-    result[i] = arr[i] & 0x80;
+    result[i] = arr[i] | 0x80;
     i += 1;
   }
   result
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::patch::Kind;
+  use crate::patch::tests::apply;
+  use romhacks_testkit::rom;
+
+  /// A patch made of `windows` that brings `table` as its code table.
+  fn with_code_table(table: &CodeTable, windows: &[u8]) -> Vec<u8> {
+    let default = CodeTable::DEFAULT.to_bytes();
+    let mut encoded = Vec::new();
+    encode(
+      &SourceIndex::new(&default),
+      &table.to_bytes(),
+      None,
+      &mut encoded,
+    )
+    .unwrap();
+    let mut patch = MAGIC.to_vec();
+    patch.extend([0, VCD_CODETABLE]);
+    patch.write_vcdiff_int(encoded.len() as u64 + 2).unwrap();
+    patch.extend([table.near_cache_size, table.same_cache_size]);
+    patch.extend(encoded);
+    patch.extend(windows);
+    patch
+  }
+
+  #[test]
+  fn magic_has_msb_set() {
+    // RFC 3284, section 4.1: 0xD6 0xC3 0xC4 are "VCD" with their MSBs set.
+    assert_eq!(MAGIC, [0xD6, 0xC3, 0xC4]);
+    assert_eq!(set_msb([0x00, 0x7F, 0x80, 0xFF]), [0x80, 0xFF, 0x80, 0xFF]);
+  }
+
+  #[test]
+  fn reads_app_header_after_magic() {
+    let mut patch = MAGIC.to_vec();
+    patch.extend([0, HAS_APPHEADER, 3]);
+    patch.extend(b"abc");
+    let header = read_app_header(&mut io::Cursor::new(patch)).unwrap();
+    assert_eq!(header.as_deref(), Some(&b"abc"[..]));
+  }
+
+  #[test]
+  fn default_code_table_in_patch() {
+    let rom = rom::random(0x1000, 1);
+    let target = rom::with_bytes(&rom, 0x100, b"romhacks");
+    let target = rom::with_bytes(&target, 0x800, &[0xFF; 0x40]);
+    let mut patch = Vec::new();
+    encode(&SourceIndex::new(&rom), &target, None, &mut patch).unwrap();
+    // The header without a code table or application header.
+    let windows = &patch[5..];
+    let with_table = with_code_table(&CodeTable::DEFAULT, windows);
+    assert!(apply(Kind::VCD, &rom, patch.clone()).unwrap() == target);
+    assert!(apply(Kind::VCD, &rom, with_table).unwrap() == target);
+  }
+
+  #[test]
+  fn custom_code_table() {
+    // The default table backwards, so that each code stands for what 255
+    // minus it does in the default table.
+    let mut pairs = CodeTable::DEFAULT.pairs;
+    pairs.reverse();
+    let table = CodeTable { pairs, ..CodeTable::DEFAULT };
+    let reversed = |code: u8| 255 - code;
+
+    let rom = rom::random(0x100, 2);
+    let mut window = WindowEncoder::default();
+    // A run of 8 bytes, an add of 5 bytes, and a copy of 10 bytes from 0x20.
+    window.instructions.push(reversed(WindowEncoder::RUN));
+    window.instructions.write_vcdiff_int(8).unwrap();
+    window.data.push(0xAA);
+    window.instructions.push(reversed(WindowEncoder::ADD + 5));
+    window.data.extend(b"hello");
+    window
+      .instructions
+      .push(reversed(WindowEncoder::COPY_SELF + 10 - 3));
+    window.addresses.write_vcdiff_int(0x20).unwrap();
+    let mut windows = Vec::new();
+    window.write(rom.len(), 23, &mut windows).unwrap();
+
+    let expected = [&[0xAA; 8][..], b"hello", &rom[0x20..0x2A]].concat();
+    let patch = with_code_table(&table, &windows);
+    assert!(apply(Kind::VCD, &rom, patch).unwrap() == expected);
+    // The same codes mean something else in the default table.
+    let patch = [MAGIC, &[0, 0], &windows].concat();
+    assert!(apply(Kind::VCD, &rom, patch).ok() != Some(expected));
+  }
+}
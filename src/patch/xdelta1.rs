@@ -0,0 +1,52 @@
+//! Patches made by xdelta 1.x, which aren't VCDIFF but a container of that
+//! version's own. They can't be applied, but they're recognized so that
+//! they aren't reported as an unknown or corrupt patch.
+//!
+//! Applying them isn't planned. The container's control block is serialized
+//! with xdelta 1's own serialization library, whose encoding is only defined
+//! by that library's code, and no xdelta 1 patches are available to check a
+//! decoder against. Users are pointed to xdelta 1.1.3 instead.
+
+use crate::io::prelude::*;
+use std::io;
+
+/// The magic strings of each version of the container, from the oldest, in
+/// xdelta 0.14, to the last, in xdelta 1.1.
+pub const MAGICS: [&[u8]; 6] = [
+  b"%XDELTA%",
+  b"%XDZ000%",
+  b"%XDZ001%",
+  b"%XDZ002%",
+  b"%XDZ003%",
+  b"%XDZ004%",
+];
+
+/// Checks whether `patch` starts with one of the [`MAGICS`].
+pub fn detect(patch: &mut (impl Read + Seek)) -> io::Result<bool> {
+  patch.seek(io::SeekFrom::Start(0))?;
+  let mut magic = Vec::with_capacity(8);
+  (&mut *patch).take(8).read_to_end(&mut magic)?;
+  Ok(MAGICS.contains(&magic.as_slice()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+
+  #[test]
+  fn detects_every_version() {
+    for magic in MAGICS {
+      let mut patch = magic.to_vec();
+      patch.extend([0; 24]);
+      assert!(detect(&mut Cursor::new(patch)).unwrap());
+    }
+  }
+
+  #[test]
+  fn ignores_other_files() {
+    for patch in [&b""[..], b"%XDZ00", b"%XDZ005%", b"\xD6\xC3\xC4\x00\x00"] {
+      assert!(!detect(&mut Cursor::new(patch)).unwrap());
+    }
+  }
+}
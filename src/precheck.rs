@@ -0,0 +1,57 @@
+use crate::error::prelude::*;
+use crate::io::prelude::*;
+use crate::render::{Stream, Style};
+use crate::{console, fs, i18n, io, patch};
+use std::path;
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct Args {
+  /// The patch to check.
+  #[arg(short, long)]
+  pub patch: path::PathBuf,
+  /// A ROM the patch will be applied to, whose size is checked against the
+  /// one the patch expects. Can be given more than once.
+  #[arg(short, long)]
+  pub rom: Vec<path::PathBuf>,
+}
+
+impl Args {
+  pub fn call(self) -> Result<(), Error> {
+    let mut patch = patch::compression::open(&self.patch)?;
+    let Some(kind) = patch::Kind::detect(&mut patch)? else {
+      return Err(match patch::xdelta1::detect(&mut patch)? {
+        true => patch::Error::Xdelta1.into(),
+        false => patch::UnknownPatchKindError(()).into(),
+      });
+    };
+    let prechecked = patch::precheck::patch(kind, &mut patch)?;
+    for rom_path in &self.rom {
+      let mut rom = fs::File::open(rom_path)?;
+      let rom_len = rom.known_len()?;
+      let rom_console = console::Rom::detect(&mut rom, rom_path)?;
+      // `apply` explains ROMs with copier headers once they've been hashed.
+      let as_is = rom_console.is_none_or(|rom| rom.header.is_none());
+      prechecked.rom(rom_len, as_is)?;
+    }
+    log::info!(
+      "{}",
+      Stream::Stderr.paint(
+        Style::Ok,
+        i18n::format("romhacks::precheck::ok", &[("format", &kind)])
+      )
+    );
+    Ok(())
+  }
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error(transparent)]
+  UnknownPatchKind(#[from] patch::UnknownPatchKindError),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Patch(#[from] patch::Error),
+}
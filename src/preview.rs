@@ -0,0 +1,187 @@
+//! Showing what a patch would change in a region of a ROM, without applying
+//! the whole patch.
+//!
+//! Only the operations that touch the region are replayed, over a copy of
+//! the ROM's bytes in it, so previewing a patch for a large disc image reads
+//! little more than the patch.
+
+use crate::crc::Crc32;
+use crate::error::prelude::*;
+use crate::io::prelude::*;
+use crate::patch::ops::{DecodedPatch, Op};
+use crate::render::{Stream, Style};
+use crate::{fs, i18n, io, patch};
+use std::ops::Range;
+use std::path;
+
+/// The number of bytes in each row of the hexdump.
+const BYTES_PER_ROW: usize = 16;
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct Args {
+  /// The patch to preview. Only IPS, UPS and PPF patches can be previewed.
+  pub patch: path::PathBuf,
+  /// The ROM the patch would be applied to.
+  #[arg(short, long)]
+  pub rom: path::PathBuf,
+  /// The offset of the first byte to show. Hexadecimal offsets need a "0x"
+  /// prefix.
+  #[arg(long, value_parser = parse_offset)]
+  pub at: u64,
+  /// The number of bytes to show.
+  #[arg(long, value_parser = parse_offset, default_value = "64")]
+  pub len: u64,
+}
+
+impl Args {
+  /// Prints the bytes of the region before and after patching, with the
+  /// bytes the patch changes highlighted.
+  pub fn call(self) -> Result<(), Error> {
+    let mut patch = patch::compression::open(&self.patch)?;
+    let kind = patch::Kind::detect(&mut patch)?.ok_or(patch::UnknownPatchKindError(()))?;
+    if !patch::ops::decodes(kind) {
+      return Err(Error::Unsupported { format: kind });
+    }
+    let decoded = DecodedPatch::decode(kind, &mut patch)?;
+    let mut rom = fs::File::open(&self.rom)?;
+    if decoded.header().source_crc32.is_some() {
+      decoded.check_source(Crc32::read_and_hash(&mut io::BufReader::new(&mut rom))?)?;
+    }
+    let rom_len = rom.known_len()?;
+    let range = self.at..self.at.saturating_add(self.len);
+    let mut before = Vec::new();
+    rom.seek(io::SeekFrom::Start(range.start))?;
+    (&mut rom).take(self.len).read_to_end(&mut before)?;
+    let after = simulate(decoded.ops(), &range, rom_len, &before)?;
+    if before == after {
+      log::info!("{}", i18n::text("romhacks::preview::unchanged"));
+    }
+    print_hexdump(range.start, &before, &after);
+    Ok(())
+  }
+}
+
+/// Replays the parts of `ops` that fall within `range` over `before`, the
+/// bytes of the ROM there, and returns the bytes the patched file would have
+/// there. Operations outside the range only matter if they resize the file.
+fn simulate(
+  ops: &[Op<'_>],
+  range: &Range<u64>,
+  rom_len: u64,
+  before: &[u8],
+) -> Result<Vec<u8>, patch::Error> {
+  let window_len = (range.end - range.start) as usize;
+  // Bytes past the end of the file read as zeroes, like the zeroes a file
+  // is extended with.
+  let mut region = before.to_vec();
+  region.resize(window_len, 0);
+  let index = |offset: u64| (offset - range.start) as usize;
+  let mut file_len = rom_len;
+  for op in ops {
+    let end = match op {
+      Op::Expect { .. } => None,
+      Op::Write { offset, data } | Op::Xor { offset, data } => Some(offset + data.len() as u64),
+      Op::Fill { offset, len, .. } => Some(offset + len),
+      Op::Resize { len } => {
+        file_len = *len;
+        let kept = len.saturating_sub(range.start).min(window_len as u64) as usize;
+        region[kept..].fill(0);
+        continue;
+      }
+    };
+    file_len = file_len.max(end.unwrap_or(0));
+    match op.clip(range) {
+      Some(Op::Expect { offset, data }) => {
+        if region[index(offset)..][..data.len()] != data[..] {
+          return Err(patch::Error::WrongInputFile);
+        }
+      }
+      Some(Op::Write { offset, data }) => {
+        region[index(offset)..][..data.len()].copy_from_slice(&data);
+      }
+      Some(Op::Fill { offset, len, byte }) => {
+        region[index(offset)..][..len as usize].fill(byte);
+      }
+      Some(Op::Xor { offset, data }) => {
+        for (byte, mask) in region[index(offset)..].iter_mut().zip(data.iter()) {
+          *byte ^= mask;
+        }
+      }
+      Some(Op::Resize { .. }) | None => {}
+    }
+  }
+  region.truncate(file_len.saturating_sub(range.start).min(window_len as u64) as usize);
+  Ok(region)
+}
+
+/// Prints rows of 16 bytes in hexadecimal and as ASCII. Rows the patch
+/// changes are printed twice, before ("-") and after ("+") patching, with
+/// the changed bytes highlighted.
+fn print_hexdump(start: u64, before: &[u8], after: &[u8]) {
+  let len = before.len().max(after.len());
+  for row_start in (0..len).step_by(BYTES_PER_ROW) {
+    let row = row_start..(row_start + BYTES_PER_ROW);
+    let offset = format!("{:08X}", start + row_start as u64);
+    let changed = |i: usize| before.get(i) != after.get(i);
+    if !row.clone().any(changed) {
+      println!("{offset}    {}", format_row(before, row, |_| None));
+      continue;
+    }
+    let (removed, added) = (Some(Style::Failure), Some(Style::Ok));
+    let before_row = format_row(before, row.clone(), |i| removed.filter(|_| changed(i)));
+    let after_row = format_row(after, row, |i| added.filter(|_| changed(i)));
+    println!("{offset}  - {before_row}");
+    println!("{offset}  + {after_row}");
+  }
+}
+
+/// Formats the bytes of `bytes` in `row` as hexadecimal and ASCII, padding
+/// bytes past its end with spaces, and styling each byte with `style`.
+fn format_row(bytes: &[u8], row: Range<usize>, style: impl Fn(usize) -> Option<Style>) -> String {
+  let paint = |i: usize, text: String| match style(i) {
+    Some(style) => Stream::Stdout.paint(style, text).to_string(),
+    None => text,
+  };
+  let hex: Vec<String> = (row.clone())
+    .map(|i| match bytes.get(i) {
+      Some(byte) => paint(i, format!("{byte:02X}")),
+      None => "  ".to_owned(),
+    })
+    .collect();
+  let ascii: String = (row)
+    .map(|i| match bytes.get(i) {
+      Some(&byte) => {
+        let char = match byte {
+          0x20..=0x7E => byte as char,
+          _ => '.',
+        };
+        paint(i, char.to_string())
+      }
+      None => " ".to_owned(),
+    })
+    .collect();
+  format!("{}  |{ascii}|", hex.join(" "))
+}
+
+fn parse_offset(arg: &str) -> Result<u64, String> {
+  let offset = match arg.strip_prefix("0x").or_else(|| arg.strip_prefix("0X")) {
+    Some(hex) => u64::from_str_radix(hex, 16),
+    None => arg.parse(),
+  };
+  offset.map_err(|_| i18n::text("romhacks::preview::bad_offset").to_owned())
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Patch(#[from] patch::Error),
+  #[error(transparent)]
+  UnknownPatchKind(#[from] patch::UnknownPatchKindError),
+  #[error("{}", i18n::format("romhacks::preview::unsupported", &[("format", format)]))]
+  #[diagnostic(code(romhacks::preview::unsupported))]
+  Unsupported { format: patch::Kind },
+}
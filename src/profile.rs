@@ -0,0 +1,94 @@
+use std::sync::OnceLock;
+
+static PROFILE: OnceLock<Profile> = OnceLock::new();
+
+/// Command line options that trade speed for memory usage.
+#[derive(Clone, Debug, clap::Args)]
+pub struct Options {
+  /// Use small buffers, keep less data in memory and patch on a single
+  /// thread, for devices with little RAM.
+  #[arg(long, global = true)]
+  pub low_memory: bool,
+  /// The capacity of read and write buffers, in bytes. By default, each
+  /// buffer is sized for the files being read or written.
+  #[arg(long, global = true, value_parser = clap::value_parser!(u32).range(512..))]
+  pub buffer_size: Option<u32>,
+}
+
+/// How much memory the program may use for buffering.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Profile {
+  /// The capacity of buffered readers and writers whose contents have no
+  /// known size, and the smallest capacity of writers for whole files.
+  pub buf_size: usize,
+  /// The largest capacity that [`buffers`](crate::buffers) chooses.
+  pub max_buf_size: usize,
+  /// Whether buffers are sized for the files being read or written, rather
+  /// than always having a capacity of `buf_size`.
+  pub adaptive_buffers: bool,
+  /// Patched files up to this size are kept in memory until they're written out.
+  pub spool_threshold: usize,
+  /// Buffers that grow beyond this size while patching are shrunk back down
+  /// afterwards, instead of keeping their capacity for reuse.
+  pub retained_buf_size: usize,
+  /// Whether work may be split across threads.
+  pub parallel: bool,
+}
+
+impl Profile {
+  pub const DEFAULT: Profile = Profile {
+    buf_size: 8 * 1024,
+    max_buf_size: 1024 * 1024,
+    adaptive_buffers: true,
+    spool_threshold: 64 * 1024 * 1024,
+    retained_buf_size: usize::MAX,
+    parallel: true,
+  };
+
+  pub const LOW_MEMORY: Profile = Profile {
+    buf_size: 2 * 1024,
+    max_buf_size: 2 * 1024,
+    adaptive_buffers: true,
+    spool_threshold: 1024 * 1024,
+    retained_buf_size: 1024 * 1024,
+    parallel: false,
+  };
+}
+
+impl Default for Profile {
+  fn default() -> Self {
+    Self::DEFAULT
+  }
+}
+
+impl From<&Options> for Profile {
+  fn from(args: &Options) -> Self {
+    let profile = match args.low_memory {
+      true => Profile::LOW_MEMORY,
+      false => Profile::DEFAULT,
+    };
+    match args.buffer_size {
+      Some(buf_size) => Profile {
+        buf_size: buf_size as usize,
+        adaptive_buffers: false,
+        ..profile
+      },
+      None => profile,
+    }
+  }
+}
+
+/// Sets the profile returned by [`get`]. Until this is called, the default
+/// profile is used.
+pub fn init(profile: Profile) {
+  if PROFILE.set(profile).is_ok() && !profile.parallel {
+    // Fails iff the global thread pool was already initialized.
+    let _ = rayon::ThreadPoolBuilder::new()
+      .num_threads(1)
+      .build_global();
+  }
+}
+
+pub fn get() -> &'static Profile {
+  PROFILE.get_or_init(Profile::default)
+}
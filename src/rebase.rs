@@ -0,0 +1,103 @@
+use crate::crc::Crc32;
+use crate::create::{self, Format};
+use crate::error::prelude::*;
+use crate::io::prelude::*;
+use crate::patch::job::PatchJob;
+use crate::{dirs, fs, i18n, io, patch, profile};
+use std::path;
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct Args {
+  /// The patch to rebase.
+  #[arg(short, long)]
+  pub patch: path::PathBuf,
+  /// The ROM revision the patch was made for.
+  #[arg(long)]
+  pub from: path::PathBuf,
+  /// The ROM revision the new patch should apply to.
+  #[arg(long)]
+  pub to: path::PathBuf,
+  /// The checksum the old revision should have, in hexadecimal, for patches
+  /// that don't record it, such as IPS patches.
+  #[arg(long, value_name = "CRC32", value_parser = parse_crc32)]
+  pub from_crc32: Option<Crc32>,
+  /// The checksum the old revision should have once it's patched, in
+  /// hexadecimal.
+  #[arg(long, value_name = "CRC32", value_parser = parse_crc32)]
+  pub patched_crc32: Option<Crc32>,
+  /// Rebase the patch even if the old revision or the patched file doesn't
+  /// have the checksum given for it, or the checksums of a GBA APS patch's
+  /// blocks don't match, with a warning.
+  #[arg(long)]
+  pub force: bool,
+  /// The format of the new patch.
+  #[arg(short, long, value_enum, default_value = "bps")]
+  pub format: Format,
+  /// Where to write the new patch, or "-" for standard output.
+  #[arg(short, long, default_value = io::STDIO)]
+  pub output: path::PathBuf,
+}
+
+impl Args {
+  /// Applies the patch to the old revision, then diffs the result against the
+  /// new one. The patched ROM never touches the disk unless it's too large to
+  /// keep in memory.
+  pub fn call(self) -> Result<(), Error> {
+    let mut patch = patch::compression::open(&self.patch)?;
+    let mut from = fs::File::open(&self.from)?;
+    let mut patched = io::SpooledTempBuffer::new(profile::get().spool_threshold, dirs::temp_dir());
+    let mut job = PatchJob::new()
+      .source(&mut from)
+      .patch(&mut patch)
+      .output(&mut patched)
+      .strict(!self.force);
+    if let Some(crc32) = self.from_crc32 {
+      job = job.expect_source(crc32);
+    }
+    if let Some(crc32) = self.patched_crc32 {
+      job = job.expect_output(crc32);
+    }
+    let report = job.run()?;
+    for warning in &report.warnings {
+      log::warn!("{warning}");
+    }
+
+    let mut target = Vec::with_capacity(report.output.size as usize);
+    patched.seek(io::SeekFrom::Start(0))?;
+    patched.read_to_end(&mut target)?;
+    drop(patched);
+    let source = fs::read(&self.to)?;
+    let rebased = create::build(self.format, &create::Source::new(&source), &target, None)?;
+    io::write_file_or_stdout(&self.output, &rebased)?;
+    Ok(())
+  }
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Patch(#[from] patch::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Job(#[from] patch::job::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Create(#[from] create::Error),
+}
+
+/// Parses a checksum written in hexadecimal, optionally with a "0x" prefix.
+fn parse_crc32(arg: &str) -> Result<Crc32, String> {
+  let digits = arg
+    .strip_prefix("0x")
+    .or_else(|| arg.strip_prefix("0X"))
+    .unwrap_or(arg);
+  match digits.len() {
+    1..=8 => u32::from_str_radix(digits, 16).ok().map(Crc32::new),
+    _ => None,
+  }
+  .ok_or_else(|| i18n::text("romhacks::rebase::bad_crc32").to_owned())
+}
@@ -0,0 +1,140 @@
+use crate::crc::Crc32;
+use crate::i18n;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{env, fmt, io};
+
+static STDOUT_COLORED: AtomicBool = AtomicBool::new(false);
+static STDERR_COLORED: AtomicBool = AtomicBool::new(false);
+
+/// When to color the program's output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, clap::ValueEnum)]
+pub enum ColorChoice {
+  /// Color output that's written to a terminal, unless `NO_COLOR` is set.
+  #[default]
+  Auto,
+  /// Always color output.
+  Always,
+  /// Never color output.
+  Never,
+}
+
+impl ColorChoice {
+  fn should_color(self, stream_is_terminal: bool) -> bool {
+    match self {
+      ColorChoice::Always => true,
+      ColorChoice::Never => false,
+      ColorChoice::Auto => {
+        // See https://no-color.org/
+        let no_color = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+        let dumb_terminal = env::var_os("TERM").is_some_and(|term| term == "dumb");
+        stream_is_terminal && !no_color && !dumb_terminal
+      }
+    }
+  }
+}
+
+/// Decides which output streams are colored, including miette's error reports.
+/// Until this is called, nothing is colored.
+pub fn init(choice: ColorChoice) {
+  let stdout = choice.should_color(io::stdout().is_terminal());
+  let stderr = choice.should_color(io::stderr().is_terminal());
+  STDOUT_COLORED.store(stdout, Ordering::Relaxed);
+  STDERR_COLORED.store(stderr, Ordering::Relaxed);
+  // Fails iff a hook was already installed, in which case that one is kept.
+  let _ = miette::set_hook(Box::new(move |_| {
+    // Colored reports are always graphical, even when stderr isn't a terminal.
+    Box::new(
+      miette::MietteHandlerOpts::new()
+        .color(stderr)
+        .force_graphical(stderr)
+        .build(),
+    )
+  }));
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Stream {
+  Stdout,
+  Stderr,
+}
+
+impl Stream {
+  pub fn is_colored(self) -> bool {
+    match self {
+      Stream::Stdout => STDOUT_COLORED.load(Ordering::Relaxed),
+      Stream::Stderr => STDERR_COLORED.load(Ordering::Relaxed),
+    }
+  }
+
+  /// Wraps `value` so it's displayed in `style` if this stream is colored.
+  pub fn paint<T: fmt::Display>(self, style: Style, value: T) -> Painted<T> {
+    Painted { style: self.is_colored().then_some(style), value }
+  }
+
+  /// Describes a checksum mismatch, highlighting the digits of `actual` that
+  /// differ from `expected`.
+  pub fn checksum_diff(self, expected: Crc32, actual: Crc32) -> String {
+    let expected_hex = format!("{:08X}", expected.value());
+    let actual_hex: String = format!("{:08X}", actual.value())
+      .chars()
+      .zip(expected_hex.chars())
+      .map(|(digit, expected_digit)| {
+        if digit == expected_digit {
+          digit.to_string()
+        } else {
+          self.paint(Style::Failure, digit).to_string()
+        }
+      })
+      .collect();
+    i18n::format(
+      "romhacks::render::checksum_diff",
+      &[("expected", &expected_hex), ("actual", &actual_hex)],
+    )
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Style {
+  /// Something succeeded.
+  Ok,
+  /// Something needs the user's attention.
+  Warning,
+  /// Something failed.
+  Failure,
+}
+
+impl Style {
+  /// The style of log messages at `level`, if any.
+  pub fn for_level(level: log::Level) -> Option<Self> {
+    match level {
+      log::Level::Error => Some(Style::Failure),
+      log::Level::Warn => Some(Style::Warning),
+      log::Level::Info | log::Level::Debug | log::Level::Trace => None,
+    }
+  }
+
+  fn ansi_code(self) -> &'static str {
+    match self {
+      Style::Ok => "32",
+      Style::Warning => "33",
+      Style::Failure => "31",
+    }
+  }
+}
+
+/// A value that's displayed with ANSI color codes. See [`Stream::paint`].
+#[derive(Clone, Copy, Debug)]
+pub struct Painted<T> {
+  style: Option<Style>,
+  value: T,
+}
+
+impl<T: fmt::Display> fmt::Display for Painted<T> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.style {
+      Some(style) => write!(f, "\x1b[{}m{}\x1b[0m", style.ansi_code(), self.value),
+      None => self.value.fmt(f),
+    }
+  }
+}
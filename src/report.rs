@@ -0,0 +1,550 @@
+//! Reports of applied patches, written next to the patched file so that the
+//! formats, checksums, warnings and timings of a patching run can be archived
+//! with it.
+
+use crate::crc::Crc32;
+use crate::error::prelude::*;
+use crate::{fs, hack, i18n, info, io, json, kdl, mem};
+use std::{ffi, fmt, path, time};
+
+/// The extension added to the patched file's name, before the format's own.
+pub const EXTENSION: &str = "report";
+
+const REPORT_VERSION: &str = "1.0";
+
+// nodes
+const ROMHACKS_REPORT: &str = "romhacks-report";
+const ROM: &str = "rom";
+const PATCH: &str = "patch";
+const OUTPUT: &str = "output";
+const HACK: &str = "hack";
+const WARNING: &str = "warning";
+const TIMING: &str = "timing";
+
+// props
+const VERSION: &str = "version";
+const FORMAT: &str = "format";
+const SIZE: &str = "size";
+const CRC_32: &str = "crc32";
+const CRC_32_AFTER: &str = "crc32-after";
+const URL: &str = "url";
+const PHASE: &str = "phase";
+const MICROSECONDS: &str = "microseconds";
+const BYTES: &str = "bytes";
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct Args {
+  #[command(subcommand)]
+  pub command: Command,
+}
+
+#[derive(Clone, Debug, clap::Subcommand)]
+pub enum Command {
+  /// Print a report written by `apply --report`.
+  Show {
+    report: path::PathBuf,
+    /// Print the report as JSON instead of a table.
+    #[arg(long)]
+    json: bool,
+  },
+}
+
+impl Args {
+  pub fn call(self) -> Result<(), Error> {
+    match self.command {
+      Command::Show { report, json } => {
+        let report = Report::read(&report)?;
+        match json {
+          true => println!("{}", report.to_json()),
+          false => info::print_table(&report.table()),
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+/// The formats reports can be written in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, clap::ValueEnum)]
+pub enum Format {
+  Kdl,
+  Json,
+}
+
+impl Format {
+  fn extension(self) -> &'static str {
+    match self {
+      Format::Kdl => "kdl",
+      Format::Json => "json",
+    }
+  }
+
+  /// Guesses the format of a report from its extension. Anything but JSON is
+  /// read as KDL.
+  fn of(path: &path::Path) -> Self {
+    match (path.extension()).is_some_and(|ext| ext.eq_ignore_ascii_case("json")) {
+      true => Format::Json,
+      false => Format::Kdl,
+    }
+  }
+}
+
+/// The path of the report for the file at `path`, with ".report.kdl" or
+/// ".report.json" appended to its name.
+pub fn sidecar_path(path: &path::Path, format: Format) -> path::PathBuf {
+  let mut buf = ffi::OsString::from(path);
+  buf.push(".");
+  buf.push(EXTENSION);
+  buf.push(".");
+  buf.push(format.extension());
+  buf.into()
+}
+
+/// What happened when a patch was applied to a ROM.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Report {
+  /// The name of the patch's format.
+  pub format: String,
+  pub rom: File,
+  /// The ROM's checksum when it was hashed again after patching, with
+  /// --paranoid.
+  pub rom_after: Option<Crc32>,
+  pub patch: File,
+  pub output: File,
+  pub hack_url: String,
+  pub hack_version: hack::Version,
+  /// The warnings logged while patching, in order.
+  pub warnings: Vec<String>,
+  /// How long each phase of patching took, in order. Reports written before
+  /// timings were recorded have none.
+  pub timings: Vec<Timing>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct File {
+  pub path: String,
+  pub size: u64,
+  pub crc32: Crc32,
+}
+
+/// The parts of patching that are timed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Phase {
+  /// Hashing the ROM, unless its checksum was cached.
+  SourceHash,
+  /// Detecting the patch's format, hashing it and verifying its signature.
+  /// When patching several ROMs, this includes decoding the patch once.
+  PatchParse,
+  /// Copying the ROM and applying the patch to it, including any sector
+  /// conversion and padding.
+  Apply,
+  OutputHash,
+  /// Moving the patched file into place and copying the ROM's metadata.
+  Rename,
+  /// Hashing the patched file again once it's in place.
+  OutputVerify,
+  /// Hashing the chunks of the patched file that the patch changed, and a
+  /// sample of the others, once it's in place. The bytes are those hashed.
+  OutputVerifySampled,
+}
+
+impl Phase {
+  const ALL: [Phase; 7] = [
+    Phase::SourceHash,
+    Phase::PatchParse,
+    Phase::Apply,
+    Phase::OutputHash,
+    Phase::Rename,
+    Phase::OutputVerify,
+    Phase::OutputVerifySampled,
+  ];
+
+  /// The name of the phase in reports.
+  fn name(self) -> &'static str {
+    match self {
+      Phase::SourceHash => "source-hash",
+      Phase::PatchParse => "patch-parse",
+      Phase::Apply => "apply",
+      Phase::OutputHash => "output-hash",
+      Phase::Rename => "rename",
+      Phase::OutputVerify => "output-verify",
+      Phase::OutputVerifySampled => "output-verify-sampled",
+    }
+  }
+
+  fn from_name(name: &str) -> Option<Self> {
+    Self::ALL.into_iter().find(|phase| phase.name() == name)
+  }
+}
+
+impl fmt::Display for Phase {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(i18n::text(match self {
+      Phase::SourceHash => "romhacks::report::source_hash",
+      Phase::PatchParse => "romhacks::report::patch_parse",
+      Phase::Apply => "romhacks::report::apply",
+      Phase::OutputHash => "romhacks::report::output_hash",
+      Phase::Rename => "romhacks::report::rename",
+      Phase::OutputVerify => "romhacks::report::output_verify",
+      Phase::OutputVerifySampled => "romhacks::report::output_verify_sampled",
+    }))
+  }
+}
+
+/// How long a phase took and how many bytes it processed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Timing {
+  pub phase: Phase,
+  pub duration: time::Duration,
+  pub bytes: u64,
+}
+
+impl Timing {
+  /// Times a phase that started at `start` and ends now, and logs it.
+  pub fn since(phase: Phase, start: time::Instant, bytes: u64) -> Self {
+    let timing = Self { phase, duration: start.elapsed(), bytes };
+    log::debug!("{timing}");
+    timing
+  }
+
+  /// The throughput in tenths of a megabyte (10^6 bytes) per second, or
+  /// `None` if the phase was too quick to measure.
+  fn tenths_of_mb_per_second(&self) -> Option<u128> {
+    let micros = self.duration.as_micros();
+    (micros > 0).then(|| u128::from(self.bytes) * 10 / micros)
+  }
+}
+
+impl fmt::Display for Timing {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let milliseconds = format!("{:.1}", self.duration.as_secs_f64() * 1000.0);
+    match self.tenths_of_mb_per_second() {
+      Some(tenths) => f.write_str(&i18n::format(
+        "romhacks::report::timing",
+        &[
+          ("phase", &self.phase),
+          ("milliseconds", &milliseconds),
+          ("throughput", &format!("{}.{}", tenths / 10, tenths % 10)),
+        ],
+      )),
+      None => f.write_str(&i18n::format(
+        "romhacks::report::timing_instant",
+        &[("phase", &self.phase), ("milliseconds", &milliseconds)],
+      )),
+    }
+  }
+}
+
+impl Report {
+  /// Reads a report, in the format its extension names.
+  pub fn read(path: &path::Path) -> Result<Self, Error> {
+    let text = fs::read_to_string(path)?;
+    let report = match Format::of(path) {
+      Format::Kdl => Self::from_kdl(&text.parse().map_err(ParseError::from)?)?,
+      Format::Json => Self::from_json(&text.parse().map_err(ParseError::from)?)?,
+    };
+    Ok(report)
+  }
+
+  /// Writes the report next to the patched file and returns its path.
+  pub fn write(&self, patched: &path::Path, format: Format) -> io::Result<path::PathBuf> {
+    let path = sidecar_path(patched, format);
+    let text = match format {
+      Format::Kdl => self.to_kdl().to_string(),
+      Format::Json => format!("{}\n", self.to_json()),
+    };
+    fs::write(&path, text)?;
+    Ok(path)
+  }
+
+  pub fn to_kdl(&self) -> kdl::KdlDocument {
+    let file_node = |name: &str, file: &File| {
+      mem::init(kdl::KdlNode::new(name), |node| {
+        node.push(file.path.as_str());
+        node.insert(SIZE, file.size as i128);
+        node.insert(CRC_32, i128::from(file.crc32.value()));
+      })
+    };
+    mem::init(kdl::KdlDocument::new(), |doc| {
+      let nodes = doc.nodes_mut();
+      nodes.push(mem::init(kdl::KdlNode::new(ROMHACKS_REPORT), |node| {
+        node.insert(VERSION, REPORT_VERSION);
+        node.insert(FORMAT, self.format.as_str());
+      }));
+      nodes.push(mem::init(file_node(ROM, &self.rom), |node| {
+        if let Some(after) = self.rom_after {
+          node.insert(CRC_32_AFTER, i128::from(after.value()));
+        }
+      }));
+      nodes.push(file_node(PATCH, &self.patch));
+      nodes.push(file_node(OUTPUT, &self.output));
+      nodes.push(mem::init(kdl::KdlNode::new(HACK), |node| {
+        node.insert(URL, self.hack_url.as_str());
+        node.insert(VERSION, self.hack_version.as_str());
+      }));
+      for warning in &self.warnings {
+        nodes.push(mem::init(kdl::KdlNode::new(WARNING), |node| {
+          node.push(warning.as_str());
+        }));
+      }
+      for timing in &self.timings {
+        nodes.push(mem::init(kdl::KdlNode::new(TIMING), |node| {
+          node.insert(PHASE, timing.phase.name());
+          node.insert(MICROSECONDS, timing.duration.as_micros() as i128);
+          node.insert(BYTES, timing.bytes as i128);
+        }));
+      }
+      doc.autoformat();
+    })
+  }
+
+  pub fn from_kdl(doc: &kdl::KdlDocument) -> Result<Self, ParseError> {
+    let node = |name: &str| doc.get(name).ok_or(ParseError::Malformed);
+    let get_str = |node: &kdl::KdlNode, key: kdl::NodeKey| {
+      (node.get(key).and_then(|value| value.as_string()))
+        .map(str::to_owned)
+        .ok_or(ParseError::Malformed)
+    };
+    let get_integer = |node: &kdl::KdlNode, key: &str| {
+      (node.get(key).and_then(|value| value.as_integer())).ok_or(ParseError::Malformed)
+    };
+    let file = |name: &str| -> Result<File, ParseError> {
+      let node = node(name)?;
+      Ok(File {
+        path: get_str(node, 0.into())?,
+        size: u64::try_from(get_integer(node, SIZE)?).map_err(|_| ParseError::Malformed)?,
+        crc32: Crc32::new(
+          u32::try_from(get_integer(node, CRC_32)?).map_err(|_| ParseError::Malformed)?,
+        ),
+      })
+    };
+    let hack = node(HACK)?;
+    Ok(Self {
+      format: get_str(node(ROMHACKS_REPORT)?, FORMAT.into())?,
+      rom: file(ROM)?,
+      rom_after: match node(ROM)?.get(CRC_32_AFTER) {
+        None => None,
+        Some(_) => Some(Crc32::new(
+          u32::try_from(get_integer(node(ROM)?, CRC_32_AFTER)?)
+            .map_err(|_| ParseError::Malformed)?,
+        )),
+      },
+      patch: file(PATCH)?,
+      output: file(OUTPUT)?,
+      hack_url: get_str(hack, URL.into())?,
+      hack_version: (get_str(hack, VERSION.into())?)
+        .parse()
+        .map_err(|_| ParseError::Malformed)?,
+      warnings: (doc.nodes().iter())
+        .filter(|node| node.name().value() == WARNING)
+        .map(|node| get_str(node, 0.into()))
+        .collect::<Result<_, _>>()?,
+      timings: (doc.nodes().iter())
+        .filter(|node| node.name().value() == TIMING)
+        .map(|node| {
+          timing(
+            &get_str(node, PHASE.into())?,
+            get_integer(node, MICROSECONDS)?,
+            get_integer(node, BYTES)?,
+          )
+        })
+        .collect::<Result<_, _>>()?,
+    })
+  }
+
+  pub fn to_json(&self) -> json::Value {
+    use json::Value as V;
+    let string = |str: &str| V::String(str.to_owned());
+    let file = |file: &File| {
+      V::Object(vec![
+        ("path".to_owned(), string(&file.path)),
+        ("size".to_owned(), V::Integer(file.size.into())),
+        ("crc32".to_owned(), V::Integer(file.crc32.value().into())),
+      ])
+    };
+    V::Object(vec![
+      ("version".to_owned(), string(REPORT_VERSION)),
+      ("format".to_owned(), string(&self.format)),
+      (
+        "rom".to_owned(),
+        mem::init(file(&self.rom), |rom| {
+          if let (V::Object(fields), Some(after)) = (rom, self.rom_after) {
+            fields.push(("crc32_after".to_owned(), V::Integer(after.value().into())));
+          }
+        }),
+      ),
+      ("patch".to_owned(), file(&self.patch)),
+      ("output".to_owned(), file(&self.output)),
+      ("hack_url".to_owned(), string(&self.hack_url)),
+      (
+        "hack_version".to_owned(),
+        string(self.hack_version.as_str()),
+      ),
+      (
+        "warnings".to_owned(),
+        V::Array(
+          self
+            .warnings
+            .iter()
+            .map(|warning| string(warning))
+            .collect(),
+        ),
+      ),
+      (
+        "timings".to_owned(),
+        V::Array(
+          (self.timings.iter())
+            .map(|timing| {
+              V::Object(vec![
+                ("phase".to_owned(), string(timing.phase.name())),
+                (
+                  "microseconds".to_owned(),
+                  V::Integer(timing.duration.as_micros() as i128),
+                ),
+                ("bytes".to_owned(), V::Integer(timing.bytes.into())),
+              ])
+            })
+            .collect(),
+        ),
+      ),
+    ])
+  }
+
+  pub fn from_json(value: &json::Value) -> Result<Self, ParseError> {
+    let get_str = |value: &json::Value, key: &str| {
+      (value.get(key).and_then(json::Value::as_str))
+        .map(str::to_owned)
+        .ok_or(ParseError::Malformed)
+    };
+    let get_integer = |value: &json::Value, key: &str| {
+      (value.get(key).and_then(json::Value::as_integer)).ok_or(ParseError::Malformed)
+    };
+    let file = |key: &str| -> Result<File, ParseError> {
+      let value = value.get(key).ok_or(ParseError::Malformed)?;
+      Ok(File {
+        path: get_str(value, "path")?,
+        size: u64::try_from(get_integer(value, "size")?).map_err(|_| ParseError::Malformed)?,
+        crc32: Crc32::new(
+          u32::try_from(get_integer(value, "crc32")?).map_err(|_| ParseError::Malformed)?,
+        ),
+      })
+    };
+    let rom = value.get("rom").ok_or(ParseError::Malformed)?;
+    Ok(Self {
+      format: get_str(value, "format")?,
+      rom: file("rom")?,
+      rom_after: match rom.get("crc32_after") {
+        None => None,
+        Some(_) => Some(Crc32::new(
+          u32::try_from(get_integer(rom, "crc32_after")?).map_err(|_| ParseError::Malformed)?,
+        )),
+      },
+      patch: file("patch")?,
+      output: file("output")?,
+      hack_url: get_str(value, "hack_url")?,
+      hack_version: (get_str(value, "hack_version")?)
+        .parse()
+        .map_err(|_| ParseError::Malformed)?,
+      warnings: (value.get("warnings").and_then(json::Value::as_array))
+        .ok_or(ParseError::Malformed)?
+        .iter()
+        .map(|warning| {
+          warning
+            .as_str()
+            .map(str::to_owned)
+            .ok_or(ParseError::Malformed)
+        })
+        .collect::<Result<_, _>>()?,
+      timings: match value.get("timings") {
+        None => Vec::new(),
+        Some(timings) => (timings.as_array())
+          .ok_or(ParseError::Malformed)?
+          .iter()
+          .map(|value| {
+            timing(
+              &get_str(value, "phase")?,
+              get_integer(value, "microseconds")?,
+              get_integer(value, "bytes")?,
+            )
+          })
+          .collect::<Result<_, _>>()?,
+      },
+    })
+  }
+
+  /// Lists each part of the report on its own row.
+  fn table(&self) -> Vec<Vec<String>> {
+    let row = |key: &'static str, value: String| vec![i18n::text(key).to_owned(), value];
+    let file = |file: &File| {
+      i18n::format(
+        "romhacks::report::file",
+        &[
+          ("path", &file.path),
+          ("size", &file.size),
+          ("crc32", &format!("{:08X}", file.crc32.value())),
+        ],
+      )
+    };
+    let mut rows = vec![
+      row("romhacks::report::format", self.format.clone()),
+      row("romhacks::report::rom", file(&self.rom)),
+    ];
+    rows.extend((self.rom_after).map(|after| {
+      row(
+        "romhacks::report::rom_after",
+        i18n::format(
+          "romhacks::report::crc32",
+          &[("crc32", &format!("{:08X}", after.value()))],
+        ),
+      )
+    }));
+    rows.extend([
+      row("romhacks::report::patch", file(&self.patch)),
+      row("romhacks::report::output", file(&self.output)),
+      row(
+        "romhacks::report::hack",
+        format!("{} {}", self.hack_url, self.hack_version),
+      ),
+    ]);
+    rows.extend(
+      (self.warnings.iter()).map(|warning| row("romhacks::report::warning", warning.clone())),
+    );
+    rows.extend(
+      (self.timings.iter()).map(|timing| row("romhacks::report::timing_row", timing.to_string())),
+    );
+    rows
+  }
+}
+
+fn timing(phase: &str, microseconds: i128, bytes: i128) -> Result<Timing, ParseError> {
+  Ok(Timing {
+    phase: Phase::from_name(phase).ok_or(ParseError::Malformed)?,
+    duration: time::Duration::from_micros(
+      u64::try_from(microseconds).map_err(|_| ParseError::Malformed)?,
+    ),
+    bytes: u64::try_from(bytes).map_err(|_| ParseError::Malformed)?,
+  })
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum ParseError {
+  #[error(transparent)]
+  Kdl(#[from] kdl::KdlError),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Json(#[from] json::ParseError),
+  #[error("{}", i18n::text("romhacks::report::malformed"))]
+  #[diagnostic(code(romhacks::report::malformed))]
+  Malformed,
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Parse(#[from] ParseError),
+}
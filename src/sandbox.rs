@@ -0,0 +1,310 @@
+//! Confining the process before it reads untrusted patches, so that a bug in
+//! a patch parser can't be used to read or overwrite the user's other files,
+//! reach the network or run other programs.
+//!
+//! On Linux, Landlock limits the files that can be opened to those in a
+//! [`Policy`], and a seccomp filter makes creating sockets, running programs
+//! and inspecting other processes fail. Either is skipped with a warning if
+//! the kernel or architecture doesn't support it. Other platforms can't be
+//! sandboxed.
+
+use crate::{i18n, io};
+use std::path;
+
+/// The files and directories the process can still use once it's sandboxed.
+/// Paths that don't exist are ignored.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Policy {
+  /// Files that can be read, or directories whose files can be read.
+  pub read: Vec<path::PathBuf>,
+  /// Directories in which files can be read, created, written, renamed and
+  /// deleted.
+  pub write: Vec<path::PathBuf>,
+}
+
+/// Restricts this process, and any threads it starts afterwards, to
+/// `policy`. This can't be undone.
+pub fn enter(policy: &Policy) -> io::Result<()> {
+  #[cfg(target_os = "linux")]
+  {
+    linux::set_no_new_privs()?;
+    match linux::restrict_paths(policy)? {
+      true => log::debug!("{}", i18n::text("romhacks::sandbox::landlock")),
+      false => log::warn!("{}", i18n::text("romhacks::sandbox::no_landlock")),
+    }
+    match linux::filter_syscalls()? {
+      true => log::debug!("{}", i18n::text("romhacks::sandbox::seccomp")),
+      false => log::warn!("{}", i18n::text("romhacks::sandbox::no_seccomp")),
+    }
+    Ok(())
+  }
+  #[cfg(not(target_os = "linux"))]
+  {
+    let _ = policy;
+    log::warn!("{}", i18n::text("romhacks::sandbox::unsupported"));
+    Ok(())
+  }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+  use super::Policy;
+  use crate::io;
+  use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+  use std::os::unix::ffi::OsStrExt;
+  use std::{ffi, mem, path, ptr};
+
+  // Landlock's filesystem access rights, from linux/landlock.h.
+  const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+  const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+  const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+  const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+  const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+  const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+  const ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+  const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+  const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+  const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+  const ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+  const ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+  const ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+  /// Renaming and linking files across directories, since ABI version 2.
+  const ACCESS_FS_REFER: u64 = 1 << 13;
+  /// Truncating files, since ABI version 3.
+  const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+  /// The rights of the first version of Landlock.
+  const ACCESS_FS_V1: u64 = ACCESS_FS_EXECUTE
+    | ACCESS_FS_WRITE_FILE
+    | ACCESS_FS_READ_FILE
+    | ACCESS_FS_READ_DIR
+    | ACCESS_FS_REMOVE_DIR
+    | ACCESS_FS_REMOVE_FILE
+    | ACCESS_FS_MAKE_CHAR
+    | ACCESS_FS_MAKE_DIR
+    | ACCESS_FS_MAKE_REG
+    | ACCESS_FS_MAKE_SOCK
+    | ACCESS_FS_MAKE_FIFO
+    | ACCESS_FS_MAKE_BLOCK
+    | ACCESS_FS_MAKE_SYM;
+
+  /// The rights that apply to files rather than directories.
+  const ACCESS_FS_FILE: u64 =
+    ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE | ACCESS_FS_TRUNCATE;
+
+  const CREATE_RULESET_VERSION: u32 = 1 << 0;
+  const RULE_PATH_BENEATH: libc::c_int = 1;
+
+  #[repr(C)]
+  struct RulesetAttr {
+    handled_access_fs: u64,
+  }
+
+  #[repr(C, packed)]
+  struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+  }
+
+  /// Keeps this process and its children from gaining privileges, e.g.
+  /// through setuid programs, which Landlock and seccomp filters require.
+  pub fn set_no_new_privs() -> io::Result<()> {
+    // SAFETY: PR_SET_NO_NEW_PRIVS takes no pointers.
+    match unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } {
+      0 => Ok(()),
+      _ => Err(io::Error::last_os_error()),
+    }
+  }
+
+  /// Limits the files this thread can open to those `policy` allows.
+  /// Returns `false` if the kernel doesn't support Landlock.
+  pub fn restrict_paths(policy: &Policy) -> io::Result<bool> {
+    // SAFETY: asking for the ABI version takes a null attribute.
+    let abi = unsafe {
+      libc::syscall(
+        libc::SYS_landlock_create_ruleset,
+        ptr::null::<RulesetAttr>(),
+        0usize,
+        CREATE_RULESET_VERSION,
+      )
+    };
+    if abi < 1 {
+      let err = io::Error::last_os_error();
+      return match err.raw_os_error() {
+        Some(libc::ENOSYS | libc::EOPNOTSUPP) => Ok(false),
+        _ => Err(err),
+      };
+    }
+    let mut handled = ACCESS_FS_V1;
+    if abi >= 2 {
+      handled |= ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+      handled |= ACCESS_FS_TRUNCATE;
+    }
+    let attr = RulesetAttr { handled_access_fs: handled };
+    // SAFETY: `attr` is a ruleset attribute of the size given.
+    let fd = unsafe {
+      libc::syscall(
+        libc::SYS_landlock_create_ruleset,
+        &attr as *const RulesetAttr,
+        mem::size_of::<RulesetAttr>(),
+        0u32,
+      )
+    };
+    if fd < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    // SAFETY: landlock_create_ruleset returned a new file descriptor.
+    let ruleset = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+    for path in &policy.read {
+      allow(&ruleset, path, ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR)?;
+    }
+    for dir in &policy.write {
+      allow(&ruleset, dir, handled & !ACCESS_FS_EXECUTE)?;
+    }
+    // SAFETY: `ruleset` is a Landlock ruleset.
+    match unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0u32) } {
+      0 => Ok(true),
+      _ => Err(io::Error::last_os_error()),
+    }
+  }
+
+  /// Adds a rule to `ruleset` that allows `access` to `path` and, if it's a
+  /// directory, everything in it.
+  fn allow(ruleset: &OwnedFd, path: &path::Path, access: u64) -> io::Result<()> {
+    let c_path = ffi::CString::new(path.as_os_str().as_bytes())
+      .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    // SAFETY: `c_path` is NUL-terminated.
+    let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd < 0 {
+      let err = io::Error::last_os_error();
+      return match err.kind() {
+        io::ErrorKind::NotFound => Ok(()),
+        _ => Err(err),
+      };
+    }
+    // SAFETY: open returned a new file descriptor.
+    let parent = unsafe { OwnedFd::from_raw_fd(fd) };
+    // Rights that only apply to directories can't be given for a file.
+    let access = match path.is_dir() {
+      true => access,
+      false => access & ACCESS_FS_FILE,
+    };
+    let attr = PathBeneathAttr {
+      allowed_access: access,
+      parent_fd: parent.as_raw_fd(),
+    };
+    // SAFETY: `attr` is a path beneath attribute, which is what
+    // RULE_PATH_BENEATH expects.
+    let added = unsafe {
+      libc::syscall(
+        libc::SYS_landlock_add_rule,
+        ruleset.as_raw_fd(),
+        RULE_PATH_BENEATH,
+        &attr as *const PathBeneathAttr,
+        0u32,
+      )
+    };
+    match added {
+      0 => Ok(()),
+      _ => Err(io::Error::last_os_error()),
+    }
+  }
+
+  /// The value of `seccomp_data.arch` for this architecture.
+  #[cfg(target_arch = "x86_64")]
+  const AUDIT_ARCH: Option<u32> = Some(0xC000_003E);
+  #[cfg(target_arch = "aarch64")]
+  const AUDIT_ARCH: Option<u32> = Some(0xC000_00B7);
+  #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+  const AUDIT_ARCH: Option<u32> = None;
+
+  /// System calls that fail with EPERM: those that open sockets, run
+  /// programs or read and write other processes' memory.
+  #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+  const DENIED: &[libc::c_long] = &[
+    libc::SYS_socket,
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+  ];
+  #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+  const DENIED: &[libc::c_long] = &[];
+
+  /// System call numbers with this bit set are for the x32 ABI.
+  const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+  /// Installs a seccomp filter that makes the [`DENIED`] system calls fail.
+  /// Returns `false` if the architecture isn't supported.
+  pub fn filter_syscalls() -> io::Result<bool> {
+    let Some(arch) = AUDIT_ARCH else {
+      return Ok(false);
+    };
+    let statement = |code: u32, k: u32| libc::sock_filter { code: code as u16, jt: 0, jf: 0, k };
+    let jump = |code: u32, k: u32, jt: usize, jf: usize| libc::sock_filter {
+      code: code as u16,
+      jt: jt as u8,
+      jf: jf as u8,
+      k,
+    };
+    let arch_offset = mem::offset_of!(libc::seccomp_data, arch) as u32;
+    let nr_offset = mem::offset_of!(libc::seccomp_data, nr) as u32;
+    // The instructions are followed by one that allows the call and one
+    // that denies it, so jumps count down to those.
+    let checks = DENIED.len() + 1;
+    let mut filter = vec![
+      statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, arch_offset),
+      // System calls made for another architecture are denied.
+      jump(
+        libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+        arch,
+        0,
+        checks + 2,
+      ),
+      statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, nr_offset),
+      jump(
+        libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+        X32_SYSCALL_BIT,
+        checks,
+        0,
+      ),
+    ];
+    for (index, &nr) in DENIED.iter().enumerate() {
+      let to_deny = checks - index - 1;
+      filter.push(jump(
+        libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+        nr as u32,
+        to_deny,
+        0,
+      ));
+    }
+    filter.push(statement(
+      libc::BPF_RET | libc::BPF_K,
+      libc::SECCOMP_RET_ALLOW,
+    ));
+    filter.push(statement(
+      libc::BPF_RET | libc::BPF_K,
+      libc::SECCOMP_RET_ERRNO | (libc::EPERM as u32 & libc::SECCOMP_RET_DATA),
+    ));
+    let program = libc::sock_fprog {
+      len: filter.len() as u16,
+      filter: filter.as_mut_ptr(),
+    };
+    // SAFETY: `program` points to `filter`, which outlives the call, and
+    // the kernel copies it.
+    let installed = unsafe {
+      libc::prctl(
+        libc::PR_SET_SECCOMP,
+        libc::SECCOMP_MODE_FILTER,
+        &program as *const libc::sock_fprog,
+      )
+    };
+    match installed {
+      0 => Ok(true),
+      _ => Err(io::Error::last_os_error()),
+    }
+  }
+}
@@ -0,0 +1,290 @@
+//! Verifying [minisign] signatures of patches, so that hack distributors can
+//! sign their releases.
+//!
+//! [minisign]: https://jedisct1.github.io/minisign/
+
+use crate::error::prelude::*;
+use crate::io::prelude::*;
+use crate::{fs, i18n, io};
+use base64::Engine;
+use blake2::{Blake2b512, Digest};
+use ed25519_dalek::Verifier;
+use std::{ffi, fmt, path};
+
+/// The extension appended to a patch's name to get the name of its signature.
+pub const EXTENSION: &str = "sig";
+
+const UNTRUSTED_COMMENT: &str = "untrusted comment: ";
+const TRUSTED_COMMENT: &str = "trusted comment: ";
+
+/// Signs the file's contents.
+const LEGACY_ALGORITHM: [u8; 2] = *b"Ed";
+/// Signs the BLAKE2b-512 hash of the file.
+const PREHASHED_ALGORITHM: [u8; 2] = *b"ED";
+
+/// Returns the default path of the signature for the patch at `path`.
+pub fn sidecar_path(path: &path::Path) -> path::PathBuf {
+  let mut buf = ffi::OsString::from(path);
+  buf.push(".");
+  buf.push(EXTENSION);
+  buf.into()
+}
+
+/// The number minisign uses to tell keys apart, which signatures also record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct KeyId([u8; 8]);
+
+impl fmt::Display for KeyId {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    // minisign prints key IDs as little-endian numbers.
+    write!(f, "{:016X}", u64::from_le_bytes(self.0))
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct PublicKey {
+  id: KeyId,
+  key: ed25519_dalek::VerifyingKey,
+}
+
+impl PublicKey {
+  /// Reads a public key file written by `minisign -G`.
+  pub fn read(path: &path::Path) -> Result<Self, Error> {
+    let text = fs::read_to_string(path)?;
+    let malformed = || Error::MalformedKey { path: path.to_owned() };
+    let line = (text.lines())
+      .map(str::trim)
+      .find(|line| !line.is_empty() && !line.starts_with(UNTRUSTED_COMMENT))
+      .ok_or_else(malformed)?;
+    let bytes: [u8; 42] = decode(line).ok_or_else(malformed)?;
+    let (algorithm, rest) = bytes.split_at(2);
+    let (id, key) = rest.split_at(8);
+    if algorithm != LEGACY_ALGORITHM {
+      return Err(malformed());
+    }
+    Ok(Self {
+      id: KeyId(id.try_into().unwrap()),
+      key: ed25519_dalek::VerifyingKey::from_bytes(key.try_into().unwrap())
+        .map_err(|_| malformed())?,
+    })
+  }
+}
+
+/// A signature file written by `minisign -S`.
+#[derive(Clone, Debug)]
+pub struct Signature {
+  algorithm: [u8; 2],
+  key_id: KeyId,
+  signature: ed25519_dalek::Signature,
+  trusted_comment: String,
+  global_signature: ed25519_dalek::Signature,
+}
+
+impl std::str::FromStr for Signature {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut lines = s.lines().map(|line| line.trim_end_matches('\r'));
+    lines
+      .next()
+      .filter(|line| line.starts_with(UNTRUSTED_COMMENT))
+      .ok_or(())?;
+    let bytes: [u8; 74] = lines.next().and_then(decode).ok_or(())?;
+    let trusted_comment = (lines.next())
+      .and_then(|line| line.strip_prefix(TRUSTED_COMMENT))
+      .ok_or(())?;
+    let global_signature: [u8; 64] = lines.next().and_then(decode).ok_or(())?;
+    let (algorithm, rest) = bytes.split_at(2);
+    let (key_id, signature) = rest.split_at(8);
+    Ok(Self {
+      algorithm: algorithm.try_into().unwrap(),
+      key_id: KeyId(key_id.try_into().unwrap()),
+      signature: ed25519_dalek::Signature::from_bytes(signature.try_into().unwrap()),
+      trusted_comment: trusted_comment.to_owned(),
+      global_signature: ed25519_dalek::Signature::from_bytes(&global_signature),
+    })
+  }
+}
+
+/// A signature that was made by a trusted key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Verified {
+  pub key_id: KeyId,
+  /// The comment that was signed along with the patch, which usually
+  /// records when it was signed and the name of the file.
+  pub trusted_comment: String,
+}
+
+/// Checks that the signature next to the patch at `patch_path` was made with
+/// one of the trusted keys.
+pub fn verify(
+  trusted_keys: &[PublicKey],
+  patch_path: &path::Path,
+  patch: &mut (impl Read + Seek),
+) -> Result<Verified, Error> {
+  let signature_path = sidecar_path(patch_path);
+  let signature: Signature = match fs::read_to_string(&signature_path) {
+    Ok(text) => text.parse(),
+    Err(err) if err.kind() == io::ErrorKind::NotFound => {
+      return Err(Error::Missing { path: signature_path });
+    }
+    Err(err) => return Err(err.into()),
+  }
+  .map_err(|_| Error::MalformedSignature { path: signature_path })?;
+
+  let key = (trusted_keys.iter())
+    .find(|key| key.id == signature.key_id)
+    .ok_or(Error::UntrustedKey { key_id: signature.key_id })?;
+
+  patch.seek(io::SeekFrom::Start(0))?;
+  let message: Vec<u8> = match signature.algorithm {
+    PREHASHED_ALGORITHM => {
+      let mut hasher = Blake2b512::new();
+      io::copy(patch, &mut hasher)?;
+      hasher.finalize().to_vec()
+    }
+    LEGACY_ALGORITHM => {
+      let mut contents = Vec::new();
+      patch.read_to_end(&mut contents)?;
+      contents
+    }
+    _ => return Err(Error::UnsupportedAlgorithm),
+  };
+  patch.seek(io::SeekFrom::Start(0))?;
+
+  let global_message = [
+    &signature.signature.to_bytes()[..],
+    signature.trusted_comment.as_bytes(),
+  ]
+  .concat();
+  (key.key.verify(&message, &signature.signature))
+    .and_then(|()| key.key.verify(&global_message, &signature.global_signature))
+    .map_err(|_| Error::Invalid { key_id: key.id })?;
+
+  Ok(Verified {
+    key_id: key.id,
+    trusted_comment: signature.trusted_comment,
+  })
+}
+
+/// Decodes a base64 line into exactly `N` bytes.
+fn decode<const N: usize>(line: &str) -> Option<[u8; N]> {
+  let bytes = base64::engine::general_purpose::STANDARD
+    .decode(line)
+    .ok()?;
+  bytes.try_into().ok()
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error("{}", i18n::format("romhacks::signature::malformed_key", &[("path", &path.display())]))]
+  #[diagnostic(code(romhacks::signature::malformed_key))]
+  MalformedKey { path: path::PathBuf },
+  #[error("{}", i18n::format("romhacks::signature::missing", &[("path", &path.display())]))]
+  #[diagnostic(code(romhacks::signature::missing))]
+  Missing { path: path::PathBuf },
+  #[error("{}", i18n::format("romhacks::signature::malformed", &[("path", &path.display())]))]
+  #[diagnostic(code(romhacks::signature::malformed))]
+  MalformedSignature { path: path::PathBuf },
+  #[error("{}", i18n::text("romhacks::signature::unsupported_algorithm"))]
+  #[diagnostic(code(romhacks::signature::unsupported_algorithm))]
+  UnsupportedAlgorithm,
+  #[error("{}", i18n::format("romhacks::signature::untrusted_key", &[("key_id", key_id)]))]
+  #[diagnostic(code(romhacks::signature::untrusted_key))]
+  UntrustedKey { key_id: KeyId },
+  #[error("{}", i18n::format("romhacks::signature::invalid", &[("key_id", key_id)]))]
+  #[diagnostic(code(romhacks::signature::invalid))]
+  Invalid { key_id: KeyId },
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::{env, process};
+
+  // Fixtures made with a test key, in the layout minisign writes. The
+  // trusted comments are the ones `minisign -S` writes by default.
+  const PUBLIC_KEY: &str = "untrusted comment: minisign public key 88796A5B4C3D2E1F
+RWQfLj1MW2p5iDy1WOTna31RzVkyFuo6EceWl+ZJaM9a4UXRLAE9Zr9a
+";
+  const PATCH: &[u8] = b"PATCH\x00\x00\x10\x00\x08romhacksEOF";
+  /// Signs the patch itself, as `minisign -S -l` does.
+  const LEGACY_SIGNATURE: &str = "untrusted comment: signature from minisign secret key
+RWQfLj1MW2p5iJySW+zHfruMoZpjC8zMzjAsFjpcVu/iM4OTWKxMokPz4iuEpaJ7PGK0neM5tVmYTJPIefOzmyrLGG//nbaeugo=
+trusted comment: timestamp:1767225600\tfile:hack.ips
+r1UlYydkz1gaZXEIihz8RiFeCTsbaoKjRyQgzj/Sx+UKRG8VkCAOTkljGDCJlKN2MWMC2uBxa3SU7VppFXVnBg==
+";
+  /// Signs the patch's BLAKE2b-512 hash, as `minisign -S` does.
+  const PREHASHED_SIGNATURE: &str = "untrusted comment: signature from minisign secret key
+RUQfLj1MW2p5iLg7FsYaPWPDD7jyGacF4nHXC2GgnQf+lww9mcp0MxCkCVEGl/VbO3BLtfhisD+a02AaY80ytxv74euVcFC6dAc=
+trusted comment: timestamp:1767225600\tfile:hack.ips\thashed
+ixGdh2wuEl386uSweoN8FIFTPSLy+H54sc+dTub5yp/npc3K1XFjoD4SzHUyqQZBvI4z8F4g+D8KtKAYWHjWCQ==
+";
+
+  /// Verifies `patch` against `signature`, trusting the test key, in a
+  /// directory of its own named after `name`.
+  fn verify_with(name: &str, signature: &str, patch: &[u8]) -> Result<Verified, Error> {
+    let dir = env::temp_dir().join(format!("romhacks-signature-{name}-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let key_path = dir.join("test.pub");
+    let patch_path = dir.join("hack.ips");
+    fs::write(&key_path, PUBLIC_KEY).unwrap();
+    fs::write(sidecar_path(&patch_path), signature).unwrap();
+    let key = PublicKey::read(&key_path).unwrap();
+    let result = verify(&[key], &patch_path, &mut io::Cursor::new(patch));
+    fs::remove_dir_all(&dir).unwrap();
+    result
+  }
+
+  #[test]
+  fn verifies_both_algorithms() {
+    for (name, signature, comment) in [
+      (
+        "legacy",
+        LEGACY_SIGNATURE,
+        "timestamp:1767225600\tfile:hack.ips",
+      ),
+      (
+        "prehashed",
+        PREHASHED_SIGNATURE,
+        "timestamp:1767225600\tfile:hack.ips\thashed",
+      ),
+    ] {
+      let verified = verify_with(name, signature, PATCH).unwrap();
+      assert_eq!(verified.key_id.to_string(), "88796A5B4C3D2E1F");
+      assert_eq!(verified.trusted_comment, comment);
+    }
+  }
+
+  #[test]
+  fn refuses_tampered_trusted_comment() {
+    for (name, signature) in [
+      ("comment-legacy", LEGACY_SIGNATURE),
+      ("comment-prehashed", PREHASHED_SIGNATURE),
+    ] {
+      let signature = signature.replace("hack.ips", "other.ips");
+      assert!(matches!(
+        verify_with(name, &signature, PATCH),
+        Err(Error::Invalid { .. })
+      ));
+    }
+  }
+
+  #[test]
+  fn refuses_tampered_patch() {
+    let mut patch = PATCH.to_vec();
+    patch[10] ^= 1;
+    for (name, signature) in [
+      ("patch-legacy", LEGACY_SIGNATURE),
+      ("patch-prehashed", PREHASHED_SIGNATURE),
+    ] {
+      assert!(matches!(
+        verify_with(name, signature, &patch),
+        Err(Error::Invalid { .. })
+      ));
+    }
+  }
+}
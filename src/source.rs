@@ -0,0 +1,127 @@
+//! Keeping a ROM in memory to apply many patches to it.
+//!
+//! Opening and hashing a multi-gigabyte ROM for every patch tried against it
+//! would read it from disk again each time. A [`SourceCache`] reads and
+//! hashes it once, and can be shared between threads behind an [`Arc`], each
+//! of which reads it through its own [`SourceReader`].
+
+use crate::cache::DigestCache;
+use crate::crc::Crc32;
+use crate::io::prelude::*;
+use crate::{fs, io};
+use std::path;
+use std::sync::Arc;
+
+/// The contents and checksum of a ROM.
+#[derive(Debug)]
+pub struct SourceCache {
+  data: Box<[u8]>,
+  digest: Crc32,
+}
+
+impl SourceCache {
+  /// Reads the file at `path` into memory and hashes it, unless `digest_cache`
+  /// already has its checksum.
+  pub fn load(
+    digest_cache: Option<&mut DigestCache>,
+    path: impl Into<path::PathBuf>,
+  ) -> io::Result<Arc<Self>> {
+    let path = path.into();
+    let data = fs::read(&path)?.into_boxed_slice();
+    let cached = (digest_cache.as_ref())
+      .map(|digest_cache| digest_cache.get(&path))
+      .transpose()?
+      .flatten();
+    let digest = match cached {
+      Some(digest) => digest,
+      None => {
+        let digest = Crc32::read_and_hash(&mut &data[..])?;
+        if let Some(digest_cache) = digest_cache {
+          digest_cache.insert(&path, digest)?;
+        }
+        digest
+      }
+    };
+    Ok(Arc::new(Self { data, digest }))
+  }
+
+  pub fn len(&self) -> u64 {
+    self.data.len() as u64
+  }
+
+  pub fn digest(&self) -> Crc32 {
+    self.digest
+  }
+
+  pub fn bytes(&self) -> &[u8] {
+    &self.data
+  }
+
+  /// Returns a reader positioned at the start of the ROM.
+  pub fn reader(self: &Arc<Self>) -> SourceReader {
+    SourceReader { cache: Arc::clone(self), position: 0 }
+  }
+}
+
+/// A reader over a [`SourceCache`] with its own position, so that several
+/// threads can read the same ROM at once.
+#[derive(Clone, Debug)]
+pub struct SourceReader {
+  cache: Arc<SourceCache>,
+  position: u64,
+}
+
+impl SourceReader {
+  /// The bytes from the reader's position to the end of the ROM.
+  fn rest(&self) -> &[u8] {
+    let data = self.cache.bytes();
+    &data[data.len().min(self.position as usize)..]
+  }
+}
+
+impl Read for SourceReader {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let read = self.rest().read(buf)?;
+    self.position += read as u64;
+    Ok(read)
+  }
+}
+
+impl BufRead for SourceReader {
+  fn fill_buf(&mut self) -> io::Result<&[u8]> {
+    Ok(self.rest())
+  }
+
+  fn consume(&mut self, amt: usize) {
+    self.position += amt as u64;
+  }
+}
+
+impl Seek for SourceReader {
+  fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+    let (base, offset) = match pos {
+      io::SeekFrom::Start(offset) => {
+        self.position = offset;
+        return Ok(offset);
+      }
+      io::SeekFrom::End(offset) => (self.cache.len(), offset),
+      io::SeekFrom::Current(offset) => (self.position, offset),
+    };
+    match base.checked_add_signed(offset) {
+      Some(position) => {
+        self.position = position;
+        Ok(position)
+      }
+      None => Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "invalid seek to a negative or overflowing position",
+      )),
+    }
+  }
+}
+
+impl KnownLen for SourceReader {
+  fn known_len(&self) -> io::Result<u64> {
+    Ok(self.cache.len())
+  }
+}
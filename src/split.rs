@@ -0,0 +1,186 @@
+use crate::error::prelude::*;
+use crate::patch::ops::{self, DecodedPatch, Op};
+use crate::{filename, fs, i18n, io, kdl, patch};
+use std::ops::Range;
+use std::path;
+
+// nodes
+const REGION: &str = "region";
+
+// props
+const START: &str = "start";
+const END: &str = "end";
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct Args {
+  /// The patch to split.
+  pub patch: path::PathBuf,
+  /// Split the patch into regions of this many bytes. Hexadecimal sizes
+  /// need a "0x" prefix.
+  #[arg(long, value_parser = parse_size, required_unless_present = "regions")]
+  pub by: Option<u64>,
+  /// A KDL file of regions to split the patch into, with one
+  /// `region "name" start=0x0 end=0x8000` node per region.
+  #[arg(long, conflicts_with = "by")]
+  pub regions: Option<path::PathBuf>,
+  /// The directory to write the new patches to. Defaults to the directory of
+  /// the patch being split.
+  #[arg(short, long)]
+  pub output: Option<path::PathBuf>,
+}
+
+impl Args {
+  pub fn call(self) -> Result<(), Error> {
+    let mut patch = patch::compression::open(&self.patch)?;
+    let kind = patch::Kind::detect(&mut patch)?.ok_or(patch::UnknownPatchKindError(()))?;
+    let decoded = DecodedPatch::decode(kind, &mut patch)?;
+    let regions = match (&self.regions, self.by) {
+      (Some(regions_path), _) => parse_regions(&fs::read_to_string(regions_path)?)?,
+      (None, Some(size)) => fixed_regions(decoded.ops(), size),
+      (None, None) => unreachable!("clap requires --by or --regions"),
+    };
+
+    for op in decoded.ops().iter().filter(|op| is_change(op)) {
+      if regions
+        .iter()
+        .all(|region| touches(op, &region.range).is_none())
+      {
+        log::warn!("{}", i18n::text("romhacks::split::outside_regions"));
+        break;
+      }
+    }
+
+    let dir = match &self.output {
+      Some(dir) => dir.clone(),
+      None => (self.patch.parent())
+        .map(path::Path::to_path_buf)
+        .unwrap_or_default(),
+    };
+    let stem = self.patch.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = self.patch.extension().unwrap_or_default().to_string_lossy();
+    for region in &regions {
+      let part: Vec<Op<'_>> = (decoded.ops().iter())
+        .filter_map(|op| touches(op, &region.range))
+        .collect();
+      if !part.iter().any(is_change) {
+        continue;
+      }
+      let mut encoded = Vec::new();
+      ops::encode(kind, &part, &mut encoded)?;
+      fs::write(
+        dir.join(&*filename::safe_file_name(&format!(
+          "{stem}.{}.{extension}",
+          region.name
+        ))),
+        encoded,
+      )?;
+    }
+    Ok(())
+  }
+}
+
+/// A named range of offsets in the patched file.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Region {
+  name: String,
+  range: Range<u64>,
+}
+
+/// Returns the part of `op` that belongs in a patch confined to `range`.
+///
+/// Expected contents are preconditions of the whole patch, so they're kept in
+/// every region, while a resize belongs to the region containing the new end
+/// of the file.
+fn touches<'a>(op: &'a Op<'_>, range: &Range<u64>) -> Option<Op<'a>> {
+  match op {
+    Op::Expect { offset, data } => Some(Op::Expect { offset: *offset, data: data.as_ref().into() }),
+    Op::Resize { len } => range
+      .contains(&len.saturating_sub(1))
+      .then_some(Op::Resize { len: *len }),
+    _ => op.clip(range),
+  }
+}
+
+/// Whether `op` modifies the file, as opposed to checking it.
+fn is_change(op: &Op<'_>) -> bool {
+  !matches!(op, Op::Expect { .. })
+}
+
+/// Divides the file modified by `ops` into consecutive regions of `size` bytes.
+fn fixed_regions(ops: &[Op<'_>], size: u64) -> Vec<Region> {
+  let end = (ops.iter())
+    .map(|op| match op {
+      Op::Expect { .. } => 0,
+      Op::Write { offset, data } | Op::Xor { offset, data } => offset + data.len() as u64,
+      Op::Fill { offset, len, .. } => offset + len,
+      Op::Resize { len } => *len,
+    })
+    .max()
+    .unwrap_or(0);
+  (0..end.div_ceil(size))
+    .map(|i| i * size)
+    .map(|start| Region {
+      name: format!("{start:06X}"),
+      range: start..start.saturating_add(size),
+    })
+    .collect()
+}
+
+fn parse_regions(source: &str) -> Result<Vec<Region>, ParseError> {
+  let doc: kdl::KdlDocument = source.parse()?;
+  (doc.nodes().iter())
+    .filter(|node| node.name().value() == REGION)
+    .map(|node| {
+      let name = (node.get(0).and_then(|value| value.as_string()))
+        .ok_or(ParseError::Malformed)?
+        .to_owned();
+      let get_u64 = |key: &str| -> Result<u64, ParseError> {
+        (node.get(key).and_then(|value| value.as_integer()))
+          .and_then(|value| u64::try_from(value).ok())
+          .ok_or(ParseError::Malformed)
+      };
+      let range = get_u64(START)?..get_u64(END)?;
+      if range.is_empty() || name.contains(path::is_separator) {
+        return Err(ParseError::Malformed);
+      }
+      Ok(Region { name, range })
+    })
+    .collect()
+}
+
+/// Parses a positive decimal or "0x"-prefixed hexadecimal size.
+fn parse_size(arg: &str) -> Result<u64, String> {
+  let size = match arg.strip_prefix("0x").or_else(|| arg.strip_prefix("0X")) {
+    Some(hex) => u64::from_str_radix(hex, 16),
+    None => arg.parse(),
+  };
+  match size {
+    Ok(0) | Err(_) => Err(i18n::text("romhacks::split::bad_size").to_owned()),
+    Ok(size) => Ok(size),
+  }
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum ParseError {
+  #[error(transparent)]
+  Kdl(#[from] kdl::KdlError),
+  #[error("{}", i18n::text("romhacks::split::malformed_regions"))]
+  #[diagnostic(code(romhacks::split::malformed_regions))]
+  Malformed,
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error(transparent)]
+  UnknownPatchKind(#[from] patch::UnknownPatchKindError),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Patch(#[from] patch::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Regions(#[from] ParseError),
+}
@@ -0,0 +1,152 @@
+//! Naming temporary files and finding the ones left behind by runs that
+//! crashed or were killed.
+//!
+//! A temporary file is named `.<name>.<pid>.romhacks.tmp` after the file it
+//! will become and the process that created it, so that it's hidden on Unix
+//! and it's plain where a leftover one came from. If a file already has that
+//! name, a number is added to the process ID: `.<name>.<pid>-1.romhacks.tmp`.
+//! Only files with the `.romhacks.tmp` extension are ever taken for
+//! leftovers, since directories such as the system's temporary directory
+//! hold other programs' files too.
+
+use crate::fs;
+use std::{io, path, process, time};
+
+/// The extension of temporary files, which sets them apart from other
+/// programs' temporary files.
+const EXTENSION: &str = ".romhacks.tmp";
+
+/// Names are shortened to this many bytes, so that the temporary file's name
+/// stays within the 255 bytes most filesystems allow.
+const MAX_NAME_LEN: usize = 200;
+
+/// How many numbered names are tried after the first one before giving up.
+const MAX_ATTEMPTS: u32 = 1000;
+
+/// The name used for temporary files that won't become a named file.
+pub const DEFAULT_NAME: &str = "romhacks";
+
+/// How long ago a temporary file must have last been written to before it's
+/// assumed to be left behind, rather than in use by a run that's still going.
+pub const STALE_AGE: time::Duration = time::Duration::from_secs(60 * 60);
+
+/// Creates a temporary file in `dir` for the file named `name`, open for
+/// reading and writing.
+pub fn create(dir: &path::Path, name: &str) -> io::Result<fs::File> {
+  create_with(dir, name, |path| {
+    fs::OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create_new(true)
+      .open(path)
+  })
+}
+
+/// Calls `create` with the path of a temporary file in `dir` for the file
+/// named `name`, and again with the next numbered name for as long as it
+/// fails because the file already exists.
+pub fn create_with<T>(
+  dir: &path::Path,
+  name: &str,
+  mut create: impl FnMut(&path::Path) -> io::Result<T>,
+) -> io::Result<T> {
+  let mut attempt = 0;
+  loop {
+    match create(&dir.join(file_name(name, attempt))) {
+      Err(err) if err.kind() == io::ErrorKind::AlreadyExists && attempt < MAX_ATTEMPTS => {
+        attempt += 1;
+      }
+      result => return result,
+    }
+  }
+}
+
+/// The name of the temporary file for `name`, numbered after the first
+/// attempt.
+fn file_name(name: &str, attempt: u32) -> String {
+  let mut end = name.len().min(MAX_NAME_LEN);
+  while !name.is_char_boundary(end) {
+    end -= 1;
+  }
+  let name = &name[..end];
+  let pid = process::id();
+  match attempt {
+    0 => format!(".{name}.{pid}{EXTENSION}"),
+    _ => format!(".{name}.{pid}-{attempt}{EXTENSION}"),
+  }
+}
+
+/// Whether `file_name` is the name of a temporary file, and if so, the ID of
+/// the process that created it.
+fn parse(file_name: &str) -> Option<u32> {
+  let stem = file_name.strip_suffix(EXTENSION)?.strip_prefix('.')?;
+  let (name, tag) = stem.rsplit_once('.')?;
+  let pid = match tag.split_once('-') {
+    Some((pid, attempt)) => attempt.parse::<u32>().ok().and(pid.parse().ok())?,
+    None => tag.parse().ok()?,
+  };
+  (!name.is_empty()).then_some(pid)
+}
+
+/// A temporary file that was left behind.
+#[derive(Clone, Debug)]
+pub struct Leftover {
+  pub path: path::PathBuf,
+  pub len: u64,
+  /// How long ago it was last written to.
+  pub age: time::Duration,
+}
+
+/// Lists the temporary files in `dir` that were last written to at least
+/// `min_age` ago, sorted by path. The files of the current process are never
+/// listed, however old they are.
+pub fn find_leftovers(dir: &path::Path, min_age: time::Duration) -> io::Result<Vec<Leftover>> {
+  let now = time::SystemTime::now();
+  let mut leftovers = Vec::new();
+  for entry in fs::read_dir(dir)? {
+    let entry = entry?;
+    let file_name = entry.file_name();
+    let Some(pid) = file_name.to_str().and_then(parse) else {
+      continue;
+    };
+    if pid == process::id() {
+      continue;
+    }
+    let metadata = entry.metadata()?;
+    if !metadata.is_file() {
+      continue;
+    }
+    let age = now.duration_since(metadata.modified()?).unwrap_or_default();
+    if age >= min_age {
+      leftovers.push(Leftover { path: entry.path(), len: metadata.len(), age });
+    }
+  }
+  leftovers.sort_by(|a, b| a.path.cmp(&b.path));
+  Ok(leftovers)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_own_names() {
+    assert_eq!(parse(&file_name("game.sfc", 0)), Some(process::id()));
+    assert_eq!(parse(&file_name("game.sfc", 3)), Some(process::id()));
+    assert_eq!(parse(".game.sfc.1234.romhacks.tmp"), Some(1234));
+  }
+
+  #[test]
+  fn ignores_other_programs_files() {
+    for name in [
+      ".game.sfc.1234.tmp",
+      "01ARZ3NDEKTSV4RRFFQ69G5FAV.tmp",
+      ".1234.romhacks.tmp",
+      "game.sfc.1234.romhacks.tmp",
+      ".game.sfc.romhacks.tmp",
+      ".game.sfc.12a4.romhacks.tmp",
+    ] {
+      assert_eq!(parse(name), None, "{name}");
+    }
+  }
+}
@@ -0,0 +1,138 @@
+//! Detecting and padding trimmed Nintendo DS, GameCube and Wii dumps.
+//!
+//! Trimming removes the unused space at the end of a dump. Patches are
+//! usually made for untrimmed dumps, so they may not apply to trimmed ones,
+//! or may fail their checksums.
+
+use crate::console::{Console, Rom};
+use crate::io::prelude::*;
+use crate::{i18n, io};
+use std::fmt;
+
+/// A dump that's smaller than the cartridge or disc it came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Trimmed {
+  pub console: Console,
+  pub len: u64,
+  /// The size of the untrimmed dump.
+  pub full_len: u64,
+  /// The byte that unused space is filled with.
+  pub filler: u8,
+}
+
+impl Trimmed {
+  /// Returns how the ROM was trimmed, if it was.
+  pub fn detect(rom: &Rom) -> Option<Self> {
+    let filler = rom.policy().trim_filler?;
+    let full_len = rom.full_len?;
+    (rom.len < full_len).then_some(Self {
+      console: rom.console,
+      len: rom.len,
+      full_len,
+      filler,
+    })
+  }
+
+  /// Returns the size of the file if it were trimmed again: its end, less
+  /// any trailing filler, but no smaller than the original dump.
+  pub fn trimmed_len(&self, file: &mut (impl Read + Seek)) -> io::Result<u64> {
+    const CHUNK_LEN: u64 = 64 * 1024;
+    let mut end = file.seek(io::SeekFrom::End(0))?;
+    let mut chunk = vec![0u8; CHUNK_LEN as usize];
+    while end > self.len {
+      let start = end.saturating_sub(CHUNK_LEN).max(self.len);
+      let chunk = &mut chunk[..(end - start) as usize];
+      file.seek(io::SeekFrom::Start(start))?;
+      file.read_exact(chunk)?;
+      if let Some(last) = chunk.iter().rposition(|byte| *byte != self.filler) {
+        return Ok(start + last as u64 + 1);
+      }
+      end = start;
+    }
+    Ok(self.len)
+  }
+}
+
+impl fmt::Display for Trimmed {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&i18n::format(
+      "romhacks::trim::trimmed",
+      &[
+        ("console", &self.console),
+        ("len", &self.len),
+        ("full_len", &self.full_len),
+      ],
+    ))
+  }
+}
+
+/// Reads a file followed by filler bytes up to a given size.
+#[derive(Debug)]
+pub struct Padded<R> {
+  inner: R,
+  inner_len: u64,
+  /// The size of the padded file, or `None` if it isn't padded.
+  len: Option<u64>,
+  filler: u8,
+  pos: u64,
+}
+
+impl<R: Read + Seek> Padded<R> {
+  /// Pads the dump to its untrimmed size.
+  pub fn new(mut inner: R, trimmed: &Trimmed) -> io::Result<Self> {
+    let inner_len = inner.seek(io::SeekFrom::End(0))?;
+    inner.seek(io::SeekFrom::Start(0))?;
+    Ok(Self {
+      inner,
+      inner_len,
+      len: Some(trimmed.full_len),
+      filler: trimmed.filler,
+      pos: 0,
+    })
+  }
+
+  /// Reads `inner` as it is.
+  pub fn unchanged(inner: R) -> Self {
+    Self { inner, inner_len: 0, len: None, filler: 0, pos: 0 }
+  }
+}
+
+impl<R: Read + Seek> Read for Padded<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let Some(len) = self.len else {
+      return self.inner.read(buf);
+    };
+    if self.pos < self.inner_len {
+      let n = self.inner.read(buf)?;
+      self.pos += n as u64;
+      return Ok(n);
+    }
+    let n = buf.len().min(len.saturating_sub(self.pos) as usize);
+    buf[..n].fill(self.filler);
+    self.pos += n as u64;
+    Ok(n)
+  }
+}
+
+impl<R: Read + Seek> Seek for Padded<R> {
+  fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+    let Some(len) = self.len else {
+      return self.inner.seek(pos);
+    };
+    let new_pos = match pos {
+      io::SeekFrom::Start(offset) => Some(offset),
+      io::SeekFrom::End(offset) => len.checked_add_signed(offset),
+      io::SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+    };
+    self.pos = new_pos.ok_or_else(|| {
+      io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "invalid seek to a negative position",
+      )
+    })?;
+    self
+      .inner
+      .seek(io::SeekFrom::Start(self.pos.min(self.inner_len)))?;
+    Ok(self.pos)
+  }
+}
@@ -0,0 +1,252 @@
+//! Extracting the patches from a hack's release archive, such as those
+//! downloaded from romhacking.net, leaving out files that aren't needed to
+//! apply them.
+
+use crate::crc::Crc32;
+use crate::error::prelude::*;
+use crate::io::prelude::*;
+use crate::{filename, fs, i18n, identify, io, kdl, mem, patch, zip};
+use std::collections::HashSet;
+use std::path;
+
+/// The name of the recipe written with `--recipe`.
+pub const RECIPE_FILE_NAME: &str = "pack.kdl";
+
+const RECIPE_VERSION: &str = "1.0";
+
+// nodes
+const ROMHACKS_PACK: &str = "romhacks-pack";
+const PATCH: &str = "patch";
+const README: &str = "readme";
+
+// props
+const VERSION: &str = "version";
+const ARCHIVE: &str = "archive";
+const FORMAT: &str = "format";
+const SOURCE_SIZE: &str = "source-size";
+const SOURCE_CRC_32: &str = "source-crc32";
+const TARGET_SIZE: &str = "target-size";
+const TARGET_CRC_32: &str = "target-crc32";
+
+/// Extensions of documentation that's kept with the patches.
+const README_EXTENSIONS: &[&str] = &["txt", "nfo", "diz", "md", "htm", "html", "pdf"];
+
+/// Extensions of screenshots and other images, which are only kept with
+/// `--keep-images`.
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "bmp", "webp"];
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct Args {
+  /// The ZIP archive the hack was released in.
+  pub archive: path::PathBuf,
+  /// The directory to extract the files to. It's created if it doesn't
+  /// exist.
+  #[arg(short, long, default_value = ".")]
+  pub dest: path::PathBuf,
+  /// Also extract screenshots and other images.
+  #[arg(long)]
+  pub keep_images: bool,
+  /// Also write a recipe named "pack.kdl" that lists the extracted patches
+  /// with their formats and the checksums of the ROMs they expect.
+  #[arg(long)]
+  pub recipe: bool,
+  /// Overwrite files in the destination directory that have the same name
+  /// as an extracted file, rather than stop.
+  #[arg(long)]
+  pub force: bool,
+}
+
+impl Args {
+  pub fn call(self) -> Result<(), Error> {
+    let mut archive = zip::Archive::new(fs::File::open(&self.archive)?)?;
+    fs::create_dir_all(&self.dest)?;
+    let mut taken: HashSet<String> = HashSet::new();
+    let mut patches: Vec<Extracted> = Vec::new();
+    let mut readmes: Vec<String> = Vec::new();
+    for entry in archive.entries().to_vec() {
+      let Some(name) = normalize(&entry.name).filter(|_| !entry.is_dir()) else {
+        continue;
+      };
+      let extension = path::Path::new(&name)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+      let is_readme = README_EXTENSIONS.contains(&extension.as_str());
+      let is_image = IMAGE_EXTENSIONS.contains(&extension.as_str());
+      if is_image && !self.keep_images {
+        log::debug!("{}", skipped(&entry.name));
+        continue;
+      }
+      let data = archive.read(&entry)?;
+      let kind = match is_readme || is_image {
+        true => None,
+        false => patch::Kind::detect(&mut io::Cursor::new(&data))?,
+      };
+      if kind.is_none() && !is_readme && !is_image {
+        log::debug!("{}", skipped(&entry.name));
+        continue;
+      }
+      // A patch that looks like one but can't be read is left out, rather
+      // than stop extracting the rest of the archive.
+      let header = match kind.map(|kind| patch::header::read(kind, &mut io::Cursor::new(&data))) {
+        Some(Err(patch::Error::IO(err))) => return Err(err.into()),
+        Some(Err(err)) => {
+          log::warn!(
+            "{}",
+            i18n::format(
+              "romhacks::unpack::unreadable_patch",
+              &[("entry", &entry.name), ("error", &err)]
+            )
+          );
+          continue;
+        }
+        Some(Ok(header)) => Some(header),
+        None => None,
+      };
+      let name = unique(&mut taken, name);
+      self.write(&self.dest.join(&name), &data)?;
+      log::info!(
+        "{}",
+        i18n::format(
+          "romhacks::unpack::extracted",
+          &[("entry", &entry.name), ("name", &name)]
+        )
+      );
+      match kind.zip(header) {
+        Some((kind, header)) => patches.push(Extracted { name, kind, header }),
+        None if is_readme => readmes.push(name),
+        None => {}
+      }
+    }
+    if patches.is_empty() {
+      return Err(Error::NoPatches);
+    }
+    if self.recipe {
+      let archive_name = self
+        .archive
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy();
+      let recipe = recipe(&archive_name, &patches, &readmes);
+      self.write(
+        &self.dest.join(RECIPE_FILE_NAME),
+        recipe.to_string().as_bytes(),
+      )?;
+    }
+    Ok(())
+  }
+
+  /// Writes an extracted file, failing if one already exists at `path`
+  /// unless `--force` is given.
+  fn write(&self, path: &path::Path, data: &[u8]) -> Result<(), Error> {
+    let mut options = fs::OpenOptions::new();
+    match self.force {
+      true => options.write(true).create(true).truncate(true),
+      false => options.write(true).create_new(true),
+    };
+    let mut file = options.open(path).map_err(|err| match err.kind() {
+      io::ErrorKind::AlreadyExists => Error::Exists { path: path.to_path_buf() },
+      _ => err.into(),
+    })?;
+    file.write_all(data)?;
+    Ok(())
+  }
+}
+
+/// A patch that was extracted, and what its header says about the ROM.
+struct Extracted {
+  name: String,
+  kind: patch::Kind,
+  header: patch::header::Header,
+}
+
+fn skipped(entry: &str) -> String {
+  i18n::format("romhacks::unpack::skipped", &[("entry", &entry)])
+}
+
+/// Turns the path of a file in an archive into a name for the extracted
+/// file: without its directories and [safe](filename::safe_file_name) to
+/// write. Entries whose names are only periods and spaces are skipped.
+fn normalize(entry_name: &str) -> Option<String> {
+  let base_name = entry_name.rsplit(['/', '\\']).next()?;
+  let name = base_name.trim().trim_end_matches(['.', ' ']);
+  (!name.is_empty()).then(|| filename::safe_file_name(name).into_owned())
+}
+
+/// Returns `name`, or `name` with a number added before its extension if
+/// another extracted file has the same name. Names are compared without
+/// case, since some filesystems ignore it.
+fn unique(taken: &mut HashSet<String>, name: String) -> String {
+  let path = path::Path::new(&name);
+  let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+  let extension = path
+    .extension()
+    .map(|ext| format!(".{}", ext.to_string_lossy()));
+  let name = (1..)
+    .map(|n| match n {
+      1 => name.clone(),
+      n => format!("{stem} ({n}){}", extension.as_deref().unwrap_or_default()),
+    })
+    .find(|candidate| !taken.contains(&candidate.to_lowercase()))
+    .unwrap();
+  taken.insert(name.to_lowercase());
+  name
+}
+
+fn recipe(archive_name: &str, patches: &[Extracted], readmes: &[String]) -> kdl::KdlDocument {
+  let crc32 = |crc32: Crc32| i128::from(crc32.value());
+  mem::init(kdl::KdlDocument::new(), |doc| {
+    let nodes = doc.nodes_mut();
+    nodes.push(mem::init(kdl::KdlNode::new(ROMHACKS_PACK), |node| {
+      node.insert(VERSION, RECIPE_VERSION);
+      node.insert(ARCHIVE, archive_name);
+    }));
+    for patch in patches {
+      let header = &patch.header;
+      nodes.push(mem::init(kdl::KdlNode::new(PATCH), |node| {
+        node.push(patch.name.as_str());
+        node.insert(FORMAT, identify::name(patch.kind));
+        if let Some(size) = header.source_size {
+          node.insert(SOURCE_SIZE, i128::from(size));
+        }
+        if let Some(source_crc32) = header.source_crc32 {
+          node.insert(SOURCE_CRC_32, crc32(source_crc32));
+        }
+        if let Some(size) = header.target_size {
+          node.insert(TARGET_SIZE, i128::from(size));
+        }
+        if let Some(target_crc32) = header.target_crc32 {
+          node.insert(TARGET_CRC_32, crc32(target_crc32));
+        }
+      }));
+    }
+    for readme in readmes {
+      nodes.push(mem::init(kdl::KdlNode::new(README), |node| {
+        node.push(readme.as_str());
+      }));
+    }
+    doc.autoformat();
+  })
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Zip(#[from] zip::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Patch(#[from] patch::Error),
+  #[error("{}", i18n::text("romhacks::unpack::no_patches"))]
+  #[diagnostic(code(romhacks::unpack::no_patches))]
+  NoPatches,
+  #[error("{}", i18n::format("romhacks::unpack::exists", &[("path", &path.display())]))]
+  #[diagnostic(
+    code(romhacks::unpack::exists),
+    help("{}", i18n::text("romhacks::unpack::exists::help"))
+  )]
+  Exists { path: path::PathBuf },
+}
@@ -0,0 +1,83 @@
+use crate::error::prelude::*;
+use crate::{apply, dirs, hack, i18n, io, manifest};
+use std::path;
+
+#[derive(Clone, Debug, clap::Args)]
+// The flattened apply::Args already uses the default group name.
+#[group(skip)]
+pub struct Args {
+  #[command(flatten)]
+  pub apply: apply::Args,
+  /// Apply the patch with a warning even if the manifest records the same or
+  /// a newer version of the hack.
+  #[arg(long)]
+  pub allow_downgrade: bool,
+}
+
+impl Args {
+  /// Checks the versions of the hack recorded in each ROM's manifest, then
+  /// applies the patch.
+  pub fn call(mut self) -> Result<(), Error> {
+    let hack = &self.apply.hack;
+    for rom_path in &self.apply.rom {
+      // With --patch-dir, the patch isn't picked yet. Its name only matters
+      // here if the template puts it in the name of a directory.
+      let patch_path = self.apply.patch.as_deref().unwrap_or(path::Path::new(""));
+      let output_name =
+        (self.apply.output_name(rom_path, patch_path)).map_err(apply::Error::from)?;
+      let output_dir = dirs::temp_dir_for(path::Path::new(&output_name));
+      let manifest = match manifest::read(&manifest::path_for(rom_path, &output_dir)) {
+        Ok(manifest) => manifest,
+        Err(manifest::Error::IO(err)) if err.kind() == io::ErrorKind::NotFound => continue,
+        Err(err) => return Err(err.into()),
+      };
+      let Some(recorded) = manifest.newest_version(hack.url.as_str()) else {
+        continue;
+      };
+      if *recorded < hack.version {
+        log::info!(
+          "{}",
+          i18n::format(
+            "romhacks::upgrade::upgrading",
+            &[("recorded", recorded), ("version", &hack.version)]
+          )
+        );
+      } else if self.allow_downgrade {
+        log::warn!(
+          "{}",
+          i18n::format(
+            "romhacks::upgrade::not_newer",
+            &[("recorded", recorded), ("version", &hack.version)]
+          )
+        );
+      } else {
+        return Err(Error::NotNewer {
+          recorded: recorded.clone(),
+          version: hack.version.clone(),
+        });
+      }
+    }
+    self.apply.upgrade = true;
+    Ok(self.apply.call()?)
+  }
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Manifest(#[from] manifest::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Apply(#[from] apply::Error),
+  #[error("{}", i18n::format(
+    "romhacks::upgrade::not_newer_allow",
+    &[("recorded", recorded), ("version", version)]
+  ))]
+  #[diagnostic(code(romhacks::upgrade::not_newer))]
+  NotNewer {
+    recorded: hack::Version,
+    version: hack::Version,
+  },
+}
@@ -1,5 +1,6 @@
-use crate::kdl::prelude::*;
-use crate::{kdl, manifest};
+use crate::error::prelude::*;
+use crate::render::{Stream, Style};
+use crate::{fs, i18n, kdl, manifest};
 use std::path;
 
 #[derive(Clone, Debug, clap::Args)]
@@ -8,11 +9,27 @@ pub struct Args {
 }
 
 impl Args {
-  pub fn call(self) -> Result<(), kdl::CheckFailure> {
-    kdl::Schema::parse(manifest::SCHEMA)
-      .unwrap()
-      .check_file_matches(self.manifest_path)?;
-    log::info!("File is valid.");
+  pub fn call(self) -> Result<(), Error> {
+    let text = fs::read_to_string(&self.manifest_path)?;
+    kdl::check_all(
+      &kdl::Schema::parse(manifest::SCHEMA).unwrap(),
+      &self.manifest_path.to_string_lossy(),
+      &text,
+    )?;
+    log::info!(
+      "{}",
+      Stream::Stderr.paint(Style::Ok, i18n::text("romhacks::validate::valid"))
+    );
     Ok(())
   }
 }
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] std::io::Error),
+  #[error(transparent)]
+  #[diagnostic(transparent)]
+  Check(#[from] kdl::CheckFailures),
+}
@@ -0,0 +1,199 @@
+//! Reading the files in a ZIP archive, which is how most hacks are released.
+//!
+//! Only stored and deflated files are supported, which is what every common
+//! zip tool writes. ZIP64 archives and encrypted files aren't.
+
+use crate::error::prelude::*;
+use crate::io::prelude::*;
+use crate::{i18n, io};
+
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4B50;
+const CENTRAL_DIRECTORY_HEADER: u32 = 0x0201_4B50;
+const LOCAL_FILE_HEADER: u32 = 0x0403_4B50;
+
+/// The size of the end of central directory record without its comment,
+/// which can be up to 65535 bytes long.
+const END_RECORD_LEN: u64 = 22;
+const MAX_COMMENT_LEN: u64 = u16::MAX as u64;
+
+const LOCAL_HEADER_LEN: u64 = 30;
+
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+// flags
+const ENCRYPTED: u16 = 1 << 0;
+
+/// A file in an archive.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+  /// The path of the file within the archive, with `/` between directories.
+  pub name: String,
+  /// The size of the file once extracted.
+  pub size: u64,
+  method: u16,
+  flags: u16,
+  crc32: u32,
+  compressed_size: u64,
+  local_header_offset: u64,
+}
+
+impl Entry {
+  pub fn is_dir(&self) -> bool {
+    self.name.ends_with('/')
+  }
+}
+
+/// A ZIP archive whose central directory has been read.
+#[derive(Debug)]
+pub struct Archive<R> {
+  reader: R,
+  entries: Vec<Entry>,
+}
+
+impl<R: Read + Seek> Archive<R> {
+  pub fn new(mut reader: R) -> Result<Self, Error> {
+    let (count, offset) = read_end_record(&mut reader)?;
+    reader.seek(io::SeekFrom::Start(offset))?;
+    let mut reader = io::BufReader::new(reader);
+    let entries = (0..count)
+      .map(|_| read_central_header(&mut reader))
+      .collect::<Result<Vec<_>, _>>()?;
+    Ok(Self { reader: reader.into_inner(), entries })
+  }
+
+  pub fn entries(&self) -> &[Entry] {
+    &self.entries
+  }
+
+  /// Extracts a file and checks its checksum.
+  pub fn read(&mut self, entry: &Entry) -> Result<Vec<u8>, Error> {
+    if entry.flags & ENCRYPTED != 0 {
+      return Err(Error::Encrypted { name: entry.name.clone() });
+    }
+    let reader = &mut self.reader;
+    reader.seek(io::SeekFrom::Start(entry.local_header_offset))?;
+    let header: [u8; LOCAL_HEADER_LEN as usize] = reader.read_array()?;
+    if u32::from_le_bytes(header[..4].try_into().unwrap()) != LOCAL_FILE_HEADER {
+      return Err(Error::Corrupt { name: entry.name.clone() });
+    }
+    let name_len = u16::from_le_bytes([header[26], header[27]]);
+    let extra_len = u16::from_le_bytes([header[28], header[29]]);
+    reader.seek(io::SeekFrom::Current(
+      i64::from(name_len) + i64::from(extra_len),
+    ))?;
+    let mut compressed = Vec::new();
+    (&mut *reader)
+      .take(entry.compressed_size)
+      .read_to_end(&mut compressed)?;
+    let data = match entry.method {
+      STORED => compressed,
+      DEFLATED => {
+        miniz_oxide::inflate::decompress_to_vec_with_limit(&compressed, entry.size as usize)
+          .map_err(|_| Error::Corrupt { name: entry.name.clone() })?
+      }
+      method => return Err(Error::UnsupportedMethod { name: entry.name.clone(), method }),
+    };
+    if data.len() as u64 != entry.size || crc32fast::hash(&data) != entry.crc32 {
+      return Err(Error::Corrupt { name: entry.name.clone() });
+    }
+    Ok(data)
+  }
+}
+
+/// Finds the end of central directory record, which is followed by nothing
+/// but the archive's comment, and returns the number of entries and the
+/// offset of the central directory.
+fn read_end_record(reader: &mut (impl Read + Seek)) -> Result<(u16, u64), Error> {
+  let len = reader.seek(io::SeekFrom::End(0))?;
+  let search_len = len.min(END_RECORD_LEN + MAX_COMMENT_LEN);
+  reader.seek(io::SeekFrom::Start(len - search_len))?;
+  let mut tail = Vec::with_capacity(search_len as usize);
+  reader.read_to_end(&mut tail)?;
+  let signature = END_OF_CENTRAL_DIRECTORY.to_le_bytes();
+  let start = (0..tail.len().saturating_sub(END_RECORD_LEN as usize - 1))
+    .rev()
+    .find(|&i| tail[i..].starts_with(&signature))
+    .ok_or(Error::NotZip)?;
+  let mut record = &tail[start + 4..];
+  let _disk = record.read_u16::<LE>()?;
+  let _central_directory_disk = record.read_u16::<LE>()?;
+  let _disk_entries = record.read_u16::<LE>()?;
+  let count = record.read_u16::<LE>()?;
+  let _size = record.read_u32::<LE>()?;
+  let offset = record.read_u32::<LE>()?;
+  if count == u16::MAX || offset == u32::MAX {
+    return Err(Error::Zip64);
+  }
+  Ok((count, offset.into()))
+}
+
+fn read_central_header(reader: &mut impl Read) -> Result<Entry, Error> {
+  if reader.read_u32::<LE>()? != CENTRAL_DIRECTORY_HEADER {
+    return Err(Error::NotZip);
+  }
+  let _version_made_by = reader.read_u16::<LE>()?;
+  let _version_needed = reader.read_u16::<LE>()?;
+  let flags = reader.read_u16::<LE>()?;
+  let method = reader.read_u16::<LE>()?;
+  let _time = reader.read_u16::<LE>()?;
+  let _date = reader.read_u16::<LE>()?;
+  let crc32 = reader.read_u32::<LE>()?;
+  let compressed_size = reader.read_u32::<LE>()?;
+  let size = reader.read_u32::<LE>()?;
+  let name_len = reader.read_u16::<LE>()?;
+  let extra_len = reader.read_u16::<LE>()?;
+  let comment_len = reader.read_u16::<LE>()?;
+  let _disk = reader.read_u16::<LE>()?;
+  let _internal_attributes = reader.read_u16::<LE>()?;
+  let _external_attributes = reader.read_u32::<LE>()?;
+  let local_header_offset = reader.read_u32::<LE>()?;
+  if [compressed_size, size, local_header_offset].contains(&u32::MAX) {
+    return Err(Error::Zip64);
+  }
+  let mut name = vec![0u8; name_len.into()];
+  reader.read_exact(&mut name)?;
+  io::copy(
+    &mut reader.take(u64::from(extra_len) + u64::from(comment_len)),
+    &mut io::sink(),
+  )?;
+  // Names are flagged as UTF-8 or in code page 437, but many tools write
+  // UTF-8 without the flag. Other names are read as Latin-1, which matches
+  // code page 437 in ASCII, where most file names are.
+  let name = String::from_utf8(name)
+    .unwrap_or_else(|err| err.into_bytes().iter().map(|&byte| byte as char).collect());
+  Ok(Entry {
+    name,
+    size: size.into(),
+    method,
+    flags,
+    crc32,
+    compressed_size: compressed_size.into(),
+    local_header_offset: local_header_offset.into(),
+  })
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+  #[error(transparent)]
+  IO(#[from] io::Error),
+  #[error("{}", i18n::text("romhacks::zip::not_zip"))]
+  #[diagnostic(code(romhacks::zip::not_zip))]
+  NotZip,
+  #[error("{}", i18n::text("romhacks::zip::zip64"))]
+  #[diagnostic(code(romhacks::zip::zip64))]
+  Zip64,
+  #[error("{}", i18n::format("romhacks::zip::encrypted", &[("name", name)]))]
+  #[diagnostic(code(romhacks::zip::encrypted))]
+  Encrypted { name: String },
+  #[error("{}", i18n::format(
+    "romhacks::zip::unsupported_method",
+    &[("name", name), ("method", method)]
+  ))]
+  #[diagnostic(code(romhacks::zip::unsupported_method))]
+  UnsupportedMethod { name: String, method: u16 },
+  #[error("{}", i18n::format("romhacks::zip::corrupt", &[("name", name)]))]
+  #[diagnostic(code(romhacks::zip::corrupt))]
+  Corrupt { name: String },
+}
@@ -0,0 +1,99 @@
+//! Building APS patches for Game Boy Advance ROMs.
+
+/// The size of the blocks records XOR.
+pub const BLOCK_SIZE: usize = 0x10000;
+
+/// Builds a GBA APS patch out of records, which are applied in the order
+/// they're added, with CRCs that match the blocks they XOR.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ApsGbaBuilder {
+  source: Vec<u8>,
+  target_size: u32,
+  records: Vec<(u32, Vec<u8>)>,
+}
+
+impl ApsGbaBuilder {
+  /// Starts a patch for `source` that leaves its size unchanged.
+  pub fn new(source: &[u8]) -> Self {
+    Self {
+      source: source.to_vec(),
+      target_size: u32::try_from(source.len()).expect("APS sizes are 32-bit"),
+      records: Vec::new(),
+    }
+  }
+
+  /// Truncates or extends the patched file to `len` bytes.
+  pub fn resize(mut self, len: u32) -> Self {
+    self.target_size = len;
+    self
+  }
+
+  /// Adds a record that XORs `xor` into the block at `offset`.
+  ///
+  /// # Panics
+  ///
+  /// If `xor` is longer than a block.
+  pub fn xor(mut self, offset: u32, xor: &[u8]) -> Self {
+    assert!(xor.len() <= BLOCK_SIZE, "APS records XOR at most one block");
+    self.records.push((offset, xor.to_vec()));
+    self
+  }
+
+  /// The patched file.
+  pub fn target(&self) -> Vec<u8> {
+    self.apply().0
+  }
+
+  pub fn build(self) -> Vec<u8> {
+    let mut patch = b"APS1".to_vec();
+    patch.extend_from_slice(&(self.source.len() as u32).to_le_bytes());
+    patch.extend_from_slice(&self.target_size.to_le_bytes());
+    patch.extend_from_slice(&self.apply().1);
+    patch
+  }
+
+  /// Applies the records to the source, as a patcher would, and returns the
+  /// patched file and the records with their CRCs.
+  fn apply(&self) -> (Vec<u8>, Vec<u8>) {
+    // The file is as large as either size while records are applied.
+    let mut file = self.source.clone();
+    file.resize(file.len().max(self.target_size as usize), 0);
+    let mut records = Vec::new();
+    for (offset, xor) in &self.records {
+      // Bytes past the end of the file are read as zeroes and not written.
+      let start = (*offset as usize).min(file.len());
+      let end = file.len().min(start + BLOCK_SIZE);
+      let mut block = file[start..end].to_vec();
+      block.resize(BLOCK_SIZE, 0);
+      let source_crc = crc16(&block);
+      for (byte, xor) in block.iter_mut().zip(xor) {
+        *byte ^= *xor;
+      }
+      let target_crc = crc16(&block);
+      file[start..end].copy_from_slice(&block[..end - start]);
+
+      records.extend_from_slice(&offset.to_le_bytes());
+      records.extend_from_slice(&source_crc.to_le_bytes());
+      records.extend_from_slice(&target_crc.to_le_bytes());
+      records.extend_from_slice(xor);
+      records.resize(records.len() + BLOCK_SIZE - xor.len(), 0);
+    }
+    file.truncate(self.target_size as usize);
+    (file, records)
+  }
+}
+
+/// CRC-16/CCITT-FALSE, which records are checked with.
+fn crc16(bytes: &[u8]) -> u16 {
+  let mut crc: u16 = 0xFFFF;
+  for &byte in bytes {
+    crc ^= u16::from(byte) << 8;
+    for _ in 0..8 {
+      crc = match crc & 0x8000 {
+        0 => crc << 1,
+        _ => (crc << 1) ^ 0x1021,
+      };
+    }
+  }
+  crc
+}
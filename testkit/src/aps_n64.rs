@@ -0,0 +1,89 @@
+//! Building APS patches for Nintendo 64 ROMs.
+
+/// Where the cartridge ID and the CRCs are in a ROM's internal header, and
+/// their lengths.
+pub const CART_ID_OFFSET: usize = 0x3C;
+pub const CART_ID_LEN: usize = 3;
+pub const CRC_OFFSET: usize = 0x10;
+pub const CRC_LEN: usize = 8;
+
+/// Builds an N64 APS patch out of records, which are written in the order
+/// they're added.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ApsN64Builder {
+  description: String,
+  cart: Option<Vec<u8>>,
+  target_size: u32,
+  records: Vec<u8>,
+}
+
+impl ApsN64Builder {
+  /// A patch whose patched file is `target_size` bytes.
+  pub fn new(target_size: u32) -> Self {
+    Self { target_size, ..Self::default() }
+  }
+
+  /// Sets the description in the patch's header, which is cut off after
+  /// 50 bytes.
+  pub fn description(mut self, description: &str) -> Self {
+    self.description = description.to_owned();
+    self
+  }
+
+  /// Gives the patch an N64 header with the cartridge ID and CRCs of `rom`,
+  /// so that it only applies to ROMs with the same ones.
+  ///
+  /// # Panics
+  ///
+  /// If `rom` is too short to hold them.
+  pub fn cart(mut self, rom: &[u8]) -> Self {
+    let id = rom
+      .get(CART_ID_OFFSET..CART_ID_OFFSET + CART_ID_LEN)
+      .expect("the ROM is too short for a cartridge ID");
+    let crc = rom
+      .get(CRC_OFFSET..CRC_OFFSET + CRC_LEN)
+      .expect("the ROM is too short for its CRCs");
+    self.cart = Some([id, crc].concat());
+    self
+  }
+
+  /// Adds a record that writes `bytes` at `offset`.
+  ///
+  /// # Panics
+  ///
+  /// If `bytes` is empty or longer than 255 bytes.
+  pub fn hunk(mut self, offset: u32, bytes: &[u8]) -> Self {
+    assert!(!bytes.is_empty(), "an empty hunk would be read as RLE");
+    let len = u8::try_from(bytes.len()).expect("APS hunks are at most 255 bytes");
+    self.records.extend_from_slice(&offset.to_le_bytes());
+    self.records.push(len);
+    self.records.extend_from_slice(bytes);
+    self
+  }
+
+  /// Adds a run-length encoded record that writes `len` copies of `byte` at
+  /// `offset`.
+  pub fn rle(mut self, offset: u32, byte: u8, len: u8) -> Self {
+    self.records.extend_from_slice(&offset.to_le_bytes());
+    self.records.extend_from_slice(&[0, byte, len]);
+    self
+  }
+
+  pub fn build(self) -> Vec<u8> {
+    let mut patch = b"APS10".to_vec();
+    patch.push(self.cart.is_some() as u8);
+    patch.push(0); // encoding method
+    let mut description = [b' '; 50];
+    let len = self.description.len().min(description.len());
+    description[..len].copy_from_slice(&self.description.as_bytes()[..len]);
+    patch.extend_from_slice(&description);
+    if let Some(cart) = &self.cart {
+      patch.push(0); // not byte-swapped
+      patch.extend_from_slice(cart);
+      patch.extend_from_slice(&[0; 5]);
+    }
+    patch.extend_from_slice(&self.target_size.to_le_bytes());
+    patch.extend_from_slice(&self.records);
+    patch
+  }
+}
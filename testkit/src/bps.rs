@@ -0,0 +1,106 @@
+//! Building BPS patches.
+
+use crate::varint;
+
+/// Builds a BPS patch out of actions, which are written in the order
+/// they're added. The target is built up as they are, so that the patch's
+/// target size and checksum match it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BpsBuilder {
+  source: Vec<u8>,
+  target: Vec<u8>,
+  metadata: Vec<u8>,
+  actions: Vec<u8>,
+  /// Where the last SourceCopy and TargetCopy actions left off, which the
+  /// next ones are relative to.
+  source_relative: usize,
+  target_relative: usize,
+}
+
+impl BpsBuilder {
+  /// Starts a patch for `source` with an empty target.
+  pub fn new(source: &[u8]) -> Self {
+    Self {
+      source: source.to_vec(),
+      target: Vec::new(),
+      metadata: Vec::new(),
+      actions: Vec::new(),
+      source_relative: 0,
+      target_relative: 0,
+    }
+  }
+
+  /// Sets the metadata, which is usually XML.
+  pub fn metadata(mut self, metadata: &str) -> Self {
+    self.metadata = metadata.as_bytes().to_vec();
+    self
+  }
+
+  fn action(&mut self, kind: u64, len: usize) {
+    assert!(len > 0, "BPS actions write at least one byte");
+    varint::write(&mut self.actions, ((len as u64 - 1) << 2) | kind);
+  }
+
+  /// Copies `len` bytes of the source from the same offset they end up at
+  /// in the target.
+  pub fn source_read(mut self, len: usize) -> Self {
+    self.action(0, len);
+    let start = self.target.len();
+    self.target.extend_from_slice(&self.source[start..start + len]);
+    self
+  }
+
+  /// Writes `bytes` from the patch.
+  pub fn target_read(mut self, bytes: &[u8]) -> Self {
+    self.action(1, bytes.len());
+    self.actions.extend_from_slice(bytes);
+    self.target.extend_from_slice(bytes);
+    self
+  }
+
+  /// Copies `len` bytes of the source from `offset`.
+  pub fn source_copy(mut self, offset: usize, len: usize) -> Self {
+    self.action(2, len);
+    write_relative(&mut self.actions, self.source_relative, offset);
+    self.target.extend_from_slice(&self.source[offset..offset + len]);
+    self.source_relative = offset + len;
+    self
+  }
+
+  /// Copies `len` bytes of the target from `offset`, which may overlap the
+  /// bytes being written to repeat a pattern.
+  pub fn target_copy(mut self, offset: usize, len: usize) -> Self {
+    self.action(3, len);
+    write_relative(&mut self.actions, self.target_relative, offset);
+    for i in offset..offset + len {
+      self.target.push(self.target[i]);
+    }
+    self.target_relative = offset + len;
+    self
+  }
+
+  /// The file the patch turns the source into.
+  pub fn target(&self) -> &[u8] {
+    &self.target
+  }
+
+  pub fn build(self) -> Vec<u8> {
+    let mut patch = b"BPS1".to_vec();
+    varint::write(&mut patch, self.source.len() as u64);
+    varint::write(&mut patch, self.target.len() as u64);
+    varint::write(&mut patch, self.metadata.len() as u64);
+    patch.extend_from_slice(&self.metadata);
+    patch.extend_from_slice(&self.actions);
+    varint::write_footer(&mut patch, &self.source, &self.target);
+    patch
+  }
+}
+
+/// Writes the distance from `from` to `to`, with its sign in the lowest bit.
+fn write_relative(out: &mut Vec<u8>, from: usize, to: usize) {
+  let value = match to >= from {
+    true => ((to - from) as u64) << 1,
+    false => (((from - to) as u64) << 1) | 1,
+  };
+  varint::write(out, value);
+}
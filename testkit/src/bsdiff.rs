@@ -0,0 +1,99 @@
+//! Building bsdiff patches in the BSDIFF40 container.
+
+use bzip2::Compression;
+use bzip2::write::BzEncoder;
+use std::io::Write;
+
+/// Builds a bsdiff patch out of controls, which are applied in the order
+/// they're added, and keeps track of the file it produces.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BsdiffBuilder {
+  source: Vec<u8>,
+  target: Vec<u8>,
+  source_pos: i64,
+  controls: Vec<u8>,
+  diff: Vec<u8>,
+  extra: Vec<u8>,
+  target_size: Option<i64>,
+}
+
+impl BsdiffBuilder {
+  /// A patch for `source`.
+  pub fn new(source: &[u8]) -> Self {
+    Self { source: source.to_vec(), ..Self::default() }
+  }
+
+  /// Adds a control that writes `target` over the source's bytes from the
+  /// current position, then writes `extra`, then moves `seek` bytes in the
+  /// source. Source bytes past either end are read as zeroes.
+  pub fn control(mut self, target: &[u8], extra: &[u8], seek: i64) -> Self {
+    let source_byte = |pos: i64| {
+      usize::try_from(pos)
+        .ok()
+        .and_then(|pos| self.source.get(pos))
+        .copied()
+        .unwrap_or(0)
+    };
+    let diff = target
+      .iter()
+      .zip(self.source_pos..)
+      .map(|(&byte, pos)| byte.wrapping_sub(source_byte(pos)));
+    self.diff.extend(diff);
+    self.extra.extend_from_slice(extra);
+    self.target.extend_from_slice(target);
+    self.target.extend_from_slice(extra);
+    self = self.raw_control(target.len() as i64, extra.len() as i64, seek);
+    self.source_pos = self.source_pos.wrapping_add(target.len() as i64);
+    self.source_pos = self.source_pos.wrapping_add(seek);
+    self
+  }
+
+  /// Adds a control with the given values and no diff or extra bytes, for
+  /// patches whose controls are wrong.
+  pub fn raw_control(mut self, diff_len: i64, extra_len: i64, seek: i64) -> Self {
+    for value in [diff_len, extra_len, seek] {
+      self.controls.extend_from_slice(&offtout(value));
+    }
+    self
+  }
+
+  /// Declares `size` as the size of the patched file rather than the size
+  /// of the file the controls produce.
+  pub fn target_size(mut self, size: i64) -> Self {
+    self.target_size = Some(size);
+    self
+  }
+
+  /// The file the patch produces.
+  pub fn target(&self) -> &[u8] {
+    &self.target
+  }
+
+  pub fn build(self) -> Vec<u8> {
+    let controls = compress(&self.controls);
+    let diff = compress(&self.diff);
+    let extra = compress(&self.extra);
+    let mut patch = b"BSDIFF40".to_vec();
+    patch.extend_from_slice(&offtout(controls.len() as i64));
+    patch.extend_from_slice(&offtout(diff.len() as i64));
+    let target_size = self.target_size.unwrap_or(self.target.len() as i64);
+    patch.extend_from_slice(&offtout(target_size));
+    patch.extend_from_slice(&controls);
+    patch.extend_from_slice(&diff);
+    patch.extend_from_slice(&extra);
+    patch
+  }
+}
+
+/// Writes a number as bsdiff stores it: 8 bytes in little-endian order, with
+/// the sign in the highest bit rather than in two's complement.
+fn offtout(value: i64) -> [u8; 8] {
+  let sign = u64::from(value < 0) << 63;
+  (value.unsigned_abs() | sign).to_le_bytes()
+}
+
+fn compress(bytes: &[u8]) -> Vec<u8> {
+  let mut encoder = BzEncoder::new(Vec::new(), Compression::default());
+  encoder.write_all(bytes).unwrap();
+  encoder.finish().unwrap()
+}
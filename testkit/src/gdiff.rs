@@ -0,0 +1,97 @@
+//! Building patches in the Generic Diff Format (GDIFF).
+
+/// Builds a GDIFF patch out of commands, which are applied in the order
+/// they're added, and keeps track of the file it produces. Each command is
+/// written with the narrowest opcode that holds its numbers.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GdiffBuilder {
+  source: Vec<u8>,
+  target: Vec<u8>,
+  commands: Vec<u8>,
+}
+
+impl GdiffBuilder {
+  /// A patch for `source`.
+  pub fn new(source: &[u8]) -> Self {
+    Self { source: source.to_vec(), ..Self::default() }
+  }
+
+  /// Adds a command that appends `bytes` from the patch.
+  ///
+  /// # Panics
+  ///
+  /// If `bytes` is empty or longer than an `int` can count.
+  pub fn data(mut self, bytes: &[u8]) -> Self {
+    assert!(!bytes.is_empty(), "an empty data command would be read as EOF");
+    match bytes.len() {
+      len @ 1..=246 => self.commands.push(len as u8),
+      len @ ..=0xFFFF => {
+        self.commands.push(247);
+        self.commands.extend_from_slice(&(len as u16).to_be_bytes());
+      }
+      len => {
+        let len = i32::try_from(len).expect("GDIFF data is at most i32::MAX bytes");
+        self.commands.push(248);
+        self.commands.extend_from_slice(&len.to_be_bytes());
+      }
+    }
+    self.commands.extend_from_slice(bytes);
+    self.target.extend_from_slice(bytes);
+    self
+  }
+
+  /// Adds a command that appends `len` of the source's bytes from
+  /// `position`.
+  ///
+  /// # Panics
+  ///
+  /// If the bytes are past the end of the source, or `len` doesn't fit in
+  /// an `int`.
+  pub fn copy(mut self, position: usize, len: usize) -> Self {
+    let bytes = (self.source.get(position..position + len))
+      .expect("the copy is past the end of the source");
+    self.target.extend_from_slice(bytes);
+    let len = i32::try_from(len).expect("GDIFF copies are at most i32::MAX bytes");
+    // The first opcode for each width of position, which is followed by the
+    // ones for ushort and int lengths.
+    let (first, position) = if let Ok(position) = u16::try_from(position) {
+      (249, position.to_be_bytes().to_vec())
+    } else if let Ok(position) = i32::try_from(position) {
+      (252, position.to_be_bytes().to_vec())
+    } else {
+      (255, (position as i64).to_be_bytes().to_vec())
+    };
+    let (opcode, len) = if first == 255 {
+      // Long positions only come with int lengths.
+      (255, len.to_be_bytes().to_vec())
+    } else if let Ok(len) = u8::try_from(len) {
+      (first, vec![len])
+    } else if let Ok(len) = u16::try_from(len) {
+      (first + 1, len.to_be_bytes().to_vec())
+    } else {
+      (first + 2, len.to_be_bytes().to_vec())
+    };
+    self.commands.push(opcode);
+    self.commands.extend_from_slice(&position);
+    self.commands.extend_from_slice(&len);
+    self
+  }
+
+  /// Adds `command` as it is, for commands the builder wouldn't write.
+  pub fn raw(mut self, command: &[u8]) -> Self {
+    self.commands.extend_from_slice(command);
+    self
+  }
+
+  /// The file the patch produces.
+  pub fn target(&self) -> &[u8] {
+    &self.target
+  }
+
+  pub fn build(self) -> Vec<u8> {
+    let mut patch = vec![0xD1, 0xFF, 0xD1, 0xFF, 4];
+    patch.extend_from_slice(&self.commands);
+    patch.push(0); // EOF
+    patch
+  }
+}
@@ -0,0 +1,107 @@
+//! Building IPS and IPS32 patches.
+
+/// The largest offset a record can have, since offsets are 24-bit.
+pub const MAX_OFFSET: u32 = (1 << 24) - 1;
+
+/// Builds an IPS patch out of records, which are written in the order
+/// they're added. [`IpsBuilder::ips32`] builds IPS32 patches instead, whose
+/// offsets and truncation size are 32-bit.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IpsBuilder {
+  ips32: bool,
+  records: Vec<u8>,
+  truncate: Option<u32>,
+}
+
+impl IpsBuilder {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn ips32() -> Self {
+    Self { ips32: true, ..Self::default() }
+  }
+
+  /// Adds a record that writes `bytes` at `offset`.
+  ///
+  /// # Panics
+  ///
+  /// If `offset` doesn't fit in 24 bits in an IPS patch, or `bytes` is empty
+  /// or longer than a record can be.
+  pub fn hunk(mut self, offset: u32, bytes: &[u8]) -> Self {
+    assert!(!bytes.is_empty(), "an empty hunk would be read as RLE");
+    let len = u16::try_from(bytes.len()).expect("IPS hunks are at most 65535 bytes");
+    self.push_offset(offset);
+    self.records.extend_from_slice(&len.to_be_bytes());
+    self.records.extend_from_slice(bytes);
+    self
+  }
+
+  /// Adds a run-length encoded record that writes `len` copies of `byte` at
+  /// `offset`.
+  ///
+  /// # Panics
+  ///
+  /// If `offset` doesn't fit in 24 bits in an IPS patch, or `len` is zero.
+  pub fn rle(mut self, offset: u32, byte: u8, len: u16) -> Self {
+    assert!(len > 0, "RLE records write at least one byte");
+    self.push_offset(offset);
+    self.records.extend_from_slice(&[0, 0]);
+    self.records.extend_from_slice(&len.to_be_bytes());
+    self.records.push(byte);
+    self
+  }
+
+  /// Truncates or extends the patched file to `len` bytes, with the
+  /// extension written after the "EOF" or "EEOF" marker.
+  pub fn truncate(mut self, len: u32) -> Self {
+    assert!(self.ips32 || len <= MAX_OFFSET, "IPS sizes are 24-bit");
+    self.truncate = Some(len);
+    self
+  }
+
+  pub fn build(self) -> Vec<u8> {
+    let mut patch = self.magic().to_vec();
+    patch.extend_from_slice(&self.records);
+    patch.extend_from_slice(self.eof());
+    if let Some(len) = self.truncate {
+      patch.extend_from_slice(&len.to_be_bytes()[self.offset_start()..]);
+    }
+    patch
+  }
+
+  /// Builds the patch without its "EOF" marker, as some tools write them.
+  pub fn build_without_eof(self) -> Vec<u8> {
+    let mut patch = self.magic().to_vec();
+    patch.extend_from_slice(&self.records);
+    patch
+  }
+
+  fn push_offset(&mut self, offset: u32) {
+    assert!(self.ips32 || offset <= MAX_OFFSET, "IPS offsets are 24-bit");
+    let start = self.offset_start();
+    self.records.extend_from_slice(&offset.to_be_bytes()[start..]);
+  }
+
+  /// Where offsets start in their big-endian bytes.
+  fn offset_start(&self) -> usize {
+    match self.ips32 {
+      true => 0,
+      false => 1,
+    }
+  }
+
+  fn magic(&self) -> &'static [u8] {
+    match self.ips32 {
+      true => b"IPS32",
+      false => b"PATCH",
+    }
+  }
+
+  fn eof(&self) -> &'static [u8] {
+    match self.ips32 {
+      true => b"EEOF",
+      false => b"EOF",
+    }
+  }
+}
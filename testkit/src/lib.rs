@@ -0,0 +1,28 @@
+//! Builders for the ROMs and patches used in romhacks' tests, so that tests
+//! describe what a patch does rather than spell out its bytes.
+//!
+//! Each patch builder writes a patch in the layout its format's parser
+//! expects, with any sizes and checksums filled in. [`rom`] generates ROMs to
+//! apply them to.
+
+pub mod aps_gba;
+pub mod aps_n64;
+pub mod bps;
+pub mod bsdiff;
+pub mod gdiff;
+pub mod ips;
+pub mod ninja2;
+pub mod ppf;
+pub mod rom;
+pub mod ups;
+mod varint;
+
+pub use aps_gba::ApsGbaBuilder;
+pub use aps_n64::ApsN64Builder;
+pub use bps::BpsBuilder;
+pub use bsdiff::BsdiffBuilder;
+pub use gdiff::GdiffBuilder;
+pub use ips::IpsBuilder;
+pub use ninja2::Ninja2Builder;
+pub use ppf::PpfBuilder;
+pub use ups::UpsBuilder;
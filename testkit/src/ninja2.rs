@@ -0,0 +1,90 @@
+//! Building NINJA 2.0 patches.
+
+use md5::{Digest, Md5};
+
+/// Where the commands start, after the header.
+const COMMANDS_START: usize = 0x800;
+
+/// The name the file is recorded under.
+const NAME: &[u8] = b"game.sfc";
+
+/// Builds a NINJA 2.0 patch for one file out of XOR records, which are
+/// applied in the order they're added. The target is built up as they are,
+/// so that the patch's target MD5 matches it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ninja2Builder {
+  source: Vec<u8>,
+  target: Vec<u8>,
+  target_md5: Option<[u8; 16]>,
+  records: Vec<u8>,
+}
+
+impl Ninja2Builder {
+  /// Starts a patch for `source`.
+  pub fn new(source: &[u8]) -> Self {
+    Self {
+      source: source.to_vec(),
+      target: source.to_vec(),
+      target_md5: None,
+      records: Vec::new(),
+    }
+  }
+
+  /// Adds a record that XORs `xor` into the file at `offset`.
+  ///
+  /// # Panics
+  ///
+  /// If the record reaches past the end of the file.
+  pub fn xor(mut self, offset: usize, xor: &[u8]) -> Self {
+    let bytes = (self.target.get_mut(offset..offset + xor.len()))
+      .expect("the record is past the end of the file");
+    for (byte, xor) in bytes.iter_mut().zip(xor) {
+      *byte ^= *xor;
+    }
+    self.records.push(0x02);
+    self.records.extend(vlv(offset as u64));
+    self.records.extend(vlv(xor.len() as u64));
+    self.records.extend_from_slice(xor);
+    self
+  }
+
+  /// Records `md5` as the patched file's MD5 rather than the target's.
+  pub fn target_md5(mut self, md5: [u8; 16]) -> Self {
+    self.target_md5 = Some(md5);
+    self
+  }
+
+  pub fn target(&self) -> &[u8] {
+    &self.target
+  }
+
+  pub fn build(self) -> Vec<u8> {
+    let mut patch = b"NINJA2".to_vec();
+    patch.resize(COMMANDS_START, b' ');
+    // Open the file.
+    patch.push(0x01);
+    patch.extend(vlv(NAME.len() as u64));
+    patch.extend_from_slice(NAME);
+    patch.push(0); // file type
+    patch.extend(vlv(self.source.len() as u64));
+    patch.extend(vlv(self.target.len() as u64));
+    patch.extend_from_slice(&Md5::digest(&self.source));
+    let target_md5 = (self.target_md5).unwrap_or_else(|| Md5::digest(&self.target).into());
+    patch.extend_from_slice(&target_md5);
+    patch.extend_from_slice(&self.records);
+    patch.push(0x00); // end
+    patch
+  }
+}
+
+/// Writes a variable-length value: a byte holding how many bytes the value
+/// has, followed by the value in little-endian order.
+fn vlv(value: u64) -> Vec<u8> {
+  let bytes = value.to_le_bytes();
+  let len = (bytes.iter())
+    .rposition(|&byte| byte != 0)
+    .map_or(0, |i| i + 1);
+  let mut vlv = vec![len as u8];
+  vlv.extend_from_slice(&bytes[..len]);
+  vlv
+}
@@ -0,0 +1,170 @@
+//! Building PPF patches.
+
+/// The length of the block of the image a block check compares.
+pub const BLOCK_CHECK_LEN: usize = 1024;
+
+/// The kind of disc image a PPF3 patch was made for, which decides where
+/// its block check is read from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ImageType {
+  /// A BIN image with 2352-byte sectors, checked at 0x9320.
+  #[default]
+  Bin = 0,
+  /// A PrimoDVD image, checked at 0x80A0.
+  Gi = 1,
+}
+
+impl ImageType {
+  /// The offset in the image of the block a block check compares.
+  pub fn block_check_offset(self) -> usize {
+    match self {
+      ImageType::Bin => 0x9320,
+      ImageType::Gi => 0x80A0,
+    }
+  }
+}
+
+/// The versions of the format.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Version {
+  /// PPF1, with 32-bit offsets and nothing but records after the
+  /// description.
+  V1 = 1,
+  /// PPF2, with 32-bit offsets, the size of the image and a block check.
+  V2 = 2,
+  /// PPF3, with 64-bit offsets, an optional block check and optional undo
+  /// data.
+  #[default]
+  V3 = 3,
+}
+
+/// Builds a PPF patch out of records, which are written in the order
+/// they're added. Patches are PPF3 unless another version is chosen.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PpfBuilder {
+  version: Version,
+  description: String,
+  image_type: ImageType,
+  image_len: u32,
+  block_check: Option<Vec<u8>>,
+  undo_data: Option<Vec<u8>>,
+  records: Vec<(u64, Vec<u8>)>,
+}
+
+impl PpfBuilder {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Sets the description in the patch's header, which is cut off after
+  /// 50 bytes.
+  pub fn description(mut self, description: &str) -> Self {
+    self.description = description.to_owned();
+    self
+  }
+
+  pub fn version(mut self, version: Version) -> Self {
+    self.version = version;
+    self
+  }
+
+  pub fn image_type(mut self, image_type: ImageType) -> Self {
+    self.image_type = image_type;
+    self
+  }
+
+  /// Adds a block check with the bytes of `image` at the image type's block
+  /// check offset, so that the patch only applies to images with those
+  /// bytes. PPF2 patches also record the size of `image`.
+  ///
+  /// # Panics
+  ///
+  /// If `image` is too short to hold the block.
+  pub fn block_check(mut self, image: &[u8]) -> Self {
+    let offset = self.image_type.block_check_offset();
+    let block = image
+      .get(offset..offset + BLOCK_CHECK_LEN)
+      .expect("the image is too short for a block check");
+    self.block_check = Some(block.to_vec());
+    self.image_len = u32::try_from(image.len()).unwrap_or(u32::MAX);
+    self
+  }
+
+  /// Adds undo data to each record: the bytes of `image` it overwrites, so
+  /// that the patch can be reversed to get `image` back. Only PPF3 patches
+  /// can hold undo data.
+  pub fn undo_data(mut self, image: &[u8]) -> Self {
+    self.undo_data = Some(image.to_vec());
+    self
+  }
+
+  /// Adds a record that writes `bytes` at `offset`.
+  ///
+  /// # Panics
+  ///
+  /// If `bytes` is empty or longer than 255 bytes.
+  pub fn hunk(mut self, offset: u64, bytes: &[u8]) -> Self {
+    assert!(!bytes.is_empty(), "PPF hunks write at least one byte");
+    assert!(bytes.len() <= 255, "PPF hunks are at most 255 bytes");
+    self.records.push((offset, bytes.to_vec()));
+    self
+  }
+
+  /// # Panics
+  ///
+  /// If the version can't hold an offset, the block check or the undo data
+  /// the patch has, if a PPF2 patch has no block check, or if a record
+  /// overwrites bytes past the end of the image undo data was added from.
+  pub fn build(self) -> Vec<u8> {
+    let version = self.version as u8;
+    let mut patch = format!("PPF{version}0").into_bytes();
+    // The encoding method, which is one less than the version.
+    patch.push(version - 1);
+    let mut description = [b' '; 50];
+    let len = self.description.len().min(description.len());
+    description[..len].copy_from_slice(&self.description.as_bytes()[..len]);
+    patch.extend_from_slice(&description);
+    match self.version {
+      Version::V1 => assert!(
+        self.block_check.is_none(),
+        "PPF1 patches have no block check"
+      ),
+      Version::V2 => {
+        assert!(self.block_check.is_some(), "PPF2 patches have a block check");
+        patch.extend_from_slice(&self.image_len.to_le_bytes());
+      }
+      Version::V3 => patch.extend_from_slice(&[
+        self.image_type as u8,
+        self.block_check.is_some() as u8,
+        self.undo_data.is_some() as u8,
+        0, // unused
+      ]),
+    }
+    assert!(
+      self.undo_data.is_none() || self.version == Version::V3,
+      "only PPF3 patches have undo data"
+    );
+    if let Some(block) = &self.block_check {
+      patch.extend_from_slice(block);
+    }
+    for (offset, bytes) in &self.records {
+      match self.version {
+        Version::V3 => patch.extend_from_slice(&offset.to_le_bytes()),
+        _ => {
+          let offset = u32::try_from(*offset).expect("PPF1 and PPF2 offsets are 32-bit");
+          patch.extend_from_slice(&offset.to_le_bytes());
+        }
+      }
+      patch.push(bytes.len() as u8);
+      patch.extend_from_slice(bytes);
+      if let Some(image) = &self.undo_data {
+        let start = *offset as usize;
+        let undo = image
+          .get(start..start + bytes.len())
+          .expect("a record overwrites bytes past the end of the image");
+        patch.extend_from_slice(undo);
+      }
+    }
+    patch
+  }
+}
@@ -0,0 +1,58 @@
+//! Synthetic ROMs.
+//!
+//! The bytes of each ROM depend only on its arguments, so a test that fails
+//! fails the same way every time.
+
+/// A ROM of `len` bytes counting up from 0 and wrapping around after 0xFF,
+/// so that each byte's value shows where it came from.
+pub fn sequential(len: usize) -> Vec<u8> {
+  (0..len).map(|i| i as u8).collect()
+}
+
+/// A ROM of `len` copies of `byte`, like the padding at the end of a
+/// cartridge dump.
+pub fn filled(len: usize, byte: u8) -> Vec<u8> {
+  vec![byte; len]
+}
+
+/// A ROM of `len` pseudorandom bytes. Different seeds give different ROMs,
+/// and ROMs made from the same seed start with the same bytes.
+pub fn random(len: usize, seed: u64) -> Vec<u8> {
+  // xorshift64*, seeded through a step of splitmix64 so that every seed
+  // gives a different state, which can't be zero.
+  let mut state = {
+    let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    (z ^ (z >> 31)).max(1)
+  };
+  let mut next = || {
+    state ^= state >> 12;
+    state ^= state << 25;
+    state ^= state >> 27;
+    state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+  };
+  let mut rom = Vec::with_capacity(len);
+  while rom.len() < len {
+    let bytes = next().to_le_bytes();
+    rom.extend_from_slice(&bytes[..bytes.len().min(len - rom.len())]);
+  }
+  rom
+}
+
+/// Returns a copy of `rom` with `header` in front of it, like a dump from a
+/// copier that adds a header.
+pub fn with_header(header: &[u8], rom: &[u8]) -> Vec<u8> {
+  [header, rom].concat()
+}
+
+/// Returns a copy of `rom` with `bytes` written at `offset`, extending it if
+/// they go past its end.
+pub fn with_bytes(rom: &[u8], offset: usize, bytes: &[u8]) -> Vec<u8> {
+  let mut rom = rom.to_vec();
+  if rom.len() < offset + bytes.len() {
+    rom.resize(offset + bytes.len(), 0);
+  }
+  rom[offset..][..bytes.len()].copy_from_slice(bytes);
+  rom
+}
@@ -0,0 +1,68 @@
+//! Building UPS patches.
+
+use crate::varint;
+
+/// Builds a UPS patch that turns a source into a target. Since a UPS patch
+/// records the checksums of both, the source is needed to build one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpsBuilder {
+  source: Vec<u8>,
+  target: Vec<u8>,
+}
+
+impl UpsBuilder {
+  /// Starts a patch that leaves `source` unchanged.
+  pub fn new(source: &[u8]) -> Self {
+    Self { source: source.to_vec(), target: source.to_vec() }
+  }
+
+  /// Writes `bytes` at `offset` of the target, extending it with zeroes if
+  /// they go past its end.
+  pub fn hunk(mut self, offset: usize, bytes: &[u8]) -> Self {
+    if self.target.len() < offset + bytes.len() {
+      self.target.resize(offset + bytes.len(), 0);
+    }
+    self.target[offset..][..bytes.len()].copy_from_slice(bytes);
+    self
+  }
+
+  /// Truncates or extends the target to `len` bytes.
+  pub fn resize(mut self, len: usize) -> Self {
+    self.target.resize(len, 0);
+    self
+  }
+
+  /// The file the patch turns the source into.
+  pub fn target(&self) -> &[u8] {
+    &self.target
+  }
+
+  pub fn build(self) -> Vec<u8> {
+    let Self { source, target } = self;
+    let mut patch = b"UPS1".to_vec();
+    varint::write(&mut patch, source.len() as u64);
+    varint::write(&mut patch, target.len() as u64);
+    // Bytes past the end of either file are read as zero.
+    let byte = |file: &[u8], i: usize| file.get(i).copied().unwrap_or(0);
+    let len = source.len().max(target.len());
+    let mut last_end = 0;
+    let mut i = 0;
+    while i < len {
+      if byte(&source, i) == byte(&target, i) {
+        i += 1;
+        continue;
+      }
+      varint::write(&mut patch, (i - last_end) as u64);
+      while i < len && byte(&source, i) != byte(&target, i) {
+        patch.push(byte(&source, i) ^ byte(&target, i));
+        i += 1;
+      }
+      // Each hunk ends with a zero, which also skips the byte after it.
+      patch.push(0);
+      i += 1;
+      last_end = i;
+    }
+    varint::write_footer(&mut patch, &source, &target);
+    patch
+  }
+}
@@ -0,0 +1,24 @@
+/// Appends `value` to `out` as a UPS or BPS varint.
+pub fn write(out: &mut Vec<u8>, mut value: u64) {
+  loop {
+    let byte = (value & 0x7F) as u8;
+    value >>= 7;
+    if value == 0 {
+      out.push(byte | 0x80);
+      return;
+    }
+    out.push(byte);
+    // BPS and UPS subtract 1 after encoding each byte, so that every value
+    // has a single encoding.
+    value -= 1;
+  }
+}
+
+/// Appends the footer UPS and BPS patches end with: the CRC32s of `source`
+/// and `target`, and then of the patch up to that point.
+pub fn write_footer(out: &mut Vec<u8>, source: &[u8], target: &[u8]) {
+  out.extend_from_slice(&crc32fast::hash(source).to_le_bytes());
+  out.extend_from_slice(&crc32fast::hash(target).to_le_bytes());
+  let patch_crc32 = crc32fast::hash(out);
+  out.extend_from_slice(&patch_crc32.to_le_bytes());
+}